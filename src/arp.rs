@@ -0,0 +1,161 @@
+use crate::packet::{EthernetFrame, ETHERNET_HEADER_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+pub const PACKET_SIZE: usize = 28;
+
+// arp packet for ipv4 over ethernet, see RFC 826:
+// 2 bytes hardware type
+// 2 bytes protocol type
+// 1 byte hardware address length
+// 1 byte protocol address length
+// 2 bytes operation
+// 6 bytes sender hardware address
+// 4 bytes sender protocol address
+// 6 bytes target hardware address
+// 4 bytes target protocol address
+
+fn is_ipv4_over_ethernet(packet: &[u8]) -> bool {
+    packet.len() >= PACKET_SIZE
+        && u16::from_be_bytes([packet[0], packet[1]]) == HARDWARE_TYPE_ETHERNET
+        && u16::from_be_bytes([packet[2], packet[3]]) == ETHERTYPE_IPV4
+        && packet[4] == 6
+        && packet[5] == 4
+}
+
+/// Whether the ARP packet is a request for `address`
+pub fn is_request_for(packet: &[u8], address: [u8; 4]) -> bool {
+    is_ipv4_over_ethernet(packet)
+        && u16::from_be_bytes([packet[6], packet[7]]) == OPERATION_REQUEST
+        && packet[24..28] == address
+}
+
+/// Builds the reply to the ARP request `request`, returns the length of the frame written to `out`
+pub fn build_reply(request: &[u8], out: &mut [u8], mac: [u8; 6], address: [u8; 4]) -> usize {
+    let mut requester_mac = [0; 6];
+    requester_mac.copy_from_slice(&request[8..14]);
+    let mut frame = EthernetFrame::new_checked(&mut out[..]).unwrap();
+    frame.set_destination(requester_mac);
+    frame.set_source(mac);
+    frame.set_ethertype(ETHERTYPE_ARP);
+
+    let reply = &mut out[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + PACKET_SIZE];
+    reply[0..6].copy_from_slice(&request[0..6]);
+    reply[6..8].copy_from_slice(&OPERATION_REPLY.to_be_bytes());
+    reply[8..14].copy_from_slice(&mac);
+    reply[14..18].copy_from_slice(&address);
+    reply[18..28].copy_from_slice(&request[8..18]);
+    ETHERNET_HEADER_SIZE + PACKET_SIZE
+}
+
+/// Whether the ARP packet is a reply
+pub fn is_reply(packet: &[u8]) -> bool {
+    is_ipv4_over_ethernet(packet) && u16::from_be_bytes([packet[6], packet[7]]) == OPERATION_REPLY
+}
+
+/// The sender's protocol and hardware address, `None` if the packet is not for IPv4 over ethernet
+pub fn sender(packet: &[u8]) -> Option<([u8; 4], [u8; 6])> {
+    if !is_ipv4_over_ethernet(packet) {
+        return None;
+    }
+    let mut address = [0; 4];
+    address.copy_from_slice(&packet[14..18]);
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&packet[8..14]);
+    Some((address, mac))
+}
+
+/// Builds a request for the hardware address of `target`, returns the length of the frame written to `out`
+///
+/// Requests are broadcast, unless `target_mac` is given to validate a cached address with a unicast
+/// request, see RFC 1122 section 2.3.2.1.
+pub fn build_request(
+    out: &mut [u8],
+    mac: [u8; 6],
+    address: [u8; 4],
+    target: [u8; 4],
+    target_mac: Option<[u8; 6]>,
+) -> usize {
+    let mut frame = EthernetFrame::new_checked(&mut out[..]).unwrap();
+    frame.set_destination(target_mac.unwrap_or([0xff; 6]));
+    frame.set_source(mac);
+    frame.set_ethertype(ETHERTYPE_ARP);
+
+    let request = &mut out[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + PACKET_SIZE];
+    request[0..2].copy_from_slice(&HARDWARE_TYPE_ETHERNET.to_be_bytes());
+    request[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    request[4] = 6;
+    request[5] = 4;
+    request[6..8].copy_from_slice(&OPERATION_REQUEST.to_be_bytes());
+    request[8..14].copy_from_slice(&mac);
+    request[14..18].copy_from_slice(&address);
+    request[18..24].copy_from_slice(&[0; 6]);
+    request[24..28].copy_from_slice(&target);
+    ETHERNET_HEADER_SIZE + PACKET_SIZE
+}
+
+/// Builds a gratuitous ARP request announcing that `address` is at `mac`, see RFC 5227 section 3
+///
+/// Returns the length of the frame written to `out`.
+pub fn build_gratuitous(out: &mut [u8], mac: [u8; 6], address: [u8; 4]) -> usize {
+    build_request(out, mac, address, address, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0, 0, 1];
+    const ADDRESS: [u8; 4] = [10, 0, 1, 1];
+    const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x05];
+    const HOST: [u8; 4] = [10, 0, 1, 5];
+
+    fn request(target_mac: Option<[u8; 6]>) -> Vec<u8> {
+        let mut out = vec![0; ETHERNET_HEADER_SIZE + PACKET_SIZE];
+        assert_eq!(build_request(&mut out, MAC, ADDRESS, HOST, target_mac), out.len());
+        out
+    }
+
+    #[test]
+    fn broadcasts_request_unless_validating_cached_address() {
+        let broadcast = request(None);
+        let frame = EthernetFrame::new_checked(&broadcast[..]).unwrap();
+        assert_eq!((frame.destination(), frame.source()), ([0xff; 6], MAC));
+        assert_eq!(frame.ethertype(), ETHERTYPE_ARP);
+        let packet = frame.payload();
+        assert!(is_request_for(packet, HOST));
+        assert!(!is_request_for(packet, ADDRESS));
+        assert!(!is_reply(packet));
+        assert_eq!(sender(packet), Some((ADDRESS, MAC)));
+
+        let unicast = request(Some(HOST_MAC));
+        assert_eq!(EthernetFrame::new_checked(&unicast[..]).unwrap().destination(), HOST_MAC);
+        assert_eq!(unicast[ETHERNET_HEADER_SIZE..], broadcast[ETHERNET_HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn replies_to_requester() {
+        let mut request = vec![0; ETHERNET_HEADER_SIZE + PACKET_SIZE];
+        build_request(&mut request, HOST_MAC, HOST, ADDRESS, None);
+        let mut reply = vec![0; ETHERNET_HEADER_SIZE + PACKET_SIZE];
+        build_reply(&request[ETHERNET_HEADER_SIZE..], &mut reply, MAC, ADDRESS);
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!((frame.destination(), frame.source()), (HOST_MAC, MAC));
+        let packet = frame.payload();
+        assert!(is_reply(packet));
+        assert_eq!(sender(packet), Some((ADDRESS, MAC)));
+        assert_eq!(packet[18..24], HOST_MAC);
+        assert_eq!(packet[24..28], HOST);
+    }
+
+    #[test]
+    fn ignores_other_hardware_and_protocols() {
+        let mut packet = request(None)[ETHERNET_HEADER_SIZE..].to_vec();
+        packet[5] = 16;
+        assert_eq!(sender(&packet), None);
+        assert!(!is_request_for(&packet, HOST));
+        assert_eq!(sender(&packet[..PACKET_SIZE - 1]), None);
+    }
+}
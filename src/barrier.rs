@@ -0,0 +1,52 @@
+//! Memory barriers for sharing memory with the virtio devices, see the Arm ARM section B2.3.
+//!
+//! The devices access RAM coherently within the inner shareable domain, like another core, so
+//! `dmb ish` variants suffice between accesses to the rings. MMIO registers are device memory in
+//! the outer shareable domain. Every barrier is also a compiler barrier.
+//!
+//! Host tests simulate the devices on the testing thread, so there the barriers only keep the
+//! compiler from reordering the accesses.
+
+#[cfg(test)]
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Orders earlier writes to shared memory before later ones, e.g. descriptors before the ring index
+pub fn write_barrier() {
+    #[cfg(not(test))]
+    unsafe { asm!("dmb ishst", options(nostack, preserves_flags)) };
+    #[cfg(test)]
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Orders earlier reads from shared memory before later ones, e.g. the ring index before its entries
+pub fn read_barrier() {
+    #[cfg(not(test))]
+    unsafe { asm!("dmb ishld", options(nostack, preserves_flags)) };
+    #[cfg(test)]
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Orders earlier reads and writes before later ones, e.g. a flag write before re-reading the ring
+pub fn memory_barrier() {
+    #[cfg(not(test))]
+    unsafe { asm!("dmb ish", options(nostack, preserves_flags)) };
+    #[cfg(test)]
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Orders writes to shared memory before a later MMIO write, e.g. the ring index before the notification
+pub fn io_write_barrier() {
+    #[cfg(not(test))]
+    unsafe { asm!("dmb oshst", options(nostack, preserves_flags)) };
+    #[cfg(test)]
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Waits until all earlier memory accesses completed, e.g. translation table writes before enabling the MMU
+#[cfg_attr(test, allow(dead_code))]
+pub fn system_barrier() {
+    #[cfg(not(test))]
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+    #[cfg(test)]
+    compiler_fence(Ordering::SeqCst);
+}
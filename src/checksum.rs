@@ -0,0 +1,41 @@
+/// Adds `data` to a ones' complement sum, see RFC 1071
+pub fn add(data: &[u8], initial: u32) -> u32 {
+    let mut sum = initial;
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum
+}
+
+/// Folds a ones' complement sum into the final checksum
+pub fn finish(sum: u32) -> u16 {
+    let mut sum = sum;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Computes the internet checksum over `data`
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    finish(add(data, 0))
+}
+
+/// Updates `checksum` after a 16 bit word of the covered data changed, see RFC 1624 section 3
+pub fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    finish(!checksum as u32 + !old as u32 + new as u32)
+}
+
+/// The ones' complement sum of the IPv6 pseudo-header, see RFC 8200 section 8.1
+pub fn ipv6_pseudo_header(source: [u8; 16], destination: [u8; 16], len: usize, next_header: u8) -> u32 {
+    let sum = add(&source, 0);
+    let sum = add(&destination, sum);
+    let sum = add(&(len as u32).to_be_bytes(), sum);
+    add(&[0, 0, 0, next_header], sum)
+}
@@ -0,0 +1,193 @@
+use crate::routing::{Ipv4Route, Ipv6Route, RouteTarget};
+use core::time::Duration;
+
+/// Number of ICMP errors that may be sent back to back
+pub const ICMP_RATE_LIMIT_BURST: u32 = 10;
+/// Time until the ICMP rate limit allows one more error
+pub const ICMP_RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Distributor, GICv2 CPU interface and GICv3 redistributors of the QEMU virt machine
+#[cfg_attr(test, allow(dead_code))]
+pub const GICD_ADDRESS: usize = 0x08000000;
+#[cfg_attr(test, allow(dead_code))]
+pub const GICC_ADDRESS: usize = 0x08010000;
+#[cfg_attr(test, allow(dead_code))]
+pub const GICR_ADDRESS: usize = 0x080a0000;
+/// The QEMU virt machine's virtio-mmio transports, each 0x200 bytes with an SPI of its own
+#[cfg_attr(test, allow(dead_code))]
+pub const VIRTIO_MMIO_ADDRESS: usize = 0x0a000000;
+#[cfg_attr(test, allow(dead_code))]
+pub const VIRTIO_MMIO_SIZE: usize = 0x200;
+#[cfg_attr(test, allow(dead_code))]
+pub const VIRTIO_MMIO_FIRST_INTERRUPT: u32 = 48;
+/// QEMU passes the device tree at the start of RAM to ELF images loaded above it
+#[cfg_attr(test, allow(dead_code))]
+pub const DEVICE_TREE_ADDRESS: usize = 0x40000000;
+/// The non-secure EL1 physical timer's PPI on the QEMU virt machine
+#[cfg_attr(test, allow(dead_code))]
+pub const TIMER_INTERRUPT: u32 = 30;
+/// Time without a received frame before the router waits for interrupts
+#[cfg_attr(test, allow(dead_code))]
+pub const IDLE_TIME_BEFORE_SLEEP: Duration = Duration::from_millis(10);
+/// Interval of the neighbor discovery and router advertisement timers of the ports
+#[cfg_attr(test, allow(dead_code))]
+pub const PORT_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+/// Cores forwarding frames, if QEMU has as many, see aarch64-qemu.ld for their stacks
+pub const MAX_CORES: usize = 4;
+/// Interval at which idle secondary cores poll, the devices' interrupts only wake the first core
+#[cfg_attr(test, allow(dead_code))]
+pub const SECONDARY_IDLE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Static configuration of a single router port.
+pub struct PortConfig {
+    /// Base address of the port's virtio-mmio register block
+    pub mmio_address: usize,
+    /// MTU used if the device does not offer VIRTIO_NET_F_MTU, and the largest MTU used if it does
+    pub mtu: u16,
+    /// Receive and transmit queue pairs used if the device offers VIRTIO_NET_F_MQ
+    ///
+    /// Every core polls the pairs whose index modulo the number of cores is its own, so forwarding
+    /// scales up to as many cores as pairs. Every pair of 1024 descriptor queues takes about 3 MiB of
+    /// the 16 MiB virtqueue memory.
+    pub queue_pairs: u16,
+    /// Descriptors per receive and transmit queue, a power of two up to 32768
+    ///
+    /// Lowered to the largest power of two within the device's QueueNumMax, e.g. 256 for some vhost-user backends.
+    pub queue_size: u16,
+    /// Keeps the device receiving frames to any MAC address, if it offers VIRTIO_NET_F_CTRL_RX
+    ///
+    /// Otherwise only frames to the port's MAC address, broadcasts and its IPv6 multicast groups are received.
+    pub promiscuous: bool,
+    /// The router's address on the attached network
+    pub ipv4_address: [u8; 4],
+    /// The router's global IPv6 address on the attached network
+    pub ipv6_address: [u8; 16],
+    /// Router advertisements sent on downstream ports, `None` on upstream ports
+    pub router_advertisement: Option<RouterAdvertisementConfig>,
+}
+
+/// Contents of the router advertisements of a port, see RFC 4861 section 6.2.1
+#[derive(Clone, Copy, Debug)]
+pub struct RouterAdvertisementConfig {
+    /// Seconds hosts may use the router as default router, 0 if it is no default router
+    pub router_lifetime: u16,
+    /// On-link prefixes for stateless address autoconfiguration
+    pub prefixes: &'static [PrefixConfig],
+    /// Recursive DNS servers, see RFC 8106
+    pub dns_servers: &'static [[u8; 16]],
+    /// Seconds hosts may use the DNS servers
+    pub dns_lifetime: u32,
+}
+
+/// A prefix advertised in a prefix information option, see RFC 4861 section 4.6.2
+#[derive(Clone, Copy, Debug)]
+pub struct PrefixConfig {
+    pub prefix: [u8; 16],
+    pub prefix_len: u8,
+    /// Seconds the prefix is valid
+    pub valid_lifetime: u32,
+    /// Seconds addresses generated from the prefix remain preferred
+    pub preferred_lifetime: u32,
+}
+
+/// The router's ports, routes refer to them by index
+pub const PORTS: &[PortConfig] = &[
+    // Downstream
+    PortConfig {
+        mmio_address: 0x000000000a003e00,
+        mtu: 1500,
+        queue_pairs: 2,
+        queue_size: 1024,
+        promiscuous: false,
+        ipv4_address: [10, 0, 0, 1],
+        ipv6_address: [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        router_advertisement: Some(RouterAdvertisementConfig {
+            router_lifetime: 1800,
+            prefixes: &[PrefixConfig {
+                prefix: [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                prefix_len: 64,
+                valid_lifetime: 2_592_000,
+                preferred_lifetime: 604_800,
+            }],
+            dns_servers: &[[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x53]],
+            dns_lifetime: 1800,
+        }),
+    },
+    // Upstream
+    PortConfig {
+        mmio_address: 0x000000000a003c00,
+        mtu: 1500,
+        queue_pairs: 1,
+        queue_size: 1024,
+        promiscuous: false,
+        ipv4_address: [10, 0, 1, 1],
+        ipv6_address: [0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        router_advertisement: None,
+    },
+];
+
+/// The upstream router's address on the upstream port's network
+pub const UPSTREAM_IPV4_GATEWAY: [u8; 4] = [10, 0, 1, 254];
+
+pub const IPV4_ROUTES: &[Ipv4Route] = &[
+    Ipv4Route {
+        prefix: [10, 0, 0, 0],
+        prefix_len: 24,
+        target: RouteTarget::Port(0),
+        gateway: None,
+    },
+    Ipv4Route {
+        prefix: [10, 0, 1, 0],
+        prefix_len: 24,
+        target: RouteTarget::Port(1),
+        gateway: None,
+    },
+    // Off-link destinations are reached through the upstream router
+    Ipv4Route {
+        prefix: [0, 0, 0, 0],
+        prefix_len: 0,
+        target: RouteTarget::Port(1),
+        gateway: Some(UPSTREAM_IPV4_GATEWAY),
+    },
+];
+
+/// The upstream router's address on the upstream port's network
+pub const UPSTREAM_IPV6_GATEWAY: [u8; 16] = [0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfe];
+
+pub const IPV6_ROUTES: &[Ipv6Route] = &[
+    Ipv6Route {
+        prefix: [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        prefix_len: 64,
+        target: RouteTarget::Port(0),
+        gateway: None,
+    },
+    Ipv6Route {
+        prefix: [0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        prefix_len: 64,
+        target: RouteTarget::Port(1),
+        gateway: None,
+    },
+    // Off-link destinations are reached through the upstream router
+    Ipv6Route {
+        prefix: [0; 16],
+        prefix_len: 0,
+        target: RouteTarget::Port(1),
+        gateway: Some(UPSTREAM_IPV6_GATEWAY),
+    },
+];
+
+/// Time between neighbor solicitations for the same address, RetransTimer of RFC 4861 section 6.3.2
+pub const NDP_RETRANS_TIMER: Duration = Duration::from_secs(1);
+/// Time a neighbor is considered reachable after a confirmation, see RFC 4861 section 6.3.2
+pub const NDP_REACHABLE_TIME: Duration = Duration::from_secs(30);
+/// Time before a stale neighbor in use is probed, DELAY_FIRST_PROBE_TIME of RFC 4861 section 10
+pub const NDP_DELAY_FIRST_PROBE_TIME: Duration = Duration::from_secs(5);
+
+/// Time between unsolicited router advertisements, MinRtrAdvInterval and MaxRtrAdvInterval of
+/// RFC 4861 section 6.2.1
+pub const RA_MIN_INTERVAL: Duration = Duration::from_secs(200);
+pub const RA_MAX_INTERVAL: Duration = Duration::from_secs(600);
+/// Time between the first advertisements, MAX_INITIAL_RTR_ADVERT_INTERVAL of RFC 4861 section 10
+pub const RA_INITIAL_INTERVAL: Duration = Duration::from_secs(16);
+/// Time between multicast advertisements, MIN_DELAY_BETWEEN_RAS of RFC 4861 section 10
+pub const RA_MIN_DELAY_BETWEEN: Duration = Duration::from_secs(3);
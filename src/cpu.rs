@@ -0,0 +1,86 @@
+/// Unmasks IRQs at the current exception level
+#[cfg(not(test))]
+pub fn enable_irq() {
+    unsafe { asm!("msr daifclr, #2", options(nomem, nostack)) };
+}
+
+/// Masks IRQs at the current exception level
+#[cfg(not(test))]
+pub fn disable_irq() {
+    unsafe { asm!("msr daifset, #2", options(nomem, nostack)) };
+}
+
+/// Waits for an interrupt, pending interrupts wake the core even while they are masked
+#[cfg(not(test))]
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi", options(nomem, nostack)) };
+}
+
+/// Whether the GIC system register interface is implemented, i.e. the GIC is a GICv3 or later
+///
+/// See the GIC field of ID_AA64PFR0_EL1 in the Arm ARM, section D13.2.64.
+#[cfg(not(test))]
+pub fn has_gic_system_registers() -> bool {
+    let pfr0: u64;
+    unsafe { asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0, options(nomem, nostack)) };
+    (pfr0 >> 24) & 0xf != 0
+}
+
+/// Waits for an event, e.g. from `send_event` on another core
+#[cfg(not(test))]
+pub fn wait_for_event() {
+    unsafe { asm!("wfe", options(nomem, nostack)) };
+}
+
+/// Wakes all cores waiting in `wait_for_event`
+///
+/// Earlier writes are made visible first, so woken cores see them.
+#[cfg(not(test))]
+pub fn send_event() {
+    unsafe { asm!("dsb ishst", "sev", options(nostack)) };
+}
+
+/// Index of the calling core, its affinity level 0 in MPIDR_EL1
+///
+/// See the Arm ARM section D13.2.86. Cores of the QEMU virt machine are numbered from 0 in one cluster.
+#[cfg(not(test))]
+pub fn core_index() -> usize {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack)) };
+    (mpidr & 0xff) as usize
+}
+
+// Host tests run the router on the first core without interrupts, waiting for another core is spinning instead
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub fn enable_irq() {}
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub fn disable_irq() {}
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub fn wait_for_interrupt() {
+    core::hint::spin_loop();
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub fn has_gic_system_registers() -> bool {
+    false
+}
+
+#[cfg(test)]
+pub fn wait_for_event() {
+    core::hint::spin_loop();
+}
+
+#[cfg(test)]
+pub fn send_event() {}
+
+#[cfg(test)]
+pub fn core_index() -> usize {
+    0
+}
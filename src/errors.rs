@@ -5,6 +5,23 @@ pub enum DeviceInitializationError {
     InvalidMagicNumber(u32),
     InvalidVersion(u32),
     InvalidInterface(ReadMMIOInterfaceError),
+    Interrupt(InterruptRegistrationError),
+    /// The device set DEVICE_NEEDS_RESET or FAILED while it was initialized
+    Failed,
+    /// A control queue command needed for initialization failed
+    Control(ControlCommandError),
+    /// The configured queue size is no power of two up to 32768, see section 2.4
+    InvalidQueueSize(u32),
+    /// The virtqueue with this index already has a QueuePFN
+    QueueInUse(u32),
+    /// The device reports a QueueNumMax of 0 for the virtqueue with this index
+    QueueUnavailable(u32),
+    /// A modern device cleared FEATURES_OK, it does not support the negotiated features
+    FeaturesRejected,
+    /// The MTU is below the 68 bytes every IPv4 link carries, see RFC 791
+    InvalidMtu(u16),
+    /// The virtqueues do not fit into the remaining virtqueue memory
+    Memory(MemoryReservationError),
 }
 
 #[derive(Debug)]
@@ -18,3 +35,91 @@ pub enum ReadMMIOInterfaceError {
 pub enum MemoryReservationError {
     MemoryExhausted,
 }
+
+/// Reasons to drop an IPv4 packet, see RFC 1812 section 5.2.2 and 5.3.7
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum Ipv4ValidationError {
+    Truncated,
+    InvalidVersion(u8),
+    InvalidHeaderLength(u8),
+    InvalidTotalLength(u16),
+    InvalidChecksum,
+    MartianSource([u8; 4]),
+    MartianDestination([u8; 4]),
+    /// Class D destinations are valid, but the router does not forward multicast
+    MulticastDestination([u8; 4]),
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum PacketError {
+    /// The buffer is shorter than the header or the length it claims
+    Truncated,
+    /// A length field is smaller than the header
+    InvalidLength,
+}
+
+/// Reasons to drop an IPv6 packet, see RFC 8200 and RFC 4291 section 2.5.6
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum Ipv6ValidationError {
+    Truncated,
+    InvalidVersion(u8),
+    InvalidPayloadLength(u16),
+    InvalidSource([u8; 16]),
+    InvalidDestination([u8; 16]),
+}
+
+/// Reasons a control virtqueue command failed, see section 5.1.6.5
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum ControlCommandError {
+    /// The control virtqueue or the feature the command depends on was not negotiated
+    Unsupported,
+    /// The command data does not fit into the command buffer
+    TooLarge,
+    /// The device did not use the command buffers in time, or still has those of an earlier command
+    Timeout,
+    /// The device answered with VIRTIO_NET_ERR
+    Rejected,
+}
+
+/// Reasons an interrupt handler could not be registered
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum InterruptRegistrationError {
+    /// The interrupt ID is one of the special IDs 1020 to 1023 or beyond
+    InvalidInterrupt(u32),
+    /// The handler table is full
+    TooManyHandlers,
+}
+
+/// Reasons a timer could not be scheduled or cancelled
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum TimerError {
+    /// The timer wheel is full
+    TooManyTimers,
+    /// The timer already fired or was cancelled
+    UnknownTimer,
+    /// Periodic timers need a period above zero
+    InvalidPeriod,
+}
+
+/// Error codes of PSCI functions, see the PSCI spec section 5.2.2
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    /// A code not defined by the spec
+    Unknown(i32),
+}
@@ -0,0 +1,138 @@
+use crate::gic;
+use crate::mmu;
+use crate::router;
+use crate::util;
+
+/// Registers and system registers saved by `exception_entry` in start.s
+#[repr(C)]
+pub struct ExceptionFrame {
+    x: [u64; 31],
+    elr: u64,
+    spsr: u64,
+    esr: u64,
+    far: u64,
+    /// Index of the vector that was taken
+    vector: u64,
+}
+
+// exception syndrome register, see the Arm ARM section D13.2.37:
+// 6 bits exception class
+// 1 bit instruction length
+// 25 bits instruction specific syndrome
+const EC_DATA_ABORT_LOWER_EL: u64 = 0x24;
+const EC_DATA_ABORT_SAME_EL: u64 = 0x25;
+const EC_INSTRUCTION_ABORT_LOWER_EL: u64 = 0x20;
+const EC_INSTRUCTION_ABORT_SAME_EL: u64 = 0x21;
+/// The FAR is not valid
+const ISS_ABORT_FNV: u64 = 1 << 10;
+/// The data abort was caused by a write
+const ISS_DATA_ABORT_WNR: u64 = 1 << 6;
+
+/// Called by the IRQ vectors in start.s with the caller-saved registers already saved
+#[no_mangle]
+pub extern "C" fn handle_irq() {
+    gic::handle_interrupts();
+}
+
+/// Called by every other vector in start.s, prints the exception and the registers
+///
+/// Powers the machine off afterwards, so test harnesses see a failure instead of a hang.
+#[no_mangle]
+pub extern "C" fn handle_exception(frame: &ExceptionFrame) -> ! {
+    let _ = util::print(format_args!(
+        "\n{} exception from {}\n",
+        exception_type(frame.vector),
+        exception_source(frame.vector)
+    ));
+    let class = frame.esr >> 26;
+    let syndrome = frame.esr & 0x1ffffff;
+    let _ = util::print(format_args!(
+        "ESR_EL1  0x{:016x} ({}",
+        frame.esr,
+        exception_class(class)
+    ));
+    let abort = match class {
+        EC_DATA_ABORT_LOWER_EL | EC_DATA_ABORT_SAME_EL | EC_INSTRUCTION_ABORT_LOWER_EL | EC_INSTRUCTION_ABORT_SAME_EL => {
+            true
+        }
+        _ => false,
+    };
+    if abort {
+        let _ = util::print(format_args!(": {}", fault_status(syndrome & 0x3f)));
+        if class == EC_DATA_ABORT_LOWER_EL || class == EC_DATA_ABORT_SAME_EL {
+            let access = if syndrome & ISS_DATA_ABORT_WNR != 0 { "write" } else { "read" };
+            let _ = util::print(format_args!(" on {}", access));
+        }
+    }
+    let _ = util::print(format_args!(")\n"));
+    if abort && syndrome & ISS_ABORT_FNV == 0 {
+        let _ = util::print(format_args!("FAR_EL1  0x{:016x}\n", frame.far));
+        if mmu::is_stack_guard(frame.far) {
+            let _ = util::print(format_args!("stack overflow\n"));
+        }
+    }
+    let _ = util::print(format_args!("ELR_EL1  0x{:016x}\n", frame.elr));
+    let _ = util::print(format_args!("SPSR_EL1 0x{:016x}\n", frame.spsr));
+    for (i, pair) in frame.x.chunks(2).enumerate() {
+        let _ = util::print(format_args!("x{:<2} 0x{:016x}", 2 * i, pair[0]));
+        if let Some(x) = pair.get(1) {
+            let _ = util::print(format_args!("  x{:<2} 0x{:016x}", 2 * i + 1, x));
+        }
+        let _ = util::print(format_args!("\n"));
+    }
+    router::shutdown()
+}
+
+fn exception_type(vector: u64) -> &'static str {
+    match vector % 4 {
+        0 => "Synchronous",
+        1 => "IRQ",
+        2 => "FIQ",
+        _ => "SError",
+    }
+}
+
+fn exception_source(vector: u64) -> &'static str {
+    match vector / 4 {
+        0 => "the current EL using SP_EL0",
+        1 => "the current EL using SP_ELx",
+        2 => "a lower EL using AArch64",
+        _ => "a lower EL using AArch32",
+    }
+}
+
+/// See the Arm ARM section D13.2.37
+fn exception_class(class: u64) -> &'static str {
+    match class {
+        0x00 => "unknown reason",
+        0x01 => "trapped WFI or WFE",
+        0x0e => "illegal execution state",
+        0x15 => "SVC",
+        0x16 => "HVC",
+        0x17 => "SMC",
+        0x18 => "trapped MSR, MRS or system instruction",
+        EC_INSTRUCTION_ABORT_LOWER_EL => "instruction abort from a lower EL",
+        EC_INSTRUCTION_ABORT_SAME_EL => "instruction abort from the current EL",
+        0x22 => "PC alignment fault",
+        EC_DATA_ABORT_LOWER_EL => "data abort from a lower EL",
+        EC_DATA_ABORT_SAME_EL => "data abort from the current EL",
+        0x26 => "SP alignment fault",
+        0x2f => "SError interrupt",
+        0x3c => "BRK instruction",
+        _ => "unexpected exception class",
+    }
+}
+
+/// Decodes the data or instruction fault status code of an abort
+fn fault_status(code: u64) -> &'static str {
+    match code {
+        0x00..=0x03 => "address size fault",
+        0x04..=0x07 => "translation fault",
+        0x09..=0x0b => "access flag fault",
+        0x0d..=0x0f => "permission fault",
+        0x10 => "synchronous external abort",
+        0x21 => "alignment fault",
+        0x30 => "TLB conflict abort",
+        _ => "unexpected fault status",
+    }
+}
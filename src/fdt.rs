@@ -0,0 +1,262 @@
+use core::convert::TryInto;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const HEADER_SIZE: usize = 40;
+
+// flattened device tree header, see the Devicetree Specification section 5.2:
+// 4 bytes magic
+// 4 bytes total size
+// 4 bytes offset of the structure block
+// 4 bytes offset of the strings block
+// 4 bytes offset of the memory reservation block
+// 4 bytes version
+// 4 bytes last compatible version
+// 4 bytes physical ID of the boot core
+// 4 bytes size of the strings block
+// 4 bytes size of the structure block
+//
+// property, following an FDT_PROP token:
+// 4 bytes value length
+// 4 bytes offset of the name in the strings block
+// value, padded to 4 bytes
+
+/// A flattened device tree blob, as passed by the boot loader
+///
+/// All fields are big endian, every access is bounds checked.
+pub struct DeviceTree {
+    blob: &'static [u8],
+}
+
+impl DeviceTree {
+    /// The device tree at `address`, `None` if there is no valid header
+    ///
+    /// QEMU places it at the start of RAM when booting an ELF image that does not cover it.
+    pub fn at(address: usize) -> Option<DeviceTree> {
+        let header = unsafe { core::slice::from_raw_parts(address as *const u8, HEADER_SIZE) };
+        if read_u32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let size = read_u32(header, 4)? as usize;
+        if size < HEADER_SIZE {
+            return None;
+        }
+        Some(DeviceTree {
+            blob: unsafe { core::slice::from_raw_parts(address as *const u8, size) },
+        })
+    }
+
+    /// The value of `property` of the first node named `node`, with or without a unit address
+    ///
+    /// Nodes are searched at any depth, string values include their terminating NUL.
+    pub fn property(&self, node: &str, property: &str) -> Option<&'static [u8]> {
+        let blob = self.blob;
+        let structure = read_u32(blob, 8)? as usize;
+        let strings = read_u32(blob, 12)? as usize;
+        let mut offset = structure;
+        // Depth of the innermost node named `node` the walk is in, if any
+        let mut depth = 0u32;
+        let mut matched_depth = None;
+        loop {
+            let token = read_u32(blob, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_str(blob, offset)?;
+                    offset = align(offset + name.len() + 1);
+                    depth += 1;
+                    let base_name = name.split(|&byte| byte == b'@').next()?;
+                    if matched_depth.is_none() && base_name == node.as_bytes() {
+                        matched_depth = Some(depth);
+                    }
+                }
+                FDT_END_NODE => {
+                    if matched_depth == Some(depth) {
+                        // The properties of a node precede its children
+                        return None;
+                    }
+                    // An unbalanced FDT_END_NODE makes the blob invalid
+                    depth = depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let len = read_u32(blob, offset)? as usize;
+                    let name_offset = read_u32(blob, offset + 4)? as usize;
+                    let value = blob.get(offset + 8..offset + 8 + len)?;
+                    offset = align(offset + 8 + len);
+                    if matched_depth == Some(depth) && read_str(blob, strings + name_offset)? == property.as_bytes() {
+                        return Some(value);
+                    }
+                }
+                FDT_NOP => {}
+                // FDT_END, or a token this parser does not know
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn read_u32(blob: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(blob.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// The NUL terminated string at `offset`, without the NUL
+fn read_str(blob: &[u8], offset: usize) -> Option<&[u8]> {
+    let rest = blob.get(offset..)?;
+    let len = rest.iter().position(|&byte| byte == 0)?;
+    Some(&rest[..len])
+}
+
+/// Tokens are aligned to 4 bytes
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FDT_END: u32 = 9;
+
+    /// Writes a device tree blob, the strings block follows the structure block
+    struct Builder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new() -> Builder {
+            Builder {
+                structure: Vec::new(),
+                strings: Vec::new(),
+            }
+        }
+
+        fn token(&mut self, token: u32) -> &mut Builder {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn begin_node(&mut self, name: &str) -> &mut Builder {
+            self.token(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.structure.resize(align(self.structure.len()), 0);
+            self
+        }
+
+        fn end_node(&mut self) -> &mut Builder {
+            self.token(FDT_END_NODE)
+        }
+
+        fn property(&mut self, name: &str, value: &[u8]) -> &mut Builder {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP);
+            self.structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            self.structure.extend_from_slice(&name_offset.to_be_bytes());
+            self.structure.extend_from_slice(value);
+            self.structure.resize(align(self.structure.len()), 0);
+            self
+        }
+
+        fn finish(&mut self) -> &'static [u8] {
+            self.token(FDT_END);
+            let structure = HEADER_SIZE;
+            let strings = structure + self.structure.len();
+            let size = strings + self.strings.len();
+            let mut blob = vec![0; HEADER_SIZE];
+            for (i, field) in [FDT_MAGIC, size as u32, structure as u32, strings as u32].iter().enumerate() {
+                blob[i * 4..i * 4 + 4].copy_from_slice(&field.to_be_bytes());
+            }
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+            Box::leak(blob.into_boxed_slice())
+        }
+    }
+
+    /// A tree like QEMU's, with the psci node named `psci_name`
+    fn qemu_tree(psci_name: &str) -> &'static [u8] {
+        Builder::new()
+            .begin_node("")
+            .property("#address-cells", &2u32.to_be_bytes())
+            .begin_node("cpus")
+            .begin_node("cpu@0")
+            .property("enable-method", b"psci\0")
+            .end_node()
+            .end_node()
+            .begin_node(psci_name)
+            .property("compatible", b"arm,psci-1.0\0")
+            .property("method", b"smc\0")
+            .end_node()
+            .end_node()
+            .finish()
+    }
+
+    #[test]
+    fn finds_property_with_and_without_unit_address() {
+        for &name in &["psci", "psci@0"] {
+            let tree = DeviceTree::at(qemu_tree(name).as_ptr() as usize).unwrap();
+            assert_eq!(tree.property("psci", "method"), Some(&b"smc\0"[..]));
+            assert_eq!(tree.property("psci", "compatible"), Some(&b"arm,psci-1.0\0"[..]));
+            assert_eq!(tree.property("cpu", "enable-method"), Some(&b"psci\0"[..]));
+            assert_eq!(tree.property("psci", "cpu_on"), None);
+        }
+        let tree = DeviceTree::at(qemu_tree("psci-1").as_ptr() as usize).unwrap();
+        assert_eq!(tree.property("psci", "method"), None);
+    }
+
+    #[test]
+    fn ignores_properties_of_child_nodes() {
+        let blob = Builder::new()
+            .begin_node("")
+            .begin_node("psci")
+            .property("compatible", b"arm,psci-1.0\0")
+            .begin_node("child")
+            .property("method", b"hvc\0")
+            .end_node()
+            .end_node()
+            .begin_node("psci")
+            .property("method", b"smc\0")
+            .end_node()
+            .end_node()
+            .finish();
+        let tree = DeviceTree::at(blob.as_ptr() as usize).unwrap();
+        // Only the first psci node is searched
+        assert_eq!(tree.property("psci", "method"), None);
+        assert_eq!(tree.property("child", "method"), Some(&b"hvc\0"[..]));
+    }
+
+    #[test]
+    fn rejects_blob_without_magic() {
+        let mut blob = qemu_tree("psci").to_vec();
+        blob[0] = 0;
+        assert!(DeviceTree::at(blob.as_ptr() as usize).is_none());
+    }
+
+    #[test]
+    fn truncated_blob_has_no_properties() {
+        // The property name is the last string, so every truncation cuts into what the lookup reads
+        let blob = qemu_tree("psci");
+        assert!(DeviceTree { blob }.property("psci", "method").is_some());
+        for len in 0..blob.len() {
+            let tree = DeviceTree { blob: &blob[..len] };
+            assert_eq!(tree.property("psci", "method"), None, "{}", len);
+        }
+    }
+
+    #[test]
+    fn unbalanced_end_node_makes_blob_invalid() {
+        let blob = Builder::new()
+            .end_node()
+            .begin_node("psci")
+            .property("method", b"smc\0")
+            .end_node()
+            .finish();
+        let tree = DeviceTree::at(blob.as_ptr() as usize).unwrap();
+        assert_eq!(tree.property("psci", "method"), None);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::ipv4;
+use crate::packet::{Ipv4Packet, Ipv6Packet};
+
+/// The default key of Microsoft's RSS verification suite, also used by most NICs
+const RSS_KEY: [u8; 40] = [
+    0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0, 0xd0, 0xca, 0x2b,
+    0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac,
+    0x01, 0xfa,
+];
+
+/// Size of the longest hash input: two IPv6 addresses and two ports
+const MAX_INPUT_SIZE: usize = 36;
+
+/// Computes the Toeplitz hash of `input`, see the RSS section of the NDIS documentation
+///
+/// `input` must be at most `MAX_INPUT_SIZE` bytes.
+fn toeplitz(input: &[u8]) -> u32 {
+    let mut result = 0;
+    let mut window = u32::from_be_bytes([RSS_KEY[0], RSS_KEY[1], RSS_KEY[2], RSS_KEY[3]]);
+    for (i, byte) in input.iter().enumerate() {
+        let next = RSS_KEY[i + 4];
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                result ^= window;
+            }
+            window = (window << 1) | ((next >> (7 - bit)) & 1) as u32;
+        }
+    }
+    result
+}
+
+/// The source and destination port of a TCP or UDP payload
+///
+/// IPv6 next header values share the IPv4 protocol numbers.
+fn ports(protocol: u8, payload: &[u8]) -> Option<&[u8]> {
+    if protocol != ipv4::PROTOCOL_TCP && protocol != ipv4::PROTOCOL_UDP {
+        return None;
+    }
+    payload.get(..4)
+}
+
+/// Hashes the addresses of an IPv4 packet and, unless it is a fragment, its TCP or UDP ports
+///
+/// Packets of one flow get the same hash, so they stay on one queue pair and in order.
+pub fn ipv4_flow_hash(packet: &Ipv4Packet<&[u8]>) -> u32 {
+    let mut input = [0; MAX_INPUT_SIZE];
+    input[..4].copy_from_slice(&packet.source());
+    input[4..8].copy_from_slice(&packet.destination());
+    let mut len = 8;
+    // Only the first fragment carries the ports, so fragments are hashed by their addresses alone
+    if !packet.more_fragments() && packet.fragment_offset() == 0 {
+        if let Some(ports) = ports(packet.protocol(), packet.payload()) {
+            input[len..len + 4].copy_from_slice(ports);
+            len += 4;
+        }
+    }
+    toeplitz(&input[..len])
+}
+
+/// Hashes the addresses of an IPv6 packet and the TCP or UDP ports directly following its header
+///
+/// Packets with extension headers are hashed by their addresses alone.
+pub fn ipv6_flow_hash(packet: &Ipv6Packet<&[u8]>) -> u32 {
+    let mut input = [0; MAX_INPUT_SIZE];
+    input[..16].copy_from_slice(&packet.source());
+    input[16..32].copy_from_slice(&packet.destination());
+    let mut len = 32;
+    if let Some(ports) = ports(packet.next_header(), packet.payload()) {
+        input[len..len + 4].copy_from_slice(ports);
+        len += 4;
+    }
+    toeplitz(&input[..len])
+}
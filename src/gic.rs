@@ -0,0 +1,282 @@
+use crate::config;
+use crate::cpu;
+use crate::errors::InterruptRegistrationError;
+use register::{mmio::*, register_structs};
+
+/// Interrupt IDs from this one on are special, e.g. 1023 is returned if no interrupt is pending
+const SPECIAL_INTERRUPT_ID: u32 = 1020;
+/// Interrupt IDs below this one are private to a core (SGIs and PPIs)
+const FIRST_SPI: u32 = 32;
+const DEFAULT_PRIORITY: u8 = 0xa0;
+/// Interrupt handlers beyond this number are rejected
+const MAX_HANDLERS: usize = 16;
+
+const GICD_CTLR_ENABLE: u32 = 1;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICC_CTLR_ENABLE: u32 = 1;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+/// Size of a redistributor's frames, the SGI and PPI frame follows the control frame
+const GICR_STRIDE: usize = 0x20000;
+
+register_structs! {
+    /// Distributor, see GICv2 spec section 4.3 and GICv3 spec section 12.9
+    pub GicDistributorRegister {
+        (0x0000 => ctlr: ReadWrite<u32>),
+        (0x0004 => typer: ReadOnly<u32>),
+        (0x0008 => iidr: ReadOnly<u32>),
+        (0x000C => _reserved1),
+        (0x0080 => igroupr: [ReadWrite<u32>; 32]),
+        (0x0100 => isenabler: [ReadWrite<u32>; 32]),
+        (0x0180 => icenabler: [ReadWrite<u32>; 32]),
+        (0x0200 => _reserved2),
+        (0x0400 => ipriorityr: [ReadWrite<u8>; 1020]),
+        (0x07FC => _reserved3),
+        // GICv2 only
+        (0x0800 => itargetsr: [ReadWrite<u8>; 1020]),
+        (0x0BFC => _reserved4),
+        (0x0C00 => icfgr: [ReadWrite<u32>; 64]),
+        (0x0D00 => _reserved5),
+        // GICv3 only, with affinity routing enabled
+        (0x6000 => irouter: [ReadWrite<u64>; 1020]),
+        (0x7FE0 => @END),
+    },
+
+    /// GICv2 CPU interface, see GICv2 spec section 4.4
+    pub GicCpuInterfaceRegister {
+        (0x00 => ctlr: ReadWrite<u32>),
+        (0x04 => pmr: ReadWrite<u32>),
+        (0x08 => bpr: ReadWrite<u32>),
+        (0x0C => iar: ReadOnly<u32>),
+        (0x10 => eoir: WriteOnly<u32>),
+        (0x14 => @END),
+    },
+
+    /// GICv3 redistributor of one core, see GICv3 spec section 12.10 and 12.11
+    pub GicRedistributorRegister {
+        (0x00000 => ctlr: ReadWrite<u32>),
+        (0x00004 => iidr: ReadOnly<u32>),
+        (0x00008 => typer: ReadOnly<u64>),
+        (0x00010 => _reserved1),
+        (0x00014 => waker: ReadWrite<u32>),
+        (0x00018 => _reserved2),
+        (0x10080 => igroupr0: ReadWrite<u32>),
+        (0x10084 => _reserved3),
+        (0x10100 => isenabler0: ReadWrite<u32>),
+        (0x10104 => _reserved4),
+        (0x10180 => icenabler0: ReadWrite<u32>),
+        (0x10184 => _reserved5),
+        (0x10400 => ipriorityr: [ReadWrite<u8>; 32]),
+        (0x10420 => @END),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    /// Memory-mapped CPU interface
+    V2,
+    /// System register CPU interface and redistributors
+    V3,
+}
+
+#[derive(Clone, Copy)]
+struct Handler {
+    interrupt: u32,
+    handler: fn(usize),
+    context: usize,
+}
+
+/// Written during initialization and handler registration, which run with IRQs masked
+static mut VERSION: Version = Version::V2;
+static mut HANDLERS: [Option<Handler>; MAX_HANDLERS] = [None; MAX_HANDLERS];
+
+fn distributor() -> &'static GicDistributorRegister {
+    unsafe { &*(config::GICD_ADDRESS as *const GicDistributorRegister) }
+}
+
+fn cpu_interface() -> &'static GicCpuInterfaceRegister {
+    unsafe { &*(config::GICC_ADDRESS as *const GicCpuInterfaceRegister) }
+}
+
+fn redistributor(core: usize) -> &'static GicRedistributorRegister {
+    unsafe { &*((config::GICR_ADDRESS + core * GICR_STRIDE) as *const GicRedistributorRegister) }
+}
+
+pub fn version() -> Version {
+    unsafe { VERSION }
+}
+
+/// Initializes the distributor and the calling core's CPU interface, IRQs must be masked
+///
+/// All interrupts stay disabled until they are enabled with `enable`.
+pub fn initialize() {
+    let version = if cpu::has_gic_system_registers() {
+        Version::V3
+    } else {
+        Version::V2
+    };
+    unsafe { VERSION = version };
+    let distributor = distributor();
+    distributor.ctlr.set(0);
+    wait_for_register_write();
+    match version {
+        Version::V2 => distributor.ctlr.set(GICD_CTLR_ENABLE),
+        Version::V3 => {
+            // Non-secure group 1 interrupts are signaled as IRQs to EL1
+            for igroupr in distributor.igroupr.iter().skip(1) {
+                igroupr.set(u32::MAX);
+            }
+            distributor.ctlr.set(GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1);
+            wait_for_register_write();
+        }
+    }
+    initialize_core(0);
+}
+
+/// Initializes the CPU interface of the calling core, `core` is its index
+#[cfg(not(test))]
+pub fn initialize_core(core: usize) {
+    match version() {
+        Version::V2 => {
+            let cpu_interface = cpu_interface();
+            cpu_interface.pmr.set(0xff);
+            cpu_interface.ctlr.set(GICC_CTLR_ENABLE);
+        }
+        Version::V3 => {
+            // Wake the redistributor, see GICv3 spec section 12.11.40
+            let redistributor = redistributor(core);
+            redistributor
+                .waker
+                .set(redistributor.waker.get() & !GICR_WAKER_PROCESSOR_SLEEP);
+            while redistributor.waker.get() & GICR_WAKER_CHILDREN_ASLEEP != 0 {}
+            redistributor.igroupr0.set(u32::MAX);
+            unsafe {
+                // ICC_SRE_EL1.SRE enables the system register interface
+                asm!(
+                    "mrs {tmp}, S3_0_C12_C12_5",
+                    "orr {tmp}, {tmp}, #1",
+                    "msr S3_0_C12_C12_5, {tmp}",
+                    "isb",
+                    // ICC_PMR_EL1, let all priorities through
+                    "mov {tmp}, #0xff",
+                    "msr S3_0_C4_C6_0, {tmp}",
+                    // ICC_IGRPEN1_EL1
+                    "mov {tmp}, #1",
+                    "msr S3_0_C12_C12_7, {tmp}",
+                    "isb",
+                    tmp = out(reg) _,
+                    options(nostack),
+                );
+            }
+        }
+    }
+}
+
+/// Waits until the distributor applied a write to its control register, see GICv3 spec section 12.9.4
+fn wait_for_register_write() {
+    if version() == Version::V3 {
+        while distributor().ctlr.get() & GICD_CTLR_RWP != 0 {}
+    }
+}
+
+/// Enables an interrupt and routes it to the first core
+///
+/// `core` selects the redistributor of private interrupts on a GICv3.
+pub fn enable(interrupt: u32, core: usize) {
+    let index = (interrupt / 32) as usize;
+    let bit = 1 << (interrupt % 32);
+    if interrupt < FIRST_SPI && version() == Version::V3 {
+        let redistributor = redistributor(core);
+        redistributor.ipriorityr[interrupt as usize].set(DEFAULT_PRIORITY);
+        redistributor.isenabler0.set(bit);
+        return;
+    }
+    let distributor = distributor();
+    distributor.ipriorityr[interrupt as usize].set(DEFAULT_PRIORITY);
+    if interrupt >= FIRST_SPI {
+        match version() {
+            Version::V2 => distributor.itargetsr[interrupt as usize].set(1),
+            Version::V3 => distributor.irouter[interrupt as usize].set(0),
+        }
+    }
+    distributor.isenabler[index].set(bit);
+}
+
+/// Registers `handler` to be called with `context` whenever `interrupt` fires and enables it
+///
+/// Must be called with IRQs masked.
+pub fn register(interrupt: u32, handler: fn(usize), context: usize) -> Result<(), InterruptRegistrationError> {
+    if interrupt >= SPECIAL_INTERRUPT_ID {
+        return Err(InterruptRegistrationError::InvalidInterrupt(interrupt));
+    }
+    let free = (0..MAX_HANDLERS)
+        .find(|&index| unsafe { HANDLERS[index].is_none() })
+        .ok_or(InterruptRegistrationError::TooManyHandlers)?;
+    unsafe {
+        HANDLERS[free] = Some(Handler {
+            interrupt,
+            handler,
+            context,
+        })
+    };
+    enable(interrupt, 0);
+    Ok(())
+}
+
+/// Acknowledges the highest priority pending interrupt, `None` if there is none
+#[cfg(not(test))]
+fn acknowledge() -> Option<u32> {
+    let interrupt = match version() {
+        Version::V2 => cpu_interface().iar.get() & 0x3ff,
+        Version::V3 => {
+            let iar: u64;
+            // ICC_IAR1_EL1
+            unsafe { asm!("mrs {}, S3_0_C12_C12_0", out(reg) iar, options(nomem, nostack)) };
+            iar as u32 & 0xffffff
+        }
+    };
+    if interrupt >= SPECIAL_INTERRUPT_ID {
+        None
+    } else {
+        Some(interrupt)
+    }
+}
+
+#[cfg(not(test))]
+fn end_of_interrupt(interrupt: u32) {
+    match version() {
+        Version::V2 => cpu_interface().eoir.set(interrupt),
+        // ICC_EOIR1_EL1
+        Version::V3 => unsafe {
+            asm!("msr S3_0_C12_C12_1, {}", in(reg) interrupt as u64, options(nomem, nostack))
+        },
+    }
+}
+
+// Host tests take no interrupts
+
+#[cfg(test)]
+pub fn initialize_core(_core: usize) {}
+
+#[cfg(test)]
+fn acknowledge() -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+fn end_of_interrupt(_interrupt: u32) {}
+
+/// Calls the handlers of all pending interrupts, called from the IRQ vector
+pub fn handle_interrupts() {
+    while let Some(interrupt) = acknowledge() {
+        for index in 0..MAX_HANDLERS {
+            match unsafe { HANDLERS[index] } {
+                Some(handler) if handler.interrupt == interrupt => (handler.handler)(handler.context),
+                _ => {}
+            }
+        }
+        end_of_interrupt(interrupt);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::ipv4;
+use crate::packet::{
+    EthernetFrame, IcmpMessage, Ipv4Packet, ETHERNET_HEADER_SIZE, ETHERTYPE_IPV4, ICMP_HEADER_SIZE,
+    IPV4_MIN_HEADER_SIZE,
+};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const TYPE_TIME_EXCEEDED: u8 = 11;
+
+const CODE_NET_UNREACHABLE: u8 = 0;
+const CODE_HOST_UNREACHABLE: u8 = 1;
+const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+const CODE_PORT_UNREACHABLE: u8 = 3;
+const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+const CODE_ADMINISTRATIVELY_PROHIBITED: u8 = 13;
+const CODE_TTL_EXCEEDED: u8 = 0;
+
+const DEFAULT_TTL: u8 = 64;
+/// Maximum length of an ICMP error datagram, see RFC 1812 section 4.3.2.3
+const MAX_ERROR_DATAGRAM_SIZE: usize = 576;
+
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum ErrorMessage {
+    /// The TTL expired in transit
+    TimeExceeded,
+    /// There is no route to the destination network
+    NetUnreachable,
+    /// The destination host cannot be reached
+    HostUnreachable,
+    /// The transport protocol is not served
+    ProtocolUnreachable,
+    /// The destination protocol port is not served
+    PortUnreachable,
+    /// The packet must be fragmented but has DF set, see RFC 1191 section 4
+    FragmentationNeeded { next_hop_mtu: u16 },
+    /// Forwarding was rejected by a filter, see RFC 1812 section 5.2.7.1
+    AdministrativelyProhibited,
+}
+
+impl ErrorMessage {
+    /// ICMP type, code and the rest of the ICMP header
+    fn header(&self) -> (u8, u8, [u8; 4]) {
+        match *self {
+            ErrorMessage::TimeExceeded => (TYPE_TIME_EXCEEDED, CODE_TTL_EXCEEDED, [0; 4]),
+            ErrorMessage::NetUnreachable => (TYPE_DESTINATION_UNREACHABLE, CODE_NET_UNREACHABLE, [0; 4]),
+            ErrorMessage::HostUnreachable => (TYPE_DESTINATION_UNREACHABLE, CODE_HOST_UNREACHABLE, [0; 4]),
+            ErrorMessage::ProtocolUnreachable => {
+                (TYPE_DESTINATION_UNREACHABLE, CODE_PROTOCOL_UNREACHABLE, [0; 4])
+            }
+            ErrorMessage::PortUnreachable => (TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE, [0; 4]),
+            ErrorMessage::FragmentationNeeded { next_hop_mtu } => {
+                let mtu = next_hop_mtu.to_be_bytes();
+                (TYPE_DESTINATION_UNREACHABLE, CODE_FRAGMENTATION_NEEDED, [0, 0, mtu[0], mtu[1]])
+            }
+            ErrorMessage::AdministrativelyProhibited => {
+                (TYPE_DESTINATION_UNREACHABLE, CODE_ADMINISTRATIVELY_PROHIBITED, [0; 4])
+            }
+        }
+    }
+}
+
+/// Whether an ICMP error may be sent in response to the packet, see RFC 1812 section 4.3.2.7
+pub fn error_permitted(frame: &EthernetFrame<&[u8]>, packet: &Ipv4Packet<&[u8]>) -> bool {
+    // Never in response to a link-layer broadcast or multicast
+    if frame.is_multicast() {
+        return false;
+    }
+    // Never in response to an IP broadcast or multicast
+    let destination = packet.destination();
+    if destination == [255, 255, 255, 255] || is_multicast(destination) {
+        return false;
+    }
+    // Never to a source that does not define a single host
+    let source = packet.source();
+    if source == [0, 0, 0, 0] || source == [255, 255, 255, 255] || is_multicast(source) || source[0] == 127 {
+        return false;
+    }
+    // Never in response to a non-initial fragment
+    if packet.fragment_offset() != 0 {
+        return false;
+    }
+    // Never in response to an ICMP error message
+    if packet.protocol() == ipv4::PROTOCOL_ICMP {
+        return match IcmpMessage::new_checked(packet.payload()) {
+            Ok(message) => !is_error_type(message.message_type()),
+            Err(_) => false,
+        };
+    }
+    true
+}
+
+fn is_multicast(address: [u8; 4]) -> bool {
+    address[0] & 0xf0 == 0xe0
+}
+
+fn is_error_type(icmp_type: u8) -> bool {
+    match icmp_type {
+        3 | 4 | 5 | 11 | 12 => true,
+        _ => false,
+    }
+}
+
+/// Whether the packet carries an ICMP echo request with a valid checksum
+pub fn is_echo_request(packet: &Ipv4Packet<&[u8]>) -> bool {
+    if packet.protocol() != ipv4::PROTOCOL_ICMP {
+        return false;
+    }
+    match IcmpMessage::new_checked(packet.payload()) {
+        Ok(message) => message.message_type() == TYPE_ECHO_REQUEST && message.verify_checksum(),
+        Err(_) => false,
+    }
+}
+
+/// Builds the echo reply to the echo request `packet` received in `frame`, see RFC 792
+///
+/// Returns the length of the frame written to `out`.
+pub fn build_echo_reply(
+    frame: &EthernetFrame<&[u8]>,
+    packet: &Ipv4Packet<&[u8]>,
+    out: &mut [u8],
+    source_mac: [u8; 6],
+) -> usize {
+    let request = packet.payload();
+    // Options of the request are not returned
+    let total_len = IPV4_MIN_HEADER_SIZE + request.len();
+    let mut reply = write_headers(out, frame.source(), source_mac, total_len, packet.destination(), packet.source());
+
+    let mut message = IcmpMessage::new_unchecked(reply.payload_mut());
+    message.data_mut().copy_from_slice(&request[ICMP_HEADER_SIZE..]);
+    message.set_message_type(TYPE_ECHO_REPLY);
+    message.set_code(0);
+    message.set_rest_of_header([request[4], request[5], request[6], request[7]]);
+    message.fill_checksum();
+
+    ETHERNET_HEADER_SIZE + total_len
+}
+
+/// Builds an ICMP error message in response to `packet` received in `frame`, quoting its header and leading data
+///
+/// Returns the length of the frame written to `out`.
+pub fn build_error(
+    frame: &EthernetFrame<&[u8]>,
+    packet: &Ipv4Packet<&[u8]>,
+    out: &mut [u8],
+    source_mac: [u8; 6],
+    source_address: [u8; 4],
+    message: ErrorMessage,
+) -> usize {
+    let (icmp_type, code, rest_of_header) = message.header();
+    let offending = packet.as_bytes();
+    let quote_len = core::cmp::min(
+        offending.len(),
+        MAX_ERROR_DATAGRAM_SIZE - IPV4_MIN_HEADER_SIZE - ICMP_HEADER_SIZE,
+    );
+    let total_len = IPV4_MIN_HEADER_SIZE + ICMP_HEADER_SIZE + quote_len;
+    let mut reply = write_headers(out, frame.source(), source_mac, total_len, source_address, packet.source());
+
+    let mut message = IcmpMessage::new_unchecked(reply.payload_mut());
+    message.set_message_type(icmp_type);
+    message.set_code(code);
+    message.set_rest_of_header(rest_of_header);
+    message.data_mut().copy_from_slice(&offending[..quote_len]);
+    message.fill_checksum();
+
+    ETHERNET_HEADER_SIZE + total_len
+}
+
+/// Writes the ethernet header and an option-less IPv4 header carrying ICMP
+fn write_headers(
+    out: &mut [u8],
+    destination_mac: [u8; 6],
+    source_mac: [u8; 6],
+    total_len: usize,
+    source: [u8; 4],
+    destination: [u8; 4],
+) -> Ipv4Packet<&mut [u8]> {
+    let mut frame = EthernetFrame::new_checked(&mut out[..ETHERNET_HEADER_SIZE + total_len]).unwrap();
+    frame.set_destination(destination_mac);
+    frame.set_source(source_mac);
+    frame.set_ethertype(ETHERTYPE_IPV4);
+
+    let mut packet = Ipv4Packet::new_unchecked(&mut out[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + total_len]);
+    packet.set_header_len(IPV4_MIN_HEADER_SIZE);
+    packet.set_dscp_ecn(0);
+    packet.set_total_len(total_len);
+    packet.set_identification(0);
+    packet.set_fragmentation(false, false, 0);
+    packet.set_ttl(DEFAULT_TTL);
+    packet.set_protocol(ipv4::PROTOCOL_ICMP);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet.fill_checksum();
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum;
+
+    const ROUTER_MAC: [u8; 6] = [0x52, 0x54, 0, 0, 0, 1];
+    const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x10];
+    const ROUTER: [u8; 4] = [10, 0, 0, 1];
+    const HOST: [u8; 4] = [10, 0, 0, 2];
+
+    /// A frame from the host carrying an IPv4 packet with `payload`
+    fn frame(source: [u8; 4], destination: [u8; 4], protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let total_len = IPV4_MIN_HEADER_SIZE + payload.len();
+        let mut buffer = vec![0; ETHERNET_HEADER_SIZE + total_len];
+        let mut frame = EthernetFrame::new_checked(&mut buffer[..]).unwrap();
+        frame.set_destination(ROUTER_MAC);
+        frame.set_source(HOST_MAC);
+        frame.set_ethertype(ETHERTYPE_IPV4);
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer[ETHERNET_HEADER_SIZE..]);
+        packet.set_header_len(IPV4_MIN_HEADER_SIZE);
+        packet.set_total_len(total_len);
+        packet.set_ttl(1);
+        packet.set_protocol(protocol);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        packet.payload_mut().copy_from_slice(payload);
+        packet.fill_checksum();
+        buffer
+    }
+
+    fn udp(source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        frame(source, destination, ipv4::PROTOCOL_UDP, &[7; 16])
+    }
+
+    /// An ICMP message of the given type with a valid checksum
+    fn icmp(message_type: u8) -> Vec<u8> {
+        let mut message = vec![message_type, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4];
+        let message_checksum = checksum::internet_checksum(&message);
+        message[2..4].copy_from_slice(&message_checksum.to_be_bytes());
+        message
+    }
+
+    fn permitted(buffer: &[u8]) -> bool {
+        let frame = EthernetFrame::new_checked(buffer).unwrap();
+        let packet = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        error_permitted(&frame, &packet)
+    }
+
+    #[test]
+    fn permits_errors_for_unicast_packets() {
+        assert!(permitted(&udp(HOST, [10, 0, 1, 5])));
+        assert!(permitted(&frame(HOST, [10, 0, 1, 5], ipv4::PROTOCOL_ICMP, &icmp(TYPE_ECHO_REQUEST))));
+        assert!(permitted(&frame(HOST, [10, 0, 1, 5], ipv4::PROTOCOL_ICMP, &icmp(TYPE_ECHO_REPLY))));
+    }
+
+    #[test]
+    fn suppresses_errors_for_broadcast_and_multicast() {
+        for &mac in &[[0xff; 6], [0x01, 0x00, 0x5e, 0, 0, 5]] {
+            let mut buffer = udp(HOST, [10, 0, 1, 5]);
+            EthernetFrame::new_checked(&mut buffer[..]).unwrap().set_destination(mac);
+            assert!(!permitted(&buffer));
+        }
+        assert!(!permitted(&udp(HOST, [255, 255, 255, 255])));
+        assert!(!permitted(&udp(HOST, [224, 0, 0, 5])));
+        assert!(!permitted(&udp(HOST, [239, 255, 255, 250])));
+    }
+
+    #[test]
+    fn suppresses_errors_to_sources_that_are_not_a_host() {
+        for &source in &[[0, 0, 0, 0], [255, 255, 255, 255], [224, 0, 0, 1], [127, 0, 0, 1]] {
+            assert!(!permitted(&udp(source, [10, 0, 1, 5])));
+        }
+    }
+
+    #[test]
+    fn suppresses_errors_for_non_initial_fragments() {
+        for &(more_fragments, offset, expected) in &[(true, 0, true), (true, 8, false), (false, 1480, false)] {
+            let mut buffer = udp(HOST, [10, 0, 1, 5]);
+            let mut packet = Ipv4Packet::new_unchecked(&mut buffer[ETHERNET_HEADER_SIZE..]);
+            packet.set_fragmentation(false, more_fragments, offset);
+            packet.fill_checksum();
+            assert_eq!(permitted(&buffer), expected);
+        }
+    }
+
+    #[test]
+    fn suppresses_errors_for_icmp_errors() {
+        for &message_type in &[TYPE_DESTINATION_UNREACHABLE, 4, 5, TYPE_TIME_EXCEEDED, 12] {
+            assert!(!permitted(&frame(HOST, [10, 0, 1, 5], ipv4::PROTOCOL_ICMP, &icmp(message_type))));
+        }
+        // A truncated ICMP message might be an error
+        assert!(!permitted(&frame(HOST, [10, 0, 1, 5], ipv4::PROTOCOL_ICMP, &[8, 0, 0])));
+    }
+
+    /// Builds `message` for `buffer`, returns the reply's frame
+    fn error(buffer: &[u8], message: ErrorMessage) -> Vec<u8> {
+        let frame = EthernetFrame::new_checked(buffer).unwrap();
+        let packet = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        let mut out = vec![0; 2048];
+        let len = build_error(&frame, &packet, &mut out, ROUTER_MAC, ROUTER, message);
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn quotes_the_whole_small_packet() {
+        let buffer = udp(HOST, [10, 0, 1, 5]);
+        let reply = error(&buffer, ErrorMessage::TimeExceeded);
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!((frame.destination(), frame.source()), (HOST_MAC, ROUTER_MAC));
+        let packet = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert!(packet.verify_checksum());
+        assert_eq!((packet.source(), packet.destination()), (ROUTER, HOST));
+        assert_eq!((packet.protocol(), packet.ttl()), (ipv4::PROTOCOL_ICMP, DEFAULT_TTL));
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert!(message.verify_checksum());
+        assert_eq!((message.message_type(), message.code()), (TYPE_TIME_EXCEEDED, CODE_TTL_EXCEEDED));
+        assert_eq!(message.data(), &buffer[ETHERNET_HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn caps_the_error_at_576_bytes() {
+        let buffer = frame(HOST, [10, 0, 1, 5], ipv4::PROTOCOL_UDP, &[7; 1400]);
+        let reply = error(&buffer, ErrorMessage::FragmentationNeeded { next_hop_mtu: 1280 });
+        let packet = Ipv4Packet::new_checked(&reply[ETHERNET_HEADER_SIZE..]).unwrap();
+        assert_eq!(packet.total_len(), MAX_ERROR_DATAGRAM_SIZE);
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert!(message.verify_checksum());
+        assert_eq!((message.message_type(), message.code()), (TYPE_DESTINATION_UNREACHABLE, CODE_FRAGMENTATION_NEEDED));
+        assert_eq!(message.rest_of_header(), [0, 0, 0x05, 0x00]);
+        let quote_len = MAX_ERROR_DATAGRAM_SIZE - IPV4_MIN_HEADER_SIZE - ICMP_HEADER_SIZE;
+        assert_eq!(message.data(), &buffer[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + quote_len]);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::checksum;
+use crate::ipv6;
+use crate::packet::{
+    EthernetFrame, IcmpMessage, Ipv6Packet, ETHERNET_HEADER_SIZE, ETHERTYPE_IPV6, ICMP_HEADER_SIZE, IPV6_HEADER_SIZE,
+};
+
+const TYPE_DESTINATION_UNREACHABLE: u8 = 1;
+const TYPE_PACKET_TOO_BIG: u8 = 2;
+const TYPE_TIME_EXCEEDED: u8 = 3;
+/// Types below this one are error messages, see RFC 4443 section 2.1
+const TYPE_FIRST_INFORMATIONAL: u8 = 128;
+
+const CODE_NO_ROUTE: u8 = 0;
+const CODE_ADMINISTRATIVELY_PROHIBITED: u8 = 1;
+const CODE_ADDRESS_UNREACHABLE: u8 = 3;
+const CODE_PORT_UNREACHABLE: u8 = 4;
+const CODE_HOP_LIMIT_EXCEEDED: u8 = 0;
+
+const DEFAULT_HOP_LIMIT: u8 = 64;
+/// ICMPv6 errors must fit into the minimum IPv6 MTU, see RFC 4443 section 2.4
+const MIN_MTU: usize = 1280;
+
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum ErrorMessage {
+    /// The hop limit expired in transit
+    HopLimitExceeded,
+    /// There is no route to the destination
+    NoRoute,
+    /// Forwarding was rejected by a filter
+    AdministrativelyProhibited,
+    /// The destination address cannot be resolved
+    AddressUnreachable,
+    /// The destination port is not served
+    PortUnreachable,
+    /// The packet exceeds the next-hop MTU, see RFC 4443 section 3.2
+    PacketTooBig { mtu: u32 },
+}
+
+impl ErrorMessage {
+    /// ICMPv6 type, code and the rest of the ICMPv6 header
+    fn header(&self) -> (u8, u8, [u8; 4]) {
+        match *self {
+            ErrorMessage::HopLimitExceeded => (TYPE_TIME_EXCEEDED, CODE_HOP_LIMIT_EXCEEDED, [0; 4]),
+            ErrorMessage::NoRoute => (TYPE_DESTINATION_UNREACHABLE, CODE_NO_ROUTE, [0; 4]),
+            ErrorMessage::AdministrativelyProhibited => {
+                (TYPE_DESTINATION_UNREACHABLE, CODE_ADMINISTRATIVELY_PROHIBITED, [0; 4])
+            }
+            ErrorMessage::AddressUnreachable => (TYPE_DESTINATION_UNREACHABLE, CODE_ADDRESS_UNREACHABLE, [0; 4]),
+            ErrorMessage::PortUnreachable => (TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE, [0; 4]),
+            ErrorMessage::PacketTooBig { mtu } => (TYPE_PACKET_TOO_BIG, 0, mtu.to_be_bytes()),
+        }
+    }
+}
+
+/// Whether an ICMPv6 error may be sent in response to the packet, see RFC 4443 section 2.4 (e)
+pub fn error_permitted(frame: &EthernetFrame<&[u8]>, packet: &Ipv6Packet<&[u8]>, message: ErrorMessage) -> bool {
+    // Packet Too Big is the only error sent in response to multicast
+    let too_big = match message {
+        ErrorMessage::PacketTooBig { .. } => true,
+        _ => false,
+    };
+    if !too_big && (frame.is_multicast() || ipv6::is_multicast(packet.destination())) {
+        return false;
+    }
+    // Never to a source that does not define a single node
+    let source = packet.source();
+    if source == ipv6::UNSPECIFIED || ipv6::is_multicast(source) {
+        return false;
+    }
+    // Never in response to an ICMPv6 error message
+    if packet.next_header() == ipv6::NEXT_HEADER_ICMPV6 {
+        return match IcmpMessage::new_checked(packet.payload()) {
+            Ok(message) => message.message_type() >= TYPE_FIRST_INFORMATIONAL,
+            Err(_) => false,
+        };
+    }
+    true
+}
+
+/// Builds an ICMPv6 error message in response to `packet` received in `frame`, quoting as much of it as fits
+///
+/// Returns the length of the frame written to `out`.
+pub fn build_error(
+    frame: &EthernetFrame<&[u8]>,
+    packet: &Ipv6Packet<&[u8]>,
+    out: &mut [u8],
+    source_mac: [u8; 6],
+    source_address: [u8; 16],
+    message: ErrorMessage,
+) -> usize {
+    let (icmp_type, code, rest_of_header) = message.header();
+    let offending = packet.as_bytes();
+    let quote_len = core::cmp::min(offending.len(), MIN_MTU - IPV6_HEADER_SIZE - ICMP_HEADER_SIZE);
+    let payload_len = ICMP_HEADER_SIZE + quote_len;
+    let mut reply = write_headers(out, frame.source(), source_mac, payload_len, source_address, packet.source());
+
+    let mut message = IcmpMessage::new_unchecked(reply.payload_mut());
+    message.set_message_type(icmp_type);
+    message.set_code(code);
+    message.set_rest_of_header(rest_of_header);
+    message.data_mut().copy_from_slice(&offending[..quote_len]);
+    fill_checksum(&mut reply);
+
+    ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + payload_len
+}
+
+/// Writes the ethernet header and an IPv6 header carrying ICMPv6
+pub fn write_headers(
+    out: &mut [u8],
+    destination_mac: [u8; 6],
+    source_mac: [u8; 6],
+    payload_len: usize,
+    source: [u8; 16],
+    destination: [u8; 16],
+) -> Ipv6Packet<&mut [u8]> {
+    let mut frame = EthernetFrame::new_checked(&mut out[..]).unwrap();
+    frame.set_destination(destination_mac);
+    frame.set_source(source_mac);
+    frame.set_ethertype(ETHERTYPE_IPV6);
+
+    let end = ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + payload_len;
+    let mut packet = Ipv6Packet::new_unchecked(&mut out[ETHERNET_HEADER_SIZE..end]);
+    packet.set_version_class_flow(0, 0);
+    packet.set_payload_len(payload_len);
+    packet.set_next_header(ipv6::NEXT_HEADER_ICMPV6);
+    packet.set_hop_limit(DEFAULT_HOP_LIMIT);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet
+}
+
+/// Computes the ICMPv6 checksum, which covers the pseudo-header and the whole message
+pub fn fill_checksum(packet: &mut Ipv6Packet<&mut [u8]>) {
+    let source = packet.source();
+    let destination = packet.destination();
+    let payload = packet.payload_mut();
+    IcmpMessage::new_unchecked(&mut *payload).set_checksum(0);
+    let sum = checksum::ipv6_pseudo_header(source, destination, payload.len(), ipv6::NEXT_HEADER_ICMPV6);
+    let sum = checksum::finish(checksum::add(payload, sum));
+    IcmpMessage::new_unchecked(payload).set_checksum(sum);
+}
+
+/// Verifies the ICMPv6 checksum of the message carried by `packet`
+pub fn verify_checksum(packet: &Ipv6Packet<&[u8]>) -> bool {
+    let payload = packet.payload();
+    let sum = checksum::ipv6_pseudo_header(packet.source(), packet.destination(), payload.len(), ipv6::NEXT_HEADER_ICMPV6);
+    checksum::finish(checksum::add(payload, sum)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTER_MAC: [u8; 6] = [0x52, 0x54, 0, 0, 0, 0];
+    const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x10];
+    const ROUTER: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const HOST: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    const SERVER: [u8; 16] = [0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5];
+
+    /// A frame from the host carrying an IPv6 packet with `payload`
+    fn frame(source: [u8; 16], destination: [u8; 16], next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + payload.len()];
+        let mut packet = write_headers(&mut buffer, ROUTER_MAC, HOST_MAC, payload.len(), source, destination);
+        packet.set_next_header(next_header);
+        packet.payload_mut().copy_from_slice(payload);
+        buffer
+    }
+
+    fn udp(source: [u8; 16], destination: [u8; 16]) -> Vec<u8> {
+        frame(source, destination, 17, &[7; 16])
+    }
+
+    /// An ICMPv6 message of the given type, its checksum does not matter to the checks
+    fn icmpv6(message_type: u8) -> Vec<u8> {
+        vec![message_type, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4]
+    }
+
+    fn permitted(buffer: &[u8], message: ErrorMessage) -> bool {
+        let frame = EthernetFrame::new_checked(buffer).unwrap();
+        let packet = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        error_permitted(&frame, &packet, message)
+    }
+
+    #[test]
+    fn permits_errors_for_unicast_packets() {
+        assert!(permitted(&udp(HOST, SERVER), ErrorMessage::NoRoute));
+        let echo_request = frame(HOST, SERVER, ipv6::NEXT_HEADER_ICMPV6, &icmpv6(128));
+        assert!(permitted(&echo_request, ErrorMessage::HopLimitExceeded));
+    }
+
+    #[test]
+    fn sends_only_packet_too_big_for_multicast() {
+        let group = [0xff, 0x0e, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x42];
+        let mut to_multicast_mac = udp(HOST, SERVER);
+        EthernetFrame::new_checked(&mut to_multicast_mac[..])
+            .unwrap()
+            .set_destination(ipv6::multicast_mac(group));
+        for buffer in &[udp(HOST, group), to_multicast_mac] {
+            assert!(!permitted(buffer, ErrorMessage::HopLimitExceeded));
+            assert!(!permitted(buffer, ErrorMessage::NoRoute));
+            assert!(permitted(buffer, ErrorMessage::PacketTooBig { mtu: 1280 }));
+        }
+    }
+
+    #[test]
+    fn suppresses_errors_to_sources_that_are_not_a_node() {
+        for &source in &[ipv6::UNSPECIFIED, ipv6::ALL_NODES] {
+            assert!(!permitted(&udp(source, SERVER), ErrorMessage::PacketTooBig { mtu: 1280 }));
+        }
+    }
+
+    #[test]
+    fn suppresses_errors_for_icmpv6_errors() {
+        for &message_type in &[TYPE_DESTINATION_UNREACHABLE, TYPE_PACKET_TOO_BIG, TYPE_TIME_EXCEEDED, 4, 127] {
+            let buffer = frame(HOST, SERVER, ipv6::NEXT_HEADER_ICMPV6, &icmpv6(message_type));
+            assert!(!permitted(&buffer, ErrorMessage::PacketTooBig { mtu: 1280 }));
+        }
+        // A truncated ICMPv6 message might be an error
+        let buffer = frame(HOST, SERVER, ipv6::NEXT_HEADER_ICMPV6, &[128, 0, 0]);
+        assert!(!permitted(&buffer, ErrorMessage::NoRoute));
+    }
+
+    /// Builds `message` for `buffer`, returns the reply's frame
+    fn error(buffer: &[u8], message: ErrorMessage) -> Vec<u8> {
+        let frame = EthernetFrame::new_checked(buffer).unwrap();
+        let packet = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        let mut out = vec![0; 2048];
+        let len = build_error(&frame, &packet, &mut out, ROUTER_MAC, ROUTER, message);
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn quotes_the_whole_small_packet() {
+        let buffer = udp(HOST, SERVER);
+        let reply = error(&buffer, ErrorMessage::HopLimitExceeded);
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!((frame.destination(), frame.source()), (HOST_MAC, ROUTER_MAC));
+        assert_eq!(frame.ethertype(), ETHERTYPE_IPV6);
+        let packet = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!((packet.source(), packet.destination()), (ROUTER, HOST));
+        assert_eq!((packet.next_header(), packet.hop_limit()), (ipv6::NEXT_HEADER_ICMPV6, DEFAULT_HOP_LIMIT));
+        assert!(verify_checksum(&packet));
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert_eq!((message.message_type(), message.code()), (TYPE_TIME_EXCEEDED, CODE_HOP_LIMIT_EXCEEDED));
+        assert_eq!(message.data(), &buffer[ETHERNET_HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn packet_too_big_carries_the_mtu_and_fits_the_minimum_mtu() {
+        let buffer = frame(HOST, SERVER, 17, &[7; 1400]);
+        let reply = error(&buffer, ErrorMessage::PacketTooBig { mtu: 1280 });
+        assert_eq!(reply.len(), ETHERNET_HEADER_SIZE + MIN_MTU);
+        let packet = Ipv6Packet::new_checked(&reply[ETHERNET_HEADER_SIZE..]).unwrap();
+        assert!(verify_checksum(&packet));
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert_eq!((message.message_type(), message.code()), (TYPE_PACKET_TOO_BIG, 0));
+        assert_eq!(message.rest_of_header(), 1280u32.to_be_bytes());
+        let quote_len = MIN_MTU - IPV6_HEADER_SIZE - ICMP_HEADER_SIZE;
+        assert_eq!(message.data(), &buffer[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + quote_len]);
+    }
+
+    #[test]
+    fn destination_unreachable_codes() {
+        for &(message, code) in &[
+            (ErrorMessage::NoRoute, CODE_NO_ROUTE),
+            (ErrorMessage::AdministrativelyProhibited, CODE_ADMINISTRATIVELY_PROHIBITED),
+            (ErrorMessage::AddressUnreachable, CODE_ADDRESS_UNREACHABLE),
+            (ErrorMessage::PortUnreachable, CODE_PORT_UNREACHABLE),
+        ] {
+            let reply = error(&udp(HOST, SERVER), message);
+            let packet = Ipv6Packet::new_checked(&reply[ETHERNET_HEADER_SIZE..]).unwrap();
+            let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+            assert_eq!((message.message_type(), message.code()), (TYPE_DESTINATION_UNREACHABLE, code));
+            assert_eq!(message.rest_of_header(), [0; 4]);
+        }
+    }
+}
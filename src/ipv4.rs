@@ -0,0 +1,375 @@
+use crate::errors::Ipv4ValidationError;
+use crate::packet::{Ipv4Packet, IPV4_MIN_HEADER_SIZE};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// The header length field counts 32 bit words in 4 bits
+const MAX_HEADER_SIZE: usize = 60;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_COPIED: u8 = 0x80;
+
+/// Applies the checks of RFC 1812 section 5.2.2 to the received bytes after the link header
+///
+/// The returned packet is trimmed to its total length, so link-layer padding is not part of it.
+pub fn validate(buffer: &[u8]) -> Result<Ipv4Packet<&[u8]>, Ipv4ValidationError> {
+    if buffer.len() < IPV4_MIN_HEADER_SIZE {
+        return Err(Ipv4ValidationError::Truncated);
+    }
+    let packet = Ipv4Packet::new_unchecked(buffer);
+    if packet.version() != 4 {
+        return Err(Ipv4ValidationError::InvalidVersion(packet.version()));
+    }
+    let header_len = packet.header_len();
+    if header_len < IPV4_MIN_HEADER_SIZE || header_len > buffer.len() {
+        return Err(Ipv4ValidationError::InvalidHeaderLength((header_len / 4) as u8));
+    }
+    let total_len = packet.total_len();
+    if total_len < header_len || total_len > buffer.len() {
+        return Err(Ipv4ValidationError::InvalidTotalLength(total_len as u16));
+    }
+    if !packet.verify_checksum() {
+        return Err(Ipv4ValidationError::InvalidChecksum);
+    }
+    if is_martian(packet.source()) {
+        return Err(Ipv4ValidationError::MartianSource(packet.source()));
+    }
+    // Multicast is valid, but not forwarded, so it is not counted among the martians
+    if is_multicast(packet.destination()) {
+        return Err(Ipv4ValidationError::MulticastDestination(packet.destination()));
+    }
+    if is_martian(packet.destination()) {
+        return Err(Ipv4ValidationError::MartianDestination(packet.destination()));
+    }
+    Ok(Ipv4Packet::new_unchecked(packet.as_bytes()))
+}
+
+/// Addresses a router must neither forward from nor to, see RFC 1812 section 5.3.7
+///
+/// Multicast destinations are checked by `is_multicast` first.
+fn is_martian(address: [u8; 4]) -> bool {
+    match address[0] {
+        // "this network", loopback, multicast, reserved and the limited broadcast
+        0 | 127 | 224..=255 => true,
+        _ => false,
+    }
+}
+
+/// Class D addresses, see RFC 5771
+fn is_multicast(address: [u8; 4]) -> bool {
+    (224..=239).contains(&address[0])
+}
+
+/// Per-reason counters of dropped IPv4 packets
+#[derive(Debug, Default)]
+pub struct Ipv4DropCounters {
+    pub truncated: u64,
+    pub invalid_version: u64,
+    pub invalid_header_length: u64,
+    pub invalid_total_length: u64,
+    pub invalid_checksum: u64,
+    pub martian_source: u64,
+    pub martian_destination: u64,
+    pub multicast_destination: u64,
+    /// Packets too large for the egress MTU that could not be sent as a complete set of fragments
+    pub fragmentation_failed: u64,
+}
+
+impl Ipv4DropCounters {
+    pub fn count(&mut self, error: &Ipv4ValidationError) {
+        let counter = match error {
+            Ipv4ValidationError::Truncated => &mut self.truncated,
+            Ipv4ValidationError::InvalidVersion(_) => &mut self.invalid_version,
+            Ipv4ValidationError::InvalidHeaderLength(_) => &mut self.invalid_header_length,
+            Ipv4ValidationError::InvalidTotalLength(_) => &mut self.invalid_total_length,
+            Ipv4ValidationError::InvalidChecksum => &mut self.invalid_checksum,
+            Ipv4ValidationError::MartianSource(_) => &mut self.martian_source,
+            Ipv4ValidationError::MartianDestination(_) => &mut self.martian_destination,
+            Ipv4ValidationError::MulticastDestination(_) => &mut self.multicast_destination,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// Splits an IPv4 packet into fragments that fit into an MTU, see RFC 791 section 3.2
+pub struct Fragmenter<'a> {
+    link_header: &'a [u8],
+    packet: Ipv4Packet<&'a [u8]>,
+    mtu: usize,
+    /// Offset of the next fragment's data relative to the packet's data
+    offset: usize,
+}
+
+impl<'a> Fragmenter<'a> {
+    /// `link_header` is prepended to every fragment
+    pub fn new(link_header: &'a [u8], packet: Ipv4Packet<&'a [u8]>, mtu: usize) -> Self {
+        Fragmenter {
+            link_header,
+            packet,
+            mtu,
+            offset: 0,
+        }
+    }
+
+    /// Whether there are fragments left to write
+    pub fn has_next(&self) -> bool {
+        // Every fragment must have room for its header and 8 data bytes
+        self.offset < self.packet.payload().len() && self.mtu >= self.packet.header_len() + 8
+    }
+
+    /// Number of fragments left to write, zero if the MTU leaves no room for data
+    pub fn remaining(&self) -> usize {
+        if !self.has_next() {
+            return 0;
+        }
+        let data_len = self.packet.payload().len();
+        let mut offset = self.offset;
+        let mut count = 0;
+        if offset == 0 {
+            offset += (self.mtu - self.packet.header_len()) & !7;
+            count += 1;
+        }
+        if offset < data_len {
+            // Later fragments only carry the copied options, so they have at least as much room for data
+            let header_len = copy_fragment_header(self.packet.header(), &mut [0; MAX_HEADER_SIZE]);
+            let max_data_len = (self.mtu - header_len) & !7;
+            count += (data_len - offset + max_data_len - 1) / max_data_len;
+        }
+        count
+    }
+
+    /// Writes the next fragment to `out` and returns the length of the written frame
+    pub fn next_fragment(&mut self, out: &mut [u8]) -> Option<usize> {
+        if !self.has_next() {
+            return None;
+        }
+        let data = self.packet.payload();
+
+        let link_header_len = self.link_header.len();
+        out[..link_header_len].copy_from_slice(self.link_header);
+        let buffer = &mut out[link_header_len..];
+
+        // Only the first fragment carries all options
+        let header_len = if self.offset == 0 {
+            let header = self.packet.header();
+            buffer[..header.len()].copy_from_slice(header);
+            header.len()
+        } else {
+            copy_fragment_header(self.packet.header(), buffer)
+        };
+
+        // Every fragment but the last must carry a multiple of 8 data bytes
+        let max_data_len = (self.mtu - header_len) & !7;
+        let data_len = core::cmp::min(max_data_len, data.len() - self.offset);
+        buffer[header_len..header_len + data_len].copy_from_slice(&data[self.offset..self.offset + data_len]);
+
+        // The packet might already be a fragment itself
+        let last = self.offset + data_len == data.len();
+        let more_fragments = !last || self.packet.more_fragments();
+        let fragment_offset = self.packet.fragment_offset() + self.offset;
+
+        let mut fragment = Ipv4Packet::new_unchecked(buffer);
+        fragment.set_header_len(header_len);
+        fragment.set_total_len(header_len + data_len);
+        fragment.set_fragmentation(false, more_fragments, fragment_offset);
+        fragment.fill_checksum();
+
+        self.offset += data_len;
+        Some(link_header_len + header_len + data_len)
+    }
+}
+
+/// Copies the fixed header and all options with the copied flag to `out`, returns the header length
+fn copy_fragment_header(header: &[u8], out: &mut [u8]) -> usize {
+    out[..IPV4_MIN_HEADER_SIZE].copy_from_slice(&header[..IPV4_MIN_HEADER_SIZE]);
+    let mut len = IPV4_MIN_HEADER_SIZE;
+    let mut i = IPV4_MIN_HEADER_SIZE;
+    while i < header.len() {
+        let option_type = header[i];
+        if option_type == OPTION_END {
+            break;
+        }
+        if option_type == OPTION_NOP {
+            i += 1;
+            continue;
+        }
+        if i + 1 >= header.len() {
+            break;
+        }
+        let option_len = header[i + 1] as usize;
+        if option_len < 2 || i + option_len > header.len() {
+            break;
+        }
+        if option_type & OPTION_COPIED != 0 {
+            out[len..len + option_len].copy_from_slice(&header[i..i + option_len]);
+            len += option_len;
+        }
+        i += option_len;
+    }
+
+    // Pad the options with end of option list markers
+    while len % 4 != 0 {
+        out[len] = OPTION_END;
+        len += 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK_HEADER: [u8; 14] = [0xaa; 14];
+    /// Record route, not copied into later fragments
+    const RECORD_ROUTE: [u8; 7] = [0x07, 7, 4, 0, 0, 0, 0];
+    /// Router alert, copied into every fragment
+    const ROUTER_ALERT: [u8; 4] = [0x94, 4, 0, 0];
+
+    /// A packet with the given options, padded to a multiple of 4 bytes, and `data_len` bytes of data
+    fn packet(options: &[u8], data_len: usize, more_fragments: bool, fragment_offset: usize) -> Vec<u8> {
+        let header_len = (IPV4_MIN_HEADER_SIZE + options.len() + 3) & !3;
+        let mut buffer = vec![0; header_len + data_len];
+        buffer[IPV4_MIN_HEADER_SIZE..IPV4_MIN_HEADER_SIZE + options.len()].copy_from_slice(options);
+        for (i, byte) in buffer[header_len..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer[..]);
+        packet.set_header_len(header_len);
+        packet.set_total_len(header_len + data_len);
+        packet.set_identification(0x4242);
+        packet.set_fragmentation(false, more_fragments, fragment_offset);
+        packet.set_ttl(64);
+        packet.set_protocol(PROTOCOL_UDP);
+        packet.set_source([10, 0, 0, 2]);
+        packet.set_destination([10, 0, 1, 5]);
+        packet.fill_checksum();
+        buffer
+    }
+
+    /// Fragments `buffer` and returns the fragments without the link header
+    fn fragment(buffer: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut fragmenter = Fragmenter::new(&LINK_HEADER, validate(buffer).unwrap(), mtu);
+        let count = fragmenter.remaining();
+        let mut fragments = Vec::new();
+        while fragmenter.has_next() {
+            assert_eq!(fragmenter.remaining(), count - fragments.len());
+            let mut out = vec![0; 2048];
+            let len = fragmenter.next_fragment(&mut out).unwrap();
+            assert_eq!(out[..LINK_HEADER.len()], LINK_HEADER);
+            fragments.push(out[LINK_HEADER.len()..len].to_vec());
+        }
+        assert!(fragmenter.next_fragment(&mut [0; 2048]).is_none());
+        assert_eq!((fragments.len(), fragmenter.remaining()), (count, 0));
+        fragments
+    }
+
+    #[test]
+    fn counts_multicast_destinations_apart_from_martians() {
+        let mut drops = Ipv4DropCounters::default();
+        let mut check = |source, destination, expected: fn(&Ipv4ValidationError) -> bool| {
+            let mut buffer = packet(&[], 8, false, 0);
+            let mut packet = Ipv4Packet::new_unchecked(&mut buffer[..]);
+            packet.set_source(source);
+            packet.set_destination(destination);
+            packet.fill_checksum();
+            let error = validate(&buffer).unwrap_err();
+            assert!(expected(&error), "{:?}", error);
+            drops.count(&error);
+        };
+        check([10, 0, 0, 2], [224, 0, 0, 1], |error| matches!(error, Ipv4ValidationError::MulticastDestination(_)));
+        check([10, 0, 0, 2], [239, 1, 2, 3], |error| matches!(error, Ipv4ValidationError::MulticastDestination(_)));
+        check([10, 0, 0, 2], [240, 0, 0, 1], |error| matches!(error, Ipv4ValidationError::MartianDestination(_)));
+        check([10, 0, 0, 2], [255; 4], |error| matches!(error, Ipv4ValidationError::MartianDestination(_)));
+        check([224, 0, 0, 1], [10, 0, 1, 5], |error| matches!(error, Ipv4ValidationError::MartianSource(_)));
+        assert_eq!(
+            (drops.multicast_destination, drops.martian_destination, drops.martian_source),
+            (2, 2, 1)
+        );
+        assert_eq!(drops.invalid_checksum, 0);
+    }
+
+    #[test]
+    fn fragments_packet_with_options() {
+        let options = [&RECORD_ROUTE[..], &[OPTION_NOP], &ROUTER_ALERT].concat();
+        let buffer = packet(&options, 100, false, 0);
+        let fragments = fragment(&buffer, 60);
+
+        let mut data = Vec::new();
+        let mut expected_offset = 0;
+        for (i, fragment) in fragments.iter().enumerate() {
+            let packet = validate(fragment).unwrap();
+            assert!(packet.total_len() <= 60);
+            assert!(packet.verify_checksum());
+            assert_eq!(packet.identification(), 0x4242);
+            assert_eq!((packet.ttl(), packet.protocol()), (64, PROTOCOL_UDP));
+            assert!(!packet.dont_fragment());
+            assert_eq!(packet.more_fragments(), i < fragments.len() - 1);
+            assert_eq!(packet.fragment_offset(), expected_offset);
+            assert_eq!(packet.fragment_offset() % 8, 0);
+            if i == 0 {
+                assert_eq!(packet.header()[IPV4_MIN_HEADER_SIZE..], buffer[IPV4_MIN_HEADER_SIZE..32]);
+            } else {
+                // Only the copied option is repeated
+                assert_eq!(packet.header_len(), 24);
+                assert_eq!(packet.header()[IPV4_MIN_HEADER_SIZE..], ROUTER_ALERT);
+            }
+            if i < fragments.len() - 1 {
+                assert_eq!(packet.payload().len() % 8, 0);
+            }
+            expected_offset += packet.payload().len();
+            data.extend_from_slice(packet.payload());
+        }
+        assert_eq!(fragments.len(), 4);
+        assert_eq!(data, &buffer[32..]);
+    }
+
+    #[test]
+    fn fragments_of_fragments_keep_their_position() {
+        // A middle fragment stays followed by more fragments
+        let buffer = packet(&[], 64, true, 800);
+        let fragments = fragment(&buffer, 60);
+        assert_eq!(fragments.len(), 2);
+        for (fragment, offset) in fragments.iter().zip(&[800, 840]) {
+            let packet = validate(fragment).unwrap();
+            assert!(packet.more_fragments());
+            assert_eq!(packet.fragment_offset(), *offset);
+        }
+
+        // The last fragment only ends with its own last fragment
+        let buffer = packet(&[], 64, false, 800);
+        let fragments = fragment(&buffer, 60);
+        let flags: Vec<bool> = fragments.iter().map(|f| validate(f).unwrap().more_fragments()).collect();
+        assert_eq!(flags, [true, false]);
+    }
+
+    #[test]
+    fn copies_only_copied_options_and_pads_them() {
+        // Loose source route with an odd length, followed by record route
+        let loose_source_route = [0x83, 7, 4, 10, 0, 0, 1];
+        let header = packet(&[&loose_source_route[..], &RECORD_ROUTE].concat(), 0, false, 0);
+        let mut out = [0xff; 60];
+        let len = copy_fragment_header(&header, &mut out);
+        assert_eq!(len, 28);
+        assert_eq!(out[..IPV4_MIN_HEADER_SIZE], header[..IPV4_MIN_HEADER_SIZE]);
+        assert_eq!(out[IPV4_MIN_HEADER_SIZE..27], loose_source_route);
+        assert_eq!(out[27], OPTION_END);
+
+        // Options after a malformed length are not copied
+        let header = packet(&[0x94, 1, 0, 0, 0x94, 4, 0, 0], 0, false, 0);
+        assert_eq!(copy_fragment_header(&header, &mut out), IPV4_MIN_HEADER_SIZE);
+    }
+
+    #[test]
+    fn does_not_fragment_into_mtu_without_room_for_data() {
+        // 56 bytes of header leave no room for 8 data bytes
+        let mut options = [0; 36];
+        options[..2].copy_from_slice(&[0x94, 36]);
+        let buffer = packet(&options, 64, false, 0);
+        let fragmenter = Fragmenter::new(&LINK_HEADER, validate(&buffer).unwrap(), 60);
+        assert!(!fragmenter.has_next());
+        assert_eq!(fragmenter.remaining(), 0);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::errors::Ipv6ValidationError;
+use crate::packet::{Ipv6Packet, IPV6_HEADER_SIZE};
+
+pub const NEXT_HEADER_ICMPV6: u8 = 58;
+
+pub const UNSPECIFIED: [u8; 16] = [0; 16];
+const LOOPBACK: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+/// The link-local all-nodes multicast address ff02::1
+pub const ALL_NODES: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+/// The link-local all-routers multicast address ff02::2
+pub const ALL_ROUTERS: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+pub fn is_multicast(address: [u8; 16]) -> bool {
+    address[0] == 0xff
+}
+
+/// Whether the address is in fe80::/10
+pub fn is_link_local(address: [u8; 16]) -> bool {
+    address[0] == 0xfe && address[1] & 0xc0 == 0x80
+}
+
+/// The link-local address with the modified EUI-64 interface identifier of `mac`, see RFC 4291 appendix A
+pub fn link_local_address(mac: [u8; 6]) -> [u8; 16] {
+    [
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5],
+    ]
+}
+
+/// The solicited-node multicast address of `address`, see RFC 4291 section 2.7.1
+pub fn solicited_node_address(address: [u8; 16]) -> [u8; 16] {
+    [
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, address[13], address[14], address[15],
+    ]
+}
+
+/// The ethernet destination of the multicast `address`, see RFC 2464 section 7
+pub fn multicast_mac(address: [u8; 16]) -> [u8; 6] {
+    [0x33, 0x33, address[12], address[13], address[14], address[15]]
+}
+
+/// Applies the checks a router performs before forwarding to the bytes after the link header
+///
+/// The returned packet is trimmed to the payload length, so link-layer padding is not part of it.
+pub fn validate(buffer: &[u8]) -> Result<Ipv6Packet<&[u8]>, Ipv6ValidationError> {
+    if buffer.len() < IPV6_HEADER_SIZE {
+        return Err(Ipv6ValidationError::Truncated);
+    }
+    let packet = Ipv6Packet::new_unchecked(buffer);
+    if packet.version() != 6 {
+        return Err(Ipv6ValidationError::InvalidVersion(packet.version()));
+    }
+    let packet = match Ipv6Packet::new_checked(buffer) {
+        Ok(packet) => packet,
+        Err(_) => return Err(Ipv6ValidationError::InvalidPayloadLength(packet.payload_len() as u16)),
+    };
+    // A multicast source is never valid, see RFC 4291 section 2.7
+    let source = packet.source();
+    if is_multicast(source) || source == LOOPBACK {
+        return Err(Ipv6ValidationError::InvalidSource(source));
+    }
+    let destination = packet.destination();
+    if destination == UNSPECIFIED || destination == LOOPBACK {
+        return Err(Ipv6ValidationError::InvalidDestination(destination));
+    }
+    Ok(Ipv6Packet::new_unchecked(packet.as_bytes()))
+}
+
+/// Whether a router may forward the packet, see RFC 4291 section 2.5.6
+///
+/// Link-local traffic never leaves its link and multicast routing is not supported.
+pub fn forwardable(packet: &Ipv6Packet<&[u8]>) -> bool {
+    let source = packet.source();
+    let destination = packet.destination();
+    source != UNSPECIFIED && !is_link_local(source) && !is_link_local(destination) && !is_multicast(destination)
+}
+
+/// Per-reason counters of dropped IPv6 packets
+#[derive(Debug, Default)]
+pub struct Ipv6DropCounters {
+    pub truncated: u64,
+    pub invalid_version: u64,
+    pub invalid_payload_length: u64,
+    pub invalid_source: u64,
+    pub invalid_destination: u64,
+}
+
+impl Ipv6DropCounters {
+    pub fn count(&mut self, error: &Ipv6ValidationError) {
+        let counter = match error {
+            Ipv6ValidationError::Truncated => &mut self.truncated,
+            Ipv6ValidationError::InvalidVersion(_) => &mut self.invalid_version,
+            Ipv6ValidationError::InvalidPayloadLength(_) => &mut self.invalid_payload_length,
+            Ipv6ValidationError::InvalidSource(_) => &mut self.invalid_source,
+            Ipv6ValidationError::InvalidDestination(_) => &mut self.invalid_destination,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    const SERVER: [u8; 16] = [0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5];
+
+    /// An IPv6 packet with `payload_len` bytes of payload
+    fn packet(source: [u8; 16], destination: [u8; 16], payload_len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; IPV6_HEADER_SIZE + payload_len];
+        let mut packet = Ipv6Packet::new_unchecked(&mut buffer[..]);
+        packet.set_version_class_flow(0, 0);
+        packet.set_payload_len(payload_len);
+        packet.set_next_header(17);
+        packet.set_hop_limit(64);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        buffer
+    }
+
+    #[test]
+    fn accepts_packet_and_trims_link_layer_padding() {
+        let mut buffer = packet(HOST, SERVER, 8);
+        buffer.extend_from_slice(&[0; 10]);
+        let packet = validate(&buffer).unwrap();
+        assert_eq!(packet.as_bytes().len(), IPV6_HEADER_SIZE + 8);
+        assert_eq!(packet.payload().len(), 8);
+        assert!(forwardable(&packet));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let buffer = packet(HOST, SERVER, 8);
+        match validate(&buffer[..IPV6_HEADER_SIZE - 1]) {
+            Err(Ipv6ValidationError::Truncated) => {}
+            result => panic!("{:?}", result),
+        }
+        match validate(&buffer[..IPV6_HEADER_SIZE + 7]) {
+            Err(Ipv6ValidationError::InvalidPayloadLength(8)) => {}
+            result => panic!("{:?}", result),
+        }
+        let mut version4 = buffer.clone();
+        version4[0] = 0x45;
+        match validate(&version4) {
+            Err(Ipv6ValidationError::InvalidVersion(4)) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_multicast_and_loopback_addresses() {
+        for &source in &[ALL_NODES, LOOPBACK] {
+            match validate(&packet(source, SERVER, 0)) {
+                Err(Ipv6ValidationError::InvalidSource(address)) => assert_eq!(address, source),
+                result => panic!("{:?}", result),
+            }
+        }
+        for &destination in &[UNSPECIFIED, LOOPBACK] {
+            match validate(&packet(HOST, destination, 0)) {
+                Err(Ipv6ValidationError::InvalidDestination(address)) => assert_eq!(address, destination),
+                result => panic!("{:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_link_local_and_multicast_traffic_on_the_link() {
+        let link_local = link_local_address([0x02, 0, 0, 0, 0, 0x10]);
+        for &(source, destination) in &[
+            (link_local, SERVER),
+            (HOST, link_local),
+            (HOST, ALL_NODES),
+            (HOST, solicited_node_address(SERVER)),
+            (UNSPECIFIED, SERVER),
+        ] {
+            let buffer = packet(source, destination, 0);
+            let packet = validate(&buffer).unwrap();
+            assert!(!forwardable(&packet));
+        }
+    }
+
+    #[test]
+    fn derives_addresses_from_mac() {
+        let mac = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+        let link_local = link_local_address(mac);
+        assert_eq!(link_local, [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x50, 0x54, 0, 0xff, 0xfe, 0x12, 0x34, 0x56]);
+        assert!(is_link_local(link_local));
+        let solicited_node = solicited_node_address(link_local);
+        assert_eq!(solicited_node, [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0x12, 0x34, 0x56]);
+        assert_eq!(multicast_mac(solicited_node), [0x33, 0x33, 0xff, 0x12, 0x34, 0x56]);
+    }
+}
@@ -1,93 +1,162 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(asm)]
 #![feature(global_asm)]
 
+#[cfg(not(test))]
 global_asm!(include_str!("start.s"));
 
 extern crate register;
 
+mod arp;
+mod barrier;
+mod checksum;
+mod config;
+mod cpu;
 mod errors;
+#[cfg(not(test))]
+mod exceptions;
+mod fdt;
+mod flow;
+// Boot-time modules, only reached from the entry points that host tests do not build
+#[cfg_attr(test, allow(dead_code))]
+mod gic;
+mod icmp;
+mod icmpv6;
+mod ipv4;
+mod ipv6;
 mod memory_handle;
+mod mmio;
+#[cfg_attr(test, allow(dead_code))]
+mod mmu;
+#[cfg(test)]
+mod mock_device;
+mod ndp;
+mod packet;
+mod percpu;
+mod port;
+#[cfg_attr(test, allow(dead_code))]
+mod psci;
+mod router;
+mod router_advertisement;
+mod routing;
+#[cfg_attr(test, allow(dead_code))]
+mod smp;
+mod spinlock;
+mod timer;
+mod token_bucket;
 mod util;
 mod virtio;
+mod virtio_control;
 mod virtio_device_register;
 mod virtqueue;
 mod virtqueue_network;
+mod virtqueue_packed;
 
+#[cfg(not(test))]
 use core::panic::PanicInfo;
+use core::sync::atomic::AtomicPtr;
+#[cfg(not(test))]
+use core::sync::atomic::Ordering;
 
+#[cfg(not(test))]
 use memory_handle::MemoryHandle;
-use virtio::VirtioMMIONetworkDevice;
+use router::Router;
+use timer::{Instant, TimerWheel};
 
-const ETHERTYPE_IPV4: u16 = 0x0800;
-const ETHERTYPE_ARP: u16 = 0x0806;
-const ETHERTYPE_IPV6: u16 = 0x86dd;
+/// Published by the first core once the router is initialized, the other cores wait for it
+#[cfg_attr(test, allow(dead_code))]
+static ROUTER: AtomicPtr<Router> = AtomicPtr::new(core::ptr::null_mut());
 
+#[cfg(not(test))]
 #[panic_handler]
-fn handle_panic(_panic_info: &PanicInfo) -> ! {
-    // let _ = util::print(format_args!("Panic! {:?}\n", panic_info));
-    loop {}
+fn handle_panic(panic_info: &PanicInfo) -> ! {
+    let _ = util::print(format_args!("Panic! {}\n", panic_info));
+    router::shutdown()
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn main() -> ! {
+    mmu::initialize();
     let mut memory = MemoryHandle::new(0x46000000, 0x1000000);
-    let mut ingress_nic = VirtioMMIONetworkDevice::initialize(0x000000000a003e00, &mut memory).unwrap();
-    let mut egress_nic = VirtioMMIONetworkDevice::initialize(0x000000000a003c00, &mut memory).unwrap();
-    let ingress_recv_rq = &mut ingress_nic.receiveq1;
-    let egress_send_rq = &mut egress_nic.sendq1;
-    egress_nic.register.queue_notify.set(1);
-    //let mut counter: u64 = 0;
-    loop {
-        if let Some(queue_element) = ingress_recv_rq.try_take() {
-            //util::print(format_args!("handling packet {:x}\n", counter)).unwrap();
-            //counter += 1;
-            let (_header, data) = queue_element.as_network_packet();
-            //// util::print(format_args!("data = {:x?} \n", &_data[0..128])).unwrap();
-            // data contains the entire ethernet frame:
-            // 6 bytes MAC destination
-            // 6 bytes MAC source
-            // (802.1Q tag, optional, so no)
-            // 2 bytes ethertype
-            // payload!
-                // ipv4:
-                // 1 byte version and IHL
-                // 1 byte DSCP and ECN
-                // 2 bytes total length
-                // 2 bytes identification
-                // 2 bytes flags and fragment offset
-                // 1 byte TTL
-                // 1 byte protocol
-                // 2 bytes header checksum
-                // 4 bytes source address
-                // 4 bytes destination address
-                // ipv6:
+    // IRQs stay masked, except for taking the interrupts that woke the core from wfi
+    cpu::disable_irq();
+    gic::initialize();
+    timer::initialize().unwrap();
+    psci::initialize();
+    // The cores are counted before the ports are initialized, which distribute their queue pairs
+    smp::start_secondary_cores();
+    let router = Router::new(config::PORTS, &mut memory);
+    router.set_receive_interrupts(false);
+    // main never returns, so the router lives as long as the other cores use it
+    ROUTER.store(&router as *const Router as *mut Router, Ordering::Release);
+    cpu::send_event();
+    let mut timers = TimerWheel::new(Instant::now());
+    timers
+        .schedule_periodic(Instant::now(), config::PORT_TIMER_INTERVAL, |router: &mut &Router, now| {
+            router.poll_timers(now)
+        })
+        .unwrap();
+    run(&router, &mut timers)
+}
+
+/// Called by `secondary_entry` in start.s on the cores started by `smp::start_secondary_cores`
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn secondary_main(core: usize) -> ! {
+    mmu::initialize_core();
+    gic::initialize_core(core);
+    timer::initialize_core(core);
+    let router = loop {
+        let router = ROUTER.load(Ordering::Acquire);
+        if !router.is_null() {
+            break unsafe { &*router };
+        }
+        cpu::wait_for_event();
+    };
+    run(router, &mut TimerWheel::new(Instant::now()))
+}
 
-            let mut ethertype_bytes: [u8; 2] = [0; 2];
-            ethertype_bytes.clone_from_slice(&data[12..14]);
-            let ethertype = u16::from_be_bytes(ethertype_bytes);
-            if ethertype == ETHERTYPE_IPV4 {
-                let _source: &[u8] = &data[14+12..14+12+4];
-                let _destination: &[u8] = &data[14+16..14+16+4];
-                // util::print(format_args!("### ipv4 source = {:?}, destination = {:?} \n", source, destination)).unwrap();
-                if let Some(egress_queue_element) = egress_send_rq.try_take() {
-                    // util::print(format_args!("############# passing packet to sendqueue\n"));
-                    egress_queue_element.copy_from(&queue_element);
-                    egress_send_rq.offer(egress_queue_element.desc_idx);
-                    egress_nic.register.queue_notify.set(1);
-                } else {
-                    // util::print(format_args!("[warn] egress send queue full\n")).unwrap();
-                }
-            } else if ethertype == ETHERTYPE_IPV6 {
-                // util::print(format_args!("[warn] ipv6 packet\n")).unwrap();
-            } else if ethertype == ETHERTYPE_ARP {
-                // util::print(format_args!("[warn] arp packet\n")).unwrap();
-            } else {
-                // util::print(format_args!("[warn] unknown ethertype {}\n", ethertype)).unwrap();
+/// Forwards the frames of the calling core's queue pairs and calls the timers that are due
+#[cfg_attr(test, allow(dead_code))]
+fn run<'a>(router: &'a Router, timers: &mut TimerWheel<&'a Router>) -> ! {
+    // The devices' interrupts are routed to the first core
+    let first_core = cpu::core_index() == 0;
+    let mut context = router;
+    let mut last_received = Instant::now();
+    loop {
+        let now = Instant::now();
+        timers.tick(now, &mut context);
+        if router.poll(now) {
+            last_received = now;
+            continue;
+        }
+        if now - last_received < config::IDLE_TIME_BEFORE_SLEEP {
+            continue;
+        }
+        // Busy polling found nothing for a while, sleep until a device has received a frame or
+        // the next timer is due. Frames received before the interrupts are enabled are found by
+        // the final poll. The other cores wake up periodically to poll.
+        let deadline = if first_core {
+            router.set_receive_interrupts(true);
+            timers.next_deadline()
+        } else {
+            Some(now + config::SECONDARY_IDLE_POLL_INTERVAL)
+        };
+        if !router.poll(now) {
+            if let Some(deadline) = deadline {
+                timer::set_alarm(deadline);
             }
-            ingress_recv_rq.offer(queue_element.desc_idx);
-            ingress_nic.register.queue_notify.set(0);
+            cpu::wait_for_interrupt();
+            // Take the pending interrupts to acknowledge them
+            cpu::enable_irq();
+            cpu::disable_irq();
+            timer::cancel_alarm();
+        }
+        // After a timer woke the core, the loop goes back to sleep unless a frame arrived
+        if first_core {
+            router.set_receive_interrupts(false);
         }
     }
 }
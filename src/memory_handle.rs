@@ -12,15 +12,19 @@ impl MemoryHandle {
         MemoryHandle { start, len, pos: 0 }
     }
 
+    /// Reserves `len` bytes starting at a multiple of `alignment`, which is a power of two
     pub fn allocate(
         &mut self,
         len: usize,
-        _alignment: u32,
+        alignment: u32,
     ) -> Result<usize, MemoryReservationError> {
-        //TODO alignment
-        let segment_start = self.start + self.pos;
-        //TODO overflow
-        self.pos += len;
+        let mask = alignment as usize - 1;
+        let segment_start = (self.start + self.pos + mask) & !mask;
+        let end = segment_start - self.start + len;
+        if end > self.len {
+            return Err(MemoryReservationError::MemoryExhausted);
+        }
+        self.pos = end;
         Ok(segment_start)
     }
 }
@@ -0,0 +1,85 @@
+//! Access to memory-mapped device registers through a backend
+//!
+//! The firmware accesses the hardware, host tests pass a simulated device instead, see `mock_device`.
+
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use register::{Field, FieldValue, LocalRegisterCopy, RegisterLongName};
+
+/// Reads and writes of device registers by their address
+pub trait MmioBackend: Debug + Sync {
+    fn read_u8(&self, address: usize) -> u8;
+    fn read_u16(&self, address: usize) -> u16;
+    fn read_u32(&self, address: usize) -> u32;
+    fn write_u32(&self, address: usize, value: u32);
+}
+
+/// Volatile accesses to the registers mapped at their address, see `mmu::initialize`
+#[derive(Debug)]
+#[cfg_attr(test, allow(dead_code))]
+pub struct Hardware;
+
+#[cfg_attr(test, allow(dead_code))]
+pub static HARDWARE: Hardware = Hardware;
+
+impl MmioBackend for Hardware {
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { (address as *const u8).read_volatile() }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { (address as *const u16).read_volatile() }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { (address as *const u32).read_volatile() }
+    }
+
+    fn write_u32(&self, address: usize, value: u32) {
+        unsafe { (address as *mut u32).write_volatile(value) }
+    }
+}
+
+/// A 32 bit register, with the interface of the `register` crate's MMIO registers
+pub struct Register<R: RegisterLongName = ()> {
+    backend: &'static dyn MmioBackend,
+    address: usize,
+    associated_register: PhantomData<R>,
+}
+
+impl<R: RegisterLongName> Register<R> {
+    pub fn new(backend: &'static dyn MmioBackend, address: usize) -> Self {
+        Register {
+            backend,
+            address,
+            associated_register: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> u32 {
+        self.backend.read_u32(self.address)
+    }
+
+    pub fn set(&self, value: u32) {
+        self.backend.write_u32(self.address, value)
+    }
+
+    /// Reads the register once, for looking at several of its fields
+    pub fn extract(&self) -> LocalRegisterCopy<u32, R> {
+        LocalRegisterCopy::new(self.get())
+    }
+
+    pub fn is_set(&self, field: Field<u32, R>) -> bool {
+        LocalRegisterCopy::new(self.get()).is_set(field)
+    }
+
+    /// Writes the fields of `value`, the other fields are zero
+    pub fn write(&self, value: FieldValue<u32, R>) {
+        self.set(value.value)
+    }
+
+    /// Writes the fields of `value`, the other fields keep their value
+    pub fn modify(&self, value: FieldValue<u32, R>) {
+        self.set(value.modify(self.get()))
+    }
+}
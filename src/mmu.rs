@@ -0,0 +1,185 @@
+use crate::barrier;
+use crate::config;
+
+/// Index into MAIR_EL1 of device memory, used for the MMIO regions
+const ATTR_DEVICE: u64 = 0;
+/// Index into MAIR_EL1 of write-back cacheable normal memory, used for RAM
+const ATTR_NORMAL: u64 = 1;
+/// Device-nGnRnE and Normal Inner/Outer Write-Back Read/Write-Allocate, see the Arm ARM section D13.2.95
+const MAIR: u64 = (0x00 << (8 * ATTR_DEVICE)) | (0xff << (8 * ATTR_NORMAL));
+
+// level 1 and 2 block and level 3 page descriptor for a 4 KiB granule, see the Arm ARM section D8.3:
+// bits 0 to 1 descriptor type, 0b01 for a block, 0b11 for a page
+// bits 2 to 4 memory attribute index
+// bits 6 to 7 access permissions, 0 for read/write at EL1 only
+// bits 8 to 9 shareability
+// bit 10 access flag
+// bits 12 to 47 output address, aligned to the size mapped by the descriptor
+// bit 53 privileged execute never
+// bit 54 execute never
+const DESCRIPTOR_BLOCK: u64 = 0b01;
+const DESCRIPTOR_PAGE: u64 = 0b11;
+/// Level 1 and 2 descriptor pointing to the next level table, bits 12 to 47 are its address
+const DESCRIPTOR_TABLE: u64 = 0b11;
+const DESCRIPTOR_INNER_SHAREABLE: u64 = 0b11 << 8;
+const DESCRIPTOR_ACCESS_FLAG: u64 = 1 << 10;
+const DESCRIPTOR_EXECUTE_NEVER: u64 = (1 << 53) | (1 << 54);
+
+/// Entries of a 4 KiB translation table
+const TABLE_ENTRIES: usize = 512;
+/// A level 1 entry maps 1 GiB
+const LEVEL1_BLOCK_SIZE: u64 = 1 << 30;
+/// A level 2 entry maps 2 MiB
+const LEVEL2_BLOCK_SIZE: u64 = 1 << 21;
+const PAGE_SIZE: u64 = 1 << 12;
+/// Size of the identity mapped address space, covering the MMIO regions and up to 3 GiB of RAM
+const ADDRESS_SPACE_SIZE: u64 = 1 << 32;
+/// The QEMU virt machine's RAM starts here, everything below is MMIO or flash
+const RAM_START: u64 = 0x40000000;
+
+// TCR_EL1, see the Arm ARM section D13.2.131
+/// Translation starts at level 1 for a 39 bit address space and a 4 KiB granule
+const TCR_T0SZ: u64 = 64 - 39;
+const TCR_IRGN0_WRITE_BACK: u64 = 0b01 << 8;
+const TCR_ORGN0_WRITE_BACK: u64 = 0b01 << 10;
+const TCR_SH0_INNER_SHAREABLE: u64 = 0b11 << 12;
+/// No translations through TTBR1_EL1, the upper half of the address space is unused
+const TCR_EPD1: u64 = 1 << 23;
+const TCR_IPS_SHIFT: u64 = 32;
+
+// SCTLR_EL1, see the Arm ARM section D13.2.118
+const SCTLR_MMU: u64 = 1 << 0;
+const SCTLR_ALIGNMENT_CHECK: u64 = 1 << 1;
+const SCTLR_DATA_CACHE: u64 = 1 << 2;
+const SCTLR_INSTRUCTION_CACHE: u64 = 1 << 12;
+
+#[repr(C, align(4096))]
+struct TranslationTable([u64; TABLE_ENTRIES]);
+
+// Only written by `initialize` before the MMU is on
+static mut LEVEL1_TABLE: TranslationTable = TranslationTable([0; TABLE_ENTRIES]);
+/// Maps the GiB holding the stacks
+static mut LEVEL2_TABLE: TranslationTable = TranslationTable([0; TABLE_ENTRIES]);
+/// Maps the 2 MiB holding the stacks
+static mut LEVEL3_TABLE: TranslationTable = TranslationTable([0; TABLE_ENTRIES]);
+
+extern "C" {
+    /// Start of the cores' guard pages and stacks, see aarch64-qemu.ld
+    static LD_STACKS: u8;
+    /// Size of a core's guard page and stacks, its address is the value
+    static LD_STACK_STRIDE: u8;
+    /// Number of cores stacks are reserved for, its address is the value
+    static LD_MAX_CORES: u8;
+}
+
+fn stacks() -> u64 {
+    unsafe { &LD_STACKS as *const u8 as u64 }
+}
+
+/// Whether `address` is in the guard page below a core's stack, which is left unmapped
+pub fn is_stack_guard(address: u64) -> bool {
+    let stride = unsafe { &LD_STACK_STRIDE as *const u8 as u64 };
+    let offset = address.wrapping_sub(stacks());
+    offset < stride * config::MAX_CORES as u64 && offset % stride < PAGE_SIZE
+}
+
+/// Attributes of a block or page at `address`, RAM is normal memory and MMIO device memory
+fn attributes(address: u64) -> u64 {
+    if address >= RAM_START {
+        (ATTR_NORMAL << 2) | DESCRIPTOR_INNER_SHAREABLE | DESCRIPTOR_ACCESS_FLAG
+    } else {
+        (ATTR_DEVICE << 2) | DESCRIPTOR_ACCESS_FLAG | DESCRIPTOR_EXECUTE_NEVER
+    }
+}
+
+/// Identity maps the address space and turns on the MMU and the caches of the first core
+///
+/// RAM is mapped as normal write-back memory, which the virtio devices access coherently. The MMIO
+/// regions below it are device memory. The 2 MiB holding the stacks are split into pages, leaving
+/// the guard pages unmapped.
+///
+/// With the MMU off all data accesses are Device-nGnRnE, which fault if they are unaligned. This
+/// and `initialize_core` run before the MMU is on, so the target is built with `+strict-align`.
+pub fn initialize() {
+    // Cores beyond the reserved stacks would run on memory that is not theirs
+    let max_cores = unsafe { &LD_MAX_CORES as *const u8 as usize };
+    assert_eq!(max_cores, config::MAX_CORES, "LD_MAX_CORES in aarch64-qemu.ld differs from config::MAX_CORES");
+    let level2_start = stacks() & !(LEVEL1_BLOCK_SIZE - 1);
+    let level3_start = stacks() & !(LEVEL2_BLOCK_SIZE - 1);
+    unsafe {
+        for i in 0..TABLE_ENTRIES {
+            let address = level3_start + i as u64 * PAGE_SIZE;
+            LEVEL3_TABLE.0[i] = if is_stack_guard(address) {
+                0
+            } else {
+                address | DESCRIPTOR_PAGE | attributes(address)
+            };
+        }
+        for i in 0..TABLE_ENTRIES {
+            let address = level2_start + i as u64 * LEVEL2_BLOCK_SIZE;
+            LEVEL2_TABLE.0[i] = if address == level3_start {
+                core::ptr::addr_of!(LEVEL3_TABLE) as u64 | DESCRIPTOR_TABLE
+            } else {
+                address | DESCRIPTOR_BLOCK | attributes(address)
+            };
+        }
+        for i in 0..TABLE_ENTRIES {
+            let address = i as u64 * LEVEL1_BLOCK_SIZE;
+            LEVEL1_TABLE.0[i] = if address >= ADDRESS_SPACE_SIZE {
+                0
+            } else if address == level2_start {
+                core::ptr::addr_of!(LEVEL2_TABLE) as u64 | DESCRIPTOR_TABLE
+            } else {
+                address | DESCRIPTOR_BLOCK | attributes(address)
+            };
+        }
+    }
+    // The table writes must be visible to the table walkers of all cores before their MMU is on
+    barrier::system_barrier();
+    initialize_core();
+}
+
+/// Turns on the MMU and the caches of the calling core, with the tables built by `initialize`
+#[cfg(not(test))]
+pub fn initialize_core() {
+    let table = unsafe { core::ptr::addr_of!(LEVEL1_TABLE) as u64 };
+
+    // The physical address size is limited to what the core supports
+    let mmfr0: u64;
+    unsafe { asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0, options(nomem, nostack)) };
+    let tcr = TCR_T0SZ
+        | TCR_IRGN0_WRITE_BACK
+        | TCR_ORGN0_WRITE_BACK
+        | TCR_SH0_INNER_SHAREABLE
+        | TCR_EPD1
+        | ((mmfr0 & 0b111) << TCR_IPS_SHIFT);
+
+    unsafe {
+        asm!(
+            "msr mair_el1, {mair}",
+            "msr tcr_el1, {tcr}",
+            "msr ttbr0_el1, {table}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb ish",
+            "isb",
+            "mrs {sctlr}, sctlr_el1",
+            "orr {sctlr}, {sctlr}, {enable}",
+            "bic {sctlr}, {sctlr}, {disable}",
+            "msr sctlr_el1, {sctlr}",
+            "isb",
+            mair = in(reg) MAIR,
+            tcr = in(reg) tcr,
+            table = in(reg) table,
+            enable = in(reg) SCTLR_MMU | SCTLR_DATA_CACHE | SCTLR_INSTRUCTION_CACHE,
+            // The alignment check is cleared explicitly, the boot firmware may have set it
+            disable = in(reg) SCTLR_ALIGNMENT_CHECK,
+            sctlr = out(reg) _,
+            options(nostack),
+        )
+    };
+}
+
+/// Host tests run with the host's translation tables
+#[cfg(test)]
+pub fn initialize_core() {}
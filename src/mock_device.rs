@@ -0,0 +1,697 @@
+//! A simulated virtio-net device behind the virtio-mmio registers, for host tests
+//!
+//! The device lives in host memory and handles the driver's register accesses through
+//! `MmioBackend`. The driver's memory is a host buffer, so guest addresses are host addresses and
+//! the device uses the rings in place. It processes the control and transmit queues when the driver
+//! notifies it and fills receive buffers with the frames a test injects.
+
+use crate::memory_handle::MemoryHandle;
+use crate::mmio::MmioBackend;
+use crate::virtio_device_register::VirtioMMIORegister;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const MAGIC_VALUE: u32 = 0x74726976;
+const DEVICE_ID_NETWORK: u32 = 1;
+const VENDOR_ID: u32 = 0x554d4551;
+
+// Register offsets, see section 4.2.2
+const MAGIC_VALUE_OFFSET: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID_OFFSET: usize = 0x00c;
+const HOST_FEATURES: usize = 0x010;
+const HOST_FEATURES_SEL: usize = 0x014;
+const GUEST_FEATURES: usize = 0x020;
+const GUEST_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_MAC: usize = 0x100;
+const CONFIG_STATUS: usize = 0x106;
+const CONFIG_MAX_VIRTQUEUE_PAIRS: usize = 0x108;
+const CONFIG_MTU: usize = 0x10a;
+const REGISTERS_SIZE: usize = 0x200;
+
+// Device status bits, see section 2.1
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+/// Feature bits of word 0
+pub const VIRTIO_NET_F_MTU: u32 = 1 << 3;
+pub const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+pub const VIRTIO_NET_F_STATUS: u32 = 1 << 16;
+pub const VIRTIO_NET_F_CTRL_VQ: u32 = 1 << 17;
+pub const VIRTIO_NET_F_CTRL_RX: u32 = 1 << 18;
+pub const VIRTIO_NET_F_MQ: u32 = 1 << 22;
+/// Feature bits of word 1
+pub const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+pub const VIRTIO_F_RING_PACKED: u32 = 1 << 2;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+const VIRTIO_NET_OK: u8 = 0;
+/// Size of the virtio-net header of legacy and of modern devices, see section 5.1.6
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+
+/// What the device offers, QEMU's virtio-net-device by default
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceConfig {
+    /// 1 for a legacy device, 2 for a modern one
+    pub version: u32,
+    /// Feature bits 0 to 31 and 32 to 63, modern devices need VIRTIO_F_VERSION_1
+    pub features: [u32; 2],
+    pub mac: [u8; 6],
+    pub mtu: u16,
+    pub max_virtqueue_pairs: u16,
+    pub queue_num_max: u32,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            version: 1,
+            features: [
+                VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU | VIRTIO_NET_F_STATUS | VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_RX,
+                0,
+            ],
+            mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+            mtu: 1500,
+            max_virtqueue_pairs: 1,
+            queue_num_max: 1024,
+        }
+    }
+}
+
+/// A descriptor chain the device took from the available ring or descriptors
+#[derive(Debug)]
+pub struct Chain {
+    /// Head index of split queues, buffer ID of packed queues
+    pub id: u16,
+    /// Address, length and whether the device writes to it, for every descriptor
+    pub buffers: Vec<(u64, u32, bool)>,
+}
+
+impl Chain {
+    /// The bytes of the buffers the device reads
+    pub fn read(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &(addr, len, _) in self.buffers.iter().filter(|buffer| !buffer.2) {
+            data.extend_from_slice(unsafe { std::slice::from_raw_parts(addr as *const u8, len as usize) });
+        }
+        data
+    }
+
+    /// Writes `data` to the buffers the device writes to, returns the number of bytes written
+    pub fn write(&self, data: &[u8]) -> u32 {
+        let mut written = 0;
+        for &(addr, len, _) in self.buffers.iter().filter(|buffer| buffer.2) {
+            let len = std::cmp::min(len as usize, data.len() - written);
+            unsafe { std::ptr::copy_nonoverlapping(data[written..].as_ptr(), addr as *mut u8, len) };
+            written += len;
+        }
+        written as u32
+    }
+}
+
+/// The device side of a split or packed virtqueue in the driver's memory
+#[derive(Debug, Default)]
+pub struct DeviceQueue {
+    descriptors: usize,
+    driver: usize,
+    device: usize,
+    size: u16,
+    packed: bool,
+    /// Next available ring entry or descriptor the device takes
+    next_avail: u16,
+    /// Next used ring entry or descriptor the device writes
+    next_used: u16,
+    avail_wrap_counter: bool,
+    used_wrap_counter: bool,
+}
+
+impl DeviceQueue {
+    /// A split virtqueue with the areas at the given addresses, see section 2.6
+    pub fn split(descriptors: usize, driver: usize, device: usize, size: u16) -> Self {
+        DeviceQueue {
+            descriptors,
+            driver,
+            device,
+            size,
+            ..Default::default()
+        }
+    }
+
+    /// A packed virtqueue with the ring and the event suppression structures at the given addresses, see section 2.7
+    pub fn packed(ring: usize, driver: usize, device: usize, size: u16) -> Self {
+        DeviceQueue {
+            descriptors: ring,
+            driver,
+            device,
+            size,
+            packed: true,
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
+            ..Default::default()
+        }
+    }
+
+    fn descriptor(&self, index: u16) -> (u64, u32, u16, u16) {
+        let descriptor = self.descriptors + index as usize * 16;
+        unsafe {
+            (
+                (descriptor as *const u64).read_volatile(),
+                ((descriptor + 8) as *const u32).read_volatile(),
+                ((descriptor + 12) as *const u16).read_volatile(),
+                ((descriptor + 14) as *const u16).read_volatile(),
+            )
+        }
+    }
+
+    /// Takes the next chain the driver made available
+    pub fn pop(&mut self) -> Option<Chain> {
+        if self.packed {
+            self.pop_packed()
+        } else {
+            self.pop_split()
+        }
+    }
+
+    fn pop_split(&mut self) -> Option<Chain> {
+        let avail_idx = unsafe { ((self.driver + 2) as *const u16).read_volatile() };
+        if avail_idx == self.next_avail {
+            return None;
+        }
+        let entry = self.driver + 4 + (self.next_avail % self.size) as usize * 2;
+        let head = unsafe { (entry as *const u16).read_volatile() };
+        self.next_avail = self.next_avail.wrapping_add(1);
+        let mut buffers = Vec::new();
+        let mut index = head;
+        loop {
+            assert!(index < self.size, "descriptor {} beyond the queue size", index);
+            let (addr, len, flags, next) = self.descriptor(index);
+            buffers.push((addr, len, flags & VIRTQ_DESC_F_WRITE != 0));
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Some(Chain { id: head, buffers })
+    }
+
+    fn pop_packed(&mut self) -> Option<Chain> {
+        let mut buffers = Vec::new();
+        loop {
+            let descriptor = self.descriptors + self.next_avail as usize * 16;
+            let (addr, len, _, _) = self.descriptor(self.next_avail);
+            let (id, flags) = unsafe {
+                (
+                    ((descriptor + 12) as *const u16).read_volatile(),
+                    ((descriptor + 14) as *const u16).read_volatile(),
+                )
+            };
+            let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+            let used = flags & VIRTQ_DESC_F_USED != 0;
+            if avail != self.avail_wrap_counter || used == self.avail_wrap_counter {
+                assert!(buffers.is_empty(), "chain ends in a descriptor that is not available");
+                return None;
+            }
+            buffers.push((addr, len, flags & VIRTQ_DESC_F_WRITE != 0));
+            self.next_avail += 1;
+            if self.next_avail == self.size {
+                self.next_avail = 0;
+                self.avail_wrap_counter = !self.avail_wrap_counter;
+            }
+            // The buffer ID is in the last descriptor of a chain
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Some(Chain { id, buffers });
+            }
+        }
+    }
+
+    /// Returns a chain to the driver, `len` is the number of bytes the device wrote
+    pub fn push(&mut self, chain: Chain, len: u32) {
+        if self.packed {
+            let descriptor = self.descriptors + self.next_used as usize * 16;
+            let flags = if self.used_wrap_counter {
+                VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+            } else {
+                0
+            };
+            unsafe {
+                ((descriptor + 8) as *mut u32).write_volatile(len);
+                ((descriptor + 12) as *mut u16).write_volatile(chain.id);
+                ((descriptor + 14) as *mut u16).write_volatile(flags);
+            }
+            // A used chain takes as many ring slots as it had descriptors
+            self.next_used += chain.buffers.len() as u16;
+            if self.next_used >= self.size {
+                self.next_used -= self.size;
+                self.used_wrap_counter = !self.used_wrap_counter;
+            }
+        } else {
+            let entry = self.device + 4 + (self.next_used % self.size) as usize * 8;
+            unsafe {
+                (entry as *mut u32).write_volatile(chain.id as u32);
+                ((entry + 4) as *mut u32).write_volatile(len);
+            }
+            self.next_used = self.next_used.wrapping_add(1);
+            unsafe { ((self.device + 2) as *mut u16).write_volatile(self.next_used) };
+        }
+    }
+}
+
+/// Registers of a virtqueue
+#[derive(Debug, Default)]
+struct QueueRegisters {
+    num: u32,
+    align: u32,
+    pfn: u32,
+    ready: u32,
+    descriptors: u64,
+    driver: u64,
+    device: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    status: u32,
+    features_sel: u32,
+    guest_features_sel: u32,
+    guest_features: [u32; 2],
+    page_size: u32,
+    queue_sel: u32,
+    queue_registers: Vec<QueueRegisters>,
+    /// The queues activated since the last reset
+    queues: Vec<Option<DeviceQueue>>,
+    config_status: u16,
+    /// Frames sent by the driver since it set DRIVER_OK, by transmit queue
+    transmitted: Vec<(usize, Vec<u8>)>,
+    /// Class, command and data of the control commands
+    commands: Vec<(u8, u8, Vec<u8>)>,
+    /// The ack written for control commands
+    control_ack: u8,
+    /// Whether control commands are left in the queue
+    control_stalled: bool,
+    /// Whether frames are left in the transmit queues
+    transmit_stalled: bool,
+}
+
+/// A virtio-net device at a virtio-mmio base address
+#[derive(Debug)]
+pub struct MockDevice {
+    base_address: usize,
+    config: DeviceConfig,
+    state: Mutex<State>,
+}
+
+impl MockDevice {
+    /// Creates a device living as long as the test process, like the registers of real devices
+    pub fn new(base_address: usize, config: DeviceConfig) -> &'static MockDevice {
+        let queues = 2 * config.max_virtqueue_pairs as usize + 1;
+        let state = State {
+            status: 0,
+            features_sel: 0,
+            guest_features_sel: 0,
+            guest_features: [0; 2],
+            page_size: 0,
+            queue_sel: 0,
+            queue_registers: (0..queues).map(|_| QueueRegisters::default()).collect(),
+            queues: (0..queues).map(|_| None).collect(),
+            config_status: 1,
+            transmitted: Vec::new(),
+            commands: Vec::new(),
+            control_ack: VIRTIO_NET_OK,
+            control_stalled: false,
+            transmit_stalled: false,
+        };
+        Box::leak(Box::new(MockDevice {
+            base_address,
+            config,
+            state: Mutex::new(state),
+        }))
+    }
+
+    /// The registers for the driver
+    pub fn register(&'static self) -> VirtioMMIORegister {
+        VirtioMMIORegister::with_backend(self.base_address, self)
+    }
+
+    pub fn status(&self) -> u32 {
+        self.state.lock().unwrap().status
+    }
+
+    /// The size of a queue the driver activated
+    pub fn queue_num(&self, index: usize) -> u32 {
+        self.state.lock().unwrap().queue_registers[index].num
+    }
+
+    /// The control commands the device processed
+    pub fn commands(&self) -> Vec<(u8, u8, Vec<u8>)> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// Removes the frames the driver transmitted, with the transmit queue's index
+    pub fn take_transmitted(&self) -> Vec<(usize, Vec<u8>)> {
+        std::mem::take(&mut self.state.lock().unwrap().transmitted)
+    }
+
+    /// Acknowledges the following control commands with `ack`, e.g. 1 for VIRTIO_NET_ERR
+    pub fn set_control_ack(&self, ack: u8) {
+        self.state.lock().unwrap().control_ack = ack;
+    }
+
+    /// Leaves the following control commands in the queue instead of processing them
+    pub fn set_control_stalled(&self, stalled: bool) {
+        self.state.lock().unwrap().control_stalled = stalled;
+    }
+
+    /// Leaves the following frames in the transmit queues, sends them once no longer stalled
+    pub fn set_transmit_stalled(&self, stalled: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.transmit_stalled = stalled;
+        if !stalled && state.status & STATUS_DRIVER_OK != 0 {
+            for index in (1..self.control_queue(&state)).step_by(2) {
+                self.process_transmit(&mut state, index, true);
+            }
+        }
+    }
+
+    /// Asks the driver to reset the device, see section 2.1.2
+    pub fn set_needs_reset(&self) {
+        self.state.lock().unwrap().status |= STATUS_DEVICE_NEEDS_RESET;
+    }
+
+    /// Receives a frame on the receive queue of a queue pair, `false` if it has no buffer
+    pub fn inject(&self, pair: usize, frame: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let header_size = self.header_size();
+        let queue = match state.queues[2 * pair].as_mut() {
+            Some(queue) => queue,
+            None => return false,
+        };
+        match queue.pop() {
+            Some(chain) => {
+                let mut data = vec![0; header_size];
+                if self.modern() {
+                    // num_buffers, the frame fits into one buffer
+                    data[LEGACY_HEADER_SIZE..].copy_from_slice(&1u16.to_le_bytes());
+                }
+                data.extend_from_slice(frame);
+                let len = chain.write(&data);
+                queue.push(chain, len);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn modern(&self) -> bool {
+        self.config.version == 2
+    }
+
+    fn header_size(&self) -> usize {
+        if self.modern() {
+            HEADER_SIZE
+        } else {
+            LEGACY_HEADER_SIZE
+        }
+    }
+
+    /// The control queue follows the last queue pair, see section 5.1.2
+    fn control_queue(&self, state: &State) -> usize {
+        if state.guest_features[0] & VIRTIO_NET_F_MQ != 0 {
+            2 * self.config.max_virtqueue_pairs as usize
+        } else {
+            2
+        }
+    }
+
+    fn reset(&self, state: &mut State) {
+        state.status = 0;
+        state.guest_features = [0; 2];
+        for (registers, queue) in state.queue_registers.iter_mut().zip(state.queues.iter_mut()) {
+            *registers = QueueRegisters::default();
+            *queue = None;
+        }
+    }
+
+    /// Starts using a queue once the driver passed its areas
+    fn activate(&self, state: &mut State) {
+        let index = state.queue_sel as usize;
+        let registers = &state.queue_registers[index];
+        assert!(registers.num <= self.config.queue_num_max, "queue {} is too large", index);
+        let size = registers.num as u16;
+        let queue = if self.modern() {
+            let packed = state.guest_features[1] & VIRTIO_F_RING_PACKED != 0;
+            let (descriptors, driver, device) = (
+                registers.descriptors as usize,
+                registers.driver as usize,
+                registers.device as usize,
+            );
+            if packed {
+                DeviceQueue::packed(descriptors, driver, device, size)
+            } else {
+                DeviceQueue::split(descriptors, driver, device, size)
+            }
+        } else {
+            // The used ring follows the available ring at the next multiple of the alignment, see section 2.6.2
+            assert!(registers.align.is_power_of_two(), "queue alignment {} is no power of two", registers.align);
+            let descriptors = registers.pfn as usize * state.page_size as usize;
+            let driver = descriptors + 16 * size as usize;
+            let mask = registers.align as usize - 1;
+            let device = (driver + 2 * (3 + size as usize) + mask) & !mask;
+            DeviceQueue::split(descriptors, driver, device, size)
+        };
+        state.queues[index] = Some(queue);
+    }
+
+    fn set_status(&self, state: &mut State, status: u32) {
+        if status == 0 {
+            self.reset(state);
+            return;
+        }
+        let mut status = status | (state.status & STATUS_DEVICE_NEEDS_RESET);
+        if status & STATUS_FEATURES_OK != 0 && state.status & STATUS_FEATURES_OK == 0 {
+            let accepted = (0..2).all(|i| state.guest_features[i] & !self.config.features[i] == 0)
+                && (!self.modern() || state.guest_features[1] & VIRTIO_F_VERSION_1 != 0);
+            if !accepted {
+                status &= !STATUS_FEATURES_OK;
+            }
+        }
+        let driver_ok = status & STATUS_DRIVER_OK != 0 && state.status & STATUS_DRIVER_OK == 0;
+        state.status = status;
+        if driver_ok {
+            // Like QEMU, the device sends the buffers the driver offered to the transmit queues,
+            // which are empty at this point
+            for index in (1..self.control_queue(state)).step_by(2) {
+                self.process_transmit(state, index, false);
+            }
+        }
+    }
+
+    fn process_transmit(&self, state: &mut State, index: usize, keep: bool) {
+        let header_size = self.header_size();
+        let State { queues, transmitted, .. } = state;
+        let queue = match queues[index].as_mut() {
+            Some(queue) => queue,
+            None => return,
+        };
+        while let Some(chain) = queue.pop() {
+            let data = chain.read();
+            assert!(data.len() >= header_size, "transmitted buffer without a virtio-net header");
+            // The driver sets num_buffers to 0, see section 5.1.6.2
+            let num_buffers = &data[LEGACY_HEADER_SIZE..header_size];
+            assert!(num_buffers.iter().all(|&byte| byte == 0), "transmitted num_buffers is not 0");
+            if keep {
+                transmitted.push((index, data[header_size..].to_vec()));
+            }
+            queue.push(chain, 0);
+        }
+    }
+
+    fn process_control(&self, state: &mut State) {
+        let index = self.control_queue(state);
+        if state.control_stalled {
+            return;
+        }
+        let State {
+            queues,
+            commands,
+            control_ack,
+            ..
+        } = state;
+        let queue = match queues[index].as_mut() {
+            Some(queue) => queue,
+            None => return,
+        };
+        while let Some(chain) = queue.pop() {
+            let data = chain.read();
+            assert!(data.len() >= 2, "control command without a header");
+            commands.push((data[0], data[1], data[2..].to_vec()));
+            let len = chain.write(&[*control_ack]);
+            queue.push(chain, len);
+        }
+    }
+
+    fn notify(&self, state: &mut State, index: usize) {
+        if state.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+        if index == self.control_queue(state) {
+            self.process_control(state);
+        } else if index % 2 == 1 && !state.transmit_stalled {
+            self.process_transmit(state, index, true);
+        }
+        // Receive queues are filled by `inject`
+    }
+
+    fn offset(&self, address: usize) -> usize {
+        assert!(
+            address >= self.base_address && address < self.base_address + REGISTERS_SIZE,
+            "address 0x{:x} is not a register of the device at 0x{:x}",
+            address,
+            self.base_address
+        );
+        address - self.base_address
+    }
+}
+
+impl MmioBackend for MockDevice {
+    fn read_u8(&self, address: usize) -> u8 {
+        match self.offset(address) {
+            offset if (CONFIG_MAC..CONFIG_MAC + 6).contains(&offset) => self.config.mac[offset - CONFIG_MAC],
+            offset => panic!("byte read of register 0x{:x}", offset),
+        }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        match self.offset(address) {
+            CONFIG_STATUS => self.state.lock().unwrap().config_status,
+            CONFIG_MAX_VIRTQUEUE_PAIRS => self.config.max_virtqueue_pairs,
+            CONFIG_MTU => self.config.mtu,
+            offset => panic!("16 bit read of register 0x{:x}", offset),
+        }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        let offset = self.offset(address);
+        let state = self.state.lock().unwrap();
+        let queue = state.queue_registers.get(state.queue_sel as usize);
+        match offset {
+            MAGIC_VALUE_OFFSET => MAGIC_VALUE,
+            VERSION => self.config.version,
+            DEVICE_ID => DEVICE_ID_NETWORK,
+            VENDOR_ID_OFFSET => VENDOR_ID,
+            HOST_FEATURES => self.config.features.get(state.features_sel as usize).copied().unwrap_or(0),
+            QUEUE_NUM_MAX => queue.map_or(0, |_| self.config.queue_num_max),
+            QUEUE_PFN => queue.map_or(0, |queue| queue.pfn),
+            QUEUE_READY => queue.map_or(0, |queue| queue.ready),
+            INTERRUPT_STATUS => 0,
+            STATUS => state.status,
+            _ => panic!("read of register 0x{:x}", offset),
+        }
+    }
+
+    fn write_u32(&self, address: usize, value: u32) {
+        let offset = self.offset(address);
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let modern = self.modern();
+        let selected = state.queue_sel as usize;
+        match offset {
+            HOST_FEATURES_SEL => state.features_sel = value,
+            GUEST_FEATURES_SEL => state.guest_features_sel = value,
+            GUEST_FEATURES => {
+                assert!(state.status & STATUS_FEATURES_OK == 0, "features written after FEATURES_OK");
+                if let Some(features) = state.guest_features.get_mut(state.guest_features_sel as usize) {
+                    *features = value;
+                }
+            }
+            GUEST_PAGE_SIZE if !modern => state.page_size = value,
+            QUEUE_SEL => state.queue_sel = value,
+            QUEUE_NUM => state.queue_registers[selected].num = value,
+            QUEUE_ALIGN if !modern => state.queue_registers[selected].align = value,
+            QUEUE_PFN if !modern => {
+                state.queue_registers[selected].pfn = value;
+                if value == 0 {
+                    state.queues[selected] = None;
+                } else {
+                    self.activate(state);
+                }
+            }
+            QUEUE_READY if modern => {
+                state.queue_registers[selected].ready = value;
+                if value == 0 {
+                    state.queues[selected] = None;
+                } else {
+                    self.activate(state);
+                }
+            }
+            QUEUE_DESC_LOW if modern => set_low(&mut state.queue_registers[selected].descriptors, value),
+            QUEUE_DESC_HIGH if modern => set_high(&mut state.queue_registers[selected].descriptors, value),
+            QUEUE_DRIVER_LOW if modern => set_low(&mut state.queue_registers[selected].driver, value),
+            QUEUE_DRIVER_HIGH if modern => set_high(&mut state.queue_registers[selected].driver, value),
+            QUEUE_DEVICE_LOW if modern => set_low(&mut state.queue_registers[selected].device, value),
+            QUEUE_DEVICE_HIGH if modern => set_high(&mut state.queue_registers[selected].device, value),
+            QUEUE_NOTIFY => self.notify(state, value as usize),
+            INTERRUPT_ACK => {}
+            STATUS => self.set_status(state, value),
+            _ => panic!("write of register 0x{:x} of a version {} device", offset, self.config.version),
+        }
+    }
+}
+
+fn set_low(address: &mut u64, value: u32) {
+    *address = (*address & !0xffff_ffff) | value as u64;
+}
+
+fn set_high(address: &mut u64, value: u32) {
+    *address = (*address & 0xffff_ffff) | (value as u64) << 32;
+}
+
+extern "C" {
+    fn mmap(address: *mut u8, len: usize, protection: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+// Linux values
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+/// Where the next guest memory is mapped, QEMU's virt machine has its RAM at 0x40000000
+static NEXT_GUEST_MEMORY: AtomicUsize = AtomicUsize::new(0x40000000);
+
+/// Zeroed memory for the driver's virtqueues, living as long as the test process
+///
+/// Legacy devices take the page number of a queue as 32 bit value, so the memory is mapped below
+/// 4 GiB like the firmware's RAM instead of being allocated on the heap.
+pub fn guest_memory(len: usize) -> MemoryHandle {
+    let len = (len + 4095) & !4095;
+    let address = NEXT_GUEST_MEMORY.fetch_add(len, Ordering::Relaxed);
+    let start = unsafe {
+        mmap(address as *mut u8, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+    } as usize;
+    assert!(start == address, "guest memory not mapped at 0x{:x}", address);
+    MemoryHandle::new(start, len)
+}
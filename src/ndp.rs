@@ -0,0 +1,861 @@
+use crate::config;
+use crate::icmpv6;
+use crate::ipv6;
+use crate::packet::{EthernetFrame, IcmpMessage, Ipv6Packet, ETHERNET_HEADER_SIZE, ICMP_HEADER_SIZE, IPV6_HEADER_SIZE};
+use crate::timer::Instant;
+
+const TYPE_ROUTER_SOLICITATION: u8 = 133;
+const TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+const TYPE_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const TYPE_REDIRECT: u8 = 137;
+
+pub const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+/// Options are sized in units of 8 bytes
+pub const OPTION_UNIT: usize = 8;
+
+const FLAG_ROUTER: u8 = 0x80;
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+
+/// Neighbor discovery messages are only accepted from the link, see RFC 4861 section 7.1
+pub const HOP_LIMIT: u8 = 255;
+/// Length of the target address following the ICMPv6 header
+const TARGET_SIZE: usize = 16;
+const MAX_MULTICAST_SOLICIT: u8 = 3;
+const MAX_UNICAST_SOLICIT: u8 = 3;
+const NEIGHBOR_CACHE_SIZE: usize = 64;
+/// Addresses of an interface: the link-local and the global address
+pub const ADDRESS_COUNT: usize = 2;
+
+/// Size of the largest frame written by an interface
+pub const MAX_FRAME_SIZE: usize = ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + ICMP_HEADER_SIZE + TARGET_SIZE + OPTION_UNIT;
+
+// neighbor solicitation and advertisement, see RFC 4861 section 4.3 and 4.4:
+// 8 bytes icmpv6 header, the first byte of the rest of the header carries the advertisement flags
+// 16 bytes target address
+// options, each starting with 1 byte type and 1 byte length in units of 8 bytes
+//
+// link-layer address option for ethernet, see RFC 2464 section 6:
+// 1 byte type
+// 1 byte length
+// 6 bytes mac
+
+/// Whether the packet carries a neighbor discovery message, see RFC 4861 section 4
+pub fn is_neighbor_discovery(packet: &Ipv6Packet<&[u8]>) -> bool {
+    if packet.next_header() != ipv6::NEXT_HEADER_ICMPV6 {
+        return false;
+    }
+    match IcmpMessage::new_checked(packet.payload()) {
+        Ok(message) => (TYPE_ROUTER_SOLICITATION..=TYPE_REDIRECT).contains(&message.message_type()),
+        Err(_) => false,
+    }
+}
+
+/// Whether the packet carries a valid router solicitation, see RFC 4861 section 6.1.1
+pub fn is_router_solicitation(packet: &Ipv6Packet<&[u8]>) -> bool {
+    match parse(packet) {
+        Some(Message::RouterSolicitation { .. }) => true,
+        _ => false,
+    }
+}
+
+/// A validated neighbor discovery message
+enum Message {
+    RouterSolicitation {
+        source_mac: Option<[u8; 6]>,
+    },
+    Solicitation {
+        target: [u8; 16],
+        source_mac: Option<[u8; 6]>,
+    },
+    Advertisement {
+        target: [u8; 16],
+        target_mac: Option<[u8; 6]>,
+        solicited: bool,
+        override_mac: bool,
+    },
+}
+
+/// Applies the checks of RFC 4861 section 6.1.1, 7.1.1 and 7.1.2, other neighbor discovery messages are ignored
+fn parse(packet: &Ipv6Packet<&[u8]>) -> Option<Message> {
+    if packet.hop_limit() != HOP_LIMIT || !icmpv6::verify_checksum(packet) {
+        return None;
+    }
+    let message = IcmpMessage::new_checked(packet.payload()).ok()?;
+    let data = message.data();
+    if message.code() != 0 {
+        return None;
+    }
+    if message.message_type() == TYPE_ROUTER_SOLICITATION {
+        // Router solicitations carry their options right after the ICMPv6 header
+        if !options_valid(data) {
+            return None;
+        }
+        let source_mac = link_layer_address(data, OPTION_SOURCE_LINK_LAYER_ADDRESS);
+        if packet.source() == ipv6::UNSPECIFIED && source_mac.is_some() {
+            return None;
+        }
+        return Some(Message::RouterSolicitation { source_mac });
+    }
+    if data.len() < TARGET_SIZE {
+        return None;
+    }
+    let mut target = [0; 16];
+    target.copy_from_slice(&data[..TARGET_SIZE]);
+    let options = &data[TARGET_SIZE..];
+    if ipv6::is_multicast(target) || !options_valid(options) {
+        return None;
+    }
+    match message.message_type() {
+        TYPE_NEIGHBOR_SOLICITATION => {
+            let source_mac = link_layer_address(options, OPTION_SOURCE_LINK_LAYER_ADDRESS);
+            // Duplicate address detection, the destination must be the solicited-node address
+            if packet.source() == ipv6::UNSPECIFIED
+                && (packet.destination() != ipv6::solicited_node_address(target) || source_mac.is_some())
+            {
+                return None;
+            }
+            Some(Message::Solicitation { target, source_mac })
+        }
+        TYPE_NEIGHBOR_ADVERTISEMENT => {
+            let flags = message.rest_of_header()[0];
+            let solicited = flags & FLAG_SOLICITED != 0;
+            if solicited && ipv6::is_multicast(packet.destination()) {
+                return None;
+            }
+            Some(Message::Advertisement {
+                target,
+                target_mac: link_layer_address(options, OPTION_TARGET_LINK_LAYER_ADDRESS),
+                solicited,
+                override_mac: flags & FLAG_OVERRIDE != 0,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Options must not have a length of zero
+fn options_valid(options: &[u8]) -> bool {
+    let mut i = 0;
+    while i < options.len() {
+        if i + 1 >= options.len() || options[i + 1] == 0 {
+            return false;
+        }
+        i += options[i + 1] as usize * OPTION_UNIT;
+    }
+    i == options.len()
+}
+
+fn link_layer_address(options: &[u8], option_type: u8) -> Option<[u8; 6]> {
+    let mut i = 0;
+    while i + OPTION_UNIT <= options.len() {
+        if options[i] == option_type && options[i + 1] == 1 {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&options[i + 2..i + OPTION_UNIT]);
+            return Some(mac);
+        }
+        i += options[i + 1] as usize * OPTION_UNIT;
+    }
+    None
+}
+
+/// Writes a neighbor solicitation for `target`, returns the length of the frame written to `out`
+///
+/// Solicitations from the unspecified address are duplicate address detection probes and carry no
+/// link-layer address.
+fn build_solicitation(
+    out: &mut [u8],
+    source_mac: [u8; 6],
+    destination_mac: [u8; 6],
+    source: [u8; 16],
+    destination: [u8; 16],
+    target: [u8; 16],
+) -> usize {
+    let with_option = source != ipv6::UNSPECIFIED;
+    let payload_len = ICMP_HEADER_SIZE + TARGET_SIZE + if with_option { OPTION_UNIT } else { 0 };
+    let mut packet = icmpv6::write_headers(out, destination_mac, source_mac, payload_len, source, destination);
+    packet.set_hop_limit(HOP_LIMIT);
+
+    let mut message = IcmpMessage::new_unchecked(packet.payload_mut());
+    message.set_message_type(TYPE_NEIGHBOR_SOLICITATION);
+    message.set_code(0);
+    message.set_rest_of_header([0; 4]);
+    let data = message.data_mut();
+    data[..TARGET_SIZE].copy_from_slice(&target);
+    if with_option {
+        write_link_layer_option(&mut data[TARGET_SIZE..], OPTION_SOURCE_LINK_LAYER_ADDRESS, source_mac);
+    }
+    icmpv6::fill_checksum(&mut packet);
+
+    ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + payload_len
+}
+
+/// Writes a neighbor advertisement for the router's address `target`, returns the length of the frame written to `out`
+fn build_advertisement(
+    out: &mut [u8],
+    source_mac: [u8; 6],
+    destination_mac: [u8; 6],
+    destination: [u8; 16],
+    target: [u8; 16],
+    solicited: bool,
+) -> usize {
+    let payload_len = ICMP_HEADER_SIZE + TARGET_SIZE + OPTION_UNIT;
+    let mut packet = icmpv6::write_headers(out, destination_mac, source_mac, payload_len, target, destination);
+    packet.set_hop_limit(HOP_LIMIT);
+
+    let mut message = IcmpMessage::new_unchecked(packet.payload_mut());
+    message.set_message_type(TYPE_NEIGHBOR_ADVERTISEMENT);
+    message.set_code(0);
+    let solicited_flag = if solicited { FLAG_SOLICITED } else { 0 };
+    message.set_rest_of_header([FLAG_ROUTER | FLAG_OVERRIDE | solicited_flag, 0, 0, 0]);
+    let data = message.data_mut();
+    data[..TARGET_SIZE].copy_from_slice(&target);
+    write_link_layer_option(&mut data[TARGET_SIZE..], OPTION_TARGET_LINK_LAYER_ADDRESS, source_mac);
+    icmpv6::fill_checksum(&mut packet);
+
+    ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + payload_len
+}
+
+pub fn write_link_layer_option(out: &mut [u8], option_type: u8, mac: [u8; 6]) {
+    out[0] = option_type;
+    out[1] = 1;
+    out[2..OPTION_UNIT].copy_from_slice(&mac);
+}
+
+/// Duplicate address detection state of an address, see RFC 4862 section 5.4
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AddressState {
+    /// Not yet usable, `deadline` is the end of detection once the probe was sent
+    Tentative { deadline: Option<Instant> },
+    Preferred,
+    /// Another node on the link uses the address
+    Duplicate,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LocalAddress {
+    address: [u8; 16],
+    state: AddressState,
+}
+
+/// Neighbor discovery state of one router port
+pub struct Interface {
+    mac: [u8; 6],
+    /// The link-local address followed by the configured global address
+    addresses: [LocalAddress; ADDRESS_COUNT],
+    pub neighbors: NeighborCache<[u8; 16]>,
+}
+
+impl Interface {
+    /// Both addresses start out tentative and are probed by the first calls to `poll`
+    pub fn new(mac: [u8; 6], global_address: [u8; 16]) -> Interface {
+        let tentative = AddressState::Tentative { deadline: None };
+        Interface {
+            mac,
+            addresses: [
+                LocalAddress {
+                    address: ipv6::link_local_address(mac),
+                    state: tentative,
+                },
+                LocalAddress {
+                    address: global_address,
+                    state: tentative,
+                },
+            ],
+            neighbors: NeighborCache::new(),
+        }
+    }
+
+    pub fn link_local_address(&self) -> [u8; 16] {
+        self.addresses[0].address
+    }
+
+    /// Whether `address` is assigned to the interface and passed duplicate address detection
+    pub fn is_local(&self, address: [u8; 16]) -> bool {
+        self.addresses
+            .iter()
+            .any(|local| local.address == address && local.state == AddressState::Preferred)
+    }
+
+    /// Writes an unsolicited neighbor advertisement for the address with the given index to `out`,
+    /// see RFC 4861 section 7.2.6
+    ///
+    /// The override flag makes neighbors replace their cached link-layer address. Returns the length
+    /// of the written frame, or `None` if the address has not passed duplicate address detection.
+    pub fn announcement(&self, index: usize, out: &mut [u8]) -> Option<usize> {
+        let local = self.addresses.get(index)?;
+        if local.state != AddressState::Preferred {
+            return None;
+        }
+        let destination_mac = ipv6::multicast_mac(ipv6::ALL_NODES);
+        Some(build_advertisement(out, self.mac, destination_mac, ipv6::ALL_NODES, local.address, false))
+    }
+
+    /// Handles a received neighbor discovery message, see RFC 4861 section 6.2.6 and 7.2.3 to 7.2.5
+    ///
+    /// Returns the length of the answer written to `out` if there is one. Router solicitations only
+    /// update the neighbor cache, they are answered by the port's router advertisements.
+    pub fn process(
+        &mut self,
+        frame: &EthernetFrame<&[u8]>,
+        packet: &Ipv6Packet<&[u8]>,
+        now: Instant,
+        out: &mut [u8],
+    ) -> Option<usize> {
+        match parse(packet)? {
+            Message::RouterSolicitation { source_mac } => {
+                if let Some(mac) = source_mac {
+                    self.neighbors.process_solicitation(packet.source(), mac, now);
+                }
+                None
+            }
+            Message::Solicitation { target, source_mac } => {
+                let local = self.addresses.iter_mut().find(|local| local.address == target)?;
+                let source = packet.source();
+                match local.state {
+                    AddressState::Tentative { .. } => {
+                        // Another node probes the same address, see RFC 4862 section 5.4.3
+                        if source == ipv6::UNSPECIFIED {
+                            // util::print(format_args!("[warn] duplicate address {:x?}\n", target)).unwrap();
+                            local.state = AddressState::Duplicate;
+                        }
+                        None
+                    }
+                    AddressState::Duplicate => None,
+                    AddressState::Preferred if source == ipv6::UNSPECIFIED => {
+                        // Defend the address against the probing node
+                        let destination_mac = ipv6::multicast_mac(ipv6::ALL_NODES);
+                        Some(build_advertisement(out, self.mac, destination_mac, ipv6::ALL_NODES, target, false))
+                    }
+                    AddressState::Preferred => {
+                        if let Some(mac) = source_mac {
+                            self.neighbors.process_solicitation(source, mac, now);
+                        }
+                        // Without the option the solicitation's link-layer source is the best guess
+                        let destination_mac = source_mac.unwrap_or(frame.source());
+                        Some(build_advertisement(out, self.mac, destination_mac, source, target, true))
+                    }
+                }
+            }
+            Message::Advertisement {
+                target,
+                target_mac,
+                solicited,
+                override_mac,
+            } => {
+                if let Some(local) = self.addresses.iter_mut().find(|local| local.address == target) {
+                    // util::print(format_args!("[warn] advertisement for own address {:x?}\n", target)).unwrap();
+                    if let AddressState::Tentative { .. } = local.state {
+                        local.state = AddressState::Duplicate;
+                    }
+                    return None;
+                }
+                self.neighbors
+                    .process_advertisement(target, target_mac, solicited, override_mac, now);
+                None
+            }
+        }
+    }
+
+    /// Writes the next due duplicate address detection probe or neighbor solicitation to `out`
+    ///
+    /// Returns the length of the written frame, or `None` if nothing is due.
+    pub fn poll(&mut self, now: Instant, out: &mut [u8]) -> Option<usize> {
+        for local in self.addresses.iter_mut() {
+            match local.state {
+                AddressState::Tentative { deadline: None } => {
+                    local.state = AddressState::Tentative {
+                        deadline: Some(now + config::NDP_RETRANS_TIMER),
+                    };
+                    let destination = ipv6::solicited_node_address(local.address);
+                    let destination_mac = ipv6::multicast_mac(destination);
+                    return Some(build_solicitation(
+                        out,
+                        self.mac,
+                        destination_mac,
+                        ipv6::UNSPECIFIED,
+                        destination,
+                        local.address,
+                    ));
+                }
+                AddressState::Tentative {
+                    deadline: Some(deadline),
+                } if now >= deadline => {
+                    local.state = AddressState::Preferred;
+                }
+                _ => {}
+            }
+        }
+
+        let solicitation = self.neighbors.next_solicitation(now)?;
+        let (destination, destination_mac) = match solicitation.mac {
+            Some(mac) => (solicitation.target, mac),
+            None => {
+                let destination = ipv6::solicited_node_address(solicitation.target);
+                (destination, ipv6::multicast_mac(destination))
+            }
+        };
+        let source = self.link_local_address();
+        Some(build_solicitation(out, self.mac, destination_mac, source, destination, solicitation.target))
+    }
+}
+
+/// Reachability state of a neighbor, see RFC 4861 section 7.3.2
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NeighborState {
+    Incomplete,
+    Reachable,
+    Stale,
+    Delay,
+    Probe,
+}
+
+impl NeighborState {
+    /// Order in which full caches replace entries, lowest first
+    fn eviction_rank(self) -> u8 {
+        match self {
+            NeighborState::Incomplete => 0,
+            NeighborState::Stale => 1,
+            NeighborState::Delay | NeighborState::Probe => 2,
+            NeighborState::Reachable => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Neighbor<A> {
+    address: A,
+    mac: [u8; 6],
+    state: NeighborState,
+    /// Time of the next state transition or solicitation
+    deadline: Instant,
+    solicitations: u8,
+}
+
+/// A neighbor solicitation that is due
+pub struct Solicitation<A> {
+    pub target: A,
+    /// The cached address for unicast probes, `None` for multicast address resolution
+    pub mac: Option<[u8; 6]>,
+}
+
+/// Link-layer addresses of the neighbors with protocol addresses of type `A`
+///
+/// Neighbor discovery caches IPv6 addresses. The ARP cache of IPv4 addresses validates its entries
+/// the same way, which RFC 1122 section 2.3.2.1 suggests.
+pub struct NeighborCache<A> {
+    entries: [Option<Neighbor<A>>; NEIGHBOR_CACHE_SIZE],
+}
+
+impl<A: Copy + PartialEq> NeighborCache<A> {
+    pub fn new() -> NeighborCache<A> {
+        NeighborCache {
+            entries: [None; NEIGHBOR_CACHE_SIZE],
+        }
+    }
+
+    fn find(&mut self, address: A) -> Option<&mut Neighbor<A>> {
+        self.entries
+            .iter_mut()
+            .filter_map(|entry| entry.as_mut())
+            .find(|neighbor| neighbor.address == address)
+    }
+
+    /// Stores `neighbor` in a free entry, or replaces the least useful one
+    ///
+    /// Entries still being resolved go first, then STALE ones, so unsolicited traffic to unknown
+    /// destinations does not flush the neighbors in use. Ties go to the earliest deadline.
+    fn insert(&mut self, neighbor: Neighbor<A>) {
+        let index = match self.entries.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => (0..NEIGHBOR_CACHE_SIZE)
+                .filter_map(|i| self.entries[i].map(|entry| (i, entry)))
+                .min_by_key(|(_, entry)| (entry.state.eviction_rank(), entry.deadline))
+                .map_or(0, |(i, _)| i),
+        };
+        self.entries[index] = Some(neighbor);
+    }
+
+    /// Returns the link-layer address to send a packet for `address` to, see RFC 4861 section 7.2.2 and 7.3.3
+    ///
+    /// Unknown addresses enter INCOMPLETE and are solicited by `next_solicitation`, the packet
+    /// that triggered the resolution is not queued.
+    pub fn resolve(&mut self, address: A, now: Instant) -> Option<[u8; 6]> {
+        match self.find(address) {
+            Some(neighbor) => match neighbor.state {
+                NeighborState::Incomplete => None,
+                NeighborState::Reachable if now < neighbor.deadline => Some(neighbor.mac),
+                NeighborState::Reachable | NeighborState::Stale => {
+                    neighbor.state = NeighborState::Delay;
+                    neighbor.deadline = now + config::NDP_DELAY_FIRST_PROBE_TIME;
+                    Some(neighbor.mac)
+                }
+                NeighborState::Delay | NeighborState::Probe => Some(neighbor.mac),
+            },
+            None => {
+                self.insert(Neighbor {
+                    address,
+                    mac: [0; 6],
+                    state: NeighborState::Incomplete,
+                    deadline: now,
+                    solicitations: 0,
+                });
+                None
+            }
+        }
+    }
+
+    /// Updates the cache from a solicitation carrying a source link-layer address, see RFC 4861 section 7.2.3
+    pub fn process_solicitation(&mut self, address: A, mac: [u8; 6], now: Instant) {
+        match self.find(address) {
+            Some(neighbor) => {
+                if neighbor.state == NeighborState::Incomplete || neighbor.mac != mac {
+                    neighbor.mac = mac;
+                    neighbor.state = NeighborState::Stale;
+                }
+            }
+            None => self.insert(Neighbor {
+                address,
+                mac,
+                state: NeighborState::Stale,
+                deadline: now,
+                solicitations: 0,
+            }),
+        }
+    }
+
+    /// Updates the cache from an advertisement, see RFC 4861 section 7.2.5
+    pub fn process_advertisement(
+        &mut self,
+        address: A,
+        mac: Option<[u8; 6]>,
+        solicited: bool,
+        override_mac: bool,
+        now: Instant,
+    ) {
+        let neighbor = match self.find(address) {
+            Some(neighbor) => neighbor,
+            None => return,
+        };
+        if neighbor.state == NeighborState::Incomplete {
+            neighbor.mac = match mac {
+                Some(mac) => mac,
+                None => return,
+            };
+            neighbor.solicitations = 0;
+            if solicited {
+                neighbor.state = NeighborState::Reachable;
+                neighbor.deadline = now + config::NDP_REACHABLE_TIME;
+            } else {
+                neighbor.state = NeighborState::Stale;
+            }
+            return;
+        }
+        let changed = mac.map_or(false, |mac| mac != neighbor.mac);
+        if changed && !override_mac {
+            if neighbor.state == NeighborState::Reachable {
+                neighbor.state = NeighborState::Stale;
+            }
+            return;
+        }
+        if let Some(mac) = mac {
+            neighbor.mac = mac;
+        }
+        if solicited {
+            neighbor.state = NeighborState::Reachable;
+            neighbor.deadline = now + config::NDP_REACHABLE_TIME;
+            neighbor.solicitations = 0;
+        } else if changed {
+            neighbor.state = NeighborState::Stale;
+        }
+    }
+
+    /// Advances the timers of all entries and returns the first solicitation that is due
+    ///
+    /// Entries that did not answer the maximum number of solicitations are removed.
+    pub fn next_solicitation(&mut self, now: Instant) -> Option<Solicitation<A>> {
+        for i in 0..NEIGHBOR_CACHE_SIZE {
+            let neighbor = match &mut self.entries[i] {
+                Some(neighbor) if now >= neighbor.deadline => neighbor,
+                _ => continue,
+            };
+            let (max_solicitations, mac) = match neighbor.state {
+                NeighborState::Reachable => {
+                    neighbor.state = NeighborState::Stale;
+                    continue;
+                }
+                NeighborState::Stale => continue,
+                NeighborState::Incomplete => (MAX_MULTICAST_SOLICIT, None),
+                NeighborState::Delay => {
+                    neighbor.state = NeighborState::Probe;
+                    neighbor.solicitations = 0;
+                    (MAX_UNICAST_SOLICIT, Some(neighbor.mac))
+                }
+                NeighborState::Probe => (MAX_UNICAST_SOLICIT, Some(neighbor.mac)),
+            };
+            if neighbor.solicitations >= max_solicitations {
+                // util::print(format_args!("[warn] neighbor {:x?} unreachable\n", neighbor.address)).unwrap();
+                self.entries[i] = None;
+                continue;
+            }
+            neighbor.solicitations += 1;
+            neighbor.deadline = now + config::NDP_RETRANS_TIMER;
+            return Some(Solicitation {
+                target: neighbor.address,
+                mac,
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const GLOBAL: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const NEIGHBOR_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x10];
+    const OTHER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x20];
+
+    fn neighbor(index: u8) -> [u8; 16] {
+        [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, index]
+    }
+
+    fn state(cache: &mut NeighborCache<[u8; 16]>, address: [u8; 16]) -> Option<NeighborState> {
+        cache.find(address).map(|neighbor| neighbor.state)
+    }
+
+    /// Passes a frame written by one of the builders to `interface`, returns the answer
+    fn process(interface: &mut Interface, frame: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let frame = EthernetFrame::new_checked(frame).unwrap();
+        let packet = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        let mut out = [0; MAX_FRAME_SIZE];
+        interface.process(&frame, &packet, now, &mut out).map(|len| out[..len].to_vec())
+    }
+
+    /// A neighbor solicitation for `target` from another node, a duplicate address detection probe
+    /// if `source` is unspecified
+    fn solicitation(source: [u8; 16], target: [u8; 16]) -> Vec<u8> {
+        let mut out = vec![0; MAX_FRAME_SIZE];
+        let destination = ipv6::solicited_node_address(target);
+        let destination_mac = ipv6::multicast_mac(destination);
+        let len = build_solicitation(&mut out, OTHER_MAC, destination_mac, source, destination, target);
+        out.truncate(len);
+        out
+    }
+
+    /// Returns the ICMPv6 type, the IPv6 source and destination and the target of a written message
+    fn message(frame: &[u8]) -> (u8, [u8; 16], [u8; 16], [u8; 16]) {
+        let frame = EthernetFrame::new_checked(frame).unwrap();
+        let packet = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(packet.hop_limit(), HOP_LIMIT);
+        assert!(icmpv6::verify_checksum(&packet));
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        let mut target = [0; 16];
+        target.copy_from_slice(&message.data()[..TARGET_SIZE]);
+        (message.message_type(), packet.source(), packet.destination(), target)
+    }
+
+    /// Probes both addresses of a new interface at `Instant::ZERO`
+    fn probed_interface() -> Interface {
+        let mut interface = Interface::new(MAC, GLOBAL);
+        let mut out = [0; MAX_FRAME_SIZE];
+        assert!(interface.poll(Instant::ZERO, &mut out).is_some());
+        assert!(interface.poll(Instant::ZERO, &mut out).is_some());
+        interface
+    }
+
+    /// Ends duplicate address detection of a probed interface
+    fn finish_detection(interface: &mut Interface) {
+        assert!(interface
+            .poll(Instant::ZERO + config::NDP_RETRANS_TIMER, &mut [0; MAX_FRAME_SIZE])
+            .is_none());
+    }
+
+    /// A cache with `address` resolved by a solicited advertisement at `now`
+    fn reachable(cache: &mut NeighborCache<[u8; 16]>, address: [u8; 16], now: Instant) {
+        assert_eq!(cache.resolve(address, now), None);
+        cache.process_advertisement(address, Some(NEIGHBOR_MAC), true, false, now);
+        assert_eq!(state(cache, address), Some(NeighborState::Reachable));
+    }
+
+    #[test]
+    fn probes_addresses_before_using_them() {
+        let mut interface = Interface::new(MAC, GLOBAL);
+        let link_local = interface.link_local_address();
+        let mut out = [0; MAX_FRAME_SIZE];
+        for target in &[link_local, GLOBAL] {
+            let len = interface.poll(Instant::ZERO, &mut out).unwrap();
+            // Probes come from the unspecified address and carry no link-layer address
+            assert_eq!(len, ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + ICMP_HEADER_SIZE + TARGET_SIZE);
+            let destination = ipv6::solicited_node_address(*target);
+            let expected = (TYPE_NEIGHBOR_SOLICITATION, ipv6::UNSPECIFIED, destination, *target);
+            assert_eq!(message(&out[..len]), expected);
+        }
+        assert!(interface.poll(Instant::ZERO, &mut out).is_none());
+        assert!(!interface.is_local(link_local) && !interface.is_local(GLOBAL));
+        assert!(interface.announcement(0, &mut out).is_none());
+
+        finish_detection(&mut interface);
+        assert!(interface.is_local(link_local) && interface.is_local(GLOBAL));
+        let len = interface.announcement(1, &mut out).unwrap();
+        assert_eq!(message(&out[..len]), (TYPE_NEIGHBOR_ADVERTISEMENT, GLOBAL, ipv6::ALL_NODES, GLOBAL));
+    }
+
+    #[test]
+    fn conflicting_probe_marks_address_duplicate() {
+        let mut interface = probed_interface();
+        assert!(process(&mut interface, &solicitation(ipv6::UNSPECIFIED, GLOBAL), Instant::ZERO).is_none());
+        finish_detection(&mut interface);
+        assert!(!interface.is_local(GLOBAL));
+        assert!(interface.is_local(interface.link_local_address()));
+        assert!(interface.announcement(1, &mut [0; MAX_FRAME_SIZE]).is_none());
+    }
+
+    #[test]
+    fn advertisement_for_tentative_address_marks_it_duplicate() {
+        let mut interface = probed_interface();
+        let mut frame = vec![0; MAX_FRAME_SIZE];
+        let all_nodes_mac = ipv6::multicast_mac(ipv6::ALL_NODES);
+        let len = build_advertisement(&mut frame, OTHER_MAC, all_nodes_mac, ipv6::ALL_NODES, GLOBAL, false);
+        assert!(process(&mut interface, &frame[..len], Instant::ZERO).is_none());
+        finish_detection(&mut interface);
+        assert!(!interface.is_local(GLOBAL));
+    }
+
+    #[test]
+    fn defends_preferred_address_against_probe() {
+        let mut interface = probed_interface();
+        finish_detection(&mut interface);
+        let answer = process(&mut interface, &solicitation(ipv6::UNSPECIFIED, GLOBAL), Instant::ZERO).unwrap();
+        assert_eq!(message(&answer), (TYPE_NEIGHBOR_ADVERTISEMENT, GLOBAL, ipv6::ALL_NODES, GLOBAL));
+        assert!(interface.is_local(GLOBAL));
+    }
+
+    #[test]
+    fn answers_solicitation_and_caches_the_solicitor() {
+        let mut interface = probed_interface();
+        finish_detection(&mut interface);
+        let answer = process(&mut interface, &solicitation(neighbor(1), GLOBAL), Instant::ZERO).unwrap();
+        assert_eq!(message(&answer), (TYPE_NEIGHBOR_ADVERTISEMENT, GLOBAL, neighbor(1), GLOBAL));
+        assert_eq!(EthernetFrame::new_checked(&answer[..]).unwrap().destination(), OTHER_MAC);
+        assert_eq!(state(&mut interface.neighbors, neighbor(1)), Some(NeighborState::Stale));
+        assert_eq!(interface.neighbors.resolve(neighbor(1), Instant::ZERO), Some(OTHER_MAC));
+    }
+
+    #[test]
+    fn solicits_unknown_neighbor_and_gives_up() {
+        let mut cache = NeighborCache::new();
+        let mut now = Instant::ZERO;
+        assert_eq!(cache.resolve(neighbor(1), now), None);
+        assert_eq!(state(&mut cache, neighbor(1)), Some(NeighborState::Incomplete));
+        for _ in 0..MAX_MULTICAST_SOLICIT {
+            let solicitation = cache.next_solicitation(now).unwrap();
+            assert_eq!((solicitation.target, solicitation.mac), (neighbor(1), None));
+            assert!(cache.next_solicitation(now).is_none());
+            now = now + config::NDP_RETRANS_TIMER;
+        }
+        assert!(cache.next_solicitation(now).is_none());
+        assert_eq!(state(&mut cache, neighbor(1)), None);
+    }
+
+    #[test]
+    fn resolves_neighbor_through_all_states() {
+        let mut cache = NeighborCache::new();
+        let start = Instant::ZERO;
+        reachable(&mut cache, neighbor(1), start);
+        assert_eq!(cache.resolve(neighbor(1), start), Some(NEIGHBOR_MAC));
+        assert_eq!(state(&mut cache, neighbor(1)), Some(NeighborState::Reachable));
+
+        // REACHABLE times out to STALE without a solicitation
+        let stale = start + config::NDP_REACHABLE_TIME;
+        assert!(cache.next_solicitation(stale).is_none());
+        assert_eq!(state(&mut cache, neighbor(1)), Some(NeighborState::Stale));
+
+        // Sending to a STALE neighbor delays the first probe
+        assert_eq!(cache.resolve(neighbor(1), stale), Some(NEIGHBOR_MAC));
+        assert_eq!(state(&mut cache, neighbor(1)), Some(NeighborState::Delay));
+        assert!(cache.next_solicitation(stale).is_none());
+
+        // The probes are unicast to the cached address
+        let probe = stale + config::NDP_DELAY_FIRST_PROBE_TIME;
+        let solicitation = cache.next_solicitation(probe).unwrap();
+        assert_eq!(solicitation.mac, Some(NEIGHBOR_MAC));
+        assert_eq!(state(&mut cache, neighbor(1)), Some(NeighborState::Probe));
+        assert_eq!(cache.resolve(neighbor(1), probe), Some(NEIGHBOR_MAC));
+
+        cache.process_advertisement(neighbor(1), Some(NEIGHBOR_MAC), true, false, probe);
+        assert_eq!(state(&mut cache, neighbor(1)), Some(NeighborState::Reachable));
+        assert!(cache.next_solicitation(probe + config::NDP_RETRANS_TIMER).is_none());
+    }
+
+    #[test]
+    fn removes_neighbor_after_unanswered_unicast_probes() {
+        let mut cache = NeighborCache::new();
+        reachable(&mut cache, neighbor(1), Instant::ZERO);
+        let mut now = Instant::ZERO + config::NDP_REACHABLE_TIME;
+        assert_eq!(cache.resolve(neighbor(1), now), Some(NEIGHBOR_MAC));
+        now = now + config::NDP_DELAY_FIRST_PROBE_TIME;
+        for _ in 0..MAX_UNICAST_SOLICIT {
+            assert_eq!(cache.next_solicitation(now).unwrap().mac, Some(NEIGHBOR_MAC));
+            now = now + config::NDP_RETRANS_TIMER;
+        }
+        assert!(cache.next_solicitation(now).is_none());
+        assert_eq!(state(&mut cache, neighbor(1)), None);
+        assert_eq!(cache.resolve(neighbor(1), now), None);
+    }
+
+    #[test]
+    fn updates_cached_address_from_messages() {
+        let mut cache = NeighborCache::new();
+        let now = Instant::ZERO;
+
+        // An unsolicited advertisement completes resolution as STALE
+        assert_eq!(cache.resolve(neighbor(1), now), None);
+        cache.process_advertisement(neighbor(1), Some(NEIGHBOR_MAC), false, true, now);
+        assert_eq!(state(&mut cache, neighbor(1)), Some(NeighborState::Stale));
+
+        // A different address without override only makes a REACHABLE entry STALE
+        reachable(&mut cache, neighbor(2), now);
+        cache.process_advertisement(neighbor(2), Some(OTHER_MAC), true, false, now);
+        assert_eq!(state(&mut cache, neighbor(2)), Some(NeighborState::Stale));
+        assert_eq!(cache.find(neighbor(2)).unwrap().mac, NEIGHBOR_MAC);
+
+        // With override the address is replaced, unsolicited changes leave it STALE
+        cache.process_advertisement(neighbor(2), Some(OTHER_MAC), false, true, now);
+        assert_eq!(state(&mut cache, neighbor(2)), Some(NeighborState::Stale));
+        assert_eq!(cache.find(neighbor(2)).unwrap().mac, OTHER_MAC);
+
+        // A solicitation with a new address makes the entry STALE
+        reachable(&mut cache, neighbor(3), now);
+        cache.process_solicitation(neighbor(3), OTHER_MAC, now);
+        assert_eq!(state(&mut cache, neighbor(3)), Some(NeighborState::Stale));
+        assert_eq!(cache.find(neighbor(3)).unwrap().mac, OTHER_MAC);
+
+        // Advertisements for unknown neighbors are ignored
+        cache.process_advertisement(neighbor(4), Some(OTHER_MAC), true, true, now);
+        assert_eq!(state(&mut cache, neighbor(4)), None);
+    }
+
+    #[test]
+    fn keeps_reachable_neighbors_when_full() {
+        let mut cache = NeighborCache::new();
+        let in_use = NEIGHBOR_CACHE_SIZE as u8 - 16;
+        for i in 0..in_use {
+            reachable(&mut cache, neighbor(i), Instant::ZERO);
+        }
+        // Traffic to many unknown destinations just before the neighbors in use need confirming, the
+        // solicitations push the deadlines of the new entries past theirs
+        let now = Instant::ZERO + (config::NDP_REACHABLE_TIME - config::NDP_RETRANS_TIMER);
+        for i in in_use..=255 {
+            assert_eq!(cache.resolve(neighbor(i), now), None);
+            assert_eq!(cache.next_solicitation(now).unwrap().target, neighbor(i));
+        }
+        for i in 0..in_use {
+            assert_eq!(state(&mut cache, neighbor(i)), Some(NeighborState::Reachable));
+        }
+        assert_eq!(state(&mut cache, neighbor(255)), Some(NeighborState::Incomplete));
+    }
+}
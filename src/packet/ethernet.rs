@@ -0,0 +1,105 @@
+use crate::errors::PacketError;
+use crate::packet::{read_u16, write_u16};
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+#[allow(dead_code)]
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+// 6 bytes MAC destination
+// 6 bytes MAC source
+// 2 bytes ethertype
+// payload, which starts with an 802.1Q tag if the ethertype is ETHERTYPE_VLAN
+
+#[derive(Clone, Copy, Debug)]
+pub struct EthernetFrame<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> EthernetFrame<T> {
+    pub fn new_checked(buffer: T) -> Result<EthernetFrame<T>, PacketError> {
+        if buffer.as_ref().len() < ETHERNET_HEADER_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        Ok(EthernetFrame { buffer })
+    }
+
+    #[allow(dead_code)]
+    pub fn destination(&self) -> [u8; 6] {
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&self.buffer.as_ref()[0..6]);
+        mac
+    }
+
+    pub fn source(&self) -> [u8; 6] {
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&self.buffer.as_ref()[6..12]);
+        mac
+    }
+
+    pub fn ethertype(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 12)
+    }
+
+    /// Whether the destination is a broadcast or multicast address
+    pub fn is_multicast(&self) -> bool {
+        self.buffer.as_ref()[0] & 0x01 != 0
+    }
+}
+
+impl<'a> EthernetFrame<&'a [u8]> {
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[ETHERNET_HEADER_SIZE..]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> EthernetFrame<T> {
+    pub fn set_destination(&mut self, mac: [u8; 6]) {
+        self.buffer.as_mut()[0..6].copy_from_slice(&mac);
+    }
+
+    pub fn set_source(&mut self, mac: [u8; 6]) {
+        self.buffer.as_mut()[6..12].copy_from_slice(&mac);
+    }
+
+    pub fn set_ethertype(&mut self, ethertype: u16) {
+        write_u16(self.buffer.as_mut(), 12, ethertype)
+    }
+
+    #[allow(dead_code)]
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[ETHERNET_HEADER_SIZE..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_checked_rejects_truncated_frames() {
+        assert!(matches!(EthernetFrame::new_checked(&[0u8; 13][..]), Err(PacketError::Truncated)));
+        assert!(EthernetFrame::new_checked(&[0u8; 14][..]).is_ok());
+    }
+
+    #[test]
+    fn setters_write_the_fields_getters_read() {
+        let mut buffer = [0u8; 18];
+        let mut frame = EthernetFrame::new_checked(&mut buffer[..]).unwrap();
+        frame.set_destination([0xff; 6]);
+        frame.set_source([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.set_ethertype(ETHERTYPE_IPV6);
+        frame.payload_mut().copy_from_slice(&[1, 2, 3, 4]);
+
+        let frame = EthernetFrame::new_checked(&buffer[..]).unwrap();
+        assert_eq!(frame.destination(), [0xff; 6]);
+        assert_eq!(frame.source(), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        assert_eq!(frame.ethertype(), ETHERTYPE_IPV6);
+        assert!(frame.is_multicast());
+        assert_eq!(frame.payload(), &[1, 2, 3, 4]);
+        assert_eq!(&buffer[12..14], &[0x86, 0xdd]);
+    }
+}
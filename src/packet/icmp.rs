@@ -0,0 +1,127 @@
+use crate::checksum;
+use crate::errors::PacketError;
+use crate::packet::{read_u16, write_u16};
+
+/// ICMP and ICMPv6 share the layout of the first 8 bytes
+pub const ICMP_HEADER_SIZE: usize = 8;
+
+// 1 byte type
+// 1 byte code
+// 2 bytes checksum
+// 4 bytes rest of header, depending on type and code
+// data
+
+#[derive(Clone, Copy, Debug)]
+pub struct IcmpMessage<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> IcmpMessage<T> {
+    pub fn new_checked(buffer: T) -> Result<IcmpMessage<T>, PacketError> {
+        if buffer.as_ref().len() < ICMP_HEADER_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        Ok(IcmpMessage { buffer })
+    }
+
+    /// Wraps a buffer without checks, for building a message from scratch
+    pub fn new_unchecked(buffer: T) -> IcmpMessage<T> {
+        IcmpMessage { buffer }
+    }
+
+    pub fn message_type(&self) -> u8 {
+        self.buffer.as_ref()[0]
+    }
+
+    pub fn code(&self) -> u8 {
+        self.buffer.as_ref()[1]
+    }
+
+    #[allow(dead_code)]
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    pub fn rest_of_header(&self) -> [u8; 4] {
+        let b = self.buffer.as_ref();
+        [b[4], b[5], b[6], b[7]]
+    }
+
+    /// Verifies the ICMPv4 checksum, which covers the whole message
+    pub fn verify_checksum(&self) -> bool {
+        checksum::internet_checksum(self.buffer.as_ref()) == 0
+    }
+}
+
+impl<'a> IcmpMessage<&'a [u8]> {
+    pub fn data(&self) -> &'a [u8] {
+        &self.buffer[ICMP_HEADER_SIZE..]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> IcmpMessage<T> {
+    pub fn set_message_type(&mut self, message_type: u8) {
+        self.buffer.as_mut()[0] = message_type;
+    }
+
+    pub fn set_code(&mut self, code: u8) {
+        self.buffer.as_mut()[1] = code;
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        write_u16(self.buffer.as_mut(), 2, checksum)
+    }
+
+    pub fn set_rest_of_header(&mut self, rest_of_header: [u8; 4]) {
+        self.buffer.as_mut()[4..8].copy_from_slice(&rest_of_header);
+    }
+
+    /// Computes the ICMPv4 checksum over the whole message
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0);
+        let sum = checksum::internet_checksum(self.buffer.as_ref());
+        self.set_checksum(sum)
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[ICMP_HEADER_SIZE..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_checked_rejects_truncated_messages() {
+        assert!(matches!(IcmpMessage::new_checked(&[0u8; 7][..]), Err(PacketError::Truncated)));
+        assert!(IcmpMessage::new_checked(&[0u8; 8][..]).is_ok());
+    }
+
+    #[test]
+    fn setters_write_the_fields_getters_read() {
+        let mut buffer = [0u8; 13];
+        let mut message = IcmpMessage::new_unchecked(&mut buffer[..]);
+        message.set_message_type(8);
+        message.set_code(0);
+        message.set_rest_of_header([0, 1, 0, 2]);
+        message.data_mut().copy_from_slice(b"hello");
+        message.fill_checksum();
+
+        let message = IcmpMessage::new_checked(&buffer[..]).unwrap();
+        assert_eq!(message.message_type(), 8);
+        assert_eq!(message.code(), 0);
+        assert_eq!(message.rest_of_header(), [0, 1, 0, 2]);
+        assert_eq!(message.data(), b"hello");
+        assert_eq!(message.checksum(), read_u16(&buffer, 2));
+        assert!(message.verify_checksum());
+    }
+
+    #[test]
+    fn verify_checksum_detects_changed_data() {
+        let mut buffer = [8u8, 0, 0, 0, 0, 1, 0, 1, 0xaa];
+        IcmpMessage::new_unchecked(&mut buffer[..]).fill_checksum();
+        buffer[8] ^= 1;
+        assert!(!IcmpMessage::new_checked(&buffer[..]).unwrap().verify_checksum());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::checksum;
+#[cfg(test)]
+use crate::errors::PacketError;
+use crate::packet::{read_u16, write_u16};
+
+pub const IPV4_MIN_HEADER_SIZE: usize = 20;
+
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+// 1 byte version and IHL
+// 1 byte DSCP and ECN
+// 2 bytes total length
+// 2 bytes identification
+// 2 bytes flags and fragment offset
+// 1 byte TTL
+// 1 byte protocol
+// 2 bytes header checksum
+// 4 bytes source address
+// 4 bytes destination address
+// options, up to IHL * 4 bytes
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv4Packet<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv4Packet<T> {
+    /// Wraps a buffer whose header lengths are consistent with its size
+    ///
+    /// Received packets go through `ipv4::validate`, which tells the length errors apart, so only tests use this.
+    #[cfg(test)]
+    pub fn new_checked(buffer: T) -> Result<Ipv4Packet<T>, PacketError> {
+        let packet = Ipv4Packet::new_unchecked(buffer);
+        let len = packet.buffer.as_ref().len();
+        if len < IPV4_MIN_HEADER_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        let header_len = packet.header_len();
+        let total_len = packet.total_len();
+        if header_len < IPV4_MIN_HEADER_SIZE || total_len < header_len {
+            return Err(PacketError::InvalidLength);
+        }
+        if header_len > len || total_len > len {
+            return Err(PacketError::Truncated);
+        }
+        Ok(packet)
+    }
+
+    /// Wraps a buffer without checks, for building a packet from scratch
+    pub fn new_unchecked(buffer: T) -> Ipv4Packet<T> {
+        Ipv4Packet { buffer }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buffer.as_ref()[0] >> 4
+    }
+
+    pub fn header_len(&self) -> usize {
+        (self.buffer.as_ref()[0] & 0x0f) as usize * 4
+    }
+
+    pub fn total_len(&self) -> usize {
+        read_u16(self.buffer.as_ref(), 2) as usize
+    }
+
+    #[allow(dead_code)]
+    pub fn identification(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 4)
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        read_u16(self.buffer.as_ref(), 6) & FLAG_DONT_FRAGMENT != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        read_u16(self.buffer.as_ref(), 6) & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// The fragment offset in bytes
+    pub fn fragment_offset(&self) -> usize {
+        (read_u16(self.buffer.as_ref(), 6) & FRAGMENT_OFFSET_MASK) as usize * 8
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.buffer.as_ref()[8]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.buffer.as_ref()[9]
+    }
+
+    pub fn source(&self) -> [u8; 4] {
+        let b = self.buffer.as_ref();
+        [b[12], b[13], b[14], b[15]]
+    }
+
+    pub fn destination(&self) -> [u8; 4] {
+        let b = self.buffer.as_ref();
+        [b[16], b[17], b[18], b[19]]
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        checksum::internet_checksum(&self.buffer.as_ref()[..self.header_len()]) == 0
+    }
+
+    /// The header including options
+    pub fn header(&self) -> &[u8] {
+        &self.buffer.as_ref()[..self.header_len()]
+    }
+}
+
+impl<'a> Ipv4Packet<&'a [u8]> {
+    /// The header and payload, without any trailing link-layer padding
+    pub fn as_bytes(&self) -> &'a [u8] {
+        &self.buffer[..self.total_len()]
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[self.header_len()..self.total_len()]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv4Packet<T> {
+    /// Sets version 4 and the header length, which must be a multiple of 4
+    pub fn set_header_len(&mut self, header_len: usize) {
+        self.buffer.as_mut()[0] = 0x40 | (header_len / 4) as u8;
+    }
+
+    pub fn set_dscp_ecn(&mut self, value: u8) {
+        self.buffer.as_mut()[1] = value;
+    }
+
+    pub fn set_total_len(&mut self, total_len: usize) {
+        write_u16(self.buffer.as_mut(), 2, total_len as u16)
+    }
+
+    pub fn set_identification(&mut self, identification: u16) {
+        write_u16(self.buffer.as_mut(), 4, identification)
+    }
+
+    /// Sets the flags and the fragment offset in bytes, which must be a multiple of 8
+    pub fn set_fragmentation(&mut self, dont_fragment: bool, more_fragments: bool, fragment_offset: usize) {
+        let mut value = (fragment_offset / 8) as u16 & FRAGMENT_OFFSET_MASK;
+        if dont_fragment {
+            value |= FLAG_DONT_FRAGMENT;
+        }
+        if more_fragments {
+            value |= FLAG_MORE_FRAGMENTS;
+        }
+        write_u16(self.buffer.as_mut(), 6, value)
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.buffer.as_mut()[8] = ttl;
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.buffer.as_mut()[9] = protocol;
+    }
+
+    pub fn set_source(&mut self, address: [u8; 4]) {
+        self.buffer.as_mut()[12..16].copy_from_slice(&address);
+    }
+
+    pub fn set_destination(&mut self, address: [u8; 4]) {
+        self.buffer.as_mut()[16..20].copy_from_slice(&address);
+    }
+
+    /// Recomputes the header checksum
+    pub fn fill_checksum(&mut self) {
+        let header_len = self.header_len();
+        let buffer = self.buffer.as_mut();
+        write_u16(buffer, 10, 0);
+        let sum = checksum::internet_checksum(&buffer[..header_len]);
+        write_u16(buffer, 10, sum)
+    }
+
+    /// Decrements the TTL and patches the header checksum, see RFC 1812 section 5.3.1
+    pub fn decrement_ttl(&mut self) {
+        let buffer = self.buffer.as_mut();
+        let old = read_u16(buffer, 8);
+        buffer[8] -= 1;
+        let new = read_u16(buffer, 8);
+        let sum = checksum::update(read_u16(buffer, 10), old, new);
+        write_u16(buffer, 10, sum)
+    }
+
+    #[allow(dead_code)]
+    pub fn header_mut(&mut self) -> &mut [u8] {
+        let header_len = self.header_len();
+        &mut self.buffer.as_mut()[..header_len]
+    }
+
+    /// The payload as given by the header length and the total length
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let header_len = self.header_len();
+        let total_len = self.total_len();
+        &mut self.buffer.as_mut()[header_len..total_len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(header_len: usize, payload_len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; header_len + payload_len];
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer[..]);
+        packet.set_header_len(header_len);
+        packet.set_total_len(header_len + payload_len);
+        packet.set_identification(0x1234);
+        packet.set_fragmentation(true, false, 0);
+        packet.set_ttl(64);
+        packet.set_protocol(17);
+        packet.set_source([192, 168, 0, 1]);
+        packet.set_destination([10, 0, 0, 2]);
+        packet.fill_checksum();
+        buffer
+    }
+
+    #[test]
+    fn new_checked_rejects_truncated_buffers() {
+        assert!(matches!(Ipv4Packet::new_checked(&[0x45u8; 19][..]), Err(PacketError::Truncated)));
+        // Options beyond the end of the buffer
+        let mut buffer = packet(20, 4);
+        buffer[0] = 0x47;
+        buffer[3] = 32;
+        assert!(matches!(Ipv4Packet::new_checked(&buffer[..]), Err(PacketError::Truncated)));
+        // Total length beyond the end of the buffer
+        let buffer = packet(20, 4);
+        assert!(matches!(Ipv4Packet::new_checked(&buffer[..23]), Err(PacketError::Truncated)));
+    }
+
+    #[test]
+    fn new_checked_rejects_inconsistent_lengths() {
+        let mut buffer = packet(20, 4);
+        buffer[0] = 0x44;
+        assert!(matches!(Ipv4Packet::new_checked(&buffer[..]), Err(PacketError::InvalidLength)));
+        let mut buffer = packet(24, 4);
+        Ipv4Packet::new_unchecked(&mut buffer[..]).set_total_len(20);
+        assert!(matches!(Ipv4Packet::new_checked(&buffer[..]), Err(PacketError::InvalidLength)));
+    }
+
+    #[test]
+    fn setters_write_the_fields_getters_read() {
+        let mut buffer = packet(24, 4);
+        buffer.extend_from_slice(&[0; 6]);
+        let packet = Ipv4Packet::new_checked(&buffer[..]).unwrap();
+        assert_eq!(packet.version(), 4);
+        assert_eq!(packet.header_len(), 24);
+        assert_eq!(packet.total_len(), 28);
+        assert_eq!(packet.identification(), 0x1234);
+        assert!(packet.dont_fragment());
+        assert!(!packet.more_fragments());
+        assert_eq!(packet.ttl(), 64);
+        assert_eq!(packet.protocol(), 17);
+        assert_eq!(packet.source(), [192, 168, 0, 1]);
+        assert_eq!(packet.destination(), [10, 0, 0, 2]);
+        assert!(packet.verify_checksum());
+        assert_eq!(packet.header().len(), 24);
+        assert_eq!(packet.payload().len(), 4);
+        // Link-layer padding is not part of the packet
+        assert_eq!(packet.as_bytes().len(), 28);
+    }
+
+    #[test]
+    fn fragmentation_fields_round_trip() {
+        let mut buffer = packet(20, 0);
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer[..]);
+        packet.set_fragmentation(false, true, 8 * 0x1fff);
+        assert!(!packet.dont_fragment());
+        assert!(packet.more_fragments());
+        assert_eq!(packet.fragment_offset(), 8 * 0x1fff);
+        packet.set_fragmentation(false, false, 1480);
+        assert!(!packet.more_fragments());
+        assert_eq!(packet.fragment_offset(), 1480);
+    }
+
+    #[test]
+    fn decrement_ttl_keeps_the_checksum_valid() {
+        for ttl in &[255u8, 64, 2, 1] {
+            let mut buffer = packet(20, 8);
+            let mut packet = Ipv4Packet::new_unchecked(&mut buffer[..]);
+            packet.set_ttl(*ttl);
+            packet.fill_checksum();
+            packet.decrement_ttl();
+            assert_eq!(packet.ttl(), ttl - 1);
+            assert!(packet.verify_checksum());
+            let patched = read_u16(&buffer, 10);
+            let mut packet = Ipv4Packet::new_unchecked(&mut buffer[..]);
+            packet.fill_checksum();
+            assert_eq!(read_u16(&buffer, 10), patched);
+        }
+    }
+}
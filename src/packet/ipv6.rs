@@ -0,0 +1,176 @@
+use crate::errors::PacketError;
+use crate::packet::{read_u16, read_u32, write_u16, write_u32};
+
+pub const IPV6_HEADER_SIZE: usize = 40;
+
+// 4 bits version
+// 8 bits traffic class
+// 20 bits flow label
+// 2 bytes payload length
+// 1 byte next header
+// 1 byte hop limit
+// 16 bytes source address
+// 16 bytes destination address
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv6Packet<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv6Packet<T> {
+    /// Wraps a buffer holding at least the header and the payload it announces
+    pub fn new_checked(buffer: T) -> Result<Ipv6Packet<T>, PacketError> {
+        let packet = Ipv6Packet::new_unchecked(buffer);
+        let len = packet.buffer.as_ref().len();
+        if len < IPV6_HEADER_SIZE || IPV6_HEADER_SIZE + packet.payload_len() > len {
+            return Err(PacketError::Truncated);
+        }
+        Ok(packet)
+    }
+
+    /// Wraps a buffer without checks, for building a packet from scratch
+    pub fn new_unchecked(buffer: T) -> Ipv6Packet<T> {
+        Ipv6Packet { buffer }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buffer.as_ref()[0] >> 4
+    }
+
+    #[allow(dead_code)]
+    pub fn traffic_class(&self) -> u8 {
+        (read_u16(self.buffer.as_ref(), 0) >> 4) as u8
+    }
+
+    #[allow(dead_code)]
+    pub fn flow_label(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), 0) & 0x000f_ffff
+    }
+
+    pub fn payload_len(&self) -> usize {
+        read_u16(self.buffer.as_ref(), 4) as usize
+    }
+
+    pub fn next_header(&self) -> u8 {
+        self.buffer.as_ref()[6]
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.buffer.as_ref()[7]
+    }
+
+    pub fn source(&self) -> [u8; 16] {
+        let mut address = [0; 16];
+        address.copy_from_slice(&self.buffer.as_ref()[8..24]);
+        address
+    }
+
+    pub fn destination(&self) -> [u8; 16] {
+        let mut address = [0; 16];
+        address.copy_from_slice(&self.buffer.as_ref()[24..40]);
+        address
+    }
+}
+
+impl<'a> Ipv6Packet<&'a [u8]> {
+    /// The header and payload, without any trailing link-layer padding
+    pub fn as_bytes(&self) -> &'a [u8] {
+        &self.buffer[..IPV6_HEADER_SIZE + self.payload_len()]
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[IPV6_HEADER_SIZE..IPV6_HEADER_SIZE + self.payload_len()]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv6Packet<T> {
+    /// Sets version 6, the traffic class and the flow label
+    pub fn set_version_class_flow(&mut self, traffic_class: u8, flow_label: u32) {
+        let value = 6 << 28 | (traffic_class as u32) << 20 | flow_label & 0x000f_ffff;
+        write_u32(self.buffer.as_mut(), 0, value)
+    }
+
+    pub fn set_payload_len(&mut self, len: usize) {
+        write_u16(self.buffer.as_mut(), 4, len as u16)
+    }
+
+    pub fn set_next_header(&mut self, next_header: u8) {
+        self.buffer.as_mut()[6] = next_header;
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.buffer.as_mut()[7] = hop_limit;
+    }
+
+    /// Decrements the hop limit, there is no header checksum to patch
+    pub fn decrement_hop_limit(&mut self) {
+        self.buffer.as_mut()[7] -= 1;
+    }
+
+    pub fn set_source(&mut self, address: [u8; 16]) {
+        self.buffer.as_mut()[8..24].copy_from_slice(&address);
+    }
+
+    pub fn set_destination(&mut self, address: [u8; 16]) {
+        self.buffer.as_mut()[24..40].copy_from_slice(&address);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let end = IPV6_HEADER_SIZE + self.payload_len();
+        &mut self.buffer.as_mut()[IPV6_HEADER_SIZE..end]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(payload_len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; IPV6_HEADER_SIZE + payload_len];
+        let mut packet = Ipv6Packet::new_unchecked(&mut buffer[..]);
+        packet.set_version_class_flow(0xb8, 0x12345);
+        packet.set_payload_len(payload_len);
+        packet.set_next_header(58);
+        packet.set_hop_limit(64);
+        packet.set_source([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.set_destination([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        buffer
+    }
+
+    #[test]
+    fn new_checked_rejects_truncated_buffers() {
+        assert!(matches!(Ipv6Packet::new_checked(&[0x60u8; 39][..]), Err(PacketError::Truncated)));
+        let buffer = packet(8);
+        assert!(matches!(Ipv6Packet::new_checked(&buffer[..47]), Err(PacketError::Truncated)));
+        assert!(Ipv6Packet::new_checked(&buffer[..]).is_ok());
+    }
+
+    #[test]
+    fn setters_write_the_fields_getters_read() {
+        let mut buffer = packet(8);
+        Ipv6Packet::new_unchecked(&mut buffer[..]).payload_mut().copy_from_slice(&[1; 8]);
+        buffer.extend_from_slice(&[0; 4]);
+        let packet = Ipv6Packet::new_checked(&buffer[..]).unwrap();
+        assert_eq!(packet.version(), 6);
+        assert_eq!(packet.traffic_class(), 0xb8);
+        assert_eq!(packet.flow_label(), 0x12345);
+        assert_eq!(packet.payload_len(), 8);
+        assert_eq!(packet.next_header(), 58);
+        assert_eq!(packet.hop_limit(), 64);
+        assert_eq!(packet.source()[..2], [0xfe, 0x80]);
+        assert_eq!(packet.destination()[15], 2);
+        assert_eq!(packet.payload(), &[1; 8]);
+        // Link-layer padding is not part of the packet
+        assert_eq!(packet.as_bytes().len(), 48);
+    }
+
+    #[test]
+    fn decrement_hop_limit_only_changes_the_hop_limit() {
+        let mut buffer = packet(8);
+        let original = buffer.clone();
+        Ipv6Packet::new_unchecked(&mut buffer[..]).decrement_hop_limit();
+        assert_eq!(buffer[7], 63);
+        assert_eq!(buffer[..7], original[..7]);
+        assert_eq!(buffer[8..], original[8..]);
+    }
+}
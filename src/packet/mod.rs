@@ -0,0 +1,35 @@
+//! Borrowed, bounds-checked views of the headers the router understands.
+//!
+//! Every view wraps a buffer `T: AsRef<[u8]>`, usually the frame returned by
+//! `VirtQueueElement::as_network_packet`. Views over a mutable buffer additionally
+//! offer setters for rewriting packets in place or building new ones.
+
+mod ethernet;
+mod icmp;
+mod ipv4;
+mod ipv6;
+mod tcp;
+mod udp;
+mod vlan;
+
+pub use self::ethernet::{EthernetFrame, ETHERNET_HEADER_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+pub use self::icmp::{IcmpMessage, ICMP_HEADER_SIZE};
+pub use self::ipv4::{Ipv4Packet, IPV4_MIN_HEADER_SIZE};
+pub use self::ipv6::{Ipv6Packet, IPV6_HEADER_SIZE};
+pub use self::vlan::VLAN_TAG_SIZE;
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn write_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]])
+}
+
+fn write_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
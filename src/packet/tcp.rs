@@ -0,0 +1,170 @@
+use crate::errors::PacketError;
+use crate::packet::{read_u16, read_u32, write_u16, write_u32};
+
+#[allow(dead_code)]
+pub const TCP_MIN_HEADER_SIZE: usize = 20;
+
+// 2 bytes source port
+// 2 bytes destination port
+// 4 bytes sequence number
+// 4 bytes acknowledgment number
+// 4 bits data offset, 3 bits reserved, 9 bits flags
+// 2 bytes window size
+// 2 bytes checksum
+// 2 bytes urgent pointer
+// options, up to data offset * 4 bytes
+
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub struct TcpSegment<T> {
+    buffer: T,
+}
+
+#[allow(dead_code)]
+impl<T: AsRef<[u8]>> TcpSegment<T> {
+    pub fn new_checked(buffer: T) -> Result<TcpSegment<T>, PacketError> {
+        let segment = TcpSegment { buffer };
+        let len = segment.buffer.as_ref().len();
+        if len < TCP_MIN_HEADER_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        if segment.header_len() < TCP_MIN_HEADER_SIZE {
+            return Err(PacketError::InvalidLength);
+        }
+        if segment.header_len() > len {
+            return Err(PacketError::Truncated);
+        }
+        Ok(segment)
+    }
+
+    pub fn source_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 0)
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    pub fn sequence_number(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), 4)
+    }
+
+    pub fn acknowledgment_number(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), 8)
+    }
+
+    pub fn header_len(&self) -> usize {
+        (self.buffer.as_ref()[12] >> 4) as usize * 4
+    }
+
+    /// The NS, CWR, ECE, URG, ACK, PSH, RST, SYN and FIN flags
+    pub fn flags(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 12) & 0x01ff
+    }
+
+    pub fn window_size(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 14)
+    }
+
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 16)
+    }
+
+    pub fn urgent_pointer(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 18)
+    }
+}
+
+#[allow(dead_code)]
+impl<'a> TcpSegment<&'a [u8]> {
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[self.header_len()..]
+    }
+}
+
+#[allow(dead_code)]
+impl<T: AsRef<[u8]> + AsMut<[u8]>> TcpSegment<T> {
+    pub fn set_source_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), 0, port)
+    }
+
+    pub fn set_destination_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), 2, port)
+    }
+
+    pub fn set_sequence_number(&mut self, value: u32) {
+        write_u32(self.buffer.as_mut(), 4, value)
+    }
+
+    pub fn set_acknowledgment_number(&mut self, value: u32) {
+        write_u32(self.buffer.as_mut(), 8, value)
+    }
+
+    /// Sets the data offset from a header length in bytes and the flags
+    pub fn set_header_len_and_flags(&mut self, header_len: usize, flags: u16) {
+        let value = ((header_len / 4) as u16) << 12 | flags & 0x01ff;
+        write_u16(self.buffer.as_mut(), 12, value)
+    }
+
+    pub fn set_window_size(&mut self, value: u16) {
+        write_u16(self.buffer.as_mut(), 14, value)
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        write_u16(self.buffer.as_mut(), 16, checksum)
+    }
+
+    pub fn set_urgent_pointer(&mut self, value: u16) {
+        write_u16(self.buffer.as_mut(), 18, value)
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let header_len = self.header_len();
+        &mut self.buffer.as_mut()[header_len..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_checked_rejects_truncated_and_short_headers() {
+        assert!(matches!(TcpSegment::new_checked(&[0x50u8; 19][..]), Err(PacketError::Truncated)));
+        let mut buffer = [0u8; 20];
+        buffer[12] = 0x40;
+        assert!(matches!(TcpSegment::new_checked(&buffer[..]), Err(PacketError::InvalidLength)));
+        buffer[12] = 0x60;
+        assert!(matches!(TcpSegment::new_checked(&buffer[..]), Err(PacketError::Truncated)));
+        buffer[12] = 0x50;
+        assert!(TcpSegment::new_checked(&buffer[..]).is_ok());
+    }
+
+    #[test]
+    fn setters_write_the_fields_getters_read() {
+        let mut buffer = [0u8; 27];
+        buffer[12] = 0x60;
+        let mut segment = TcpSegment::new_checked(&mut buffer[..]).unwrap();
+        segment.set_source_port(49152);
+        segment.set_destination_port(443);
+        segment.set_sequence_number(0xdeadbeef);
+        segment.set_acknowledgment_number(1);
+        segment.set_header_len_and_flags(24, 0x012);
+        segment.set_window_size(65535);
+        segment.set_checksum(0xabcd);
+        segment.set_urgent_pointer(7);
+        segment.payload_mut().copy_from_slice(&[1, 2, 3]);
+
+        let segment = TcpSegment::new_checked(&buffer[..]).unwrap();
+        assert_eq!(segment.source_port(), 49152);
+        assert_eq!(segment.destination_port(), 443);
+        assert_eq!(segment.sequence_number(), 0xdeadbeef);
+        assert_eq!(segment.acknowledgment_number(), 1);
+        assert_eq!(segment.header_len(), 24);
+        assert_eq!(segment.flags(), 0x012);
+        assert_eq!(segment.window_size(), 65535);
+        assert_eq!(segment.checksum(), 0xabcd);
+        assert_eq!(segment.urgent_pointer(), 7);
+        assert_eq!(segment.payload(), &[1, 2, 3]);
+    }
+}
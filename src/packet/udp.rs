@@ -0,0 +1,119 @@
+use crate::errors::PacketError;
+use crate::packet::{read_u16, write_u16};
+
+#[allow(dead_code)]
+pub const UDP_HEADER_SIZE: usize = 8;
+
+// 2 bytes source port
+// 2 bytes destination port
+// 2 bytes length of header and data
+// 2 bytes checksum
+
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub struct UdpDatagram<T> {
+    buffer: T,
+}
+
+#[allow(dead_code)]
+impl<T: AsRef<[u8]>> UdpDatagram<T> {
+    pub fn new_checked(buffer: T) -> Result<UdpDatagram<T>, PacketError> {
+        let datagram = UdpDatagram { buffer };
+        let len = datagram.buffer.as_ref().len();
+        if len < UDP_HEADER_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        if datagram.len() < UDP_HEADER_SIZE {
+            return Err(PacketError::InvalidLength);
+        }
+        if datagram.len() > len {
+            return Err(PacketError::Truncated);
+        }
+        Ok(datagram)
+    }
+
+    pub fn source_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 0)
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    /// Length of header and data
+    pub fn len(&self) -> usize {
+        read_u16(self.buffer.as_ref(), 4) as usize
+    }
+
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 6)
+    }
+}
+
+#[allow(dead_code)]
+impl<'a> UdpDatagram<&'a [u8]> {
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[UDP_HEADER_SIZE..self.len()]
+    }
+}
+
+#[allow(dead_code)]
+impl<T: AsRef<[u8]> + AsMut<[u8]>> UdpDatagram<T> {
+    pub fn set_source_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), 0, port)
+    }
+
+    pub fn set_destination_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), 2, port)
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        write_u16(self.buffer.as_mut(), 4, len as u16)
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        write_u16(self.buffer.as_mut(), 6, checksum)
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let len = self.len();
+        &mut self.buffer.as_mut()[UDP_HEADER_SIZE..len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_checked_rejects_truncated_and_short_datagrams() {
+        assert!(matches!(UdpDatagram::new_checked(&[0u8; 7][..]), Err(PacketError::Truncated)));
+        let mut buffer = [0u8; 12];
+        buffer[5] = 7;
+        assert!(matches!(UdpDatagram::new_checked(&buffer[..]), Err(PacketError::InvalidLength)));
+        buffer[5] = 13;
+        assert!(matches!(UdpDatagram::new_checked(&buffer[..]), Err(PacketError::Truncated)));
+        buffer[5] = 12;
+        assert!(UdpDatagram::new_checked(&buffer[..]).is_ok());
+    }
+
+    #[test]
+    fn setters_write_the_fields_getters_read() {
+        let mut buffer = [0u8; 14];
+        buffer[5] = 8;
+        let mut datagram = UdpDatagram::new_checked(&mut buffer[..]).unwrap();
+        datagram.set_source_port(68);
+        datagram.set_destination_port(67);
+        datagram.set_len(12);
+        datagram.set_checksum(0x1234);
+        datagram.payload_mut().copy_from_slice(&[9; 4]);
+
+        let datagram = UdpDatagram::new_checked(&buffer[..]).unwrap();
+        assert_eq!(datagram.source_port(), 68);
+        assert_eq!(datagram.destination_port(), 67);
+        assert_eq!(datagram.len(), 12);
+        assert_eq!(datagram.checksum(), 0x1234);
+        // The length field bounds the payload, not the buffer
+        assert_eq!(datagram.payload(), &[9; 4]);
+    }
+}
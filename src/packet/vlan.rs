@@ -0,0 +1,93 @@
+use crate::errors::PacketError;
+use crate::packet::{read_u16, write_u16};
+
+pub const VLAN_TAG_SIZE: usize = 4;
+
+// 802.1Q tag, following the ethertype ETHERTYPE_VLAN:
+// 3 bits priority code point
+// 1 bit drop eligible indicator
+// 12 bits VLAN identifier
+// 2 bytes ethertype of the payload
+
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub struct VlanTag<T> {
+    buffer: T,
+}
+
+#[allow(dead_code)]
+impl<T: AsRef<[u8]>> VlanTag<T> {
+    pub fn new_checked(buffer: T) -> Result<VlanTag<T>, PacketError> {
+        if buffer.as_ref().len() < VLAN_TAG_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        Ok(VlanTag { buffer })
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.buffer.as_ref()[0] >> 5
+    }
+
+    pub fn drop_eligible(&self) -> bool {
+        self.buffer.as_ref()[0] & 0x10 != 0
+    }
+
+    pub fn vlan_id(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 0) & 0x0fff
+    }
+
+    pub fn ethertype(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+}
+
+#[allow(dead_code)]
+impl<'a> VlanTag<&'a [u8]> {
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[VLAN_TAG_SIZE..]
+    }
+}
+
+#[allow(dead_code)]
+impl<T: AsRef<[u8]> + AsMut<[u8]>> VlanTag<T> {
+    pub fn set_tag_control(&mut self, priority: u8, drop_eligible: bool, vlan_id: u16) {
+        let tci = (priority as u16) << 13 | (drop_eligible as u16) << 12 | vlan_id & 0x0fff;
+        write_u16(self.buffer.as_mut(), 0, tci)
+    }
+
+    pub fn set_ethertype(&mut self, ethertype: u16) {
+        write_u16(self.buffer.as_mut(), 2, ethertype)
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[VLAN_TAG_SIZE..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_checked_rejects_truncated_tags() {
+        assert!(matches!(VlanTag::new_checked(&[0u8; 3][..]), Err(PacketError::Truncated)));
+        assert!(VlanTag::new_checked(&[0u8; 4][..]).is_ok());
+    }
+
+    #[test]
+    fn setters_write_the_fields_getters_read() {
+        let mut buffer = [0u8; 6];
+        let mut tag = VlanTag::new_checked(&mut buffer[..]).unwrap();
+        tag.set_tag_control(5, true, 0xabc);
+        tag.set_ethertype(0x0800);
+        tag.payload_mut().copy_from_slice(&[7, 8]);
+
+        let tag = VlanTag::new_checked(&buffer[..]).unwrap();
+        assert_eq!(tag.priority(), 5);
+        assert!(tag.drop_eligible());
+        assert_eq!(tag.vlan_id(), 0xabc);
+        assert_eq!(tag.ethertype(), 0x0800);
+        assert_eq!(tag.payload(), &[7, 8]);
+        assert_eq!(&buffer[..2], &[0xba, 0xbc]);
+    }
+}
@@ -0,0 +1,27 @@
+use crate::config;
+use crate::cpu;
+use core::cell::UnsafeCell;
+
+/// A value per core, each core only accesses its own, so no locking is needed
+pub struct PerCpu<T> {
+    values: [UnsafeCell<T>; config::MAX_CORES],
+}
+
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T: Default> PerCpu<T> {
+    pub fn new() -> PerCpu<T> {
+        PerCpu {
+            values: Default::default(),
+        }
+    }
+}
+
+impl<T> PerCpu<T> {
+    /// Calls `f` with the calling core's value
+    ///
+    /// Must neither be called from interrupt handlers nor from within `f`, which would alias the value.
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        f(unsafe { &mut *self.values[cpu::core_index()].get() })
+    }
+}
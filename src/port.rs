@@ -0,0 +1,245 @@
+use crate::arp;
+use crate::config::{self, PortConfig};
+use crate::errors::DeviceInitializationError;
+use crate::gic;
+use crate::memory_handle::MemoryHandle;
+use crate::ipv6;
+use crate::ndp::{self, NeighborCache};
+use crate::packet::{EthernetFrame, Ipv6Packet};
+use crate::router_advertisement::{self, Advertiser};
+use crate::spinlock::SpinLock;
+use crate::timer::Instant;
+use crate::virtio::{self, VirtioMMIONetworkDevice};
+use crate::virtio_control::{ControlQueue, RxMode};
+use crate::virtio_device_register::VirtioMMIORegister;
+use crate::virtqueue::VirtQueue;
+
+/// A router port: its network device and the protocol state attached to it
+///
+/// Ports are shared by all cores, the mutable state is locked.
+pub struct Port {
+    pub nic: VirtioMMIONetworkDevice,
+    pub ipv4_address: [u8; 4],
+    pub ipv6_address: [u8; 16],
+    /// Keeps the device receiving frames to any MAC address, see `PortConfig::promiscuous`
+    promiscuous: bool,
+    pub ndp: SpinLock<ndp::Interface>,
+    /// Hardware addresses of the IPv4 neighbors
+    pub arp: SpinLock<NeighborCache<[u8; 4]>>,
+    /// Router advertisements of downstream ports
+    pub advertiser: Option<SpinLock<Advertiser>>,
+}
+
+impl Port {
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn initialize(config: &PortConfig, memory: &mut MemoryHandle) -> Result<Port, DeviceInitializationError> {
+        let port = Self::with_register(config, VirtioMMIORegister::new(config.mmio_address), memory)?;
+        let interrupt = config::VIRTIO_MMIO_FIRST_INTERRUPT
+            + ((config.mmio_address - config::VIRTIO_MMIO_ADDRESS) / config::VIRTIO_MMIO_SIZE) as u32;
+        gic::register(interrupt, virtio::acknowledge_interrupt, config.mmio_address)
+            .map_err(DeviceInitializationError::Interrupt)?;
+        Ok(port)
+    }
+
+    /// Initializes the port's device through `register`, without registering its interrupt
+    ///
+    /// Host tests pass the registers of a simulated device, see `mock_device`.
+    pub fn with_register(
+        config: &PortConfig,
+        register: VirtioMMIORegister,
+        memory: &mut MemoryHandle,
+    ) -> Result<Port, DeviceInitializationError> {
+        let nic = VirtioMMIONetworkDevice::initialize(
+            register,
+            config.mtu,
+            config.queue_pairs,
+            config.queue_size,
+            memory,
+        )?;
+        if let Some(control) = &nic.control {
+            Self::configure_rx_filter(&mut control.lock(), nic.mac, config.ipv6_address, config.promiscuous);
+        }
+        let ndp = ndp::Interface::new(nic.mac, config.ipv6_address);
+        let advertiser = config
+            .router_advertisement
+            .map(|advertisement| SpinLock::new(Advertiser::new(advertisement, nic.mac, nic.mtu)));
+        Ok(Port {
+            nic,
+            ipv4_address: config.ipv4_address,
+            ipv6_address: config.ipv6_address,
+            promiscuous: config.promiscuous,
+            ndp: SpinLock::new(ndp),
+            arp: SpinLock::new(NeighborCache::new()),
+            advertiser,
+        })
+    }
+
+    /// Programs the multicast groups of the port's addresses and leaves promiscuous mode unless configured
+    ///
+    /// Devices without VIRTIO_NET_F_CTRL_RX stay in their default mode, which is promiscuous for QEMU.
+    fn configure_rx_filter(control: &mut ControlQueue, mac: [u8; 6], ipv6_address: [u8; 16], promiscuous: bool) {
+        let multicast = [
+            ipv6::multicast_mac(ipv6::ALL_NODES),
+            ipv6::multicast_mac(ipv6::ALL_ROUTERS),
+            ipv6::multicast_mac(ipv6::solicited_node_address(ipv6::link_local_address(mac))),
+            ipv6::multicast_mac(ipv6::solicited_node_address(ipv6_address)),
+        ];
+        if control.set_mac_table(&[], &multicast).is_err() {
+            // util::print(format_args!("[warn] MAC filter not supported\n")).unwrap();
+            return;
+        }
+        if control.set_rx_mode(RxMode::Promiscuous, promiscuous).is_err() {
+            // util::print(format_args!("[warn] failed to set promiscuous mode\n")).unwrap();
+        }
+    }
+
+    /// Builds a frame in a free send buffer of one of the calling core's queue pairs and transmits it
+    ///
+    /// `build` writes the frame and returns its length.
+    pub fn transmit<F: FnOnce(&mut [u8]) -> usize>(&self, build: F) {
+        let mut queue_pair = match self.nic.queue_pair_for_flow(0) {
+            Some(queue_pair) => queue_pair,
+            None => return,
+        };
+        if let Some(mut queue_element) = queue_pair.sendq.try_take() {
+            let len = build(queue_element.as_network_packet_mut());
+            queue_element.set_network_packet_len(len);
+            queue_pair.sendq.offer(queue_element);
+            queue_pair.notify_send();
+        } else {
+            // util::print(format_args!("[warn] send queue full\n")).unwrap();
+        }
+    }
+
+    /// Transmits a copy of `frame`
+    pub fn transmit_frame(&self, frame: &[u8]) {
+        self.transmit(|out| {
+            out[..frame.len()].copy_from_slice(frame);
+            frame.len()
+        });
+    }
+
+    /// Sends the duplicate address detection probes, neighbor solicitations, ARP requests and router
+    /// advertisements that are due
+    ///
+    /// Also recovers the device if it needs a reset and answers its announcement requests.
+    pub fn poll_timers(&self, now: Instant) {
+        if self.nic.needs_reset() {
+            self.recover();
+        }
+        if self.nic.announce_requested() {
+            self.announce();
+        }
+
+        let mut solicitation = [0; ndp::MAX_FRAME_SIZE];
+        loop {
+            let len = match self.ndp.lock().poll(now, &mut solicitation) {
+                Some(len) => len,
+                None => break,
+            };
+            self.transmit_frame(&solicitation[..len]);
+        }
+        loop {
+            let request = match self.arp.lock().next_solicitation(now) {
+                Some(request) => request,
+                None => break,
+            };
+            let (mac, address) = (self.nic.mac, self.ipv4_address);
+            self.transmit(|out| arp::build_request(out, mac, address, request.target, request.mac));
+        }
+
+        // Advertisements start once the link-local address passed duplicate address detection
+        let (source, source_valid) = {
+            let ndp = self.ndp.lock();
+            let source = ndp.link_local_address();
+            (source, ndp.is_local(source))
+        };
+        if !source_valid {
+            return;
+        }
+        let mut advertisement = [0; router_advertisement::MAX_FRAME_SIZE];
+        let len = match &self.advertiser {
+            Some(advertiser) => advertiser.lock().poll(now, source, &mut advertisement),
+            None => None,
+        };
+        if let Some(len) = len {
+            self.transmit_frame(&advertisement[..len]);
+        }
+    }
+
+    /// Resets the device and restores its receive filter, the other ports keep forwarding meanwhile
+    fn recover(&self) {
+        // util::print(format_args!("[warn] device needs a reset\n")).unwrap();
+        if let Err(_error) = self.nic.recover() {
+            // util::print(format_args!("[warn] failed to recover device: {:?}\n", _error)).unwrap();
+            return;
+        }
+        if let Some(control) = &self.nic.control {
+            Self::configure_rx_filter(&mut control.lock(), self.nic.mac, self.ipv6_address, self.promiscuous);
+        }
+    }
+
+    /// Announces the port's addresses with a gratuitous ARP and unsolicited neighbor advertisements
+    ///
+    /// Switches learn the port's new location this way, e.g. after the router was live migrated.
+    fn announce(&self) {
+        let mac = self.nic.mac;
+        let address = self.ipv4_address;
+        self.transmit(|out| arp::build_gratuitous(out, mac, address));
+        let mut advertisement = [0; ndp::MAX_FRAME_SIZE];
+        for index in 0..ndp::ADDRESS_COUNT {
+            let len = self.ndp.lock().announcement(index, &mut advertisement);
+            if let Some(len) = len {
+                self.transmit_frame(&advertisement[..len]);
+            }
+        }
+        let acknowledged = match &self.nic.control {
+            Some(control) => control.lock().ack_announce().is_ok(),
+            None => false,
+        };
+        if !acknowledged {
+            // util::print(format_args!("[warn] failed to acknowledge announcement\n")).unwrap();
+        }
+    }
+
+    /// Answers a received neighbor discovery message
+    ///
+    /// Router solicitations are answered if the port sends router advertisements.
+    pub fn handle_neighbor_discovery(&self, frame: &EthernetFrame<&[u8]>, packet: &Ipv6Packet<&[u8]>, now: Instant) {
+        if let Some(advertiser) = &self.advertiser {
+            if ndp::is_router_solicitation(packet) {
+                advertiser.lock().solicit(now);
+            }
+        }
+        let mut reply = [0; ndp::MAX_FRAME_SIZE];
+        let len = self.ndp.lock().process(frame, packet, now, &mut reply);
+        if let Some(len) = len {
+            self.transmit_frame(&reply[..len]);
+        }
+    }
+
+    /// Answers ARP requests for the port's address and updates the ARP cache, see RFC 826
+    pub fn handle_arp(&self, frame: &EthernetFrame<&[u8]>, now: Instant) {
+        let packet = frame.payload();
+        let (sender, sender_mac) = match arp::sender(packet) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let request = arp::is_request_for(packet, self.ipv4_address);
+        // Probes of RFC 5227 have no sender address yet, so there is nothing to cache
+        if sender != [0; 4] {
+            let mut cache = self.arp.lock();
+            if request {
+                // The requester is about to send to the port, so it is cached like a neighbor solicitor
+                cache.process_solicitation(sender, sender_mac, now);
+            } else {
+                // Other requests and replies only update cached senders, unless they answer a request
+                cache.process_advertisement(sender, Some(sender_mac), arp::is_reply(packet), true, now);
+            }
+        }
+        if request {
+            let (mac, address) = (self.nic.mac, self.ipv4_address);
+            self.transmit(|out| arp::build_reply(packet, out, mac, address));
+        }
+    }
+}
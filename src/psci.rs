@@ -0,0 +1,146 @@
+use crate::config;
+use crate::cpu;
+use crate::errors::PsciError;
+use crate::fdt::DeviceTree;
+
+// Function IDs, see the Arm Power State Coordination Interface spec section 5.1
+const PSCI_VERSION: u32 = 0x84000000;
+const PSCI_CPU_OFF: u32 = 0x84000002;
+const PSCI_CPU_ON: u32 = 0xc4000003;
+const PSCI_SYSTEM_OFF: u32 = 0x84000008;
+const PSCI_SYSTEM_RESET: u32 = 0x84000009;
+const PSCI_FEATURES: u32 = 0x8400000a;
+
+/// How PSCI calls reach the firmware, see the SMC Calling Convention
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Conduit {
+    /// Handled by a hypervisor at EL2, e.g. QEMU's own PSCI implementation
+    Hvc,
+    /// Handled by the secure monitor at EL3
+    Smc,
+}
+
+/// Written once by `initialize`, before the secondary cores are started
+static mut CONDUIT: Conduit = Conduit::Hvc;
+
+/// Selects the conduit named by the `method` property of the device tree's psci node
+///
+/// Without a device tree or psci node, the HVC conduit QEMU uses for EL1 guests is kept.
+pub fn initialize() {
+    let method = DeviceTree::at(config::DEVICE_TREE_ADDRESS).and_then(|tree| tree.property("psci", "method"));
+    let conduit = match method {
+        Some(b"smc\0") => Conduit::Smc,
+        _ => Conduit::Hvc,
+    };
+    unsafe { CONDUIT = conduit };
+}
+
+pub fn conduit() -> Conduit {
+    unsafe { CONDUIT }
+}
+
+/// The implemented PSCI version as major and minor number
+#[allow(dead_code)]
+pub fn version() -> (u16, u16) {
+    let version = call(PSCI_VERSION, 0, 0, 0) as u32;
+    ((version >> 16) as u16, version as u16)
+}
+
+/// Whether the firmware implements the function, with its feature flags, see the PSCI spec section 5.15
+#[allow(dead_code)]
+pub fn features(function: u32) -> Result<u32, PsciError> {
+    result(call(PSCI_FEATURES, function as u64, 0, 0)).map(|flags| flags as u32)
+}
+
+/// Powers on the core `target` at `entry`, which is called with `context` in x0
+pub fn cpu_on(target: u64, entry: u64, context: u64) -> Result<(), PsciError> {
+    result(call(PSCI_CPU_ON, target, entry, context)).map(|_| ())
+}
+
+/// Powers off the calling core, only returns if the firmware refused
+#[allow(dead_code)]
+pub fn cpu_off() -> PsciError {
+    result(call(PSCI_CPU_OFF, 0, 0, 0)).err().unwrap_or(PsciError::Denied)
+}
+
+/// Powers the machine off, which makes QEMU exit
+pub fn system_off() -> ! {
+    call(PSCI_SYSTEM_OFF, 0, 0, 0);
+    halt()
+}
+
+/// Resets the machine, which makes QEMU restart it unless it runs with `-no-reboot`
+#[allow(dead_code)]
+pub fn system_reset() -> ! {
+    call(PSCI_SYSTEM_RESET, 0, 0, 0);
+    halt()
+}
+
+/// Only reached if the firmware refused to power off or reset
+fn halt() -> ! {
+    loop {
+        cpu::wait_for_event();
+    }
+}
+
+fn result(value: i64) -> Result<i64, PsciError> {
+    if value >= 0 {
+        return Ok(value);
+    }
+    Err(match value {
+        -1 => PsciError::NotSupported,
+        -2 => PsciError::InvalidParameters,
+        -3 => PsciError::Denied,
+        -4 => PsciError::AlreadyOn,
+        -5 => PsciError::OnPending,
+        -6 => PsciError::InternalFailure,
+        -7 => PsciError::NotPresent,
+        -8 => PsciError::Disabled,
+        -9 => PsciError::InvalidAddress,
+        _ => PsciError::Unknown(value as i32),
+    })
+}
+
+/// Calls a PSCI function through the conduit
+#[cfg(not(test))]
+fn call(function: u32, argument1: u64, argument2: u64, argument3: u64) -> i64 {
+    let result: i64;
+    match conduit() {
+        Conduit::Hvc => unsafe {
+            asm!(
+                "hvc #0",
+                inout("x0") function as u64 => result,
+                inout("x1") argument1 => _,
+                inout("x2") argument2 => _,
+                inout("x3") argument3 => _,
+                // SMC Calling Convention version 1.0 allows x4 to x17 to be clobbered
+                lateout("x4") _, lateout("x5") _, lateout("x6") _, lateout("x7") _,
+                lateout("x8") _, lateout("x9") _, lateout("x10") _, lateout("x11") _,
+                lateout("x12") _, lateout("x13") _, lateout("x14") _, lateout("x15") _,
+                lateout("x16") _, lateout("x17") _,
+                options(nostack),
+            )
+        },
+        Conduit::Smc => unsafe {
+            asm!(
+                "smc #0",
+                inout("x0") function as u64 => result,
+                inout("x1") argument1 => _,
+                inout("x2") argument2 => _,
+                inout("x3") argument3 => _,
+                lateout("x4") _, lateout("x5") _, lateout("x6") _, lateout("x7") _,
+                lateout("x8") _, lateout("x9") _, lateout("x10") _, lateout("x11") _,
+                lateout("x12") _, lateout("x13") _, lateout("x14") _, lateout("x15") _,
+                lateout("x16") _, lateout("x17") _,
+                options(nostack),
+            )
+        },
+    }
+    result
+}
+
+/// Host tests have no firmware to call, every function is reported as not supported
+#[cfg(test)]
+fn call(_function: u32, _argument1: u64, _argument2: u64, _argument3: u64) -> i64 {
+    -1
+}
@@ -0,0 +1,878 @@
+use crate::config::{self, PortConfig};
+use crate::flow;
+use crate::icmp::{self, ErrorMessage};
+use crate::icmpv6;
+use crate::ipv4::{self, Ipv4DropCounters};
+use crate::ipv6::{self, Ipv6DropCounters};
+use crate::memory_handle::MemoryHandle;
+use crate::ndp;
+use crate::packet::{
+    EthernetFrame, Ipv4Packet, Ipv6Packet, ETHERNET_HEADER_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6,
+};
+use crate::percpu::PerCpu;
+use crate::port::Port;
+use crate::psci;
+use crate::routing::{self, RouteTarget};
+use crate::spinlock::SpinLock;
+use crate::timer::Instant;
+use crate::token_bucket::TokenBucket;
+use crate::virtio;
+use crate::virtqueue::{VirtQueue, VirtQueueElement};
+
+/// Ports configured beyond this number are not initialized
+pub const MAX_PORTS: usize = 8;
+
+/// The ports and the state shared by all cores, every core forwards the frames of its own queue pairs
+pub struct Router {
+    ports: [Option<Port>; MAX_PORTS],
+    icmp_rate_limit: SpinLock<TokenBucket>,
+    ipv4_drops: PerCpu<Ipv4DropCounters>,
+    ipv6_drops: PerCpu<Ipv6DropCounters>,
+}
+
+impl Router {
+    /// Initializes a port for every configuration, port indices are the indices into `configs`
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn new(configs: &[PortConfig], memory: &mut MemoryHandle) -> Router {
+        let mut ports: [Option<Port>; MAX_PORTS] = Default::default();
+        for (port, config) in ports.iter_mut().zip(configs) {
+            *port = Some(Port::initialize(config, memory).unwrap());
+        }
+        Self::with_ports(ports)
+    }
+
+    /// A router forwarding between initialized ports, e.g. on simulated devices in host tests
+    pub fn with_ports(ports: [Option<Port>; MAX_PORTS]) -> Router {
+        Router {
+            ports,
+            icmp_rate_limit: SpinLock::new(TokenBucket::new(
+                config::ICMP_RATE_LIMIT_BURST,
+                config::ICMP_RATE_LIMIT_INTERVAL,
+            )),
+            ipv4_drops: PerCpu::new(),
+            ipv6_drops: PerCpu::new(),
+        }
+    }
+
+    /// Services the neighbor discovery and router advertisement timers of every port
+    pub fn poll_timers(&self, now: Instant) {
+        for port in self.ports.iter().flatten() {
+            port.poll_timers(now);
+        }
+    }
+
+    /// Handles at most one received frame per queue pair of the calling core
+    ///
+    /// Returns whether a frame was received.
+    pub fn poll(&self, now: Instant) -> bool {
+        let mut received = false;
+        for index in 0..MAX_PORTS {
+            if self.ports[index].is_none() {
+                continue;
+            }
+            for pair in 0..virtio::MAX_QUEUE_PAIRS {
+                if !self.port(index).nic.owns_queue_pair(pair) {
+                    continue;
+                }
+                // The pair is unlocked while the frame is handled, which may transmit on it
+                let (queue_element, resets) = match self.port(index).nic.queue_pair(pair) {
+                    Some(mut queue_pair) => (queue_pair.receiveq.try_take(), queue_pair.resets),
+                    None => break,
+                };
+                if let Some(queue_element) = queue_element {
+                    self.receive(index, &queue_element, now);
+                    let mut queue_pair = self.port(index).nic.queue_pair(pair).unwrap();
+                    // A device reset meanwhile offered the buffer again
+                    if queue_pair.resets == resets {
+                        queue_pair.receiveq.offer(queue_element);
+                        queue_pair.notify_receive();
+                    } else {
+                        queue_element.abandon();
+                    }
+                    received = true;
+                }
+            }
+        }
+        received
+    }
+
+    /// Enables or suppresses the receive interrupts of the calling core's queue pairs of every port
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn set_receive_interrupts(&self, enabled: bool) {
+        for port in self.ports.iter().flatten() {
+            port.nic.set_receive_interrupts(enabled);
+        }
+    }
+
+    fn port(&self, index: usize) -> &Port {
+        self.ports[index].as_ref().unwrap()
+    }
+
+    /// The port a route points to, if it exists
+    fn egress_port(&self, index: usize) -> Option<&Port> {
+        self.ports.get(index).and_then(Option::as_ref)
+    }
+
+    fn receive(&self, index: usize, queue_element: &VirtQueueElement, now: Instant) {
+        let (_header, data) = queue_element.as_network_packet();
+        //// util::print(format_args!("data = {:x?} \n", &_data[0..128])).unwrap();
+        let frame = match EthernetFrame::new_checked(data) {
+            Ok(frame) => frame,
+            Err(_) => {
+                // util::print(format_args!("[warn] runt frame\n")).unwrap();
+                return;
+            }
+        };
+        match frame.ethertype() {
+            ETHERTYPE_IPV4 => self.receive_ipv4(index, queue_element, &frame, now),
+            ETHERTYPE_IPV6 => self.receive_ipv6(index, queue_element, &frame, now),
+            ETHERTYPE_ARP => self.port(index).handle_arp(&frame, now),
+            _ethertype => {
+                // util::print(format_args!("[warn] unknown ethertype {}\n", _ethertype)).unwrap();
+            }
+        }
+    }
+
+    fn receive_ipv4(&self, index: usize, queue_element: &VirtQueueElement, frame: &EthernetFrame<&[u8]>, now: Instant) {
+        let packet = match ipv4::validate(frame.payload()) {
+            Ok(packet) => packet,
+            Err(error) => {
+                // util::print(format_args!("[warn] dropping ipv4 packet: {:?}\n", error)).unwrap();
+                self.ipv4_drops.with(|drops| drops.count(&error));
+                return;
+            }
+        };
+        let destination = packet.destination();
+        // util::print(format_args!("### ipv4 source = {:?}, destination = {:?} \n", packet.source(), destination)).unwrap();
+        let error = if self.ports.iter().flatten().any(|port| port.ipv4_address == destination) {
+            deliver_local_ipv4(self.port(index), frame, &packet)
+        } else {
+            match routing::lookup_ipv4(config::IPV4_ROUTES, destination) {
+                Some(route) => match route.target {
+                    RouteTarget::Port(egress) => match self.egress_port(egress) {
+                        Some(egress) => {
+                            let next_hop = route.next_hop(destination);
+                            forward_ipv4(queue_element, &packet, egress, next_hop, now, &self.ipv4_drops)
+                        }
+                        None => Some(ErrorMessage::NetUnreachable),
+                    },
+                    RouteTarget::Unreachable if route.prefix_len == 32 => Some(ErrorMessage::HostUnreachable),
+                    RouteTarget::Unreachable => Some(ErrorMessage::NetUnreachable),
+                    RouteTarget::Prohibited => Some(ErrorMessage::AdministrativelyProhibited),
+                },
+                None => Some(ErrorMessage::NetUnreachable),
+            }
+        };
+        if let Some(message) = error {
+            self.send_icmp_error(index, now, frame, &packet, message);
+        }
+    }
+
+    fn receive_ipv6(&self, index: usize, queue_element: &VirtQueueElement, frame: &EthernetFrame<&[u8]>, now: Instant) {
+        let packet = match ipv6::validate(frame.payload()) {
+            Ok(packet) => packet,
+            Err(error) => {
+                // util::print(format_args!("[warn] dropping ipv6 packet: {:?}\n", error)).unwrap();
+                self.ipv6_drops.with(|drops| drops.count(&error));
+                return;
+            }
+        };
+        if ndp::is_neighbor_discovery(&packet) {
+            self.port(index).handle_neighbor_discovery(frame, &packet, now);
+            return;
+        }
+        let destination = packet.destination();
+        // util::print(format_args!("### ipv6 source = {:x?}, destination = {:x?} \n", packet.source(), destination)).unwrap();
+        if self.ports.iter().flatten().any(|port| port.ndp.lock().is_local(destination)) || !ipv6::forwardable(&packet) {
+            // util::print(format_args!("[warn] not forwarding ipv6 packet\n")).unwrap();
+            return;
+        }
+        let error = match routing::lookup_ipv6(config::IPV6_ROUTES, destination) {
+            Some(route) => match route.target {
+                RouteTarget::Port(egress) => match self.egress_port(egress) {
+                    Some(egress) => forward_ipv6(queue_element, &packet, egress, route.next_hop(destination), now),
+                    None => Some(icmpv6::ErrorMessage::NoRoute),
+                },
+                RouteTarget::Unreachable => Some(icmpv6::ErrorMessage::NoRoute),
+                RouteTarget::Prohibited => Some(icmpv6::ErrorMessage::AdministrativelyProhibited),
+            },
+            None => Some(icmpv6::ErrorMessage::NoRoute),
+        };
+        if let Some(message) = error {
+            self.send_icmpv6_error(index, now, frame, &packet, message);
+        }
+    }
+
+    /// Answers `packet` with an ICMP error out of the port it was received on
+    fn send_icmp_error(
+        &self,
+        index: usize,
+        now: Instant,
+        frame: &EthernetFrame<&[u8]>,
+        packet: &Ipv4Packet<&[u8]>,
+        message: ErrorMessage,
+    ) {
+        if !icmp::error_permitted(frame, packet) || !self.icmp_rate_limit.lock().try_take(now) {
+            return;
+        }
+        let port = self.port(index);
+        let (mac, address) = (port.nic.mac, port.ipv4_address);
+        port.transmit(|out| icmp::build_error(frame, packet, out, mac, address, message));
+    }
+
+    /// Answers `packet` with an ICMPv6 error out of the port it was received on
+    fn send_icmpv6_error(
+        &self,
+        index: usize,
+        now: Instant,
+        frame: &EthernetFrame<&[u8]>,
+        packet: &Ipv6Packet<&[u8]>,
+        message: icmpv6::ErrorMessage,
+    ) {
+        if !icmpv6::error_permitted(frame, packet, message) || !self.icmp_rate_limit.lock().try_take(now) {
+            return;
+        }
+        let port = self.port(index);
+        let (mac, address) = (port.nic.mac, port.ipv6_address);
+        port.transmit(|out| icmpv6::build_error(frame, packet, out, mac, address, message));
+    }
+}
+
+/// Forwards an IPv4 packet out of `egress`, fragmenting it if necessary
+///
+/// The packet is sent to the neighbor `next_hop`, either the destination itself or the route's gateway.
+/// Packets to neighbors whose hardware address is not resolved yet are dropped.
+/// Returns the ICMP error to answer the sender with if the packet cannot be forwarded. Packets whose
+/// fragments do not all fit into the send queue are dropped and counted in `drops`.
+fn forward_ipv4(
+    queue_element: &VirtQueueElement,
+    packet: &Ipv4Packet<&[u8]>,
+    egress: &Port,
+    next_hop: [u8; 4],
+    now: Instant,
+    drops: &PerCpu<Ipv4DropCounters>,
+) -> Option<ErrorMessage> {
+    let egress_mtu = egress.nic.mtu as usize;
+    if packet.ttl() <= 1 {
+        return Some(ErrorMessage::TimeExceeded);
+    }
+    if packet.total_len() > egress_mtu && packet.dont_fragment() {
+        return Some(ErrorMessage::FragmentationNeeded {
+            next_hop_mtu: egress.nic.mtu,
+        });
+    }
+    let destination_mac = egress.arp.lock().resolve(next_hop, now)?;
+    let mut link_header = [0; ETHERNET_HEADER_SIZE];
+    let mut egress_frame = EthernetFrame::new_checked(&mut link_header[..]).unwrap();
+    egress_frame.set_destination(destination_mac);
+    egress_frame.set_source(egress.nic.mac);
+    egress_frame.set_ethertype(ETHERTYPE_IPV4);
+    // Packets of one flow leave on the same queue pair, so they are not reordered
+    let mut queue_pair = egress.nic.queue_pair_for_flow(flow::ipv4_flow_hash(packet))?;
+    if packet.total_len() > egress_mtu {
+        let mut fragmenter = ipv4::Fragmenter::new(&link_header, *packet, egress_mtu);
+        // The receiver cannot reassemble an incomplete set of fragments, so either all of them are sent or none
+        let fragments = fragmenter.remaining();
+        if fragments == 0 || queue_pair.sendq.used_count() < fragments {
+            drops.with(|drops| drops.fragmentation_failed = drops.fragmentation_failed.wrapping_add(1));
+            return None;
+        }
+        while fragmenter.has_next() {
+            let mut egress_queue_element = queue_pair.sendq.try_take().unwrap();
+            let out = egress_queue_element.as_network_packet_mut();
+            let len = fragmenter.next_fragment(out).unwrap();
+            Ipv4Packet::new_unchecked(&mut out[ETHERNET_HEADER_SIZE..]).decrement_ttl();
+            egress_queue_element.set_network_packet_len(len);
+            queue_pair.sendq.offer(egress_queue_element);
+        }
+        queue_pair.notify_send();
+    } else if let Some(mut egress_queue_element) = queue_pair.sendq.try_take() {
+        // util::print(format_args!("############# passing packet to sendqueue\n"));
+        egress_queue_element.copy_from(queue_element);
+        let out = egress_queue_element.as_network_packet_mut();
+        out[..ETHERNET_HEADER_SIZE].copy_from_slice(&link_header);
+        Ipv4Packet::new_unchecked(&mut out[ETHERNET_HEADER_SIZE..]).decrement_ttl();
+        queue_pair.sendq.offer(egress_queue_element);
+        queue_pair.notify_send();
+    }
+    None
+}
+
+/// Forwards an IPv6 packet out of `egress`, routers never fragment IPv6 packets
+///
+/// The packet is sent to the neighbor `next_hop`, either the destination itself or the route's gateway.
+/// Packets to neighbors that are not resolved yet are dropped.
+/// Returns the ICMPv6 error to answer the sender with if the packet cannot be forwarded.
+fn forward_ipv6(
+    queue_element: &VirtQueueElement,
+    packet: &Ipv6Packet<&[u8]>,
+    egress: &Port,
+    next_hop: [u8; 16],
+    now: Instant,
+) -> Option<icmpv6::ErrorMessage> {
+    if packet.hop_limit() <= 1 {
+        return Some(icmpv6::ErrorMessage::HopLimitExceeded);
+    }
+    if packet.as_bytes().len() > egress.nic.mtu as usize {
+        return Some(icmpv6::ErrorMessage::PacketTooBig {
+            mtu: egress.nic.mtu as u32,
+        });
+    }
+    let destination_mac = match egress.ndp.lock().neighbors.resolve(next_hop, now) {
+        Some(mac) => mac,
+        None => return None,
+    };
+    let source_mac = egress.nic.mac;
+    let mut queue_pair = egress.nic.queue_pair_for_flow(flow::ipv6_flow_hash(packet))?;
+    if let Some(mut egress_queue_element) = queue_pair.sendq.try_take() {
+        egress_queue_element.copy_from(queue_element);
+        let out = egress_queue_element.as_network_packet_mut();
+        let mut frame = EthernetFrame::new_checked(&mut out[..]).unwrap();
+        frame.set_destination(destination_mac);
+        frame.set_source(source_mac);
+        Ipv6Packet::new_unchecked(&mut out[ETHERNET_HEADER_SIZE..]).decrement_hop_limit();
+        queue_pair.sendq.offer(egress_queue_element);
+        queue_pair.notify_send();
+    } else {
+        // util::print(format_args!("[warn] egress send queue full\n")).unwrap();
+    }
+    None
+}
+
+/// Handles an IPv4 packet addressed to one of the router's addresses
+///
+/// Returns the ICMP error to answer the sender with if the packet is not served.
+fn deliver_local_ipv4(port: &Port, frame: &EthernetFrame<&[u8]>, packet: &Ipv4Packet<&[u8]>) -> Option<ErrorMessage> {
+    // Fragments are not reassembled, so they are dropped without an error, see RFC 1812 section 4.3.2.7
+    if packet.more_fragments() || packet.fragment_offset() != 0 {
+        return None;
+    }
+    match packet.protocol() {
+        ipv4::PROTOCOL_ICMP => {
+            if icmp::is_echo_request(packet) {
+                let mac = port.nic.mac;
+                port.transmit(|out| icmp::build_echo_reply(frame, packet, out, mac));
+            }
+            None
+        }
+        ipv4::PROTOCOL_UDP => Some(ErrorMessage::PortUnreachable),
+        _ => Some(ErrorMessage::ProtocolUnreachable),
+    }
+}
+
+/// Resets the devices of all configured ports and powers the machine off
+///
+/// The devices stop their DMA first, so QEMU exits in a defined state. No locks are taken, so
+/// this also works after a panic or an exception.
+#[cfg_attr(test, allow(dead_code))]
+pub fn shutdown() -> ! {
+    for port in config::PORTS {
+        virtio::reset(port.mmio_address);
+    }
+    psci::system_off()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arp;
+    use crate::checksum;
+    use crate::mock_device::{self, DeviceConfig, MockDevice};
+    use crate::packet::IcmpMessage;
+
+    const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x10];
+    const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x05];
+
+    /// Initializes the configured ports on simulated devices
+    fn router() -> (Router, Vec<&'static MockDevice>) {
+        router_with(|_, device| device)
+    }
+
+    /// Initializes the configured ports on simulated devices, `customize` adapts the device of a port
+    fn router_with(customize: impl Fn(usize, DeviceConfig) -> DeviceConfig) -> (Router, Vec<&'static MockDevice>) {
+        let mut memory = mock_device::guest_memory(0x1000000);
+        let mut ports: [Option<Port>; MAX_PORTS] = Default::default();
+        let mut devices = Vec::new();
+        for (i, (port, config)) in ports.iter_mut().zip(config::PORTS).enumerate() {
+            let device = MockDevice::new(
+                config.mmio_address,
+                customize(
+                    i,
+                    DeviceConfig {
+                        mac: [0x52, 0x54, 0, 0, 0, i as u8],
+                        queue_num_max: 256,
+                        ..Default::default()
+                    },
+                ),
+            );
+            *port = Some(Port::with_register(config, device.register(), &mut memory).unwrap());
+            devices.push(device);
+        }
+        (Router::with_ports(ports), devices)
+    }
+
+    /// An Ethernet frame to the router with an IPv4 packet
+    fn ipv4_frame(source: [u8; 4], destination: [u8; 4], ttl: u8, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; ETHERNET_HEADER_SIZE + 20];
+        frame[..6].copy_from_slice(&[0x52, 0x54, 0, 0, 0, 0]);
+        frame[6..12].copy_from_slice(&HOST_MAC);
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let header = &mut frame[ETHERNET_HEADER_SIZE..];
+        header[0] = 0x45;
+        header[2..4].copy_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        header[8] = ttl;
+        header[9] = protocol;
+        header[12..16].copy_from_slice(&source);
+        header[16..20].copy_from_slice(&destination);
+        let header_checksum = checksum::internet_checksum(header);
+        header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// An ICMP echo request with a few bytes of data
+    fn echo_request() -> Vec<u8> {
+        let mut message = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, 0xde, 0xad, 0xbe, 0xef];
+        let message_checksum = checksum::internet_checksum(&message);
+        message[2..4].copy_from_slice(&message_checksum.to_be_bytes());
+        message
+    }
+
+    /// Makes `address` a resolved IPv4 neighbor of a port, as if it answered an ARP request
+    fn add_ipv4_neighbor(router: &Router, port: usize, address: [u8; 4], mac: [u8; 6]) {
+        let mut cache = router.port(port).arp.lock();
+        assert_eq!(cache.resolve(address, Instant::ZERO), None);
+        cache.process_advertisement(address, Some(mac), true, true, Instant::ZERO);
+    }
+
+    /// The link header of a frame forwarded to `mac` out of a port
+    fn link_header(mac: [u8; 6], port: usize) -> Vec<u8> {
+        let mut header = mac.to_vec();
+        header.extend_from_slice(&[0x52, 0x54, 0, 0, 0, port as u8]);
+        header.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        header
+    }
+
+    /// Injects a frame on the first queue pair of a port and polls the router
+    fn forward(router: &Router, devices: &[&MockDevice], port: usize, frame: &[u8]) {
+        for device in devices {
+            device.take_transmitted();
+        }
+        assert!(devices[port].inject(0, frame));
+        assert!(router.poll(Instant::ZERO));
+        assert!(!router.poll(Instant::ZERO));
+    }
+
+    #[test]
+    fn poll_finds_nothing_without_frames() {
+        let (router, devices) = router();
+        assert!(!router.poll(Instant::ZERO));
+        assert!(devices.iter().all(|device| device.take_transmitted().is_empty()));
+    }
+
+    #[test]
+    fn forwards_ipv4_packet_to_egress_port() {
+        let (router, devices) = router();
+        add_ipv4_neighbor(&router, 1, [10, 0, 1, 5], SERVER_MAC);
+        let frame = ipv4_frame([10, 0, 0, 2], [10, 0, 1, 5], 64, ipv4::PROTOCOL_UDP, &[7; 32]);
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices[0].take_transmitted().is_empty());
+        let transmitted = devices[1].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let (queue, out) = &transmitted[0];
+        assert_eq!(*queue, 1);
+        assert_eq!(out.len(), frame.len());
+        assert_eq!(out[..ETHERNET_HEADER_SIZE], link_header(SERVER_MAC, 1)[..]);
+        let packet = Ipv4Packet::new_checked(&out[ETHERNET_HEADER_SIZE..]).unwrap();
+        assert_eq!(packet.ttl(), 63);
+        assert!(packet.verify_checksum());
+        assert_eq!(packet.destination(), [10, 0, 1, 5]);
+        assert_eq!(packet.payload(), &[7; 32][..]);
+    }
+
+    #[test]
+    fn forwards_between_legacy_and_modern_ports() {
+        // The modern port's virtio-net header is 2 bytes longer
+        let (router, devices) = router_with(|i, device| match i {
+            1 => DeviceConfig {
+                version: 2,
+                features: [device.features[0], mock_device::VIRTIO_F_VERSION_1],
+                ..device
+            },
+            _ => device,
+        });
+        add_ipv4_neighbor(&router, 1, [10, 0, 1, 5], SERVER_MAC);
+        add_ipv4_neighbor(&router, 0, [10, 0, 0, 5], HOST_MAC);
+        for &(ingress, egress, destination, mac) in &[
+            (0, 1, [10, 0, 1, 5], SERVER_MAC),
+            (1, 0, [10, 0, 0, 5], HOST_MAC),
+        ] {
+            let frame = ipv4_frame([10, 0, 0, 2], destination, 64, ipv4::PROTOCOL_UDP, &[7; 32]);
+            forward(&router, &devices, ingress, &frame);
+
+            let transmitted = devices[egress].take_transmitted();
+            assert_eq!(transmitted.len(), 1);
+            let out = &transmitted[0].1;
+            assert_eq!(out.len(), frame.len());
+            assert_eq!(out[..ETHERNET_HEADER_SIZE], link_header(mac, egress)[..]);
+            let packet = Ipv4Packet::new_checked(&out[ETHERNET_HEADER_SIZE..]).unwrap();
+            assert_eq!(packet.ttl(), 63);
+            assert!(packet.verify_checksum());
+            assert_eq!(packet.destination(), destination);
+            assert_eq!(packet.payload(), &[7; 32][..]);
+        }
+    }
+
+    /// A router whose upstream port has a smaller MTU than the downstream port
+    fn router_with_small_upstream_mtu() -> (Router, Vec<&'static MockDevice>) {
+        router_with(|i, device| match i {
+            1 => DeviceConfig { mtu: 1280, ..device },
+            _ => device,
+        })
+    }
+
+    #[test]
+    fn fragments_packet_larger_than_egress_mtu() {
+        let (router, devices) = router_with_small_upstream_mtu();
+        add_ipv4_neighbor(&router, 1, [10, 0, 1, 5], SERVER_MAC);
+        let data: Vec<u8> = (0..1400).map(|i| i as u8).collect();
+        let frame = ipv4_frame([10, 0, 0, 2], [10, 0, 1, 5], 64, ipv4::PROTOCOL_UDP, &data);
+        forward(&router, &devices, 0, &frame);
+
+        let transmitted = devices[1].take_transmitted();
+        assert_eq!(transmitted.len(), 2);
+        let mut reassembled = Vec::new();
+        for (i, (_, out)) in transmitted.iter().enumerate() {
+            assert_eq!(out[..ETHERNET_HEADER_SIZE], link_header(SERVER_MAC, 1)[..]);
+            let packet = Ipv4Packet::new_checked(&out[ETHERNET_HEADER_SIZE..]).unwrap();
+            assert!(packet.total_len() <= 1280);
+            assert!(packet.verify_checksum());
+            assert_eq!(packet.ttl(), 63);
+            assert_eq!(packet.more_fragments(), i == 0);
+            assert_eq!(packet.fragment_offset(), reassembled.len());
+            reassembled.extend_from_slice(packet.payload());
+        }
+        assert_eq!(reassembled, data);
+    }
+
+    #[test]
+    fn drops_packet_whose_fragments_do_not_fit_into_send_queue() {
+        let (router, devices) = router_with(|i, device| match i {
+            1 => DeviceConfig {
+                mtu: 1280,
+                queue_num_max: 4,
+                ..device
+            },
+            _ => device,
+        });
+        add_ipv4_neighbor(&router, 1, [10, 0, 1, 5], SERVER_MAC);
+        // Three of the four send buffers stay with the device
+        devices[1].set_transmit_stalled(true);
+        for i in 0..3 {
+            let frame = ipv4_frame([10, 0, 0, 2], [10, 0, 1, 5], 64, ipv4::PROTOCOL_UDP, &[i; 32]);
+            forward(&router, &devices, 0, &frame);
+        }
+        let frame = ipv4_frame([10, 0, 0, 2], [10, 0, 1, 5], 64, ipv4::PROTOCOL_UDP, &[7; 1400]);
+        forward(&router, &devices, 0, &frame);
+        devices[1].set_transmit_stalled(false);
+        let transmitted = devices[1].take_transmitted();
+        assert_eq!(transmitted.len(), 3);
+        assert!(transmitted.iter().all(|(_, out)| out.len() == ETHERNET_HEADER_SIZE + 20 + 32));
+        router.ipv4_drops.with(|drops| assert_eq!(drops.fragmentation_failed, 1));
+
+        // Both fragments are sent once the device returned the buffers
+        forward(&router, &devices, 0, &frame);
+        assert_eq!(devices[1].take_transmitted().len(), 2);
+        router.ipv4_drops.with(|drops| assert_eq!(drops.fragmentation_failed, 1));
+    }
+
+    #[test]
+    fn answers_dont_fragment_packet_with_fragmentation_needed() {
+        let (router, devices) = router_with_small_upstream_mtu();
+        let mut frame = ipv4_frame([10, 0, 0, 2], [10, 0, 1, 5], 64, ipv4::PROTOCOL_UDP, &[7; 1400]);
+        let mut packet = Ipv4Packet::new_unchecked(&mut frame[ETHERNET_HEADER_SIZE..]);
+        packet.set_fragmentation(true, false, 0);
+        packet.fill_checksum();
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices[1].take_transmitted().is_empty());
+        let transmitted = devices[0].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let reply = EthernetFrame::new_checked(&transmitted[0].1[..]).unwrap();
+        let packet = Ipv4Packet::new_checked(reply.payload()).unwrap();
+        assert_eq!(packet.destination(), [10, 0, 0, 2]);
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert_eq!((message.message_type(), message.code()), (3, 4));
+        assert_eq!(message.rest_of_header(), [0, 0, 0x05, 0x00]);
+        assert!(message.verify_checksum());
+    }
+
+    #[test]
+    fn forwards_many_packets_through_small_queues() {
+        let (router, devices) = router();
+        add_ipv4_neighbor(&router, 0, [10, 0, 0, 9], HOST_MAC);
+        // More packets than descriptors, so the rings of both ports wrap several times
+        for i in 0..1000u32 {
+            let frame = ipv4_frame([10, 0, 1, 7], [10, 0, 0, 9], 64, ipv4::PROTOCOL_UDP, &i.to_be_bytes());
+            forward(&router, &devices, 1, &frame);
+            let transmitted = devices[0].take_transmitted();
+            assert_eq!(transmitted.len(), 1);
+            assert_eq!(&transmitted[0].1[transmitted[0].1.len() - 4..], &i.to_be_bytes()[..]);
+        }
+    }
+
+    #[test]
+    fn answers_echo_request_on_ingress_port() {
+        let (router, devices) = router();
+        let frame = ipv4_frame([10, 0, 0, 2], [10, 0, 0, 1], 64, ipv4::PROTOCOL_ICMP, &echo_request());
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices[1].take_transmitted().is_empty());
+        let transmitted = devices[0].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let reply = EthernetFrame::new_checked(&transmitted[0].1[..]).unwrap();
+        assert_eq!(reply.destination(), HOST_MAC);
+        let packet = Ipv4Packet::new_checked(reply.payload()).unwrap();
+        assert_eq!((packet.source(), packet.destination()), ([10, 0, 0, 1], [10, 0, 0, 2]));
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert_eq!(message.message_type(), 0);
+        assert!(message.verify_checksum());
+        assert_eq!(message.data(), &[0xde, 0xad, 0xbe, 0xef][..]);
+    }
+
+    #[test]
+    fn ignores_fragments_of_echo_requests() {
+        let (router, devices) = router();
+        // A first fragment, and a later one whose data happens to look like an echo request
+        for &(more_fragments, offset) in &[(true, 0), (false, 8), (true, 8)] {
+            let mut frame = ipv4_frame([10, 0, 0, 2], [10, 0, 0, 1], 64, ipv4::PROTOCOL_ICMP, &echo_request());
+            let mut packet = Ipv4Packet::new_unchecked(&mut frame[ETHERNET_HEADER_SIZE..]);
+            packet.set_fragmentation(false, more_fragments, offset);
+            packet.fill_checksum();
+            forward(&router, &devices, 0, &frame);
+            assert!(devices.iter().all(|device| device.take_transmitted().is_empty()));
+        }
+    }
+
+    #[test]
+    fn ignores_echo_request_with_invalid_checksum() {
+        let (router, devices) = router();
+        let mut message = echo_request();
+        message[2] ^= 0xff;
+        let frame = ipv4_frame([10, 0, 0, 2], [10, 0, 0, 1], 64, ipv4::PROTOCOL_ICMP, &message);
+        forward(&router, &devices, 0, &frame);
+        assert!(devices.iter().all(|device| device.take_transmitted().is_empty()));
+    }
+
+    #[test]
+    fn counts_dropped_ipv4_packets_per_reason() {
+        let (router, devices) = router();
+        let mut frame = ipv4_frame([10, 0, 0, 2], [10, 0, 1, 5], 64, ipv4::PROTOCOL_UDP, &[7; 32]);
+        frame[ETHERNET_HEADER_SIZE + 10] ^= 0xff;
+        forward(&router, &devices, 0, &frame);
+        let frame = ipv4_frame([10, 0, 0, 2], [224, 0, 0, 5], 64, ipv4::PROTOCOL_UDP, &[7; 32]);
+        forward(&router, &devices, 0, &frame);
+        let frame = ipv4_frame([127, 0, 0, 1], [10, 0, 1, 5], 64, ipv4::PROTOCOL_UDP, &[7; 32]);
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices.iter().all(|device| device.take_transmitted().is_empty()));
+        router.ipv4_drops.with(|drops| {
+            assert_eq!(drops.invalid_checksum, 1);
+            assert_eq!(drops.multicast_destination, 1);
+            assert_eq!(drops.martian_source, 1);
+            assert_eq!(drops.martian_destination, 0);
+        });
+    }
+
+    #[test]
+    fn answers_expiring_packet_with_time_exceeded() {
+        let (router, devices) = router();
+        let frame = ipv4_frame([10, 0, 0, 2], [10, 0, 1, 5], 1, ipv4::PROTOCOL_UDP, &[7; 32]);
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices[1].take_transmitted().is_empty());
+        let transmitted = devices[0].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let reply = EthernetFrame::new_checked(&transmitted[0].1[..]).unwrap();
+        let packet = Ipv4Packet::new_checked(reply.payload()).unwrap();
+        assert_eq!(packet.destination(), [10, 0, 0, 2]);
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert_eq!(message.message_type(), 11);
+    }
+
+    /// The ARP packets a port transmitted
+    fn transmitted_arp(device: &MockDevice) -> Vec<Vec<u8>> {
+        let transmitted = device.take_transmitted().into_iter().map(|(_, out)| out);
+        transmitted
+            .filter(|out| EthernetFrame::new_checked(&out[..]).unwrap().ethertype() == ETHERTYPE_ARP)
+            .collect()
+    }
+
+    #[test]
+    fn resolves_ipv4_gateway_with_arp() {
+        let (router, devices) = router();
+        let frame = ipv4_frame([10, 0, 0, 2], [192, 0, 2, 5], 64, ipv4::PROTOCOL_UDP, &[7; 32]);
+        // The packet is dropped while the gateway, not the destination, is resolved
+        forward(&router, &devices, 0, &frame);
+        assert!(devices[1].take_transmitted().is_empty());
+        router.poll_timers(Instant::ZERO);
+        let requests = transmitted_arp(devices[1]);
+        assert_eq!(requests.len(), 1);
+        let request = EthernetFrame::new_checked(&requests[0][..]).unwrap();
+        assert_eq!(request.destination(), [0xff; 6]);
+        assert!(arp::is_request_for(request.payload(), config::UPSTREAM_IPV4_GATEWAY));
+        assert_eq!(arp::sender(request.payload()), Some(([10, 0, 1, 1], [0x52, 0x54, 0, 0, 0, 1])));
+
+        let gateway_mac = [0x02, 0, 0, 0, 0, 0xfe];
+        let mut reply = vec![0; ETHERNET_HEADER_SIZE + arp::PACKET_SIZE];
+        arp::build_reply(request.payload(), &mut reply, gateway_mac, config::UPSTREAM_IPV4_GATEWAY);
+        forward(&router, &devices, 1, &reply);
+        forward(&router, &devices, 0, &frame);
+        let transmitted = devices[1].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        assert_eq!(transmitted[0].1[..ETHERNET_HEADER_SIZE], link_header(gateway_mac, 1)[..]);
+    }
+
+    #[test]
+    fn caches_the_sender_of_arp_requests() {
+        let (router, devices) = router();
+        let mut request = vec![0; ETHERNET_HEADER_SIZE + arp::PACKET_SIZE];
+        arp::build_request(&mut request, HOST_MAC, [10, 0, 0, 2], [10, 0, 0, 1], None);
+        forward(&router, &devices, 0, &request);
+        let replies = transmitted_arp(devices[0]);
+        assert_eq!(replies.len(), 1);
+        assert!(arp::is_reply(&replies[0][ETHERNET_HEADER_SIZE..]));
+
+        // Packets to the requester are forwarded without another request
+        let frame = ipv4_frame([10, 0, 1, 5], [10, 0, 0, 2], 64, ipv4::PROTOCOL_UDP, &[7; 32]);
+        forward(&router, &devices, 1, &frame);
+        let transmitted = devices[0].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        assert_eq!(transmitted[0].1[..ETHERNET_HEADER_SIZE], link_header(HOST_MAC, 0)[..]);
+    }
+
+    const HOST_IPV6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    /// On the upstream port's network
+    const SERVER_IPV6: [u8; 16] = [0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5];
+    /// Beyond the upstream router
+    const REMOTE_IPV6: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5];
+    const GATEWAY_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0xfe];
+
+    /// An Ethernet frame to the router with an IPv6 packet carrying UDP
+    fn ipv6_frame(source: [u8; 16], destination: [u8; 16], hop_limit: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; ETHERNET_HEADER_SIZE + 40];
+        frame[..6].copy_from_slice(&[0x52, 0x54, 0, 0, 0, 0]);
+        frame[6..12].copy_from_slice(&HOST_MAC);
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        let mut packet = Ipv6Packet::new_unchecked(&mut frame[ETHERNET_HEADER_SIZE..]);
+        packet.set_version_class_flow(0, 0);
+        packet.set_payload_len(payload.len());
+        packet.set_next_header(ipv4::PROTOCOL_UDP);
+        packet.set_hop_limit(hop_limit);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Makes `address` a reachable neighbor of a port, as if it answered a solicitation
+    fn add_ipv6_neighbor(router: &Router, port: usize, address: [u8; 16], mac: [u8; 6]) {
+        let neighbors = &mut router.port(port).ndp.lock().neighbors;
+        assert_eq!(neighbors.resolve(address, Instant::ZERO), None);
+        neighbors.process_advertisement(address, Some(mac), true, false, Instant::ZERO);
+    }
+
+    /// The ICMPv6 type, code and rest of header of the single error sent on a port
+    fn icmpv6_error(device: &MockDevice) -> (u8, u8, [u8; 4]) {
+        let transmitted = device.take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let reply = EthernetFrame::new_checked(&transmitted[0].1[..]).unwrap();
+        assert_eq!(reply.destination(), HOST_MAC);
+        let packet = Ipv6Packet::new_checked(reply.payload()).unwrap();
+        assert_eq!((packet.source(), packet.destination()), (config::PORTS[0].ipv6_address, HOST_IPV6));
+        assert!(icmpv6::verify_checksum(&packet));
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        (message.message_type(), message.code(), message.rest_of_header())
+    }
+
+    #[test]
+    fn forwards_ipv6_packet_to_on_link_destination() {
+        let (router, devices) = router();
+        add_ipv6_neighbor(&router, 1, SERVER_IPV6, SERVER_MAC);
+        let frame = ipv6_frame(HOST_IPV6, SERVER_IPV6, 64, &[7; 32]);
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices[0].take_transmitted().is_empty());
+        let transmitted = devices[1].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let out = EthernetFrame::new_checked(&transmitted[0].1[..]).unwrap();
+        // The more specific on-link route wins over the default route through the gateway
+        assert_eq!((out.destination(), out.source()), (SERVER_MAC, [0x52, 0x54, 0, 0, 0, 1]));
+        let packet = Ipv6Packet::new_checked(out.payload()).unwrap();
+        assert_eq!(packet.hop_limit(), 63);
+        assert_eq!(packet.destination(), SERVER_IPV6);
+        assert_eq!(packet.payload(), &[7; 32][..]);
+    }
+
+    #[test]
+    fn forwards_off_link_ipv6_packet_to_gateway() {
+        let (router, devices) = router();
+        let frame = ipv6_frame(HOST_IPV6, REMOTE_IPV6, 64, &[7; 32]);
+        // The gateway is solicited, not the destination, and the packet is dropped meanwhile
+        forward(&router, &devices, 0, &frame);
+        assert!(devices[1].take_transmitted().is_empty());
+        let solicitation = router.port(1).ndp.lock().neighbors.next_solicitation(Instant::ZERO).unwrap();
+        assert_eq!(solicitation.target, config::UPSTREAM_IPV6_GATEWAY);
+        assert!(router.port(1).ndp.lock().neighbors.next_solicitation(Instant::ZERO).is_none());
+
+        router
+            .port(1)
+            .ndp
+            .lock()
+            .neighbors
+            .process_advertisement(config::UPSTREAM_IPV6_GATEWAY, Some(GATEWAY_MAC), true, false, Instant::ZERO);
+        forward(&router, &devices, 0, &frame);
+        let transmitted = devices[1].take_transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let out = EthernetFrame::new_checked(&transmitted[0].1[..]).unwrap();
+        assert_eq!(out.destination(), GATEWAY_MAC);
+        let packet = Ipv6Packet::new_checked(out.payload()).unwrap();
+        assert_eq!((packet.destination(), packet.hop_limit()), (REMOTE_IPV6, 63));
+    }
+
+    #[test]
+    fn answers_expiring_ipv6_packet_with_time_exceeded() {
+        let (router, devices) = router();
+        add_ipv6_neighbor(&router, 1, SERVER_IPV6, SERVER_MAC);
+        let frame = ipv6_frame(HOST_IPV6, SERVER_IPV6, 1, &[7; 32]);
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices[1].take_transmitted().is_empty());
+        assert_eq!(icmpv6_error(devices[0]), (3, 0, [0; 4]));
+    }
+
+    #[test]
+    fn answers_ipv6_packet_larger_than_egress_mtu_with_packet_too_big() {
+        let (router, devices) = router_with_small_upstream_mtu();
+        add_ipv6_neighbor(&router, 1, SERVER_IPV6, SERVER_MAC);
+        let frame = ipv6_frame(HOST_IPV6, SERVER_IPV6, 64, &[7; 1400]);
+        forward(&router, &devices, 0, &frame);
+
+        assert!(devices[1].take_transmitted().is_empty());
+        assert_eq!(icmpv6_error(devices[0]), (2, 0, 1280u32.to_be_bytes()));
+    }
+
+    #[test]
+    fn answers_ipv6_packet_without_route_with_destination_unreachable() {
+        // Without the upstream port, the default route leads nowhere
+        let (router, devices) = router();
+        let Router { mut ports, .. } = router;
+        ports[1] = None;
+        let router = Router::with_ports(ports);
+        let frame = ipv6_frame(HOST_IPV6, REMOTE_IPV6, 64, &[7; 32]);
+        forward(&router, &devices, 0, &frame);
+
+        assert_eq!(icmpv6_error(devices[0]), (1, 0, [0; 4]));
+    }
+}
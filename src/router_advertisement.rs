@@ -0,0 +1,325 @@
+use crate::config::{self, RouterAdvertisementConfig};
+use crate::icmpv6;
+use crate::ipv6;
+use crate::ndp;
+use crate::packet::{IcmpMessage, ETHERNET_HEADER_SIZE, ICMP_HEADER_SIZE, IPV6_HEADER_SIZE};
+use crate::timer::Instant;
+use core::time::Duration;
+
+const TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_MTU: u8 = 5;
+const OPTION_RDNSS: u8 = 25;
+
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// Hop limit advertised to hosts
+const CUR_HOP_LIMIT: u8 = 64;
+/// Reachable time and retrans timer fields, 0 leaves them to the host
+const BODY_SIZE: usize = 8;
+const MAX_INITIAL_ADVERTISEMENTS: u8 = 3;
+const PREFIX_OPTION_SIZE: usize = 32;
+const MTU_OPTION_SIZE: usize = 8;
+/// Prefixes and DNS servers beyond these are not advertised
+const MAX_PREFIXES: usize = 4;
+const MAX_DNS_SERVERS: usize = 3;
+
+/// Size of the largest frame written by an advertiser
+pub const MAX_FRAME_SIZE: usize = ETHERNET_HEADER_SIZE
+    + IPV6_HEADER_SIZE
+    + ICMP_HEADER_SIZE
+    + BODY_SIZE
+    + ndp::OPTION_UNIT
+    + MTU_OPTION_SIZE
+    + MAX_PREFIXES * PREFIX_OPTION_SIZE
+    + ndp::OPTION_UNIT
+    + MAX_DNS_SERVERS * 16;
+
+// router advertisement, see RFC 4861 section 4.2:
+// 1 byte type
+// 1 byte code
+// 2 bytes checksum
+// 1 byte current hop limit
+// 1 byte flags
+// 2 bytes router lifetime
+// 4 bytes reachable time
+// 4 bytes retrans timer
+// options
+//
+// prefix information option, see RFC 4861 section 4.6.2:
+// 1 byte type
+// 1 byte length
+// 1 byte prefix length
+// 1 byte flags
+// 4 bytes valid lifetime
+// 4 bytes preferred lifetime
+// 4 bytes reserved
+// 16 bytes prefix
+//
+// recursive DNS server option, see RFC 8106 section 5.1:
+// 1 byte type
+// 1 byte length
+// 2 bytes reserved
+// 4 bytes lifetime
+// 16 bytes per DNS server
+
+/// Sends the router advertisements of a downstream port, see RFC 4861 section 6.2
+pub struct Advertiser {
+    config: RouterAdvertisementConfig,
+    mac: [u8; 6],
+    mtu: u16,
+    /// Time of the next advertisement
+    next: Instant,
+    /// Time of the last advertisement
+    last: Option<Instant>,
+    initial_advertisements: u8,
+    /// State of the xorshift generator spreading the advertisement intervals
+    random: u32,
+}
+
+impl Advertiser {
+    /// The first advertisement is due right away
+    pub fn new(config: RouterAdvertisementConfig, mac: [u8; 6], mtu: u16) -> Advertiser {
+        Advertiser {
+            config,
+            mac,
+            mtu,
+            next: Instant::ZERO,
+            last: None,
+            initial_advertisements: 0,
+            random: u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]) | 1,
+        }
+    }
+
+    /// Schedules an advertisement in response to a router solicitation, see RFC 4861 section 6.2.6
+    ///
+    /// Advertisements are always sent to all nodes and not more often than `RA_MIN_DELAY_BETWEEN`.
+    pub fn solicit(&mut self, now: Instant) {
+        let earliest = match self.last {
+            Some(last) => core::cmp::max(now, last + config::RA_MIN_DELAY_BETWEEN),
+            None => now,
+        };
+        if earliest < self.next {
+            self.next = earliest;
+        }
+    }
+
+    /// Writes the advertisement to `out` if one is due, `source` must be the port's link-local address
+    ///
+    /// Returns the length of the written frame.
+    pub fn poll(&mut self, now: Instant, source: [u8; 16], out: &mut [u8]) -> Option<usize> {
+        if now < self.next {
+            return None;
+        }
+        let mut interval = self.random_interval();
+        if self.initial_advertisements < MAX_INITIAL_ADVERTISEMENTS {
+            self.initial_advertisements += 1;
+            interval = core::cmp::min(interval, config::RA_INITIAL_INTERVAL);
+        }
+        self.next = now + interval;
+        self.last = Some(now);
+        Some(self.build(out, source))
+    }
+
+    /// A uniformly distributed interval between `RA_MIN_INTERVAL` and `RA_MAX_INTERVAL`
+    fn random_interval(&mut self) -> Duration {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        let range = (config::RA_MAX_INTERVAL - config::RA_MIN_INTERVAL).as_millis() as u64;
+        config::RA_MIN_INTERVAL + Duration::from_millis(self.random as u64 % range)
+    }
+
+    fn build(&self, out: &mut [u8], source: [u8; 16]) -> usize {
+        let prefixes = &self.config.prefixes[..core::cmp::min(self.config.prefixes.len(), MAX_PREFIXES)];
+        let dns_servers = &self.config.dns_servers[..core::cmp::min(self.config.dns_servers.len(), MAX_DNS_SERVERS)];
+        let rdnss_size = if dns_servers.is_empty() {
+            0
+        } else {
+            ndp::OPTION_UNIT + dns_servers.len() * 16
+        };
+        let payload_len = ICMP_HEADER_SIZE
+            + BODY_SIZE
+            + ndp::OPTION_UNIT
+            + MTU_OPTION_SIZE
+            + prefixes.len() * PREFIX_OPTION_SIZE
+            + rdnss_size;
+        let destination_mac = ipv6::multicast_mac(ipv6::ALL_NODES);
+        let mut packet = icmpv6::write_headers(out, destination_mac, self.mac, payload_len, source, ipv6::ALL_NODES);
+        packet.set_hop_limit(ndp::HOP_LIMIT);
+
+        let mut message = IcmpMessage::new_unchecked(packet.payload_mut());
+        message.set_message_type(TYPE_ROUTER_ADVERTISEMENT);
+        message.set_code(0);
+        let lifetime = self.config.router_lifetime.to_be_bytes();
+        message.set_rest_of_header([CUR_HOP_LIMIT, 0, lifetime[0], lifetime[1]]);
+        let data = message.data_mut();
+        data[..BODY_SIZE].copy_from_slice(&[0; BODY_SIZE]);
+        let mut offset = BODY_SIZE;
+
+        ndp::write_link_layer_option(&mut data[offset..], ndp::OPTION_SOURCE_LINK_LAYER_ADDRESS, self.mac);
+        offset += ndp::OPTION_UNIT;
+
+        let option = &mut data[offset..offset + MTU_OPTION_SIZE];
+        option[0] = OPTION_MTU;
+        option[1] = 1;
+        option[2..4].copy_from_slice(&[0, 0]);
+        option[4..8].copy_from_slice(&(self.mtu as u32).to_be_bytes());
+        offset += MTU_OPTION_SIZE;
+
+        for prefix in prefixes {
+            let option = &mut data[offset..offset + PREFIX_OPTION_SIZE];
+            option[0] = OPTION_PREFIX_INFORMATION;
+            option[1] = (PREFIX_OPTION_SIZE / ndp::OPTION_UNIT) as u8;
+            option[2] = prefix.prefix_len;
+            option[3] = PREFIX_FLAG_ON_LINK | PREFIX_FLAG_AUTONOMOUS;
+            option[4..8].copy_from_slice(&prefix.valid_lifetime.to_be_bytes());
+            option[8..12].copy_from_slice(&prefix.preferred_lifetime.to_be_bytes());
+            option[12..16].copy_from_slice(&[0; 4]);
+            option[16..32].copy_from_slice(&prefix.prefix);
+            offset += PREFIX_OPTION_SIZE;
+        }
+
+        if !dns_servers.is_empty() {
+            let option = &mut data[offset..offset + rdnss_size];
+            option[0] = OPTION_RDNSS;
+            option[1] = (rdnss_size / ndp::OPTION_UNIT) as u8;
+            option[2..4].copy_from_slice(&[0, 0]);
+            option[4..8].copy_from_slice(&self.config.dns_lifetime.to_be_bytes());
+            for (i, server) in dns_servers.iter().enumerate() {
+                option[8 + i * 16..8 + (i + 1) * 16].copy_from_slice(server);
+            }
+        }
+        icmpv6::fill_checksum(&mut packet);
+
+        ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + payload_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PrefixConfig;
+    use crate::packet::{EthernetFrame, Ipv6Packet};
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const PREFIX: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    const DNS_SERVERS: [[u8; 16]; 2] = [
+        [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x53],
+        [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x54],
+    ];
+
+    fn config(dns_servers: &'static [[u8; 16]]) -> RouterAdvertisementConfig {
+        RouterAdvertisementConfig {
+            router_lifetime: 1800,
+            prefixes: &[PrefixConfig {
+                prefix: PREFIX,
+                prefix_len: 64,
+                valid_lifetime: 2_592_000,
+                preferred_lifetime: 604_800,
+            }],
+            dns_servers,
+            dns_lifetime: 900,
+        }
+    }
+
+    fn seconds(seconds: u64) -> Instant {
+        Instant::ZERO + Duration::from_secs(seconds)
+    }
+
+    /// Polls the advertiser, returns the advertisement if one is due
+    fn poll(advertiser: &mut Advertiser, now: Instant) -> Option<Vec<u8>> {
+        let mut out = vec![0; MAX_FRAME_SIZE];
+        let len = advertiser.poll(now, ipv6::link_local_address(MAC), &mut out)?;
+        out.truncate(len);
+        Some(out)
+    }
+
+    #[test]
+    fn writes_header_and_options() {
+        let mut advertiser = Advertiser::new(config(&DNS_SERVERS), MAC, 1500);
+        let frame = poll(&mut advertiser, Instant::ZERO).unwrap();
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        assert_eq!((frame.destination(), frame.source()), (ipv6::multicast_mac(ipv6::ALL_NODES), MAC));
+        let packet = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(packet.source(), ipv6::link_local_address(MAC));
+        assert_eq!((packet.destination(), packet.hop_limit()), (ipv6::ALL_NODES, ndp::HOP_LIMIT));
+        assert!(icmpv6::verify_checksum(&packet));
+        let message = IcmpMessage::new_checked(packet.payload()).unwrap();
+        assert_eq!((message.message_type(), message.code()), (TYPE_ROUTER_ADVERTISEMENT, 0));
+        assert_eq!(message.rest_of_header(), [CUR_HOP_LIMIT, 0, 0x07, 0x08]);
+
+        let data = message.data();
+        assert_eq!(data[..BODY_SIZE], [0; BODY_SIZE]);
+        let options = &data[BODY_SIZE..];
+        assert_eq!(options[..8], [ndp::OPTION_SOURCE_LINK_LAYER_ADDRESS, 1, 0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        assert_eq!(options[8..16], [OPTION_MTU, 1, 0, 0, 0, 0, 0x05, 0xdc]);
+        let prefix = &options[16..48];
+        assert_eq!(prefix[..4], [OPTION_PREFIX_INFORMATION, 4, 64, 0xc0]);
+        assert_eq!(prefix[4..8], 2_592_000u32.to_be_bytes());
+        assert_eq!(prefix[8..12], 604_800u32.to_be_bytes());
+        assert_eq!(prefix[12..16], [0; 4]);
+        assert_eq!(prefix[16..], PREFIX);
+        let rdnss = &options[48..];
+        assert_eq!(rdnss.len(), 40);
+        assert_eq!(rdnss[..4], [OPTION_RDNSS, 5, 0, 0]);
+        assert_eq!(rdnss[4..8], 900u32.to_be_bytes());
+        assert_eq!(rdnss[8..24], DNS_SERVERS[0]);
+        assert_eq!(rdnss[24..], DNS_SERVERS[1]);
+    }
+
+    #[test]
+    fn omits_rdnss_option_without_dns_servers() {
+        let mut advertiser = Advertiser::new(config(&[]), MAC, 1280);
+        let frame = poll(&mut advertiser, Instant::ZERO).unwrap();
+        let packet = Ipv6Packet::new_checked(&frame[ETHERNET_HEADER_SIZE..]).unwrap();
+        assert!(icmpv6::verify_checksum(&packet));
+        let options = &IcmpMessage::new_checked(packet.payload()).unwrap().data()[BODY_SIZE..];
+        assert_eq!(options.len(), 16 + 32);
+        assert_eq!(options[8..16], [OPTION_MTU, 1, 0, 0, 0, 0, 0x05, 0x00]);
+        assert_eq!(options[16], OPTION_PREFIX_INFORMATION);
+    }
+
+    #[test]
+    fn sends_first_advertisements_at_short_intervals() {
+        let mut advertiser = Advertiser::new(config(&DNS_SERVERS), MAC, 1500);
+        let mut now = Instant::ZERO;
+        for i in 0..MAX_INITIAL_ADVERTISEMENTS + 2 {
+            assert!(poll(&mut advertiser, now).is_some());
+            let next = advertiser.next;
+            if i < MAX_INITIAL_ADVERTISEMENTS {
+                assert_eq!(next - now, config::RA_INITIAL_INTERVAL);
+            } else {
+                assert!(next - now >= config::RA_MIN_INTERVAL && next - now < config::RA_MAX_INTERVAL);
+            }
+            assert!(poll(&mut advertiser, now + Duration::from_secs(1)).is_none());
+            now = next;
+        }
+    }
+
+    #[test]
+    fn answers_solicitations_at_most_every_min_delay() {
+        let mut advertiser = Advertiser::new(config(&DNS_SERVERS), MAC, 1500);
+        assert!(poll(&mut advertiser, Instant::ZERO).is_some());
+        let mut sent = Vec::new();
+        for second in 1..=12 {
+            advertiser.solicit(seconds(second));
+            if poll(&mut advertiser, seconds(second)).is_some() {
+                sent.push(second);
+            }
+        }
+        assert_eq!(sent, [3, 6, 9, 12]);
+    }
+
+    #[test]
+    fn solicitation_never_postpones_an_advertisement() {
+        let mut advertiser = Advertiser::new(config(&DNS_SERVERS), MAC, 1500);
+        advertiser.solicit(seconds(10));
+        assert!(poll(&mut advertiser, Instant::ZERO).is_some());
+        // Solicited after the advertisement at 16 seconds was due, but before it was polled for
+        advertiser.solicit(seconds(20));
+        assert!(poll(&mut advertiser, seconds(16)).is_some());
+    }
+}
@@ -0,0 +1,220 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]
+pub enum RouteTarget {
+    /// Forward out of the port with this index in `config::PORTS`
+    Port(usize),
+    /// Drop and answer with Destination Unreachable
+    Unreachable,
+    /// Drop and answer with Communication Administratively Prohibited
+    Prohibited,
+}
+
+#[derive(Debug)]
+pub struct Ipv4Route {
+    pub prefix: [u8; 4],
+    pub prefix_len: u8,
+    pub target: RouteTarget,
+    /// Router on the egress link that packets are sent to, `None` if the prefix is on-link
+    pub gateway: Option<[u8; 4]>,
+}
+
+impl Ipv4Route {
+    pub fn matches(&self, destination: [u8; 4]) -> bool {
+        let mask = if self.prefix_len == 0 {
+            0
+        } else {
+            u32::max_value() << (32 - self.prefix_len as u32)
+        };
+        u32::from_be_bytes(destination) & mask == u32::from_be_bytes(self.prefix) & mask
+    }
+
+    /// The neighbor to resolve for a packet to `destination`
+    pub fn next_hop(&self, destination: [u8; 4]) -> [u8; 4] {
+        self.gateway.unwrap_or(destination)
+    }
+}
+
+/// Returns the longest prefix match for `destination`
+pub fn lookup_ipv4(routes: &[Ipv4Route], destination: [u8; 4]) -> Option<&Ipv4Route> {
+    let mut best: Option<&Ipv4Route> = None;
+    for route in routes {
+        if route.matches(destination) && best.map_or(true, |best| route.prefix_len > best.prefix_len) {
+            best = Some(route);
+        }
+    }
+    best
+}
+
+#[derive(Debug)]
+pub struct Ipv6Route {
+    pub prefix: [u8; 16],
+    pub prefix_len: u8,
+    pub target: RouteTarget,
+    /// Router on the egress link that packets are sent to, `None` if the prefix is on-link
+    pub gateway: Option<[u8; 16]>,
+}
+
+impl Ipv6Route {
+    pub fn matches(&self, destination: [u8; 16]) -> bool {
+        let mask = if self.prefix_len == 0 {
+            0
+        } else {
+            u128::max_value() << (128 - self.prefix_len as u32)
+        };
+        u128::from_be_bytes(destination) & mask == u128::from_be_bytes(self.prefix) & mask
+    }
+
+    /// The neighbor to resolve for a packet to `destination`, see RFC 4861 section 5.2
+    pub fn next_hop(&self, destination: [u8; 16]) -> [u8; 16] {
+        self.gateway.unwrap_or(destination)
+    }
+}
+
+/// Returns the longest prefix match for `destination`
+pub fn lookup_ipv6(routes: &[Ipv6Route], destination: [u8; 16]) -> Option<&Ipv6Route> {
+    let mut best: Option<&Ipv6Route> = None;
+    for route in routes {
+        if route.matches(destination) && best.map_or(true, |best| route.prefix_len > best.prefix_len) {
+            best = Some(route);
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_route(prefix: [u8; 4], prefix_len: u8, target: RouteTarget) -> Ipv4Route {
+        Ipv4Route {
+            prefix,
+            prefix_len,
+            target,
+            gateway: None,
+        }
+    }
+
+    fn ipv4_target(routes: &[Ipv4Route], destination: [u8; 4]) -> Option<RouteTarget> {
+        lookup_ipv4(routes, destination).map(|route| route.target)
+    }
+
+    #[test]
+    fn prefers_longest_ipv4_prefix_in_any_order() {
+        let routes = [
+            ipv4_route([10, 0, 1, 0], 24, RouteTarget::Port(2)),
+            ipv4_route([10, 0, 0, 0], 8, RouteTarget::Port(1)),
+            ipv4_route([10, 0, 1, 7], 32, RouteTarget::Unreachable),
+            ipv4_route([10, 0, 0, 0], 16, RouteTarget::Prohibited),
+        ];
+        assert_eq!(ipv4_target(&routes, [10, 0, 1, 5]), Some(RouteTarget::Port(2)));
+        assert_eq!(ipv4_target(&routes, [10, 0, 1, 7]), Some(RouteTarget::Unreachable));
+        assert_eq!(ipv4_target(&routes, [10, 0, 2, 1]), Some(RouteTarget::Prohibited));
+        assert_eq!(ipv4_target(&routes, [10, 1, 0, 1]), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv4_target(&routes, [11, 0, 1, 5]), None);
+    }
+
+    #[test]
+    fn first_of_equally_long_ipv4_prefixes_wins() {
+        let routes = [
+            ipv4_route([192, 168, 0, 0], 16, RouteTarget::Port(0)),
+            ipv4_route([192, 168, 0, 0], 16, RouteTarget::Port(1)),
+            // The host bits of a prefix are ignored
+            ipv4_route([192, 168, 9, 9], 16, RouteTarget::Port(2)),
+        ];
+        assert_eq!(ipv4_target(&routes, [192, 168, 3, 4]), Some(RouteTarget::Port(0)));
+    }
+
+    #[test]
+    fn ipv4_default_route_matches_what_nothing_else_does() {
+        let routes = [
+            ipv4_route([0, 0, 0, 0], 0, RouteTarget::Port(1)),
+            ipv4_route([10, 0, 0, 0], 24, RouteTarget::Port(0)),
+        ];
+        assert_eq!(ipv4_target(&routes, [10, 0, 0, 9]), Some(RouteTarget::Port(0)));
+        assert_eq!(ipv4_target(&routes, [10, 0, 1, 9]), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv4_target(&routes, [255, 255, 255, 255]), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv4_target(&routes, [0, 0, 0, 0]), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv4_target(&[], [10, 0, 0, 9]), None);
+    }
+
+    fn ipv6_route(prefix: [u8; 16], prefix_len: u8, target: RouteTarget) -> Ipv6Route {
+        Ipv6Route {
+            prefix,
+            prefix_len,
+            target,
+            gateway: None,
+        }
+    }
+
+    fn ipv6_target(routes: &[Ipv6Route], destination: [u8; 16]) -> Option<RouteTarget> {
+        lookup_ipv6(routes, destination).map(|route| route.target)
+    }
+
+    /// fd00:<a>::<b>
+    fn address(a: u8, b: u8) -> [u8; 16] {
+        [0xfd, 0, 0, a, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, b]
+    }
+
+    #[test]
+    fn prefers_longest_ipv6_prefix_in_any_order() {
+        let routes = [
+            ipv6_route(address(1, 0), 64, RouteTarget::Port(2)),
+            ipv6_route(address(0, 0), 8, RouteTarget::Port(1)),
+            ipv6_route(address(1, 7), 128, RouteTarget::Unreachable),
+            ipv6_route(address(0, 0), 16, RouteTarget::Prohibited),
+        ];
+        assert_eq!(ipv6_target(&routes, address(1, 5)), Some(RouteTarget::Port(2)));
+        assert_eq!(ipv6_target(&routes, address(1, 7)), Some(RouteTarget::Unreachable));
+        assert_eq!(ipv6_target(&routes, address(2, 1)), Some(RouteTarget::Prohibited));
+        let mut other_site = address(1, 5);
+        other_site[1] = 1;
+        assert_eq!(ipv6_target(&routes, other_site), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv6_target(&routes, [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]), None);
+    }
+
+    #[test]
+    fn matches_ipv6_prefixes_not_ending_on_a_byte() {
+        let routes = [
+            ipv6_route([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 10, RouteTarget::Port(0)),
+            ipv6_route(address(0, 0x80), 121, RouteTarget::Port(1)),
+        ];
+        let mut link_local = [0xfe, 0xbf, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!(ipv6_target(&routes, link_local), Some(RouteTarget::Port(0)));
+        link_local[1] = 0xc0;
+        assert_eq!(ipv6_target(&routes, link_local), None);
+        assert_eq!(ipv6_target(&routes, address(0, 0xff)), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv6_target(&routes, address(0, 0x7f)), None);
+    }
+
+    #[test]
+    fn ipv6_default_route_matches_what_nothing_else_does() {
+        let routes = [
+            ipv6_route([0; 16], 0, RouteTarget::Port(1)),
+            ipv6_route(address(0, 0), 64, RouteTarget::Port(0)),
+            ipv6_route(address(0, 0), 64, RouteTarget::Unreachable),
+        ];
+        assert_eq!(ipv6_target(&routes, address(0, 9)), Some(RouteTarget::Port(0)));
+        assert_eq!(ipv6_target(&routes, address(1, 9)), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv6_target(&routes, [0xff; 16]), Some(RouteTarget::Port(1)));
+        assert_eq!(ipv6_target(&[], address(0, 9)), None);
+    }
+
+    #[test]
+    fn next_hop_is_the_gateway_if_there_is_one() {
+        let on_link = ipv4_route([10, 0, 1, 0], 24, RouteTarget::Port(1));
+        assert_eq!(on_link.next_hop([10, 0, 1, 5]), [10, 0, 1, 5]);
+        let default = Ipv4Route {
+            gateway: Some([10, 0, 1, 254]),
+            ..ipv4_route([0; 4], 0, RouteTarget::Port(1))
+        };
+        assert_eq!(default.next_hop([192, 0, 2, 5]), [10, 0, 1, 254]);
+
+        let on_link = ipv6_route(address(1, 0), 64, RouteTarget::Port(1));
+        assert_eq!(on_link.next_hop(address(1, 5)), address(1, 5));
+        let default = Ipv6Route {
+            gateway: Some(address(1, 0xfe)),
+            ..ipv6_route([0; 16], 0, RouteTarget::Port(1))
+        };
+        assert_eq!(default.next_hop(address(2, 5)), address(1, 0xfe));
+    }
+}
@@ -0,0 +1,37 @@
+use crate::config;
+use crate::psci;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+extern "C" {
+    /// Sets up the core's stacks and calls `secondary_main` with its index, see start.s
+    fn secondary_entry();
+}
+
+/// Number of cores that were started, including the first core
+static CORE_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Starts the cores `1..config::MAX_CORES` and returns the number of cores, including the calling one
+///
+/// Cores are started in order until the firmware refuses one, e.g. because QEMU has fewer. They
+/// enter `secondary_main` with their index, which is also their MPIDR_EL1. A core that is already
+/// on waits in start.s and would never poll the queue pairs assigned to it, see
+/// `VirtioMMIONetworkDevice::owns_queue_pair`, so it also ends the count. This keeps the indices of
+/// the counted cores contiguous.
+pub fn start_secondary_cores() -> usize {
+    let mut count = 1;
+    for core in 1..config::MAX_CORES {
+        match psci::cpu_on(core as u64, secondary_entry as unsafe extern "C" fn() as usize as u64, core as u64) {
+            Ok(()) => count += 1,
+            // Both a refused and an already running core end the count, see above
+            Err(_) => break,
+        }
+    }
+    CORE_COUNT.store(count, Ordering::Relaxed);
+    count
+}
+
+/// Number of running cores, 1 until `start_secondary_cores` was called
+pub fn core_count() -> usize {
+    CORE_COUNT.load(Ordering::Relaxed)
+}
+
@@ -1,15 +1,173 @@
 .globl _start
-.extern LD_STACK_PTR
+.globl secondary_entry
+.extern LD_STACKS
+.extern LD_STACK_STRIDE
+.extern LD_EXCEPTION_STACK_SIZE
+.extern LD_BSS_START
+.extern LD_BSS_END
 
 .section ".text.boot"
 
+// Points SP_EL1 at the exception stack and SP_EL0 at the stack of the core with index x0
+// Exceptions are taken on SP_EL1, everything else runs on SP_EL0, see aarch64-qemu.ld
+.macro setup_stacks
+    ldr     x1, =LD_STACKS
+    ldr     x2, =LD_STACK_STRIDE
+    madd    x1, x0, x2, x1
+    add     x1, x1, x2
+    mov     sp, x1
+    msr     spsel, #0
+    ldr     x2, =LD_EXCEPTION_STACK_SIZE
+    sub     x1, x1, x2
+    mov     sp, x1
+.endm
+
 _start:
-    ldr     x30, =LD_STACK_PTR
-    mov     sp, x30
+    // Only the first core boots, the others are started by smp.rs, see the Arm ARM section
+    // D13.2.86 for MPIDR_EL1
+    mrs     x0, mpidr_el1
+    and     x0, x0, #0xff
+    cbnz    x0, park
+    setup_stacks
+
+    // Zero .bss, both ends are 16 byte aligned
+    ldr     x0, =LD_BSS_START
+    ldr     x1, =LD_BSS_END
+1:
+    cmp     x0, x1
+    b.hs    2f
+    stp     xzr, xzr, [x0], #16
+    b       1b
+2:
+    ldr     x0, =exception_vectors
+    msr     vbar_el1, x0
+    isb
+    mov     x29, xzr
+    mov     x30, xzr
     bl      main
+    b       park
+
+// Entry point of the cores started with PSCI CPU_ON, x0 holds the core's index
+secondary_entry:
+    mov     x19, x0
+    setup_stacks
+    ldr     x0, =exception_vectors
+    msr     vbar_el1, x0
+    isb
+    mov     x0, x19
+    mov     x29, xzr
+    mov     x30, xzr
+    bl      secondary_main
+    b       park
+
+// Cores started by the firmware instead of smp.rs wait here forever, as do cores returning from Rust
+park:
+    wfe
+    b       park
+
+// Saves the registers a called function may clobber, see the AArch64 procedure call standard
+.macro save_caller_saved
+    sub     sp, sp, #192
+    stp     x0, x1, [sp, #0]
+    stp     x2, x3, [sp, #16]
+    stp     x4, x5, [sp, #32]
+    stp     x6, x7, [sp, #48]
+    stp     x8, x9, [sp, #64]
+    stp     x10, x11, [sp, #80]
+    stp     x12, x13, [sp, #96]
+    stp     x14, x15, [sp, #112]
+    stp     x16, x17, [sp, #128]
+    stp     x18, x29, [sp, #144]
+    mrs     x0, elr_el1
+    mrs     x1, spsr_el1
+    stp     x30, x0, [sp, #160]
+    str     x1, [sp, #176]
+.endm
+
+.macro restore_caller_saved
+    ldr     x1, [sp, #176]
+    ldp     x30, x0, [sp, #160]
+    msr     spsr_el1, x1
+    msr     elr_el1, x0
+    ldp     x18, x29, [sp, #144]
+    ldp     x16, x17, [sp, #128]
+    ldp     x14, x15, [sp, #112]
+    ldp     x12, x13, [sp, #96]
+    ldp     x10, x11, [sp, #80]
+    ldp     x8, x9, [sp, #64]
+    ldp     x6, x7, [sp, #48]
+    ldp     x4, x5, [sp, #32]
+    ldp     x2, x3, [sp, #16]
+    ldp     x0, x1, [sp, #0]
+    add     sp, sp, #192
+.endm
+
+// Allocates the exception frame, saves x0 and x1 and passes the vector's index to exception_entry
+.macro exception_vector index
+.balign 0x80
+    sub     sp, sp, #288
+    stp     x0, x1, [sp, #0]
+    mov     x0, #\index
+    b       exception_entry
+.endm
+
+// Vector table, see the Arm ARM section D1.10.2
+// Four groups of four 0x80 byte entries: synchronous, IRQ, FIQ and SError
+.balign 0x800
+.globl exception_vectors
+exception_vectors:
+// Current EL with SP_EL0
+    exception_vector 0
+.balign 0x80
+    b       irq_entry
+    exception_vector 2
+    exception_vector 3
+// Current EL with SP_ELx
+    exception_vector 4
+    exception_vector 5
+    exception_vector 6
+    exception_vector 7
+// Lower EL using AArch64
+    exception_vector 8
+    exception_vector 9
+    exception_vector 10
+    exception_vector 11
+// Lower EL using AArch32
+    exception_vector 12
+    exception_vector 13
+    exception_vector 14
+    exception_vector 15
+
+irq_entry:
+    save_caller_saved
+    bl      handle_irq
+    restore_caller_saved
+    eret
 
-.equ PSCI_SYSTEM_OFF, 0x84000008
-.globl system_off
-system_off:
-    ldr     x0, =PSCI_SYSTEM_OFF
-    hvc     #0
\ No newline at end of file
+// Completes the exception frame, see ExceptionFrame in exceptions.rs, and reports the exception
+exception_entry:
+    stp     x2, x3, [sp, #16]
+    stp     x4, x5, [sp, #32]
+    stp     x6, x7, [sp, #48]
+    stp     x8, x9, [sp, #64]
+    stp     x10, x11, [sp, #80]
+    stp     x12, x13, [sp, #96]
+    stp     x14, x15, [sp, #112]
+    stp     x16, x17, [sp, #128]
+    stp     x18, x19, [sp, #144]
+    stp     x20, x21, [sp, #160]
+    stp     x22, x23, [sp, #176]
+    stp     x24, x25, [sp, #192]
+    stp     x26, x27, [sp, #208]
+    stp     x28, x29, [sp, #224]
+    str     x30, [sp, #240]
+    mrs     x1, elr_el1
+    mrs     x2, spsr_el1
+    stp     x1, x2, [sp, #248]
+    mrs     x1, esr_el1
+    mrs     x2, far_el1
+    stp     x1, x2, [sp, #264]
+    str     x0, [sp, #280]
+    mov     x0, sp
+    bl      handle_exception
+    b       park
@@ -0,0 +1,420 @@
+use crate::config;
+use crate::errors::{InterruptRegistrationError, TimerError};
+use crate::gic;
+use core::ops::{Add, Sub};
+use core::time::Duration;
+
+/// Enables the timer, see the Arm ARM section D13.8.16
+#[cfg(not(test))]
+const CNTP_CTL_ENABLE: u64 = 1;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+/// Slots of a timer wheel, timers further ahead than one revolution wait for the next
+const WHEEL_SLOTS: usize = 64;
+/// Timers beyond this number per wheel are rejected
+const MAX_TIMERS: usize = 16;
+/// Time covered by one slot of a timer wheel
+const WHEEL_RESOLUTION: Duration = Duration::from_millis(1);
+
+/// Counter frequency in Hz, set by the firmware
+#[cfg(not(test))]
+pub fn frequency() -> u64 {
+    let frequency: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack)) };
+    frequency
+}
+
+/// Host tests count nanoseconds
+#[cfg(test)]
+pub fn frequency() -> u64 {
+    NANOS_PER_SECOND as u64
+}
+
+#[cfg(not(test))]
+fn counter() -> u64 {
+    let counter: u64;
+    // The isb keeps the counter from being read ahead of preceding instructions
+    unsafe { asm!("isb", "mrs {}, cntpct_el0", out(reg) counter, options(nomem, nostack)) };
+    counter
+}
+
+/// Host tests pass their instants explicitly, the counter stands still
+#[cfg(test)]
+#[allow(dead_code)]
+fn counter() -> u64 {
+    0
+}
+
+fn to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / NANOS_PER_SECOND) as u64
+}
+
+fn from_ticks(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SECOND / frequency() as u128;
+    Duration::new((nanos / NANOS_PER_SECOND) as u64, (nanos % NANOS_PER_SECOND) as u32)
+}
+
+/// A point in time of the monotonic system counter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    /// The counter's reset value, no instant is earlier
+    pub const ZERO: Instant = Instant { ticks: 0 };
+
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn now() -> Instant {
+        Instant { ticks: counter() }
+    }
+
+    /// The time elapsed since `earlier`, zero if `earlier` is later than `self`
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        from_ticks(self.ticks.saturating_sub(earlier.ticks))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant {
+            ticks: self.ticks + to_ticks(duration),
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Registers the interrupt of the EL1 physical timer, IRQs must be masked
+#[cfg_attr(test, allow(dead_code))]
+pub fn initialize() -> Result<(), InterruptRegistrationError> {
+    cancel_alarm();
+    gic::register(config::TIMER_INTERRUPT, handle_alarm, 0)
+}
+
+/// Enables the timer interrupt of another core, every core has a timer of its own
+#[cfg_attr(test, allow(dead_code))]
+pub fn initialize_core(core: usize) {
+    cancel_alarm();
+    gic::enable(config::TIMER_INTERRUPT, core);
+}
+
+/// Raises the timer interrupt at `deadline`, right away if it passed
+///
+/// The interrupt wakes the core from `wfi`, the timer has a single alarm.
+#[cfg(not(test))]
+pub fn set_alarm(deadline: Instant) {
+    unsafe {
+        asm!(
+            "msr cntp_cval_el0, {}",
+            "msr cntp_ctl_el0, {}",
+            "isb",
+            in(reg) deadline.ticks,
+            in(reg) CNTP_CTL_ENABLE,
+            options(nomem, nostack),
+        )
+    };
+}
+
+#[cfg(not(test))]
+pub fn cancel_alarm() {
+    unsafe { asm!("msr cntp_ctl_el0, xzr", "isb", options(nomem, nostack)) };
+}
+
+// Host tests have no timer interrupt
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub fn set_alarm(_deadline: Instant) {}
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub fn cancel_alarm() {}
+
+/// Disables the timer, its interrupt stays asserted while the deadline passed and it is enabled
+#[cfg_attr(test, allow(dead_code))]
+fn handle_alarm(_context: usize) {
+    cancel_alarm();
+}
+
+/// Identifies a scheduled timer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimerId(usize);
+
+struct Timer<C> {
+    deadline: Instant,
+    /// Interval of periodic timers
+    period: Option<Duration>,
+    callback: fn(&mut C, Instant),
+    /// The slot the timer is linked into
+    slot: usize,
+    /// Next timer of the same slot
+    next: Option<usize>,
+}
+
+/// A hashed timer wheel calling `callback(context, now)` of timers that are due
+///
+/// Timers are sorted into slots of `WHEEL_RESOLUTION` by their deadline, so `tick` only looks at
+/// the slots that passed since the last tick.
+pub struct TimerWheel<C> {
+    timers: [Option<Timer<C>>; MAX_TIMERS],
+    /// First timer of every slot
+    slots: [Option<usize>; WHEEL_SLOTS],
+    start: Instant,
+    /// The next slot `tick` looks at, counted from `start`
+    next_slot: u64,
+}
+
+impl<C> TimerWheel<C> {
+    pub fn new(start: Instant) -> TimerWheel<C> {
+        TimerWheel {
+            timers: Default::default(),
+            slots: [None; WHEEL_SLOTS],
+            start,
+            next_slot: 0,
+        }
+    }
+
+    /// Calls `callback` once at `deadline`
+    #[allow(dead_code)]
+    pub fn schedule(&mut self, deadline: Instant, callback: fn(&mut C, Instant)) -> Result<TimerId, TimerError> {
+        self.insert(deadline, None, callback)
+    }
+
+    /// Calls `callback` every `period`, starting one period after `now`
+    pub fn schedule_periodic(
+        &mut self,
+        now: Instant,
+        period: Duration,
+        callback: fn(&mut C, Instant),
+    ) -> Result<TimerId, TimerError> {
+        if period == Duration::from_secs(0) {
+            return Err(TimerError::InvalidPeriod);
+        }
+        self.insert(now + period, Some(period), callback)
+    }
+
+    /// Removes a timer before it fires
+    #[allow(dead_code)]
+    pub fn cancel(&mut self, id: TimerId) -> Result<(), TimerError> {
+        let slot = match &self.timers[id.0] {
+            Some(timer) => timer.slot,
+            None => return Err(TimerError::UnknownTimer),
+        };
+        let mut link = self.slots[slot];
+        let mut previous: Option<usize> = None;
+        while let Some(index) = link {
+            let next = self.timers[index].as_ref().unwrap().next;
+            if index == id.0 {
+                match previous {
+                    Some(previous) => self.timers[previous].as_mut().unwrap().next = next,
+                    None => self.slots[slot] = next,
+                }
+                break;
+            }
+            previous = link;
+            link = next;
+        }
+        self.timers[id.0] = None;
+        Ok(())
+    }
+
+    /// The earliest deadline of all timers, for setting the alarm before waiting for interrupts
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().flatten().map(|timer| timer.deadline).min()
+    }
+
+    /// Calls the timers that are due at `now`, periodic timers are rescheduled
+    pub fn tick(&mut self, now: Instant, context: &mut C) {
+        let last_slot = self.slot_number(now);
+        if last_slot < self.next_slot {
+            return;
+        }
+        // After a full revolution every slot has been looked at
+        let slots = core::cmp::min(last_slot - self.next_slot + 1, WHEEL_SLOTS as u64);
+        for slot_number in self.next_slot..self.next_slot + slots {
+            let slot = (slot_number % WHEEL_SLOTS as u64) as usize;
+            // Timers that are not due yet are linked into the slot again
+            let mut link = self.slots[slot].take();
+            while let Some(index) = link {
+                let timer = self.timers[index].as_ref().unwrap();
+                let (deadline, period, callback) = (timer.deadline, timer.period, timer.callback);
+                link = timer.next;
+                if deadline > now {
+                    self.link(index, deadline);
+                    continue;
+                }
+                match period {
+                    Some(period) => {
+                        // Periods missed while the callbacks ran late are skipped
+                        let mut next_deadline = deadline + period;
+                        if next_deadline <= now {
+                            next_deadline = now + period;
+                        }
+                        self.timers[index].as_mut().unwrap().deadline = next_deadline;
+                        self.link(index, next_deadline);
+                    }
+                    None => self.timers[index] = None,
+                }
+                callback(context, now);
+            }
+        }
+        // The current slot may still hold timers due later in it
+        self.next_slot = last_slot;
+    }
+
+    fn insert(
+        &mut self,
+        deadline: Instant,
+        period: Option<Duration>,
+        callback: fn(&mut C, Instant),
+    ) -> Result<TimerId, TimerError> {
+        let index = self
+            .timers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(TimerError::TooManyTimers)?;
+        self.timers[index] = Some(Timer {
+            deadline,
+            period,
+            callback,
+            slot: 0,
+            next: None,
+        });
+        self.link(index, deadline);
+        Ok(TimerId(index))
+    }
+
+    fn link(&mut self, index: usize, deadline: Instant) {
+        let slot = self.slot(deadline);
+        let timer = self.timers[index].as_mut().unwrap();
+        timer.slot = slot;
+        timer.next = self.slots[slot];
+        self.slots[slot] = Some(index);
+    }
+
+    /// Slots passed between the wheel's start and `instant`
+    fn slot_number(&self, instant: Instant) -> u64 {
+        ((instant - self.start).as_nanos() / WHEEL_RESOLUTION.as_nanos()) as u64
+    }
+
+    fn slot(&self, deadline: Instant) -> usize {
+        // Timers that are overdue go to the next slot looked at
+        let slot_number = core::cmp::max(self.slot_number(deadline), self.next_slot);
+        (slot_number % WHEEL_SLOTS as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> Instant {
+        Instant::ZERO + Duration::from_millis(millis)
+    }
+
+    fn first(fired: &mut Vec<(usize, Instant)>, now: Instant) {
+        fired.push((1, now));
+    }
+
+    fn second(fired: &mut Vec<(usize, Instant)>, now: Instant) {
+        fired.push((2, now));
+    }
+
+    fn third(fired: &mut Vec<(usize, Instant)>, now: Instant) {
+        fired.push((3, now));
+    }
+
+    #[test]
+    fn fires_timers_beyond_one_revolution_when_due() {
+        let mut wheel = TimerWheel::new(Instant::ZERO);
+        let mut fired = Vec::new();
+        let deadline = at(WHEEL_SLOTS as u64 * 2 + 5);
+        wheel.schedule(deadline, first).unwrap();
+        // The timer shares its slot with the earlier revolutions
+        for millis in 0..WHEEL_SLOTS as u64 * 2 + 5 {
+            wheel.tick(at(millis), &mut fired);
+        }
+        assert!(fired.is_empty());
+        assert_eq!(wheel.next_deadline(), Some(deadline));
+        wheel.tick(deadline, &mut fired);
+        assert_eq!(fired, [(1, deadline)]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn catches_up_after_stall() {
+        let mut wheel = TimerWheel::new(Instant::ZERO);
+        let mut fired = Vec::new();
+        wheel.schedule(at(3), first).unwrap();
+        wheel.schedule(at(WHEEL_SLOTS as u64 - 1), second).unwrap();
+        wheel.schedule(at(1000), third).unwrap();
+        // A single tick several revolutions later looks at every slot once
+        let now = at(WHEEL_SLOTS as u64 * 5);
+        wheel.tick(now, &mut fired);
+        fired.sort();
+        assert_eq!(fired, [(1, now), (2, now)]);
+        assert_eq!(wheel.next_deadline(), Some(at(1000)));
+        wheel.tick(at(999), &mut fired);
+        assert_eq!(fired.len(), 2);
+        wheel.tick(at(1000), &mut fired);
+        assert_eq!(fired[2], (3, at(1000)));
+    }
+
+    #[test]
+    fn cancels_timer_in_middle_of_slot() {
+        let mut wheel = TimerWheel::new(Instant::ZERO);
+        let mut fired = Vec::new();
+        // All three deadlines fall into the same slot
+        let deadline = at(10);
+        wheel.schedule(deadline, first).unwrap();
+        let middle = wheel.schedule(deadline + Duration::from_micros(100), second).unwrap();
+        wheel.schedule(deadline + Duration::from_micros(200), third).unwrap();
+        wheel.cancel(middle).unwrap();
+        assert!(matches!(wheel.cancel(middle), Err(TimerError::UnknownTimer)));
+        wheel.tick(at(11), &mut fired);
+        fired.sort();
+        assert_eq!(fired, [(1, at(11)), (3, at(11))]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn periodic_timers_skip_missed_periods() {
+        let mut wheel = TimerWheel::new(Instant::ZERO);
+        let mut fired = Vec::new();
+        let period = Duration::from_millis(10);
+        wheel.schedule_periodic(Instant::ZERO, period, first).unwrap();
+        // Ticks that are a little late keep the timer's phase
+        wheel.tick(at(12), &mut fired);
+        assert_eq!(wheel.next_deadline(), Some(at(20)));
+        // After missing several periods the timer fires once and restarts from now
+        wheel.tick(at(55), &mut fired);
+        assert_eq!(fired, [(1, at(12)), (1, at(55))]);
+        assert_eq!(wheel.next_deadline(), Some(at(65)));
+        wheel.tick(at(64), &mut fired);
+        assert_eq!(fired.len(), 2);
+        wheel.tick(at(65), &mut fired);
+        assert_eq!(fired.len(), 3);
+        assert!(matches!(
+            wheel.schedule_periodic(Instant::ZERO, Duration::from_secs(0), first),
+            Err(TimerError::InvalidPeriod)
+        ));
+    }
+
+    #[test]
+    fn rejects_timers_beyond_capacity() {
+        let mut wheel = TimerWheel::new(Instant::ZERO);
+        let ids: Vec<TimerId> = (0..MAX_TIMERS).map(|_| wheel.schedule(at(1), first).unwrap()).collect();
+        assert!(matches!(wheel.schedule(at(1), first), Err(TimerError::TooManyTimers)));
+        wheel.cancel(ids[3]).unwrap();
+        assert_eq!(wheel.schedule(at(1), second).unwrap(), ids[3]);
+    }
+}
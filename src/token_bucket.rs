@@ -0,0 +1,86 @@
+use crate::timer::Instant;
+use core::time::Duration;
+
+/// A token bucket holding up to `capacity` tokens, refilled by one token every `interval`
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: u32,
+    tokens: u32,
+    interval: Duration,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub const fn new(capacity: u32, interval: Duration) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            interval,
+            last_refill: Instant::ZERO,
+        }
+    }
+
+    /// Takes a token if one is available at time `now`
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let refills = (now - self.last_refill).as_nanos() / self.interval.as_nanos();
+        if refills >= self.capacity as u128 {
+            self.tokens = self.capacity;
+            self.last_refill = now;
+        } else if refills > 0 {
+            self.tokens = core::cmp::min(self.tokens + refills as u32, self.capacity);
+            self.last_refill = self.last_refill + self.interval * refills as u32;
+        }
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> Instant {
+        Instant::ZERO + Duration::from_millis(millis)
+    }
+
+    /// Takes tokens at `now` until the bucket is empty, returns how many
+    fn drain(bucket: &mut TokenBucket, now: Instant) -> u32 {
+        let mut taken = 0;
+        while bucket.try_take(now) {
+            taken += 1;
+        }
+        taken
+    }
+
+    #[test]
+    fn starts_with_a_full_burst() {
+        let mut bucket = TokenBucket::new(5, Duration::from_secs(1));
+        assert_eq!(drain(&mut bucket, Instant::ZERO), 5);
+        assert!(!bucket.try_take(at(999)));
+    }
+
+    #[test]
+    fn refills_one_token_per_interval() {
+        let mut bucket = TokenBucket::new(5, Duration::from_secs(1));
+        drain(&mut bucket, Instant::ZERO);
+        assert_eq!(drain(&mut bucket, at(1000)), 1);
+        // Time left over from a partial interval is kept for the next token
+        assert_eq!(drain(&mut bucket, at(2500)), 1);
+        assert_eq!(drain(&mut bucket, at(3000)), 1);
+        assert_eq!(drain(&mut bucket, at(5999)), 2);
+    }
+
+    #[test]
+    fn refills_no_more_than_capacity() {
+        let mut bucket = TokenBucket::new(5, Duration::from_secs(1));
+        drain(&mut bucket, Instant::ZERO);
+        assert_eq!(drain(&mut bucket, at(3000)), 3);
+        assert_eq!(drain(&mut bucket, at(100_000)), 5);
+        assert_eq!(drain(&mut bucket, at(100_999)), 0);
+        assert_eq!(drain(&mut bucket, at(101_000)), 1);
+    }
+}
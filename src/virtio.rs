@@ -1,127 +1,813 @@
+use crate::barrier;
+use crate::cpu;
 use crate::errors::*;
 use crate::memory_handle::MemoryHandle;
+use crate::packet::{ETHERNET_HEADER_SIZE, VLAN_TAG_SIZE};
+use crate::smp;
+use crate::spinlock::{SpinLock, SpinLockGuard};
 use crate::virtio_device_register::DeviceStatus;
+use crate::virtio_device_register::FeatureBits1;
 use crate::virtio_device_register::NetworkDeviceFeatureBits0;
+use crate::virtio_control::ControlQueue;
 use crate::virtio_device_register::VirtioMMIORegister;
-use crate::virtqueue::VirtQueueHandle;
+use crate::virtqueue::{AnyVirtQueue, VirtQueue, VirtQueueHandle};
+use crate::virtqueue_network::{NET_HEADER_SIZE, NET_HEADER_SIZE_VERSION_1};
+use crate::virtqueue_packed::PackedVirtQueueHandle;
+use register::LocalRegisterCopy;
 
 const PAGE_SIZE: u32 = 2048;
 const MMIO_QUEUE_ALIGN: u32 = 4095;
 
+/// Ethernet header plus one 802.1Q tag
+const MAX_LINK_HEADER_SIZE: u32 = (ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE) as u32;
+/// Minimum receive buffer size without VIRTIO_NET_F_MRG_RXBUF, see section 5.1.6.3.1
+const MIN_BUFFER_SIZE: u32 = 1526;
+const CONTROL_QUEUE_SIZE: u32 = 64;
+/// Largest queue size of a split virtqueue, see section 2.4
+const MAX_QUEUE_SIZE: u32 = 32768;
+/// Status bit set by the device to request a guest announcement, see section 5.1.4
+const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
+/// Smallest MTU of an IPv4 link, see RFC 791
+const MIN_MTU: u16 = 68;
+/// Upper bound of the MTU, the largest jumbo frames
+const MAX_MTU: u16 = 9000;
+/// Upper bound of the queue pairs used per device
+pub const MAX_QUEUE_PAIRS: usize = 4;
+
+/// How the virtqueues are laid out and passed to the device
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum QueueLayout {
+    /// Split virtqueues passed by their page number, legacy devices only, see section 4.2.4
+    LegacySplit,
+    /// Split virtqueues passed by the addresses of their areas, see section 4.2.3.2
+    Split,
+    /// Packed virtqueues, if VIRTIO_F_RING_PACKED was negotiated, see section 2.7
+    Packed,
+}
+
+impl QueueLayout {
+    /// Size of the virtio-net header, which has num_buffers with VIRTIO_F_VERSION_1
+    fn header_size(self) -> usize {
+        match self {
+            QueueLayout::LegacySplit => NET_HEADER_SIZE,
+            QueueLayout::Split | QueueLayout::Packed => NET_HEADER_SIZE_VERSION_1,
+        }
+    }
+}
+
+/// A receive queue and the transmit queue following it, see section 5.1.2
+///
+/// Every core polls the receive queues of its own pairs, see `VirtioMMIONetworkDevice::owns_queue_pair`.
+#[derive(Debug)]
+pub struct QueuePair {
+    pub receiveq: AnyVirtQueue,
+    pub sendq: AnyVirtQueue,
+    register: VirtioMMIORegister,
+    /// Index of the receive queue
+    index: u32,
+    /// Number of device resets, elements taken before the last one are stale and must not be offered
+    pub resets: u32,
+}
+
+impl QueuePair {
+    /// Notifies the device of buffers offered to the receive queue, unless it suppressed notifications
+    pub fn notify_receive(&self) {
+        if self.receiveq.notification_suppressed() {
+            return;
+        }
+        barrier::io_write_barrier();
+        self.register.queue_notify().set(self.index);
+    }
+
+    /// Notifies the device of buffers offered to the transmit queue, unless it suppressed notifications
+    pub fn notify_send(&self) {
+        if self.sendq.notification_suppressed() {
+            return;
+        }
+        barrier::io_write_barrier();
+        self.register.queue_notify().set(self.index + 1);
+    }
+}
+
 pub struct VirtioMMIONetworkDevice {
     pub register: VirtioMMIORegister,
-    pub receiveq1: VirtQueueHandle,
-    pub sendq1: VirtQueueHandle,
+    /// The enabled queue pairs, `None` if there are fewer
+    queue_pairs: [Option<SpinLock<QueuePair>>; MAX_QUEUE_PAIRS],
+    /// Number of enabled queue pairs
+    pub queue_pair_count: usize,
+    /// Set if VIRTIO_NET_F_CTRL_VQ was negotiated
+    pub control: Option<SpinLock<ControlQueue>>,
+    /// The largest IP packet the device accepts, excluding the link header
+    pub mtu: u16,
+    pub mac: [u8; 6],
+    /// Set if VIRTIO_NET_F_GUEST_ANNOUNCE was negotiated
+    guest_announce: bool,
+    /// The negotiated feature bits 0 to 31, written again after a reset
+    guest_features: u32,
+    /// The negotiated feature bits 32 to 63, only modern devices have them
+    guest_features_high: u32,
+    layout: QueueLayout,
 }
 
 impl VirtioMMIONetworkDevice {
     /// Initialize the legacy device according to section 3.1.2 and 4.2.3.1.1
+    ///
+    /// `default_mtu` is used if the device does not offer VIRTIO_NET_F_MTU, and bounds the MTU it
+    /// offers. Up to `max_queue_pairs` queue pairs are enabled if the device offers VIRTIO_NET_F_MQ.
+    /// Their queues have `queue_size` descriptors, fewer if the device's QueueNumMax is lower. Modern
+    /// devices use packed virtqueues if they offer VIRTIO_F_RING_PACKED.
     pub fn initialize(
-        address: usize,
+        mut register: VirtioMMIORegister,
+        default_mtu: u16,
+        max_queue_pairs: u16,
+        queue_size: u16,
         memory: &mut MemoryHandle,
     ) -> Result<VirtioMMIONetworkDevice, DeviceInitializationError> {
-        let mut register = VirtioMMIORegister::new(address);
-        let _magic_value = register.magic_value.get();
+        let _magic_value = register.magic_value().get();
         // util::print(format_args!("magic_value = 0x{:x}\n", magic_value)).unwrap();
-        let _version = register.version.get();
+        let version = register.version().get();
         // util::print(format_args!("version = 0x{:x}\n", version)).unwrap();
+        let modern = match version {
+            1 => false,
+            2 => true,
+            _ => return Err(DeviceInitializationError::InvalidVersion(version)),
+        };
 
         // 1. Reset the device
-        register.device_status.set(0);
+        register.device_status().set(0);
         // 2. Set the ACKNOWLEDGE status bit
         register
-            .device_status
+            .device_status()
             .modify(DeviceStatus::ACKNOWLEDGE.val(1));
         // 3. Set the DRIVER status bit
-        register.device_status.modify(DeviceStatus::DRIVER.val(1));
+        register.device_status().modify(DeviceStatus::DRIVER.val(1));
 
         // 4. Read the device's feature bits and write the understood subset
-        register.host_features_sel.set(0);
-        let _host_features0 = register.host_features.get();
+        register.host_features_sel().set(0);
+        let host_features0 = register.host_features().extract();
         // util::print(format_args!("host_features0 = {:?}\n", host_features0)).unwrap();
-        register.guest_features_sel.set(0);
-        let guest_features = NetworkDeviceFeatureBits0::VIRTIO_NET_F_MAC.val(0);
-        register.guest_features.write(guest_features);
+        register.guest_features_sel().set(0);
+        let mac_offered = host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_MAC);
+        let mut guest_features = NetworkDeviceFeatureBits0::VIRTIO_NET_F_MAC.val(mac_offered as u32);
+        let mtu_offered = host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_MTU);
+        if mtu_offered {
+            guest_features += NetworkDeviceFeatureBits0::VIRTIO_NET_F_MTU.val(1);
+        }
+        let control_offered = host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_VQ);
+        if control_offered {
+            guest_features += NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_VQ.val(1);
+            // The control queue commands depend on VIRTIO_NET_F_CTRL_VQ, see section 5.1.3.1
+            for feature in &[
+                NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_RX,
+                NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_VLAN,
+                NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_MAC_ADDR,
+            ] {
+                if host_features0.is_set(*feature) {
+                    guest_features += feature.val(1);
+                }
+            }
+            if host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_RX)
+                && host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_RX_EXTRA)
+            {
+                guest_features += NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_RX_EXTRA.val(1);
+            }
+        }
+        // The status field is only valid if VIRTIO_NET_F_STATUS was negotiated, see section 5.1.4
+        let status_offered = host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_STATUS);
+        if status_offered {
+            guest_features += NetworkDeviceFeatureBits0::VIRTIO_NET_F_STATUS.val(1);
+        }
+        // Announcements are requested through the status field and acknowledged on the control queue
+        let announce_offered = control_offered
+            && status_offered
+            && host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_GUEST_ANNOUNCE);
+        if announce_offered {
+            guest_features += NetworkDeviceFeatureBits0::VIRTIO_NET_F_GUEST_ANNOUNCE.val(1);
+        }
+        // VIRTIO_NET_F_MQ requires VIRTIO_NET_F_CTRL_VQ, see section 5.1.3.1
+        let mq_offered = control_offered && host_features0.is_set(NetworkDeviceFeatureBits0::VIRTIO_NET_F_MQ);
+        if mq_offered {
+            guest_features += NetworkDeviceFeatureBits0::VIRTIO_NET_F_MQ.val(1);
+        }
+        register.guest_features().write(guest_features);
+
+        // Modern devices require VIRTIO_F_VERSION_1, see section 6.1
+        let mut layout = QueueLayout::LegacySplit;
+        let mut guest_features_high = 0;
+        if modern {
+            register.host_features_sel().set(1);
+            // The register type describes word 0, word 1 holds the device-independent feature bits
+            let host_features1 = LocalRegisterCopy::<u32, FeatureBits1::Register>::new(register.host_features().get());
+            guest_features_high = FeatureBits1::VIRTIO_F_VERSION_1.val(1).value;
+            layout = QueueLayout::Split;
+            if host_features1.is_set(FeatureBits1::VIRTIO_F_RING_PACKED) {
+                guest_features_high |= FeatureBits1::VIRTIO_F_RING_PACKED.val(1).value;
+                layout = QueueLayout::Packed;
+            }
+            register.guest_features_sel().set(1);
+            register.guest_features().set(guest_features_high);
+            Self::accept_features(&mut register)?;
+        }
+
+        // The mtu field is only valid if VIRTIO_NET_F_MTU was negotiated, see section 5.1.4
+        let mtu = if mtu_offered {
+            core::cmp::min(register.mtu(), default_mtu)
+        } else {
+            default_mtu
+        };
+        // The receive buffers are sized for the MTU, so it is bounded by the virtqueue memory
+        let mtu = core::cmp::min(mtu, MAX_MTU);
+        if mtu < MIN_MTU {
+            return Err(DeviceInitializationError::InvalidMtu(mtu));
+        }
+        let buffer_size = Self::buffer_size(mtu, layout.header_size());
+        let mut mac = [0; 6];
+        if mac_offered {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = register.mac(i);
+            }
+        }
         // util::print(format_args!(
         //    "guest_features = {:?}\n",
         //    guest_features.value
         //))
         // .unwrap();
 
+        // The max_virtqueue_pairs field is only valid if VIRTIO_NET_F_MQ was negotiated
+        let device_queue_pairs = if mq_offered {
+            register.max_virtqueue_pairs()
+        } else {
+            1
+        };
+        let mut queue_pair_count = core::cmp::min(device_queue_pairs, max_queue_pairs) as usize;
+        queue_pair_count = core::cmp::max(core::cmp::min(queue_pair_count, MAX_QUEUE_PAIRS), 1);
+
         // 7. Perform device-specific setup (i.e. do virtqueue stuff, see 5.1.2)
         // Write the queue page size to register
-        register.guest_page_size.set(PAGE_SIZE);
-        // According to section 5.1.2, 0 is receiveq1 and 1 is transmitq1.
-        let receiveq1 = Self::configure_virtqueue(0, &mut register, memory, true);
-        let sendq1 = Self::configure_virtqueue(1, &mut register, memory, false);
+        if layout == QueueLayout::LegacySplit {
+            register.guest_page_size().set(PAGE_SIZE);
+        }
+        // According to section 5.1.2, 2(N-1) is receiveqN and 2(N-1)+1 is transmitqN.
+        let mut queue_pairs: [Option<SpinLock<QueuePair>>; MAX_QUEUE_PAIRS] = Default::default();
+        for (i, queue_pair) in queue_pairs.iter_mut().take(queue_pair_count).enumerate() {
+            let index = 2 * i as u32;
+            let receiveq =
+                Self::configure_virtqueue(index, &mut register, layout, memory, queue_size, buffer_size, true)?;
+            let mut sendq =
+                Self::configure_virtqueue(index + 1, &mut register, layout, memory, queue_size, buffer_size, false)?;
+            sendq.set_interrupt_suppressed(true);
+            *queue_pair = Some(SpinLock::new(QueuePair {
+                receiveq,
+                sendq,
+                register,
+                index,
+                resets: 0,
+            }));
+        }
+        // The control queue follows the last queue pair the device offers
+        let mut control = if control_offered {
+            let index = 2 * device_queue_pairs as u32;
+            let queue = Self::configure_control_virtqueue(index, &mut register, layout, memory)?;
+            let control = ControlQueue::new(queue, register, index, guest_features.value, memory)
+                .map_err(DeviceInitializationError::Memory)?;
+            Some(control)
+        } else {
+            None
+        };
 
         // 8. Set the DRIVER_OK status bit
         register
-            .device_status
+            .device_status()
             .modify(DeviceStatus::DRIVER_OK.val(1));
 
+        // Only the first queue pair is enabled until the driver sets the number of pairs, see section 5.1.6.5.5
+        if queue_pair_count > 1 {
+            let enabled = match &mut control {
+                Some(control) => control.set_queue_pairs(queue_pair_count as u16).is_ok(),
+                None => false,
+            };
+            if !enabled {
+                // util::print(format_args!("[warn] falling back to a single queue pair\n")).unwrap();
+                // The device only uses the first pair, so the others are neither notified nor recovered
+                for queue_pair in queue_pairs.iter_mut().skip(1) {
+                    *queue_pair = None;
+                }
+                queue_pair_count = 1;
+            }
+        }
+
         // Check the status again
-        let _status = register.device_status.get();
+        let _status = register.device_status().get();
         // util::print(format_args!("status = {:?}\n", status)).unwrap();
 
-        // Notify the device of the available buffer
+        // Notify the device of the available buffers
         // util::print(format_args!("notifying device of queue 0\n")).unwrap();
-        register.queue_notify.set(0);
+        for queue_pair in queue_pairs.iter().flatten() {
+            queue_pair.lock().notify_receive();
+        }
         Ok(VirtioMMIONetworkDevice {
             register,
-            receiveq1,
-            sendq1,
+            queue_pairs,
+            queue_pair_count,
+            control: control.map(SpinLock::new),
+            mtu,
+            mac,
+            guest_announce: announce_offered,
+            guest_features: guest_features.value,
+            guest_features_high,
+            layout,
         })
     }
 
+    /// Whether the device hit an error it only recovers from by a reset, see section 2.1.1
+    pub fn needs_reset(&self) -> bool {
+        self.register
+            .device_status()
+            .is_set(DeviceStatus::DEVICE_NEEDS_RESET)
+    }
+
+    /// Resets the device and initializes it again, reusing the virtqueues' memory
+    ///
+    /// The queues are locked throughout, so other cores wait instead of using them. Frames in the
+    /// queues are lost and elements taken before are stale, see `QueuePair::resets`. The features
+    /// and the number of queue pairs stay the same, the receive filter has to be set again.
+    pub fn recover(&self) -> Result<(), DeviceInitializationError> {
+        let mut queue_pairs: [Option<SpinLockGuard<'_, QueuePair>>; MAX_QUEUE_PAIRS] = Default::default();
+        for (queue_pair, lock) in queue_pairs.iter_mut().zip(self.queue_pairs.iter()) {
+            *queue_pair = lock.as_ref().map(SpinLock::lock);
+        }
+        let mut control = self.control.as_ref().map(SpinLock::lock);
+
+        // Section 3.1.1, like `initialize`
+        let mut register = self.register;
+        register.device_status().set(0);
+        register
+            .device_status()
+            .modify(DeviceStatus::ACKNOWLEDGE.val(1));
+        register.device_status().modify(DeviceStatus::DRIVER.val(1));
+        register.guest_features_sel().set(0);
+        register.guest_features().set(self.guest_features);
+        if self.layout == QueueLayout::LegacySplit {
+            register.guest_page_size().set(PAGE_SIZE);
+        } else {
+            register.guest_features_sel().set(1);
+            register.guest_features().set(self.guest_features_high);
+            Self::accept_features(&mut register)?;
+        }
+        for queue_pair in queue_pairs.iter_mut().flatten() {
+            queue_pair.receiveq.reset(true);
+            Self::select_virtqueue(queue_pair.index, &mut register, self.layout)?;
+            Self::activate_virtqueue(&mut register, self.layout, &queue_pair.receiveq);
+            queue_pair.sendq.reset(false);
+            Self::select_virtqueue(queue_pair.index + 1, &mut register, self.layout)?;
+            Self::activate_virtqueue(&mut register, self.layout, &queue_pair.sendq);
+            queue_pair.sendq.set_interrupt_suppressed(true);
+            queue_pair.resets = queue_pair.resets.wrapping_add(1);
+        }
+        if let Some(control) = &mut control {
+            let index = control.index();
+            let queue = control.reset();
+            Self::select_virtqueue(index, &mut register, self.layout)?;
+            Self::activate_virtqueue(&mut register, self.layout, queue);
+        }
+        register
+            .device_status()
+            .modify(DeviceStatus::DRIVER_OK.val(1));
+        if register.device_status().is_set(DeviceStatus::DEVICE_NEEDS_RESET)
+            || register.device_status().is_set(DeviceStatus::FAILED)
+        {
+            return Err(DeviceInitializationError::Failed);
+        }
+
+        if self.queue_pair_count > 1 {
+            if let Some(control) = &mut control {
+                control
+                    .set_queue_pairs(self.queue_pair_count as u16)
+                    .map_err(DeviceInitializationError::Control)?;
+            }
+        }
+        for queue_pair in queue_pairs.iter().flatten() {
+            queue_pair.notify_receive();
+        }
+        Ok(())
+    }
+
+    /// Enables or suppresses the interrupts of the receive queues of the calling core's queue pairs
+    ///
+    /// Transmit queues never interrupt, their buffers are reclaimed when they are taken.
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn set_receive_interrupts(&self, enabled: bool) {
+        for index in 0..self.queue_pair_count {
+            if self.owns_queue_pair(index) {
+                if let Some(mut queue_pair) = self.queue_pair(index) {
+                    queue_pair.receiveq.set_interrupt_suppressed(!enabled);
+                }
+            }
+        }
+    }
+
+    /// Whether the device asks the driver to announce its addresses, e.g. after live migration
+    ///
+    /// The request stays pending until it is acknowledged on the control queue, see section 5.1.6.5.4.
+    pub fn announce_requested(&self) -> bool {
+        self.guest_announce && self.register.status() & VIRTIO_NET_S_ANNOUNCE != 0
+    }
+
+    /// Locks the queue pair with the given index, `None` if it is not enabled
+    pub fn queue_pair(&self, index: usize) -> Option<SpinLockGuard<'_, QueuePair>> {
+        if index >= self.queue_pair_count {
+            return None;
+        }
+        self.queue_pairs[index].as_ref().map(SpinLock::lock)
+    }
+
+    /// Whether the calling core polls the queue pair with the given index
+    ///
+    /// Core `i` of `n` owns the pairs `i`, `i + n`, `i + 2n` and so on.
+    pub fn owns_queue_pair(&self, index: usize) -> bool {
+        index % smp::core_count() == cpu::core_index()
+    }
+
+    /// Locks the queue pair the calling core transmits a flow with the given hash on
+    ///
+    /// The hash picks one of the core's own pairs, so other cores rarely wait for the lock. Cores
+    /// without a pair of their own share the pairs of the others. With automatic receive steering
+    /// the device delivers the flow's packets to the same pair, see section 5.1.6.5.6.
+    pub fn queue_pair_for_flow(&self, hash: u32) -> Option<SpinLockGuard<'_, QueuePair>> {
+        let (core, cores) = (cpu::core_index(), smp::core_count());
+        let index = if core < self.queue_pair_count {
+            let owned = (self.queue_pair_count - core + cores - 1) / cores;
+            core + (hash as usize % owned) * cores
+        } else {
+            core % self.queue_pair_count
+        };
+        self.queue_pair(index)
+    }
+
+    /// The buffer size required to hold a frame of the given MTU, see section 5.1.6.3.1
+    fn buffer_size(mtu: u16, header_size: usize) -> u32 {
+        let size = header_size as u32 + MAX_LINK_HEADER_SIZE + mtu as u32;
+        if size < MIN_BUFFER_SIZE {
+            MIN_BUFFER_SIZE
+        } else {
+            size
+        }
+    }
+
+    /// The size of a queue, `requested` lowered to the largest power of two the device supports
+    ///
+    /// The free running ring indices wrap at 65536, which only keeps them in step with the ring
+    /// positions for powers of two, see section 2.4.
+    fn queue_size(requested: u32, queue_num_max: u32) -> Result<u32, DeviceInitializationError> {
+        if requested == 0 || requested > MAX_QUEUE_SIZE || !requested.is_power_of_two() {
+            return Err(DeviceInitializationError::InvalidQueueSize(requested));
+        }
+        let size = core::cmp::min(requested, queue_num_max);
+        Ok(1 << (31 - size.leading_zeros()))
+    }
+
+    /// Sets FEATURES_OK and checks whether a modern device accepted the features, see section 3.1.1
+    fn accept_features(register: &mut VirtioMMIORegister) -> Result<(), DeviceInitializationError> {
+        register
+            .device_status()
+            .modify(DeviceStatus::FEATURES_OK.val(1));
+        if register.device_status().is_set(DeviceStatus::FEATURES_OK) {
+            Ok(())
+        } else {
+            Err(DeviceInitializationError::FeaturesRejected)
+        }
+    }
+
     fn configure_virtqueue(
         index: u32,
         register: &mut VirtioMMIORegister,
+        layout: QueueLayout,
         memory: &mut MemoryHandle,
+        requested_size: u16,
+        buffer_size: u32,
         receive: bool,
-    ) -> VirtQueueHandle {
-        if index > 1 {
-            panic!("virtqueue might overlap other mem")
+    ) -> Result<AnyVirtQueue, DeviceInitializationError> {
+        let queue_num_max = Self::select_virtqueue(index, register, layout)?;
+        let queue_size = Self::queue_size(requested_size as u32, queue_num_max)? as usize;
+        // util::print(format_args!("queue_num_max = {:?}, queue_size = {:?}\n", queue_num_max, queue_size)).unwrap();
+
+        // 4. Allocate and zero queue pages
+        let header_size = layout.header_size();
+        let virtqueue = match layout {
+            QueueLayout::Packed => PackedVirtQueueHandle::new(queue_size, buffer_size, header_size, memory, receive)
+                .map(AnyVirtQueue::Packed),
+            _ => VirtQueueHandle::new(queue_size, buffer_size, header_size, memory, receive).map(AnyVirtQueue::Split),
         }
+        .map_err(DeviceInitializationError::Memory)?;
+        Self::activate_virtqueue(register, layout, &virtqueue);
+        Ok(virtqueue)
+    }
+
+    fn configure_control_virtqueue(
+        index: u32,
+        register: &mut VirtioMMIORegister,
+        layout: QueueLayout,
+        memory: &mut MemoryHandle,
+    ) -> Result<AnyVirtQueue, DeviceInitializationError> {
+        let queue_num_max = Self::select_virtqueue(index, register, layout)?;
+        let queue_size = Self::queue_size(CONTROL_QUEUE_SIZE, queue_num_max)? as usize;
+        let virtqueue = match layout {
+            QueueLayout::Packed => PackedVirtQueueHandle::new_unbuffered(queue_size, memory).map(AnyVirtQueue::Packed),
+            _ => VirtQueueHandle::new_unbuffered(queue_size, memory).map(AnyVirtQueue::Split),
+        }
+        .map_err(DeviceInitializationError::Memory)?;
+        Self::activate_virtqueue(register, layout, &virtqueue);
+        Ok(virtqueue)
+    }
+
+    /// Selects the queue and returns its maximum size
+    fn select_virtqueue(
+        index: u32,
+        register: &mut VirtioMMIORegister,
+        layout: QueueLayout,
+    ) -> Result<u32, DeviceInitializationError> {
         // 1. Select the queue
-        register.queue_sel.set(index);
+        register.queue_sel().set(index);
 
         // 2. Check if the queue is not already in use
-        let queue_pfn = register.queue_pfn.get();
-        if queue_pfn != 0 {
-            panic!("QueuePFN should be 0 - queue is in use!")
+        let in_use = match layout {
+            QueueLayout::LegacySplit => register.queue_pfn().get() != 0,
+            QueueLayout::Split | QueueLayout::Packed => register.queue_ready().get() != 0,
+        };
+        if in_use {
+            return Err(DeviceInitializationError::QueueInUse(index));
         }
-        // util::print(format_args!("queue_pfn = {:?}\n", queue_pfn)).unwrap();
 
         // 3. Read maximum queue size
-        let queue_num_max = register.queue_num_max.get();
+        let queue_num_max = register.queue_num_max().get();
         if queue_num_max == 0 {
-            panic!("QueueNumMax should not be 0 - queue is not available!")
-        }
-        if queue_num_max < 1024 {
-            panic!("QueueNumMax < 1024!");
+            return Err(DeviceInitializationError::QueueUnavailable(index));
         }
-        let queue_size: u32 = 1024;
-        // util::print(format_args!("queue_num_max = {:?}\n", queue_num_max)).unwrap();
-
-        // 4. Allocate and zero queue pages
-        let virtqueue = VirtQueueHandle::new(queue_size as usize, memory, receive);
+        Ok(queue_num_max)
+    }
 
+    fn activate_virtqueue(register: &mut VirtioMMIORegister, layout: QueueLayout, virtqueue: &AnyVirtQueue) {
         // 5. Notify the device about the queue size
-        register.queue_num.set(queue_size);
+        register.queue_num().set(virtqueue.queue_size() as u32);
 
-        // 6. Notify the device about the used alignment
-        register.queue_align.set(MMIO_QUEUE_ALIGN + 1);
+        if layout == QueueLayout::LegacySplit {
+            // 6. Notify the device about the used alignment
+            register.queue_align().set(MMIO_QUEUE_ALIGN + 1);
 
-        // 7. Write the physical number of the first page of the queue to pfn
-        register
-            .queue_pfn
-            .set((virtqueue.base_address() / PAGE_SIZE as usize) as u32);
+            // 7. Write the physical number of the first page of the queue to pfn
+            register
+                .queue_pfn()
+                .set((virtqueue.descriptor_area() / PAGE_SIZE as usize) as u32);
+        } else {
+            // Modern devices take the address of every area and are told once the queue is ready, see section 4.2.3.2
+            let (descriptor, driver, device) = (
+                virtqueue.descriptor_area() as u64,
+                virtqueue.driver_area() as u64,
+                virtqueue.device_area() as u64,
+            );
+            register.queue_desc_low().set(descriptor as u32);
+            register.queue_desc_high().set((descriptor >> 32) as u32);
+            register.queue_driver_low().set(driver as u32);
+            register.queue_driver_high().set((driver >> 32) as u32);
+            register.queue_device_low().set(device as u32);
+            register.queue_device_high().set((device >> 32) as u32);
+            register.queue_ready().set(1);
+        }
         // util::print(format_args!(
         //     "virtqueue at 0x{:x} configured\n",
-        //     &virtqueue.base_address()
+        //     &virtqueue.descriptor_area()
         // ))
         // .unwrap();
-        virtqueue
+    }
+}
+
+/// Resets the device at `address`, which stops using its virtqueues, see section 4.2.4
+#[cfg_attr(test, allow(dead_code))]
+pub fn reset(address: usize) {
+    VirtioMMIORegister::new(address).device_status().set(0);
+}
+
+/// Acknowledges all pending interrupts of the device at `address`, see section 4.2.3.3
+///
+/// Registered as interrupt handler, the queues are serviced by the polling loop.
+#[cfg_attr(test, allow(dead_code))]
+pub fn acknowledge_interrupt(address: usize) {
+    let register = VirtioMMIORegister::new(address);
+    register.interrupt_ack().set(register.interrupt_status().get());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_device::{self, DeviceConfig, MockDevice};
+    use crate::mock_device::{VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1, VIRTIO_NET_F_MQ};
+    use crate::virtio_control::RxMode;
+
+    const ADDRESS: usize = 0x0a003e00;
+
+    fn initialize(
+        config: DeviceConfig,
+        queue_size: u16,
+    ) -> (&'static MockDevice, Result<VirtioMMIONetworkDevice, DeviceInitializationError>) {
+        let device = MockDevice::new(ADDRESS, config);
+        let mut memory = mock_device::guest_memory(0x1000000);
+        let nic = VirtioMMIONetworkDevice::initialize(device.register(), 1500, 2, queue_size, &mut memory);
+        (device, nic)
+    }
+
+    /// Receives an injected frame and transmits it back on the first queue pair
+    fn receive_and_transmit(device: &MockDevice, nic: &VirtioMMIONetworkDevice) {
+        let frame = [0xab; 60];
+        assert!(device.inject(0, &frame));
+        let mut queue_pair = nic.queue_pair(0).unwrap();
+        let element = queue_pair.receiveq.try_take().unwrap();
+        assert_eq!(element.as_network_packet().1, &frame[..]);
+        assert!(queue_pair.receiveq.try_take().is_none());
+
+        let mut send_element = queue_pair.sendq.try_take().unwrap();
+        send_element.copy_from(&element);
+        queue_pair.sendq.offer(send_element);
+        queue_pair.notify_send();
+        queue_pair.receiveq.offer(element);
+        queue_pair.notify_receive();
+        assert_eq!(device.take_transmitted(), [(1, frame.to_vec())]);
+    }
+
+    #[test]
+    fn initializes_legacy_device() {
+        let config = DeviceConfig {
+            mtu: 1400,
+            ..Default::default()
+        };
+        let (device, nic) = initialize(config, 1024);
+        let nic = nic.unwrap();
+        assert_eq!(device.status(), 0b111);
+        assert_eq!(nic.mac, config.mac);
+        assert_eq!(nic.mtu, 1400);
+        assert_eq!(nic.queue_pair_count, 1);
+        assert_eq!([device.queue_num(0), device.queue_num(1), device.queue_num(2)], [1024, 1024, 64]);
+        receive_and_transmit(device, &nic);
+    }
+
+    #[test]
+    fn initializes_modern_device_with_split_queues() {
+        let mut config = DeviceConfig {
+            version: 2,
+            ..Default::default()
+        };
+        config.features[1] = VIRTIO_F_VERSION_1;
+        let (device, nic) = initialize(config, 1024);
+        let nic = nic.unwrap();
+        assert_eq!(nic.layout, QueueLayout::Split);
+        assert_eq!(device.status(), 0b1111);
+        receive_and_transmit(device, &nic);
+    }
+
+    #[test]
+    fn initializes_modern_device_with_packed_queues() {
+        let mut config = DeviceConfig {
+            version: 2,
+            ..Default::default()
+        };
+        config.features[1] = VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED;
+        let (device, nic) = initialize(config, 1024);
+        let nic = nic.unwrap();
+        assert_eq!(nic.layout, QueueLayout::Packed);
+        receive_and_transmit(device, &nic);
+    }
+
+    #[test]
+    fn fails_if_modern_device_rejects_features() {
+        let config = DeviceConfig {
+            version: 2,
+            ..Default::default()
+        };
+        let (_device, nic) = initialize(config, 1024);
+        assert!(matches!(nic, Err(DeviceInitializationError::FeaturesRejected)));
+    }
+
+    #[test]
+    fn lowers_queue_size_to_queue_num_max() {
+        let config = DeviceConfig {
+            queue_num_max: 200,
+            ..Default::default()
+        };
+        let (device, nic) = initialize(config, 1024);
+        let nic = nic.unwrap();
+        assert_eq!([device.queue_num(0), device.queue_num(1), device.queue_num(2)], [128, 128, 64]);
+        receive_and_transmit(device, &nic);
+    }
+
+    #[test]
+    fn bounds_offered_mtu_by_configured_mtu() {
+        let config = DeviceConfig {
+            mtu: 65535,
+            ..Default::default()
+        };
+        let (_device, nic) = initialize(config, 1024);
+        assert_eq!(nic.unwrap().mtu, 1500);
+    }
+
+    #[test]
+    fn rejects_mtu_below_ipv4_minimum() {
+        let config = DeviceConfig {
+            mtu: 67,
+            ..Default::default()
+        };
+        let (_device, nic) = initialize(config, 1024);
+        assert!(matches!(nic, Err(DeviceInitializationError::InvalidMtu(67))));
+    }
+
+    #[test]
+    fn fails_if_queues_exceed_memory() {
+        let device = MockDevice::new(ADDRESS, DeviceConfig::default());
+        let mut memory = mock_device::guest_memory(0x100000);
+        let nic = VirtioMMIONetworkDevice::initialize(device.register(), 1500, 1, 1024, &mut memory);
+        assert!(matches!(nic, Err(DeviceInitializationError::Memory(_))));
+    }
+
+    #[test]
+    fn rejects_queue_size_that_is_no_power_of_two() {
+        let (_device, nic) = initialize(DeviceConfig::default(), 1000);
+        assert!(matches!(nic, Err(DeviceInitializationError::InvalidQueueSize(1000))));
+    }
+
+    #[test]
+    fn enables_queue_pairs_on_control_queue() {
+        let mut config = DeviceConfig {
+            max_virtqueue_pairs: 4,
+            ..Default::default()
+        };
+        config.features[0] |= VIRTIO_NET_F_MQ;
+        let (device, nic) = initialize(config, 256);
+        let nic = nic.unwrap();
+        assert_eq!(nic.queue_pair_count, 2);
+        assert!(nic.queue_pair(1).is_some());
+        assert!(nic.queue_pair(2).is_none());
+        // The control queue follows the device's last queue pair
+        assert_eq!(device.queue_num(8), 64);
+        assert_eq!(device.commands(), [(4, 0, vec![2, 0])]);
+    }
+
+    #[test]
+    fn drops_extra_queue_pairs_if_device_rejects_them() {
+        let mut config = DeviceConfig {
+            max_virtqueue_pairs: 4,
+            ..Default::default()
+        };
+        config.features[0] |= VIRTIO_NET_F_MQ;
+        let device = MockDevice::new(ADDRESS, config);
+        device.set_control_ack(1);
+        let mut memory = mock_device::guest_memory(0x1000000);
+        let nic = VirtioMMIONetworkDevice::initialize(device.register(), 1500, 2, 256, &mut memory).unwrap();
+        assert_eq!(nic.queue_pair_count, 1);
+        assert!(nic.queue_pairs[1].is_none());
+        assert_eq!(device.commands().len(), 1);
+
+        device.set_needs_reset();
+        nic.recover().unwrap();
+        // Only the first pair is used again, the device is not asked for more
+        assert_eq!(device.commands().len(), 1);
+        receive_and_transmit(device, &nic);
+    }
+
+    #[test]
+    fn control_queue_fails_after_timeout_until_recovered() {
+        let (device, nic) = initialize(DeviceConfig::default(), 1024);
+        let nic = nic.unwrap();
+        let control = nic.control.as_ref().unwrap();
+        device.set_control_stalled(true);
+        assert!(matches!(
+            control.lock().set_rx_mode(RxMode::Promiscuous, true),
+            Err(ControlCommandError::Timeout)
+        ));
+        // The device still owns the chain of the first command, so no other is submitted
+        device.set_control_stalled(false);
+        assert!(matches!(
+            control.lock().set_rx_mode(RxMode::AllMulticast, true),
+            Err(ControlCommandError::Timeout)
+        ));
+        assert!(device.commands().is_empty());
+
+        nic.recover().unwrap();
+        control.lock().set_rx_mode(RxMode::AllMulticast, true).unwrap();
+        assert_eq!(device.commands(), [(0, 1, vec![1])]);
+    }
+
+    #[test]
+    fn recovers_device_that_needs_reset() {
+        let (device, nic) = initialize(DeviceConfig::default(), 1024);
+        let nic = nic.unwrap();
+        assert!(device.inject(0, &[1; 60]));
+        let stale = nic.queue_pair(0).unwrap().receiveq.try_take().unwrap();
+
+        device.set_needs_reset();
+        assert!(nic.needs_reset());
+        nic.recover().unwrap();
+        assert!(!nic.needs_reset());
+        assert_eq!(device.status(), 0b111);
+        assert_eq!(nic.queue_pair(0).unwrap().resets, 1);
+        stale.abandon();
+        receive_and_transmit(device, &nic);
     }
 }
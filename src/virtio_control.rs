@@ -0,0 +1,244 @@
+use crate::barrier;
+use crate::errors::{ControlCommandError, MemoryReservationError};
+use crate::memory_handle::MemoryHandle;
+use crate::virtio_device_register::{NetworkDeviceFeatureBits0, VirtioMMIORegister};
+use crate::virtqueue::{AnyVirtQueue, VirtQueue};
+use register::{Field, LocalRegisterCopy};
+
+const CLASS_RX: u8 = 0;
+const CLASS_MAC: u8 = 1;
+const MAC_TABLE_SET: u8 = 0;
+const MAC_ADDR_SET: u8 = 1;
+const CLASS_VLAN: u8 = 2;
+const VLAN_ADD: u8 = 0;
+const VLAN_DEL: u8 = 1;
+const CLASS_ANNOUNCE: u8 = 3;
+const ANNOUNCE_ACK: u8 = 0;
+const CLASS_MQ: u8 = 4;
+const MQ_VQ_PAIRS_SET: u8 = 0;
+const MQ_VQ_PAIRS_MIN: u16 = 1;
+const MQ_VQ_PAIRS_MAX: u16 = 0x8000;
+const VLAN_ID_MAX: u16 = 4095;
+
+/// Addresses beyond this number per MAC filter table are rejected
+pub const MAX_MAC_TABLE_ENTRIES: usize = 16;
+const MAC_TABLE_SIZE: usize = 4 + MAX_MAC_TABLE_ENTRIES * 6;
+
+const VIRTIO_NET_OK: u8 = 0;
+
+/// Size of the buffer holding the command header, data and ack
+const BUFFER_SIZE: usize = 512;
+const HEADER_SIZE: usize = 2;
+/// The ack is the last byte of the buffer
+const ACK_OFFSET: usize = BUFFER_SIZE - 1;
+/// Polls of the used ring before a command is given up
+const MAX_POLLS: u32 = 1_000_000;
+/// Descriptors of the longest chain: header, up to two data segments and ack
+const MAX_CHAIN_LEN: usize = 4;
+
+// control command, see section 5.1.6.5:
+// 1 byte class
+// 1 byte command
+// command specific data
+// 1 byte ack, written by the device
+//
+// MAC filter table, see section 5.1.6.5.2:
+// 4 bytes number of entries
+// 6 bytes per MAC address
+
+/// Receive filtering modes, see section 5.1.6.5.1
+///
+/// Promiscuous and all-multicast need VIRTIO_NET_F_CTRL_RX, the others VIRTIO_NET_F_CTRL_RX_EXTRA.
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum RxMode {
+    Promiscuous = 0,
+    AllMulticast = 1,
+    AllUnicast = 2,
+    NoMulticast = 3,
+    NoUnicast = 4,
+    NoBroadcast = 5,
+}
+
+/// The control virtqueue of a network device
+#[derive(Debug)]
+pub struct ControlQueue {
+    queue: AnyVirtQueue,
+    register: VirtioMMIORegister,
+    index: u32,
+    /// The negotiated feature bits 0 to 31
+    features: u32,
+    /// Address of the command buffer
+    buffer: usize,
+    /// Set when a command timed out, the device may still use its chain and the command buffer
+    stalled: bool,
+}
+
+impl ControlQueue {
+    pub fn new(
+        queue: AnyVirtQueue,
+        register: VirtioMMIORegister,
+        index: u32,
+        features: u32,
+        memory: &mut MemoryHandle,
+    ) -> Result<Self, MemoryReservationError> {
+        let buffer = memory.allocate(BUFFER_SIZE, 16)?;
+        Ok(ControlQueue {
+            queue,
+            register,
+            index,
+            features,
+            buffer,
+            stalled: false,
+        })
+    }
+
+    /// Index of the control virtqueue
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Returns the control virtqueue to its initial state after a device reset, for activating it again
+    pub fn reset(&mut self) -> &AnyVirtQueue {
+        self.queue.reset(false);
+        self.stalled = false;
+        &self.queue
+    }
+
+    fn require(&self, feature: Field<u32, NetworkDeviceFeatureBits0::Register>) -> Result<(), ControlCommandError> {
+        if LocalRegisterCopy::<u32, NetworkDeviceFeatureBits0::Register>::new(self.features).is_set(feature) {
+            Ok(())
+        } else {
+            Err(ControlCommandError::Unsupported)
+        }
+    }
+
+    /// Sends a command and waits until the device acknowledged it
+    ///
+    /// Every segment of `data` is passed in a descriptor of its own. After a timeout the device still
+    /// owns the chain, so every command fails with `Timeout` until the queue is `reset`.
+    pub fn command(&mut self, class: u8, command: u8, data: &[&[u8]]) -> Result<(), ControlCommandError> {
+        if self.stalled {
+            return Err(ControlCommandError::Timeout);
+        }
+        let buffer = unsafe { core::slice::from_raw_parts_mut(self.buffer as *mut u8, BUFFER_SIZE) };
+        let data_len: usize = data.iter().map(|segment| segment.len()).sum();
+        if HEADER_SIZE + data_len > ACK_OFFSET || data.len() + 2 > MAX_CHAIN_LEN {
+            return Err(ControlCommandError::TooLarge);
+        }
+
+        buffer[0] = class;
+        buffer[1] = command;
+        buffer[ACK_OFFSET] = !VIRTIO_NET_OK;
+        let mut chain = [(0, 0, false); MAX_CHAIN_LEN];
+        chain[0] = (self.buffer as u64, HEADER_SIZE as u32, false);
+        let mut offset = HEADER_SIZE;
+        for (i, segment) in data.iter().enumerate() {
+            buffer[offset..offset + segment.len()].copy_from_slice(segment);
+            chain[i + 1] = ((self.buffer + offset) as u64, segment.len() as u32, false);
+            offset += segment.len();
+        }
+        chain[data.len() + 1] = ((self.buffer + ACK_OFFSET) as u64, 1, true);
+
+        self.queue.submit(&chain[..data.len() + 2]);
+        barrier::io_write_barrier();
+        self.register.queue_notify().set(self.index);
+        for _ in 0..MAX_POLLS {
+            if self.queue.try_remove_used().is_some() {
+                let ack = unsafe { ((self.buffer + ACK_OFFSET) as *const u8).read_volatile() };
+                return if ack == VIRTIO_NET_OK {
+                    Ok(())
+                } else {
+                    Err(ControlCommandError::Rejected)
+                };
+            }
+        }
+        self.stalled = true;
+        Err(ControlCommandError::Timeout)
+    }
+
+    /// Switches a receive filtering mode on or off, see section 5.1.6.5.1
+    pub fn set_rx_mode(&mut self, mode: RxMode, on: bool) -> Result<(), ControlCommandError> {
+        match mode {
+            RxMode::Promiscuous | RxMode::AllMulticast => {
+                self.require(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_RX)?
+            }
+            _ => self.require(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_RX_EXTRA)?,
+        }
+        self.command(CLASS_RX, mode as u8, &[&[on as u8]])
+    }
+
+    /// Replaces the unicast and multicast MAC filter tables, see section 5.1.6.5.2
+    ///
+    /// The device accepts frames to its own address and to the addresses in the tables.
+    pub fn set_mac_table(&mut self, unicast: &[[u8; 6]], multicast: &[[u8; 6]]) -> Result<(), ControlCommandError> {
+        self.require(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_RX)?;
+        if unicast.len() > MAX_MAC_TABLE_ENTRIES || multicast.len() > MAX_MAC_TABLE_ENTRIES {
+            return Err(ControlCommandError::TooLarge);
+        }
+        let mut unicast_table = [0; MAC_TABLE_SIZE];
+        let unicast_len = write_mac_table(&mut unicast_table, unicast);
+        let mut multicast_table = [0; MAC_TABLE_SIZE];
+        let multicast_len = write_mac_table(&mut multicast_table, multicast);
+        // Legacy devices expect each table in a descriptor of its own
+        self.command(
+            CLASS_MAC,
+            MAC_TABLE_SET,
+            &[&unicast_table[..unicast_len], &multicast_table[..multicast_len]],
+        )
+    }
+
+    /// Sets the device's default MAC address, see section 5.1.6.5.2
+    #[allow(dead_code)]
+    pub fn set_mac_address(&mut self, mac: [u8; 6]) -> Result<(), ControlCommandError> {
+        self.require(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_MAC_ADDR)?;
+        self.command(CLASS_MAC, MAC_ADDR_SET, &[&mac])
+    }
+
+    /// Accepts frames tagged with `vlan_id`, see section 5.1.6.5.3
+    ///
+    /// Once a VLAN is added, tagged frames of other VLANs are dropped by the device.
+    #[allow(dead_code)]
+    pub fn add_vlan(&mut self, vlan_id: u16) -> Result<(), ControlCommandError> {
+        self.vlan_command(VLAN_ADD, vlan_id)
+    }
+
+    /// Stops accepting frames tagged with `vlan_id`, see section 5.1.6.5.3
+    #[allow(dead_code)]
+    pub fn remove_vlan(&mut self, vlan_id: u16) -> Result<(), ControlCommandError> {
+        self.vlan_command(VLAN_DEL, vlan_id)
+    }
+
+    fn vlan_command(&mut self, command: u8, vlan_id: u16) -> Result<(), ControlCommandError> {
+        self.require(NetworkDeviceFeatureBits0::VIRTIO_NET_F_CTRL_VLAN)?;
+        if vlan_id > VLAN_ID_MAX {
+            return Err(ControlCommandError::Rejected);
+        }
+        self.command(CLASS_VLAN, command, &[&vlan_id.to_le_bytes()])
+    }
+
+    /// Acknowledges an announcement request and clears the device's announce status bit, see section 5.1.6.5.4
+    pub fn ack_announce(&mut self) -> Result<(), ControlCommandError> {
+        self.require(NetworkDeviceFeatureBits0::VIRTIO_NET_F_GUEST_ANNOUNCE)?;
+        self.command(CLASS_ANNOUNCE, ANNOUNCE_ACK, &[])
+    }
+
+    /// Enables `pairs` receive and transmit queue pairs, see section 5.1.6.5.5
+    pub fn set_queue_pairs(&mut self, pairs: u16) -> Result<(), ControlCommandError> {
+        self.require(NetworkDeviceFeatureBits0::VIRTIO_NET_F_MQ)?;
+        if !(MQ_VQ_PAIRS_MIN..=MQ_VQ_PAIRS_MAX).contains(&pairs) {
+            return Err(ControlCommandError::Rejected);
+        }
+        self.command(CLASS_MQ, MQ_VQ_PAIRS_SET, &[&pairs.to_le_bytes()])
+    }
+}
+
+/// Writes a MAC filter table and returns its length
+fn write_mac_table(out: &mut [u8], entries: &[[u8; 6]]) -> usize {
+    // Legacy devices use the guest's byte order for the number of entries
+    out[..4].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (i, mac) in entries.iter().enumerate() {
+        out[4 + i * 6..4 + (i + 1) * 6].copy_from_slice(mac);
+    }
+    4 + entries.len() * 6
+}
@@ -1,28 +1,97 @@
-use core::ops;
-use register::{mmio::*, register_bitfields, register_structs};
+use crate::mmio::{MmioBackend, Register, HARDWARE};
+use register::register_bitfields;
 
+/// Registers of legacy (version 1) and modern (version 2) devices, see section 4.2.2 and 4.2.4
+///
+/// Some registers only exist in one of the versions, the others are shared.
+#[derive(Clone, Copy, Debug)]
 pub struct VirtioMMIORegister {
     base_address: usize,
+    backend: &'static dyn MmioBackend,
 }
 
 impl VirtioMMIORegister {
+    #[cfg_attr(test, allow(dead_code))]
     pub fn new(base_address: usize) -> Self {
-        VirtioMMIORegister { base_address }
+        Self::with_backend(base_address, &HARDWARE)
     }
 
-    fn ptr(&self) -> *const LegacyVirtioDeviceRegister {
-        self.base_address as *const _
+    /// Accesses the registers through `backend`, e.g. a simulated device
+    pub fn with_backend(base_address: usize, backend: &'static dyn MmioBackend) -> Self {
+        VirtioMMIORegister { base_address, backend }
+    }
+
+    fn register<R: register::RegisterLongName>(&self, offset: usize) -> Register<R> {
+        Register::new(self.backend, self.base_address + offset)
     }
-}
 
-impl ops::Deref for VirtioMMIORegister {
-    type Target = LegacyVirtioDeviceRegister;
+    // Device-specific configuration space, see section 5.1.4
+
+    pub fn mac(&self, index: usize) -> u8 {
+        self.backend.read_u8(self.base_address + 0x100 + index)
+    }
+
+    pub fn status(&self) -> u16 {
+        self.backend.read_u16(self.base_address + 0x106)
+    }
+
+    pub fn max_virtqueue_pairs(&self) -> u16 {
+        self.backend.read_u16(self.base_address + 0x108)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr() }
+    pub fn mtu(&self) -> u16 {
+        self.backend.read_u16(self.base_address + 0x10A)
     }
 }
 
+/// Defines an accessor per 32 bit register at the given offset
+macro_rules! registers {
+    ($($(#[$attribute:meta])* ($offset:expr => $name:ident: $register:ty)),* $(,)?) => {
+        #[allow(dead_code)]
+        impl VirtioMMIORegister {
+            $(
+                $(#[$attribute])*
+                pub fn $name(&self) -> $register {
+                    self.register($offset)
+                }
+            )*
+        }
+    };
+}
+
+registers! {
+    (0x000 => magic_value: Register),
+    (0x004 => version: Register),
+    (0x008 => device_id: Register),
+    (0x00c => vendor_id: Register),
+    (0x010 => host_features: Register<NetworkDeviceFeatureBits0::Register>),
+    (0x014 => host_features_sel: Register),
+    (0x020 => guest_features: Register<NetworkDeviceFeatureBits0::Register>),
+    (0x024 => guest_features_sel: Register),
+    /// Legacy only
+    (0x028 => guest_page_size: Register),
+    (0x030 => queue_sel: Register),
+    (0x034 => queue_num_max: Register),
+    (0x038 => queue_num: Register),
+    /// Legacy only
+    (0x03C => queue_align: Register),
+    /// Legacy only
+    (0x040 => queue_pfn: Register),
+    /// Modern only
+    (0x044 => queue_ready: Register),
+    (0x050 => queue_notify: Register),
+    (0x060 => interrupt_status: Register),
+    (0x064 => interrupt_ack: Register),
+    (0x070 => device_status: Register<DeviceStatus::Register>),
+    /// Modern only, the addresses of the queue's areas
+    (0x080 => queue_desc_low: Register),
+    (0x084 => queue_desc_high: Register),
+    (0x090 => queue_driver_low: Register),
+    (0x094 => queue_driver_high: Register),
+    (0x0A0 => queue_device_low: Register),
+    (0x0A4 => queue_device_high: Register),
+}
+
 register_bitfields! {
     u32,
     pub DeviceStatus [
@@ -62,7 +131,7 @@ register_bitfields! {
         VIRTIO_NET_F_CTRL_RX OFFSET(18) NUMBITS(1) [],
         VIRTIO_NET_F_CTRL_VLAN OFFSET(19) NUMBITS(1) [],
 
-        WTF_IS_THIS_SHIT OFFSET(20) NUMBITS(1) [],
+        VIRTIO_NET_F_CTRL_RX_EXTRA OFFSET(20) NUMBITS(1) [],
         VIRTIO_NET_F_GUEST_ANNOUNCE OFFSET(21) NUMBITS(1) [],
         VIRTIO_NET_F_MQ OFFSET(22) NUMBITS(1) [],
         VIRTIO_NET_F_CTRL_MAC_ADDR OFFSET(23) NUMBITS(1) [],
@@ -76,35 +145,11 @@ register_bitfields! {
         VIRTIO_F_RING_EVENT_IDX OFFSET(26) NUMBITS(1) [],
         UNUSED OFFSET(25) NUMBITS(1) []
         //FOO OFFSET(26) NUMBITS(1) [],
+    ],
+    /// Feature bits 32 to 63, only offered by modern devices, see section 6
+    pub FeatureBits1 [
+        VIRTIO_F_VERSION_1 OFFSET(0) NUMBITS(1) [],
+        VIRTIO_F_ACCESS_PLATFORM OFFSET(1) NUMBITS(1) [],
+        VIRTIO_F_RING_PACKED OFFSET(2) NUMBITS(1) []
     ]
 }
-
-register_structs! {
-    pub LegacyVirtioDeviceRegister {
-        (0x000 => pub magic_value: ReadOnly<u32>),
-        (0x004 => pub version: ReadOnly<u32>),
-        (0x008 => pub device_id: ReadOnly<u32>),
-        (0x00c => pub vendor_id: ReadOnly<u32>),
-        (0x010 => pub host_features: ReadOnly<u32, NetworkDeviceFeatureBits0::Register>),
-        (0x014 => pub host_features_sel: WriteOnly<u32>),
-        (0x018 => _reserved1),
-        (0x020 => pub guest_features: WriteOnly<u32, NetworkDeviceFeatureBits0::Register>),
-        (0x024 => pub guest_features_sel: WriteOnly<u32>),
-        (0x028 => pub guest_page_size: WriteOnly<u32>),
-        (0x02C => _reserved2),
-        (0x030 => pub queue_sel: WriteOnly<u32>),
-        (0x034 => pub queue_num_max: ReadOnly<u32>),
-        (0x038 => pub queue_num: WriteOnly<u32>),
-        (0x03C => pub queue_align: WriteOnly<u32>),
-        (0x040 => pub queue_pfn: ReadWrite<u32>),
-        (0x044 => _reserved3),
-        (0x050 => pub queue_notify: WriteOnly<u32>),
-        (0x054 => _reserved4),
-        (0x060 => pub interrupt_status: ReadOnly<u32>),
-        (0x064 => pub interrupt_ack: WriteOnly<u32>),
-        (0x068 => _reserved5),
-        (0x070 => pub device_status: ReadWrite<u32, DeviceStatus::Register>),
-        (0x074 => _reserved6),
-        (0x100 => @END),
-    }
-}
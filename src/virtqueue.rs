@@ -1,23 +1,106 @@
+use crate::barrier;
+use crate::errors::MemoryReservationError;
 use crate::memory_handle::MemoryHandle;
 use crate::virtqueue_network::NetworkDescriptor;
 use crate::virtqueue_network::RawVirtioNetHeaderShortPointer;
-use core::sync::atomic;
-use core::sync::atomic::Ordering;
+use crate::virtqueue_packed::PackedVirtQueueHandle;
 use core::slice;
 
 const MMIO_QUEUE_ALIGN: usize = 4095;
-const BUFFER_SIZE: u32 = 4096;
 
+/// The descriptor continues in the `next` field, see section 2.4.5
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is device write-only
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The device should not interrupt when it used a buffer, see section 2.4.7
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// The driver should not notify when it offered a buffer, see section 2.4.8
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/// The operations the drivers use on a virtqueue, whatever its layout
+pub trait VirtQueue {
+    fn queue_size(&self) -> usize;
+
+    /// Offers a descriptor chain starting at descriptor 0 to the device
+    ///
+    /// Every buffer is given as address, length and whether the device writes to it. Only one chain
+    /// may be in flight at a time: every chain reuses descriptor 0 as its head, or buffer ID 0 on
+    /// packed queues, so a chain submitted before `try_remove_used` returned the previous one overwrites
+    /// it. The control queue waits for each command and stops submitting after one timed out.
+    fn submit(&mut self, buffers: &[(u64, u32, bool)]);
+
+    /// Removes the next used descriptor chain, returns its head and the number of bytes the device wrote
+    fn try_remove_used(&mut self) -> Option<(u16, u32)>;
+
+    /// Number of used descriptor chains the driver has not removed yet
+    ///
+    /// For queues the driver sends on, these are the buffers `try_take` returns without waiting.
+    fn used_count(&self) -> usize;
+
+    /// Removes the next used buffer of a queue whose buffers were allocated by `new`
+    ///
+    /// The driver owns the buffer until the element is offered again.
+    fn try_take(&mut self) -> Option<VirtQueueElement>;
+
+    /// Offers the buffer of an element taken from this queue to the device again
+    fn offer(&mut self, element: VirtQueueElement);
+
+    /// Asks the device to not interrupt the driver after using buffers
+    ///
+    /// This is only a hint, the device may interrupt anyway.
+    fn set_interrupt_suppressed(&mut self, suppressed: bool);
+
+    /// Whether the device asks the driver to not notify it of offered buffers
+    fn notification_suppressed(&self) -> bool;
+
+    /// Returns the queue to the state after `new`, reusing its memory, after the device was reset
+    ///
+    /// The rings are cleared and all buffers are offered again, so elements taken before are stale.
+    fn reset(&mut self, receive: bool);
+
+    /// Address of the descriptor table or ring, see section 4.2.2
+    fn descriptor_area(&self) -> usize;
+
+    /// Address of the available ring or driver event suppression structure
+    fn driver_area(&self) -> usize;
+
+    /// Address of the used ring or device event suppression structure
+    fn device_area(&self) -> usize;
+}
+
+/// A virtqueue with the layout the device negotiated
+#[derive(Debug)]
+pub enum AnyVirtQueue {
+    Split(VirtQueueHandle),
+    Packed(PackedVirtQueueHandle),
+}
+
+/// A buffer taken from a virtqueue, owned by the driver until it is offered again
+///
+/// A queue hands out at most one element per descriptor, so the buffer is only accessed through
+/// it. An element has to be given back with `VirtQueue::offer` or, after a device reset, with
+/// `abandon`. Dropping it leaks the descriptor, which debug builds catch.
 #[derive(Debug)]
 pub struct VirtQueueElement {
     desc: RawVirtQueueDescriptorPointer,
-    pub desc_idx: u16,
+    desc_idx: u16,
+    /// Number of bytes the device wrote to the buffer
+    len: u32,
+    /// Size of the buffer the descriptor points to
+    capacity: u32,
+    /// Size of the virtio-net header in front of the frame
+    header_size: usize,
+    /// Descriptor area of the queue the element belongs to
+    queue: usize,
 }
 
 #[derive(Debug)]
 pub struct VirtQueueHandle {
     base_address: usize,
     queue_size: usize,
+    buffer_size: u32,
+    header_size: usize,
+    #[allow(dead_code)]
     last_seen_used_ring_idx: u16,
     descriptor_table: usize,
     available_ring: AvailableRingHandle,
@@ -53,6 +136,10 @@ pub struct RawVirtQueueDescriptorPointer {
 }
 
 impl RawVirtQueueDescriptorPointer {
+    pub fn new(ptr: usize) -> Self {
+        RawVirtQueueDescriptorPointer { ptr }
+    }
+
     pub fn get_addr(&self) -> u64 {
         unsafe { ((self.ptr + 0) as *const u64).read_volatile() }
     }
@@ -61,6 +148,14 @@ impl RawVirtQueueDescriptorPointer {
         unsafe { ((self.ptr + 8) as *const u32).read_volatile() }
     }
 
+    pub fn get_flags(&self) -> u16 {
+        unsafe { ((self.ptr + 12) as *const u16).read_volatile() }
+    }
+
+    pub fn get_next(&self) -> u16 {
+        unsafe { ((self.ptr + 14) as *const u16).read_volatile() }
+    }
+
     pub fn set_addr(&mut self, addr: u64) {
         unsafe { ((self.ptr + 0) as *mut u64).write_volatile(addr) }
     }
@@ -91,30 +186,138 @@ impl RawVirtQueueUsedElementPointer {
     pub fn get_id(&self) -> u32 {
         unsafe { ((self.ptr + 0) as *const u32).read_volatile() }
     }
+
+    pub fn get_len(&self) -> u32 {
+        unsafe { ((self.ptr + 4) as *const u32).read_volatile() }
+    }
 }
 
 impl VirtQueueElement {
+    /// Only for the queues, which must not hand out a descriptor again before it is offered
+    pub fn new(
+        desc: RawVirtQueueDescriptorPointer,
+        desc_idx: u16,
+        len: u32,
+        capacity: u32,
+        header_size: usize,
+        queue: usize,
+    ) -> Self {
+        VirtQueueElement {
+            desc,
+            desc_idx,
+            len,
+            capacity,
+            header_size,
+            queue,
+        }
+    }
+
+    /// Gives the descriptor back to the queue at `queue`, which offers it to the device
+    pub fn release(self, queue: usize) -> u16 {
+        let (desc_idx, own_queue) = (self.desc_idx, self.queue);
+        core::mem::forget(self);
+        if cfg!(debug_assertions) && own_queue != queue {
+            panic!("descriptor {} offered to the virtqueue at 0x{:x} it was not taken from", desc_idx, queue);
+        }
+        desc_idx
+    }
+
+    /// Gives up an element whose queue was reset after it was taken
+    ///
+    /// The reset offered the buffer to the device again, so the element must not be offered.
+    pub fn abandon(self) {
+        core::mem::forget(self);
+    }
+
     #[inline(never)]
     pub fn as_network_packet(&self) -> (RawVirtioNetHeaderShortPointer, &[u8]) {
-        self.desc.as_network_packet()
+        self.desc.as_network_packet(self.len as usize, self.header_size)
+    }
+
+    /// The frame part of the whole buffer, for building outgoing packets
+    #[inline(never)]
+    pub fn as_network_packet_mut(&mut self) -> &mut [u8] {
+        self.desc.as_network_packet_mut(self.capacity as usize, self.header_size)
+    }
+
+    /// Sets the descriptor length to cover the virtio-net header and a frame of `len` bytes
+    pub fn set_network_packet_len(&mut self, len: usize) {
+        self.desc.set_len((self.header_size + len) as u32);
     }
 
+    /// Copies the frame of `source` behind a cleared virtio-net header and sets the descriptor length accordingly
+    ///
+    /// The ports of the elements may use virtio-net headers of different sizes, and a transmitted header must not
+    /// carry the received num_buffers, so only the frame is copied.
     #[inline(never)]
-    pub fn copy_from(&self, source: &VirtQueueElement) {
-        let dest_desc: &mut [u8] = unsafe { slice::from_raw_parts_mut(self.desc.get_addr() as _, self.desc.get_len() as usize) };
-        let src_desc: &[u8] = unsafe { slice::from_raw_parts_mut(source.desc.get_addr() as _, self.desc.get_len() as usize) };
-        dest_desc.copy_from_slice(src_desc);
+    pub fn copy_from(&mut self, source: &VirtQueueElement) {
+        let (_, frame) = source.as_network_packet();
+        let out = self.as_network_packet_mut();
+        let len = core::cmp::min(frame.len(), out.len());
+        out[..len].copy_from_slice(&frame[..len]);
+        self.set_network_packet_len(len);
     }
 }
 
+impl Drop for VirtQueueElement {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) && !unwinding() {
+            panic!("descriptor {} of the virtqueue at 0x{:x} leaked", self.desc_idx, self.queue);
+        }
+    }
+}
+
+/// Whether the thread unwinds from a panic, which must not panic again, host tests only
+#[cfg(test)]
+fn unwinding() -> bool {
+    std::thread::panicking()
+}
+
+/// Panics abort the firmware
+#[cfg(not(test))]
+fn unwinding() -> bool {
+    false
+}
+
 impl VirtQueueHandle {
+    /// Allocates the rings and a buffer of `buffer_size` per descriptor, which are all offered
+    ///
+    /// `header_size` is the size of the virtio-net header the buffers start with.
     #[inline(never)]
-    pub fn new(queue_size: usize, memory: &mut MemoryHandle, receive: bool) -> Self {
+    pub fn new(
+        queue_size: usize,
+        buffer_size: u32,
+        header_size: usize,
+        memory: &mut MemoryHandle,
+        receive: bool,
+    ) -> Result<Self, MemoryReservationError> {
+        let mut virtqueue = Self::new_unbuffered(queue_size, memory)?;
+        virtqueue.buffer_size = buffer_size;
+        virtqueue.header_size = header_size;
+
+        for i in 0..queue_size {
+            let descriptor_address = memory.allocate(buffer_size as usize, 16)? as u64;
+            let flags = if receive { VIRTQ_DESC_F_WRITE } else { 0 };
+            virtqueue.update_descriptor(i as u16, descriptor_address, buffer_size, flags, 0);
+        }
+        for i in 0..queue_size {
+            virtqueue.available_ring.advance(i as u16);
+        }
+        Ok(virtqueue)
+    }
+
+    /// Allocates the rings only, the descriptors are filled by `submit`
+    ///
+    /// `queue_size` must be a power of two, so the free running ring indices wrap around it.
+    pub fn new_unbuffered(queue_size: usize, memory: &mut MemoryHandle) -> Result<Self, MemoryReservationError> {
         let total_size = virtqueue_size(queue_size as usize, MMIO_QUEUE_ALIGN as usize);
-        let virtqueue_address = memory.allocate(total_size, 16).unwrap();
-        let mut virtqueue = VirtQueueHandle {
+        // The device places the used ring at the next multiple of the queue alignment, see section 2.4.2
+        let virtqueue_address = memory.allocate(total_size, MMIO_QUEUE_ALIGN as u32 + 1)?;
+        Ok(VirtQueueHandle {
             base_address: virtqueue_address,
             queue_size: queue_size,
+            buffer_size: 0,
+            header_size: 0,
             last_seen_used_ring_idx: 0,
             descriptor_table: virtqueue_address,
             available_ring: AvailableRingHandle::from_address(
@@ -125,58 +328,200 @@ impl VirtQueueHandle {
                 virtqueue_address + used_ring_offset(queue_size, MMIO_QUEUE_ALIGN),
                 queue_size,
             ),
-        };
+        })
+    }
 
-        for i in 0..queue_size {
-            let descriptor_address = memory.allocate(BUFFER_SIZE as usize, 16).unwrap() as u64;
-            let flags = if receive { 2 } else { 0 };
-            virtqueue.update_descriptor(i as u16, descriptor_address, BUFFER_SIZE, flags, 0);
+    fn get_descriptor(&mut self, descriptor_idx: u16) -> RawVirtQueueDescriptorPointer {
+        RawVirtQueueDescriptorPointer {
+            ptr: self.descriptor_table + (descriptor_idx as usize * 16) //TODO!
         }
-        for i in 0..1024 {
-            virtqueue.offer(i as u16);
+    }
+
+    #[inline(never)]
+    fn update_descriptor(&mut self, descriptor_idx: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let mut descriptor_ptr = self.get_descriptor(descriptor_idx);
+        descriptor_ptr.set_addr(addr);
+        descriptor_ptr.set_len(len);
+        descriptor_ptr.set_flags(flags);
+        descriptor_ptr.set_next(next);
+    }
+}
+
+impl VirtQueue for VirtQueueHandle {
+    fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    fn submit(&mut self, buffers: &[(u64, u32, bool)]) {
+        for (i, &(addr, len, device_writable)) in buffers.iter().enumerate() {
+            let mut flags = if device_writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            self.update_descriptor(i as u16, addr, len, flags, i as u16 + 1);
         }
+        self.available_ring.advance(0);
+    }
 
-        atomic::fence(Ordering::AcqRel);
-        virtqueue
+    fn try_remove_used(&mut self) -> Option<(u16, u32)> {
+        self.used_ring.try_remove()
+    }
+
+    fn used_count(&self) -> usize {
+        self.used_ring.pending()
     }
 
     #[inline(never)]
-    pub fn try_take(&mut self) -> Option<VirtQueueElement> {
-        atomic::fence(Ordering::AcqRel);
-        if let Some(descriptor_idx) = self.used_ring.try_remove() {
+    fn try_take(&mut self) -> Option<VirtQueueElement> {
+        if let Some((descriptor_idx, len)) = self.used_ring.try_remove() {
             let desc_ptr = self.get_descriptor(descriptor_idx);
-            Some(VirtQueueElement {
-                desc: desc_ptr,
-                desc_idx: descriptor_idx
-            })
+            Some(VirtQueueElement::new(
+                desc_ptr,
+                descriptor_idx,
+                len,
+                self.buffer_size,
+                self.header_size,
+                self.descriptor_table,
+            ))
         } else {
             None
         }
     }
 
     #[inline(never)]
-    pub fn offer(&mut self, desc_idx: u16) {
-        atomic::fence(Ordering::AcqRel);
+    fn offer(&mut self, element: VirtQueueElement) {
+        let desc_idx = element.release(self.descriptor_table);
         self.available_ring.advance(desc_idx);
     }
 
-    fn get_descriptor(&mut self, descriptor_idx: u16) -> RawVirtQueueDescriptorPointer {
-        RawVirtQueueDescriptorPointer {
-            ptr: self.descriptor_table + (descriptor_idx as usize * 16) //TODO!
+    fn set_interrupt_suppressed(&mut self, suppressed: bool) {
+        let flags = if suppressed { VIRTQ_AVAIL_F_NO_INTERRUPT } else { 0 };
+        self.available_ring.set_flags(flags);
+        // The device must see the flag before the driver checks the used ring again
+        barrier::memory_barrier();
+    }
+
+    fn notification_suppressed(&self) -> bool {
+        // The offered buffers must be visible before the flag is read, see section 2.4.7.2
+        barrier::memory_barrier();
+        self.used_ring.flags() & VIRTQ_USED_F_NO_NOTIFY != 0
+    }
+
+    fn reset(&mut self, receive: bool) {
+        let rings = available_ring_offset(self.queue_size);
+        let rings_size = virtqueue_size(self.queue_size, MMIO_QUEUE_ALIGN) - rings;
+        unsafe { core::ptr::write_bytes((self.base_address + rings) as *mut u8, 0, rings_size) };
+        self.used_ring.last_seen_idx = 0;
+        // Unbuffered queues fill their descriptors on every submit
+        if self.buffer_size == 0 {
+            return;
+        }
+        for i in 0..self.queue_size {
+            let descriptor_address = self.get_descriptor(i as u16).get_addr();
+            let flags = if receive { VIRTQ_DESC_F_WRITE } else { 0 };
+            self.update_descriptor(i as u16, descriptor_address, self.buffer_size, flags, 0);
+        }
+        for i in 0..self.queue_size {
+            self.available_ring.advance(i as u16);
         }
     }
 
-    #[inline(never)]
-    fn update_descriptor(&mut self, descriptor_idx: u16, addr: u64, len: u32, flags: u16, next: u16) {
-        let mut descriptor_ptr = self.get_descriptor(descriptor_idx);
-        descriptor_ptr.set_addr(addr);
-        descriptor_ptr.set_len(len);
-        descriptor_ptr.set_flags(flags);
-        descriptor_ptr.set_next(next);
+    fn descriptor_area(&self) -> usize {
+        self.descriptor_table
     }
 
-    pub fn base_address(&self) -> usize {
-        self.base_address
+    fn driver_area(&self) -> usize {
+        self.base_address + available_ring_offset(self.queue_size)
+    }
+
+    fn device_area(&self) -> usize {
+        self.base_address + used_ring_offset(self.queue_size, MMIO_QUEUE_ALIGN)
+    }
+}
+
+impl VirtQueue for AnyVirtQueue {
+    fn queue_size(&self) -> usize {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.queue_size(),
+            AnyVirtQueue::Packed(queue) => queue.queue_size(),
+        }
+    }
+
+    fn submit(&mut self, buffers: &[(u64, u32, bool)]) {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.submit(buffers),
+            AnyVirtQueue::Packed(queue) => queue.submit(buffers),
+        }
+    }
+
+    fn try_remove_used(&mut self) -> Option<(u16, u32)> {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.try_remove_used(),
+            AnyVirtQueue::Packed(queue) => queue.try_remove_used(),
+        }
+    }
+
+    fn used_count(&self) -> usize {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.used_count(),
+            AnyVirtQueue::Packed(queue) => queue.used_count(),
+        }
+    }
+
+    fn try_take(&mut self) -> Option<VirtQueueElement> {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.try_take(),
+            AnyVirtQueue::Packed(queue) => queue.try_take(),
+        }
+    }
+
+    fn offer(&mut self, element: VirtQueueElement) {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.offer(element),
+            AnyVirtQueue::Packed(queue) => queue.offer(element),
+        }
+    }
+
+    fn set_interrupt_suppressed(&mut self, suppressed: bool) {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.set_interrupt_suppressed(suppressed),
+            AnyVirtQueue::Packed(queue) => queue.set_interrupt_suppressed(suppressed),
+        }
+    }
+
+    fn notification_suppressed(&self) -> bool {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.notification_suppressed(),
+            AnyVirtQueue::Packed(queue) => queue.notification_suppressed(),
+        }
+    }
+
+    fn reset(&mut self, receive: bool) {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.reset(receive),
+            AnyVirtQueue::Packed(queue) => queue.reset(receive),
+        }
+    }
+
+    fn descriptor_area(&self) -> usize {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.descriptor_area(),
+            AnyVirtQueue::Packed(queue) => queue.descriptor_area(),
+        }
+    }
+
+    fn driver_area(&self) -> usize {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.driver_area(),
+            AnyVirtQueue::Packed(queue) => queue.driver_area(),
+        }
+    }
+
+    fn device_area(&self) -> usize {
+        match self {
+            AnyVirtQueue::Split(queue) => queue.device_area(),
+            AnyVirtQueue::Packed(queue) => queue.device_area(),
+        }
     }
 }
 
@@ -194,6 +539,10 @@ impl AvailableRingHandle {
         unsafe { self.idx.read_volatile() }
     }
 
+    pub fn set_flags(&mut self, flags: u16) {
+        unsafe { self.flags.write_volatile(flags) }
+    }
+
     #[inline(never)]
     pub fn advance(&mut self, descriptor_idx: u16) {
         unsafe {
@@ -201,11 +550,11 @@ impl AvailableRingHandle {
             self.ring
                 .offset((self.idx() % (self.queue_size as u16)) as isize)
                 .write_volatile(descriptor_idx);
-            atomic::fence(Ordering::AcqRel);
+            // The descriptors and the ring entry must be visible before the index, see section 2.6.13.3
+            barrier::write_barrier();
 
             // advance head
             self.idx.write_volatile(self.idx().wrapping_add(1));
-            atomic::fence(Ordering::AcqRel);
         }
     }
 }
@@ -221,16 +570,29 @@ impl UsedRingHandle {
         }
     }
 
+    pub fn flags(&self) -> u16 {
+        unsafe { self.flags.read_volatile() }
+    }
+
+    /// Number of entries the device added since the last `try_remove`
+    pub fn pending(&self) -> usize {
+        let used_idx = unsafe { self.idx.read_volatile() };
+        used_idx.wrapping_sub(self.last_seen_idx) as usize
+    }
+
     #[inline(never)]
-    pub fn try_remove(&mut self) -> Option<u16> {
+    pub fn try_remove(&mut self) -> Option<(u16, u32)> {
         let used_idx = unsafe { self.idx.read_volatile() };
         if self.last_seen_idx != used_idx {
+            // The entry and the buffer must not be read before the index, see section 2.6.14
+            barrier::read_barrier();
             let used_element_ptr = RawVirtQueueUsedElementPointer {
                 ptr: self.ring + ((self.last_seen_idx % self.queue_size as u16) as usize * 8)
             };
             let used_element_id = used_element_ptr.get_id();
+            let used_element_len = used_element_ptr.get_len();
             self.last_seen_idx = self.last_seen_idx.wrapping_add(1);
-            Some(used_element_id as u16 % self.queue_size as u16)
+            Some((used_element_id as u16 % self.queue_size as u16, used_element_len))
         } else {
             None
         }
@@ -299,3 +661,99 @@ impl ::core::fmt::Debug for RawVirtQueueUsedElementPointer {
         */
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_device::{self, Chain, DeviceQueue};
+    use crate::virtqueue_network::NET_HEADER_SIZE;
+
+    fn device_queue(queue: &VirtQueueHandle) -> DeviceQueue {
+        DeviceQueue::split(
+            queue.descriptor_area(),
+            queue.driver_area(),
+            queue.device_area(),
+            queue.queue_size() as u16,
+        )
+    }
+
+    /// Fills a receive buffer with a frame of `len` times `byte`
+    fn receive(device: &mut DeviceQueue, chain: Chain, byte: u8, len: usize) {
+        let mut data = vec![0; NET_HEADER_SIZE];
+        data.resize(NET_HEADER_SIZE + len, byte);
+        let written = chain.write(&data);
+        device.push(chain, written);
+    }
+
+    #[test]
+    fn takes_used_buffers_and_offers_them_again() {
+        let mut memory = mock_device::guest_memory(0x10000);
+        let mut queue = VirtQueueHandle::new(8, 128, NET_HEADER_SIZE, &mut memory, true).unwrap();
+        let mut device = device_queue(&queue);
+        assert!(queue.try_take().is_none());
+
+        let chains: Vec<Chain> = core::iter::from_fn(|| device.pop()).collect();
+        assert_eq!(chains.len(), 8);
+        assert!(chains.iter().all(|chain| chain.buffers.len() == 1 && chain.buffers[0].1 == 128));
+        let mut chains = chains.into_iter();
+        receive(&mut device, chains.next().unwrap(), 1, 20);
+        receive(&mut device, chains.next().unwrap(), 2, 30);
+
+        let first = queue.try_take().unwrap();
+        let second = queue.try_take().unwrap();
+        assert!(queue.try_take().is_none());
+        assert_eq!(first.as_network_packet().1, &[1; 20][..]);
+        assert_eq!(second.as_network_packet().1, &[2; 30][..]);
+
+        queue.offer(second);
+        queue.offer(first);
+        assert_eq!(device.pop().unwrap().id, 1);
+        assert_eq!(device.pop().unwrap().id, 0);
+        assert!(device.pop().is_none());
+    }
+
+    #[test]
+    fn used_ring_index_wraps_around() {
+        let mut memory = mock_device::guest_memory(0x10000);
+        let mut queue = VirtQueueHandle::new(4, 64, NET_HEADER_SIZE, &mut memory, true).unwrap();
+        let mut device = device_queue(&queue);
+        // Three buffers at a time pass through the queue until the free running indices wrapped
+        for round in 0..25_000u32 {
+            for i in 0..3 {
+                let chain = device.pop().unwrap();
+                receive(&mut device, chain, (round + i) as u8, 16);
+            }
+            for i in 0..3 {
+                let element = queue.try_take().unwrap();
+                assert_eq!(element.as_network_packet().1, &[(round + i) as u8; 16][..]);
+                queue.offer(element);
+            }
+            assert!(queue.try_take().is_none());
+        }
+        assert_eq!(queue.used_ring.last_seen_idx, (75_000 % 65_536) as u16);
+        assert_eq!(queue.available_ring.idx(), ((4 + 75_000) % 65_536) as u16);
+    }
+
+    #[test]
+    #[should_panic(expected = "leaked")]
+    fn dropping_element_panics() {
+        let mut memory = mock_device::guest_memory(0x10000);
+        let mut queue = VirtQueueHandle::new(4, 64, NET_HEADER_SIZE, &mut memory, true).unwrap();
+        let mut device = device_queue(&queue);
+        let chain = device.pop().unwrap();
+        receive(&mut device, chain, 0, 16);
+        drop(queue.try_take());
+    }
+
+    #[test]
+    #[should_panic(expected = "not taken from")]
+    fn offering_element_to_other_queue_panics() {
+        let mut memory = mock_device::guest_memory(0x10000);
+        let mut queue = VirtQueueHandle::new(4, 64, NET_HEADER_SIZE, &mut memory, true).unwrap();
+        let mut other_queue = VirtQueueHandle::new(4, 64, NET_HEADER_SIZE, &mut memory, true).unwrap();
+        let mut device = device_queue(&queue);
+        let chain = device.pop().unwrap();
+        receive(&mut device, chain, 0, 16);
+        other_queue.offer(queue.try_take().unwrap());
+    }
+}
@@ -1,5 +1,9 @@
 use crate::virtqueue::RawVirtQueueDescriptorPointer;
 
+pub const NET_HEADER_SIZE: usize = core::mem::size_of::<RawVirtioNetHeaderShort>();
+/// With VIRTIO_F_VERSION_1 the header always ends with num_buffers, see section 5.1.6
+pub const NET_HEADER_SIZE_VERSION_1: usize = NET_HEADER_SIZE + 2;
+
 #[repr(C, packed)]
 struct RawVirtioNetHeaderShort {
     flags: u8,
@@ -12,6 +16,7 @@ struct RawVirtioNetHeaderShort {
 
 #[derive(Clone, Copy, Debug)]
 pub struct RawVirtioNetHeaderShortPointer {
+    #[allow(dead_code)]
     address: u64,
 }
 
@@ -20,20 +25,34 @@ impl RawVirtioNetHeaderShortPointer {
 }
 
 pub trait NetworkDescriptor {
-    fn as_network_packet(&self) -> (RawVirtioNetHeaderShortPointer, &[u8]);
+    /// Splits the first `len` bytes of the buffer into the virtio-net header of `header_size` bytes and the frame
+    fn as_network_packet(&self, len: usize, header_size: usize) -> (RawVirtioNetHeaderShortPointer, &[u8]);
+
+    /// Clears the virtio-net header and returns the frame part of the first `capacity` bytes
+    fn as_network_packet_mut(&mut self, capacity: usize, header_size: usize) -> &mut [u8];
 }
 
 impl NetworkDescriptor for RawVirtQueueDescriptorPointer {
     #[inline(never)]
-    fn as_network_packet(&self) -> (RawVirtioNetHeaderShortPointer, &[u8]) {
+    fn as_network_packet(&self, len: usize, header_size: usize) -> (RawVirtioNetHeaderShortPointer, &[u8]) {
         let data = self.data();
-        let header_size = core::mem::size_of::<RawVirtioNetHeaderShort>();
-        let (_header_bytes, data_bytes) = data.split_at(header_size);
+        let len = core::cmp::max(core::cmp::min(len, data.len()), header_size);
+        let (_header_bytes, data_bytes) = data[..len].split_at(header_size);
         let header = RawVirtioNetHeaderShortPointer {
             address: self.get_addr()
         };
         (header, data_bytes)
     }
+
+    #[inline(never)]
+    fn as_network_packet_mut(&mut self, capacity: usize, header_size: usize) -> &mut [u8] {
+        let data = unsafe { core::slice::from_raw_parts_mut(self.get_addr() as *mut u8, capacity) };
+        let (header_bytes, data_bytes) = data.split_at_mut(header_size);
+        for byte in header_bytes.iter_mut() {
+            *byte = 0;
+        }
+        data_bytes
+    }
 }
 
 impl ::core::fmt::Debug for RawVirtioNetHeaderShort {
@@ -0,0 +1,366 @@
+use crate::barrier;
+use crate::errors::MemoryReservationError;
+use crate::memory_handle::MemoryHandle;
+use crate::virtqueue::{RawVirtQueueDescriptorPointer, VirtQueue, VirtQueueElement};
+use crate::virtqueue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+/// Set to the driver's wrap counter when the driver makes a descriptor available, see section 2.7.1
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+/// Set to the device's wrap counter when the device used a descriptor
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+/// Flags of the event suppression structures, see section 2.7.10
+const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// Size of the driver and the device event suppression structure
+const EVENT_SUPPRESSION_SIZE: usize = 4;
+
+// packed descriptor, see section 2.7:
+// 8 bytes buffer address
+// 4 bytes buffer length, set to the written length by the device
+// 2 bytes buffer ID
+// 2 bytes flags
+//
+// event suppression structure:
+// 2 bytes descriptor ring offset and wrap counter, only used with VIRTIO_F_RING_EVENT_IDX
+// 2 bytes flags
+
+/// A packed virtqueue, see section 2.7
+///
+/// Driver and device share a single descriptor ring. The driver writes descriptors at `next_avail`
+/// and the device overwrites them with used descriptors in the order it used them, which the driver
+/// reads at `next_used`. The wrap counters tell descriptors of the current pass over the ring from
+/// those of the previous one.
+#[derive(Debug)]
+pub struct PackedVirtQueueHandle {
+    ring: usize,
+    /// Written by the driver, tells the device whether to interrupt
+    driver_event: usize,
+    /// Written by the device, tells the driver whether to notify
+    device_event: usize,
+    /// The buffers by buffer ID, in the layout of split descriptors
+    ///
+    /// The ring only holds the buffers while they are available, so the driver keeps their address,
+    /// length and flags here. The `next` field holds the number of ring descriptors of the buffer.
+    buffers: usize,
+    queue_size: usize,
+    buffer_size: u32,
+    header_size: usize,
+    next_avail: u16,
+    avail_wrap_counter: bool,
+    next_used: u16,
+    used_wrap_counter: bool,
+}
+
+impl PackedVirtQueueHandle {
+    /// Allocates the ring and a buffer of `buffer_size` per descriptor, which are all offered
+    ///
+    /// `header_size` is the size of the virtio-net header the buffers start with.
+    pub fn new(
+        queue_size: usize,
+        buffer_size: u32,
+        header_size: usize,
+        memory: &mut MemoryHandle,
+        receive: bool,
+    ) -> Result<Self, MemoryReservationError> {
+        let mut virtqueue = Self::new_unbuffered(queue_size, memory)?;
+        virtqueue.buffer_size = buffer_size;
+        virtqueue.header_size = header_size;
+
+        for i in 0..queue_size {
+            let buffer_address = memory.allocate(buffer_size as usize, 16)? as u64;
+            virtqueue.update_buffer(i as u16, buffer_address, receive);
+        }
+        for i in 0..queue_size {
+            virtqueue.offer_buffer(i as u16);
+        }
+        Ok(virtqueue)
+    }
+
+    /// Allocates the ring only, the descriptors are filled by `submit`
+    pub fn new_unbuffered(queue_size: usize, memory: &mut MemoryHandle) -> Result<Self, MemoryReservationError> {
+        let ring = memory.allocate(queue_size * DESCRIPTOR_SIZE, 16)?;
+        let driver_event = memory.allocate(2 * EVENT_SUPPRESSION_SIZE, 4)?;
+        let buffers = memory.allocate(queue_size * DESCRIPTOR_SIZE, 16)?;
+        let mut virtqueue = PackedVirtQueueHandle {
+            ring,
+            driver_event,
+            device_event: driver_event + EVENT_SUPPRESSION_SIZE,
+            buffers,
+            queue_size,
+            buffer_size: 0,
+            header_size: 0,
+            next_avail: 0,
+            avail_wrap_counter: true,
+            next_used: 0,
+            used_wrap_counter: true,
+        };
+        virtqueue.clear();
+        Ok(virtqueue)
+    }
+
+    /// Zeroes the ring and the event suppression structures and starts over at the first descriptor
+    fn clear(&mut self) {
+        unsafe {
+            core::ptr::write_bytes(self.ring as *mut u8, 0, self.queue_size * DESCRIPTOR_SIZE);
+            core::ptr::write_bytes(self.driver_event as *mut u8, 0, 2 * EVENT_SUPPRESSION_SIZE);
+        }
+        self.next_avail = 0;
+        self.avail_wrap_counter = true;
+        self.next_used = 0;
+        self.used_wrap_counter = true;
+    }
+
+    fn buffer(&self, id: u16) -> RawVirtQueueDescriptorPointer {
+        RawVirtQueueDescriptorPointer::new(self.buffers + id as usize * DESCRIPTOR_SIZE)
+    }
+
+    fn update_buffer(&mut self, id: u16, addr: u64, receive: bool) {
+        let mut buffer = self.buffer(id);
+        buffer.set_addr(addr);
+        buffer.set_len(self.buffer_size);
+        buffer.set_flags(if receive { VIRTQ_DESC_F_WRITE } else { 0 });
+        buffer.set_next(1);
+    }
+
+    /// Writes a descriptor at `next_avail` and advances it, the flags are returned instead of written
+    ///
+    /// The flags make the descriptor available, so they are written once the chain is complete.
+    fn write_descriptor(&mut self, addr: u64, len: u32, id: u16, flags: u16) -> (usize, u16) {
+        let descriptor = self.ring + self.next_avail as usize * DESCRIPTOR_SIZE;
+        unsafe {
+            (descriptor as *mut u64).write_volatile(addr);
+            ((descriptor + 8) as *mut u32).write_volatile(len);
+            ((descriptor + 12) as *mut u16).write_volatile(id);
+        }
+        let flags = if self.avail_wrap_counter {
+            flags | VIRTQ_DESC_F_AVAIL
+        } else {
+            flags | VIRTQ_DESC_F_USED
+        };
+        self.next_avail += 1;
+        if self.next_avail as usize == self.queue_size {
+            self.next_avail = 0;
+            self.avail_wrap_counter = !self.avail_wrap_counter;
+        }
+        (descriptor, flags)
+    }
+
+    /// Makes the buffer with the given ID available to the device
+    fn offer_buffer(&mut self, id: u16) {
+        let buffer = self.buffer(id);
+        let flags = buffer.get_flags() & VIRTQ_DESC_F_WRITE;
+        let (descriptor, flags) = self.write_descriptor(buffer.get_addr(), buffer.get_len(), id, flags);
+        Self::publish(descriptor, flags);
+    }
+
+    /// Returns the buffer ID and the written length if the device used the descriptor at `position`
+    ///
+    /// `wrap_counter` is the device's wrap counter the descriptor was used with.
+    fn used_at(&self, position: u16, wrap_counter: bool) -> Option<(u16, u32)> {
+        let descriptor = self.ring + position as usize * DESCRIPTOR_SIZE;
+        let flags = unsafe { ((descriptor + 14) as *const u16).read_volatile() };
+        let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        if avail != used || used != wrap_counter {
+            return None;
+        }
+        // The other fields and the buffer must not be read before the flags
+        barrier::read_barrier();
+        let id = unsafe { ((descriptor + 12) as *const u16).read_volatile() } % self.queue_size as u16;
+        let len = unsafe { ((descriptor + 8) as *const u32).read_volatile() };
+        Some((id, len))
+    }
+
+    /// The position and wrap counter after the used chain of buffer `id` at `position`
+    fn skip_chain(&self, position: u16, wrap_counter: bool, id: u16) -> (u16, bool) {
+        // The device writes a single used descriptor for a chain and skips the rest of it
+        let chain_len = core::cmp::max(self.buffer(id).get_next(), 1);
+        let next = position + chain_len;
+        if next as usize >= self.queue_size {
+            (next - self.queue_size as u16, !wrap_counter)
+        } else {
+            (next, wrap_counter)
+        }
+    }
+
+    /// Makes a descriptor written by `write_descriptor` available to the device
+    fn publish(descriptor: usize, flags: u16) {
+        // The other fields must be visible before the flags, like the available ring index of split queues
+        barrier::write_barrier();
+        unsafe { ((descriptor + 14) as *mut u16).write_volatile(flags) };
+    }
+}
+
+impl VirtQueue for PackedVirtQueueHandle {
+    fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    /// All descriptors of the chain carry buffer ID 0, whose `next` field records the chain length
+    /// for `skip_chain`. A second outstanding chain would overwrite that length, so the used chain of
+    /// the first would be skipped by the wrong number of descriptors.
+    fn submit(&mut self, buffers: &[(u64, u32, bool)]) {
+        let mut head = (0, 0);
+        for (i, &(addr, len, device_writable)) in buffers.iter().enumerate() {
+            let mut flags = if device_writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let (descriptor, flags) = self.write_descriptor(addr, len, 0, flags);
+            if i == 0 {
+                head = (descriptor, flags);
+            } else {
+                unsafe { ((descriptor + 14) as *mut u16).write_volatile(flags) };
+            }
+        }
+        self.buffer(0).set_next(buffers.len() as u16);
+        // The device must not see the head before the rest of the chain
+        Self::publish(head.0, head.1);
+    }
+
+    fn try_remove_used(&mut self) -> Option<(u16, u32)> {
+        let (id, len) = self.used_at(self.next_used, self.used_wrap_counter)?;
+        let (next_used, used_wrap_counter) = self.skip_chain(self.next_used, self.used_wrap_counter, id);
+        self.next_used = next_used;
+        self.used_wrap_counter = used_wrap_counter;
+        Some((id, len))
+    }
+
+    fn used_count(&self) -> usize {
+        let (mut position, mut wrap_counter) = (self.next_used, self.used_wrap_counter);
+        let mut count = 0;
+        while count < self.queue_size {
+            match self.used_at(position, wrap_counter) {
+                Some((id, _)) => {
+                    let next = self.skip_chain(position, wrap_counter, id);
+                    position = next.0;
+                    wrap_counter = next.1;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    }
+
+    fn try_take(&mut self) -> Option<VirtQueueElement> {
+        let (id, len) = self.try_remove_used()?;
+        Some(VirtQueueElement::new(
+            self.buffer(id),
+            id,
+            len,
+            self.buffer_size,
+            self.header_size,
+            self.ring,
+        ))
+    }
+
+    fn offer(&mut self, element: VirtQueueElement) {
+        let id = element.release(self.ring);
+        self.offer_buffer(id);
+    }
+
+    fn set_interrupt_suppressed(&mut self, suppressed: bool) {
+        let flags = if suppressed { RING_EVENT_FLAGS_DISABLE } else { RING_EVENT_FLAGS_ENABLE };
+        unsafe { ((self.driver_event + 2) as *mut u16).write_volatile(flags) };
+        // The device must see the flags before the driver checks the ring again
+        barrier::memory_barrier();
+    }
+
+    fn notification_suppressed(&self) -> bool {
+        // The offered descriptors must be visible before the flags are read, see section 2.7.10
+        barrier::memory_barrier();
+        let flags = unsafe { ((self.device_event + 2) as *const u16).read_volatile() };
+        flags == RING_EVENT_FLAGS_DISABLE
+    }
+
+    fn reset(&mut self, receive: bool) {
+        self.clear();
+        // Unbuffered queues fill their descriptors on every submit
+        if self.buffer_size == 0 {
+            return;
+        }
+        for i in 0..self.queue_size {
+            let buffer_address = self.buffer(i as u16).get_addr();
+            self.update_buffer(i as u16, buffer_address, receive);
+        }
+        for i in 0..self.queue_size {
+            self.offer_buffer(i as u16);
+        }
+    }
+
+    fn descriptor_area(&self) -> usize {
+        self.ring
+    }
+
+    fn driver_area(&self) -> usize {
+        self.driver_event
+    }
+
+    fn device_area(&self) -> usize {
+        self.device_event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_device::{self, DeviceQueue};
+    use crate::virtqueue_network::NET_HEADER_SIZE_VERSION_1;
+
+    fn device_queue(queue: &PackedVirtQueueHandle) -> DeviceQueue {
+        DeviceQueue::packed(
+            queue.descriptor_area(),
+            queue.driver_area(),
+            queue.device_area(),
+            queue.queue_size() as u16,
+        )
+    }
+
+    #[test]
+    fn wrap_counters_follow_the_ring() {
+        let mut memory = mock_device::guest_memory(0x10000);
+        let mut queue = PackedVirtQueueHandle::new(4, 64, NET_HEADER_SIZE_VERSION_1, &mut memory, true).unwrap();
+        let mut device = device_queue(&queue);
+        // Three buffers at a time, so the ring wraps at a different descriptor every round
+        for round in 0..1000u32 {
+            for i in 0..3 {
+                let chain = device.pop().unwrap();
+                let mut data = vec![0; NET_HEADER_SIZE_VERSION_1];
+                data.resize(NET_HEADER_SIZE_VERSION_1 + 16, (round + i) as u8);
+                let len = chain.write(&data);
+                device.push(chain, len);
+            }
+            assert_eq!(queue.used_count(), 3);
+            for i in 0..3 {
+                let element = queue.try_take().unwrap();
+                assert_eq!(element.as_network_packet().1, &[(round + i) as u8; 16][..]);
+                queue.offer(element);
+            }
+            assert!(queue.try_take().is_none());
+        }
+    }
+
+    #[test]
+    fn used_chain_skips_its_descriptors() {
+        let mut memory = mock_device::guest_memory(0x10000);
+        let mut queue = PackedVirtQueueHandle::new_unbuffered(8, &mut memory).unwrap();
+        let mut device = device_queue(&queue);
+        let buffer = memory.allocate(16, 16).unwrap() as u64;
+        // Chains of three descriptors wrap in the middle of a chain every few rounds
+        for _ in 0..100 {
+            queue.submit(&[(buffer, 2, false), (buffer + 2, 4, false), (buffer + 15, 1, true)]);
+            let chain = device.pop().unwrap();
+            assert_eq!(chain.buffers, [(buffer, 2, false), (buffer + 2, 4, false), (buffer + 15, 1, true)]);
+            assert!(device.pop().is_none());
+            assert_eq!(queue.used_count(), 0);
+            device.push(chain, 1);
+            assert_eq!(queue.used_count(), 1);
+            assert_eq!(queue.try_remove_used(), Some((0, 1)));
+            assert_eq!(queue.used_count(), 0);
+            assert_eq!(queue.try_remove_used(), None);
+        }
+    }
+}